@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::sync::{Arc, Mutex};
@@ -10,11 +11,12 @@ use pyo3::exceptions::{PyKeyError, PyRuntimeError, PyValueError, PyIOError};
 use pythonize::{pythonize, depythonize};
 
 use territory_core::pblib::decode_many;
-use territory_core::Node;
+use territory_core::{GenHref, Node};
 use territory_core::resolver::{NeedData, ResolutionFailure, Resolver, SingleBlobResolver, TrieResolver};
 use territory_core::territory::index as pb;
 use territory_core::search::{self, TrieIndex};
 use territory_core::slicemap_trie::{SharedCache, SlicemapReader};
+use territory_core::text_search::{decode_postings, TextQuery};
 
 
 fn runtime_err_str<T>(e: T) -> PyErr where T: ToString {
@@ -110,7 +112,12 @@ impl SharedResolverCache {
                 .ok_or(PyValueError::new_err("missing references_trie_root"))?,
             SharedCache::new_handle(
                 &self.cache, &format!("{}/r", repo_id)));
-        let resolver = TrieResolver::new(backup_resolver, nodemap, symmap, refmap, build.repo_root_node_id);
+        let mut resolver = TrieResolver::new(backup_resolver, nodemap, symmap, refmap, build.repo_root_node_id);
+        if let Some(root) = build.text_index_trie_root {
+            resolver = resolver.with_text_index(SlicemapReader::new(
+                root,
+                SharedCache::new_handle(&self.cache, &format!("{}/t", repo_id))));
+        }
         Ok(PyResolver { resolver: Box::new(resolver) })
     }
 }
@@ -186,6 +193,50 @@ impl PyTrieIndex {
 }
 
 
+#[pyclass]
+pub struct PyTextQuery(TextQuery);
+
+#[pymethods]
+impl PyTextQuery {
+    #[new]
+    pub fn new(pattern: &str, opts: &PyAny) -> PyResult<Self> {
+        let opts = depythonize(opts)?;
+        let query = TextQuery::new(pattern, &opts).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(Self(query))
+    }
+
+    /// `trigram:` urls whose postings `candidates` needs.
+    pub fn trigram_urls(&self) -> Vec<String> {
+        self.0.trigram_query().trigrams()
+            .into_iter()
+            .map(|t| GenHref::Trigram(t).to_url())
+            .collect()
+    }
+
+    /// Takes `(url, bytes)` pairs for the trigram urls that resolved and
+    /// returns the `id:` urls of the nodes to pass to `matches`.
+    pub fn candidates(&self, postings: Vec<(String, &[u8])>) -> PyResult<Vec<String>> {
+        let mut decoded = BTreeMap::new();
+        for (url, data) in postings {
+            let Some(GenHref::Trigram(t)) = GenHref::from_url(&url) else {
+                return Err(PyValueError::new_err(format!("not a trigram url: {url}")));
+            };
+            let p = pb::TrigramPostings::decode(data).map_err(runtime_err_str)?;
+            decoded.insert(t, decode_postings(&p));
+        }
+        Ok(self.0.candidates(&decoded)
+            .into_iter()
+            .map(|id| GenHref::NodeId(id).to_url())
+            .collect())
+    }
+
+    pub fn matches(&self, py: Python, data: &[u8]) -> PyResult<PyObject> {
+        let pb_node = pb::Node::decode(data).map_err(runtime_err_str)?;
+        Ok(pythonize(py, &self.0.matches(&pb_node))?)
+    }
+}
+
+
 #[pyfunction]
 pub fn trie_from_strings<'py>(py: Python<'py>, strings: &PyAny) -> PyResult<&'py PyBytes> {
     let mut strings: Vec<String> = depythonize(strings)?;
@@ -233,6 +284,7 @@ fn tt(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
 
     m.add_class::<PyTrieIndex>()?;
     m.add_function(wrap_pyfunction!(trie_from_strings, m)?)?;
+    m.add_class::<PyTextQuery>()?;
 
     m.add_function(wrap_pyfunction!(bytes_to_node, m)?)?;
 
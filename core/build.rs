@@ -28,9 +28,11 @@ fn main() -> Result<()> {
     c.type_attribute("territory.index.Reference", "#[derive(serde::Serialize)]");
     c.field_attribute("territory.index.Reference.href", "#[serde(with = \"crate::ser::gen_href\")]");
     c.type_attribute("territory.index.Reference.href", "#[derive(Hash, Copy, Eq, serde::Serialize)]");
+    c.type_attribute("territory.index.ReferenceKind", "#[derive(serde::Serialize)]");
 
     c.type_attribute("territory.index.References", "#[derive(serde::Serialize)]");
     c.field_attribute("territory.index.References.node_id", "#[serde(with = \"crate::ser::node_id\")]");
+    c.type_attribute("territory.index.Implementation", "#[derive(serde::Serialize)]");
 
     c.type_attribute("territory.index.IndexItemKind", "#[derive(serde::Serialize)]");
 
@@ -0,0 +1,396 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+
+use prost::Message;
+use serde::Serialize;
+
+use crate::slicemap_trie::slicemap_entries;
+use crate::territory::index::{self as pb, Build};
+use crate::{pb_node_tokens, BlobSliceLoc, NodeID, Offset, TokenKind};
+
+
+/// Access to the blobs of a single build.
+pub trait BuildSource {
+    fn build(&self) -> &Build;
+
+    /// Decompressed bytes of the slice at `loc`.
+    fn load_slice(&self, loc: BlobSliceLoc) -> Result<Vec<u8>, Box<dyn Error>>;
+}
+
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeSummary {
+    pub id: NodeID,
+    pub kind: String,
+    pub path: String,
+    pub line: u32,
+    /// First line of the node text.
+    pub title: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeChange {
+    pub old: NodeSummary,
+    pub new: NodeSummary,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SymbolSummary {
+    pub sym_id: u64,
+    pub node: NodeSummary,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SymbolMove {
+    pub sym_id: u64,
+    pub old: NodeSummary,
+    pub new: NodeSummary,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReferenceSummary {
+    pub use_path: String,
+    pub line: u32,
+    pub context: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReferencesChange {
+    /// Node defining the symbol.
+    pub node_id: NodeID,
+    pub symbol: String,
+    pub path: String,
+    pub gained: Vec<ReferenceSummary>,
+    pub lost: Vec<ReferenceSummary>,
+}
+
+/// Semantic difference between two builds of the same repository.
+///
+/// Node and sym IDs are stable across builds (the indexer keeps them in the
+/// intermediate DB), so entries are matched by ID; references are matched by
+/// the defining node and the sym ID of the referenced token, or its offset
+/// within the node for tokens without one, since the offset in the file
+/// shifts whenever the code above the node changes.
+#[derive(Serialize, Debug, Default)]
+pub struct BuildDiff {
+    pub old_build: String,
+    pub new_build: String,
+    pub nodes_added: Vec<NodeSummary>,
+    pub nodes_removed: Vec<NodeSummary>,
+    pub nodes_changed: Vec<NodeChange>,
+    pub symbols_added: Vec<SymbolSummary>,
+    pub symbols_removed: Vec<SymbolSummary>,
+    /// Symbols now defined in another file, or in another order relative
+    /// to the other symbols of their file. Definitions which only shifted
+    /// along with the code around them are not moves.
+    pub symbols_moved: Vec<SymbolMove>,
+    pub references: Vec<ReferencesChange>,
+}
+
+impl BuildDiff {
+    pub fn is_empty(&self) -> bool {
+        self.nodes_added.is_empty()
+            && self.nodes_removed.is_empty()
+            && self.nodes_changed.is_empty()
+            && self.symbols_added.is_empty()
+            && self.symbols_removed.is_empty()
+            && self.symbols_moved.is_empty()
+            && self.references.is_empty()
+    }
+}
+
+
+/// Referenced token within its defining node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TokenKey {
+    Sym(u64),
+    /// Offset from the start of the node.
+    Offset(Offset),
+}
+
+/// References blob, token text and path of the defining node, by node ID and
+/// token.
+type ReferencesBySymbol = BTreeMap<(NodeID, TokenKey), (BlobSliceLoc, String, String)>;
+
+
+struct BuildView<'a> {
+    source: &'a dyn BuildSource,
+    nodes: BTreeMap<NodeID, BlobSliceLoc>,
+    syms: BTreeMap<u64, BlobSliceLoc>,
+    refs: BTreeMap<(NodeID, Offset), BlobSliceLoc>,
+    decoded: HashMap<BlobSliceLoc, pb::Node>,
+}
+
+impl<'a> BuildView<'a> {
+    fn new(source: &'a dyn BuildSource) -> Result<Self, Box<dyn Error>> {
+        let build = source.build();
+        let mut load = |loc| source.load_slice(loc);
+        let mut entries = |root: Option<BlobSliceLoc>, name: &str| {
+            let root = root.ok_or_else(|| format!("build {} has no {name} trie", build.id))?;
+            slicemap_entries(root, &mut load)
+        };
+
+        let nodes = entries(build.nodemap_trie_root, "nodemap")?
+            .into_iter()
+            .map(|e| (e.key, e.location))
+            .collect();
+        let syms = entries(build.symmap_trie_root, "symmap")?
+            .into_iter()
+            .map(|e| (e.key, e.location))
+            .collect();
+        let refs = entries(build.references_trie_root, "references")?
+            .into_iter()
+            .map(|e| ((e.key, e.token_offset.unwrap_or_default()), e.location))
+            .collect();
+
+        Ok(Self { source, nodes, syms, refs, decoded: HashMap::new() })
+    }
+
+    fn node(&mut self, loc: BlobSliceLoc) -> Result<&pb::Node, Box<dyn Error>> {
+        if !self.decoded.contains_key(&loc) {
+            let node = pb::Node::decode(&self.source.load_slice(loc)?[..])?;
+            self.decoded.insert(loc, node);
+        }
+        Ok(&self.decoded[&loc])
+    }
+
+    fn summary(&mut self, loc: BlobSliceLoc) -> Result<NodeSummary, Box<dyn Error>> {
+        Ok(node_summary(self.node(loc)?))
+    }
+
+    /// References of every token with references, keyed by the defining node
+    /// and the token.
+    fn references_by_symbol(&mut self) -> Result<ReferencesBySymbol, Box<dyn Error>> {
+        let mut by_symbol = BTreeMap::new();
+        let refs: Vec<_> = self.refs.iter().map(|(k, v)| (*k, *v)).collect();
+        for ((node_id, offset), refs_loc) in refs {
+            let Some(node_loc) = self.nodes.get(&node_id).copied() else { continue };
+            let node = self.node(node_loc)?;
+            let Some((token, name)) = token_at(node, offset) else { continue };
+            let path = node.path.clone();
+            by_symbol.insert((node_id, token), (refs_loc, name, path));
+        }
+        Ok(by_symbol)
+    }
+}
+
+
+pub fn diff_builds(old: &dyn BuildSource, new: &dyn BuildSource) -> Result<BuildDiff, Box<dyn Error>> {
+    let mut old = BuildView::new(old)?;
+    let mut new = BuildView::new(new)?;
+    let mut diff = BuildDiff {
+        old_build: old.source.build().id.clone(),
+        new_build: new.source.build().id.clone(),
+        ..BuildDiff::default()
+    };
+
+    let node_ids: BTreeSet<NodeID> = old.nodes.keys().chain(new.nodes.keys()).copied().collect();
+    for id in node_ids {
+        match (old.nodes.get(&id).copied(), new.nodes.get(&id).copied()) {
+            (Some(loc), None) => diff.nodes_removed.push(old.summary(loc)?),
+            (None, Some(loc)) => diff.nodes_added.push(new.summary(loc)?),
+            (Some(old_loc), Some(new_loc)) if old_loc != new_loc => {
+                // unchanged slices are usually shared between builds, but
+                // equal content may still have been written twice
+                if old.source.load_slice(old_loc)? == new.source.load_slice(new_loc)? {
+                    continue;
+                }
+                diff.nodes_changed.push(NodeChange { old: old.summary(old_loc)?, new: new.summary(new_loc)? });
+            },
+            _ => {},
+        }
+    }
+
+    // symbols kept in the same file, by path, to find those which changed
+    // places with their neighbours rather than shifted along with them
+    let mut kept_in_file: BTreeMap<String, Vec<SymbolMove>> = BTreeMap::new();
+    let sym_ids: BTreeSet<u64> = old.syms.keys().chain(new.syms.keys()).copied().collect();
+    for id in sym_ids {
+        match (old.syms.get(&id).copied(), new.syms.get(&id).copied()) {
+            (Some(loc), None) => diff.symbols_removed.push(SymbolSummary { sym_id: id, node: old.summary(loc)? }),
+            (None, Some(loc)) => diff.symbols_added.push(SymbolSummary { sym_id: id, node: new.summary(loc)? }),
+            (Some(old_loc), Some(new_loc)) => {
+                let old_node = old.summary(old_loc)?;
+                let new_node = new.summary(new_loc)?;
+                let sym = SymbolMove { sym_id: id, old: old_node, new: new_node };
+                if sym.old.path != sym.new.path {
+                    diff.symbols_moved.push(sym);
+                } else {
+                    kept_in_file.entry(sym.new.path.clone()).or_default().push(sym);
+                }
+            },
+            _ => {},
+        }
+    }
+    for (_, mut syms) in kept_in_file {
+        syms.sort_by_key(|m| (m.old.line, m.sym_id));
+        let new_positions: Vec<_> = syms.iter().map(|m| (m.new.line, m.sym_id)).collect();
+        let reordered = out_of_order(&new_positions);
+        diff.symbols_moved.extend(syms.into_iter().zip(reordered).filter(|(_, r)| *r).map(|(m, _)| m));
+    }
+    diff.symbols_moved.sort_by_key(|m| m.sym_id);
+
+    let old_refs = old.references_by_symbol()?;
+    let new_refs = new.references_by_symbol()?;
+    let symbols: BTreeSet<&(NodeID, TokenKey)> = old_refs.keys().chain(new_refs.keys()).collect();
+    for key in symbols {
+        let old_entry = old_refs.get(key);
+        let new_entry = new_refs.get(key);
+        if let (Some((old_loc, ..)), Some((new_loc, ..))) = (old_entry, new_entry) {
+            if old_loc == new_loc {
+                continue;
+            }
+        }
+
+        let old_list = match old_entry {
+            Some((loc, ..)) => reference_summaries(old.source, *loc)?,
+            None => Vec::new(),
+        };
+        let new_list = match new_entry {
+            Some((loc, ..)) => reference_summaries(new.source, *loc)?,
+            None => Vec::new(),
+        };
+        let (gained, lost) = multiset_difference(new_list, old_list);
+        if gained.is_empty() && lost.is_empty() {
+            continue;
+        }
+
+        let (node_id, _) = *key;
+        let (_, symbol, path) = new_entry.or(old_entry).cloned().unwrap();
+        diff.references.push(ReferencesChange { node_id, symbol, path, gained, lost });
+    }
+
+    Ok(diff)
+}
+
+
+fn node_summary(node: &pb::Node) -> NodeSummary {
+    NodeSummary {
+        id: node.id,
+        kind: format!("{:?}", node.kind()),
+        path: node.path.clone(),
+        line: node.start.as_ref().map_or(0, |loc| loc.line),
+        title: node.text.lines().next().unwrap_or_default().trim().to_string(),
+    }
+}
+
+
+/// Key and text of the token at `offset` in the file.
+fn token_at(node: &pb::Node, offset: Offset) -> Option<(TokenKey, String)> {
+    let node_start = node.start.as_ref().map_or(0, |loc| loc.offset);
+    pb_node_tokens(node)
+        .into_iter()
+        .find(|tok| tok.offset == offset && tok.type_ != TokenKind::WS)
+        .map(|tok| {
+            let key = match tok.context.sym_id {
+                Some(sym_id) => TokenKey::Sym(sym_id.0),
+                None => TokenKey::Offset(offset.saturating_sub(node_start)),
+            };
+            (key, tok.text.trim().to_string())
+        })
+}
+
+
+fn reference_summaries(source: &dyn BuildSource, loc: BlobSliceLoc) -> Result<Vec<ReferenceSummary>, Box<dyn Error>> {
+    let refs = pb::References::decode(&source.load_slice(loc)?[..])?;
+    Ok(refs.refs
+        .into_iter()
+        .map(|r| ReferenceSummary {
+            line: r.use_location.map_or(0, |loc| loc.line),
+            use_path: r.use_path,
+            context: r.context,
+        })
+        .collect())
+}
+
+
+/// Marks the fewest items that have to be taken out of `items` for the rest
+/// to be in increasing order, those outside of a longest increasing
+/// subsequence.
+fn out_of_order<T: Ord>(items: &[T]) -> Vec<bool> {
+    // tails[k]: index of the smallest item ending an increasing run of k + 1
+    let mut tails: Vec<usize> = Vec::new();
+    let mut prev: Vec<Option<usize>> = vec![None; items.len()];
+    for (i, item) in items.iter().enumerate() {
+        let k = tails.partition_point(|&t| items[t] < *item);
+        prev[i] = k.checked_sub(1).map(|k| tails[k]);
+        if k == tails.len() {
+            tails.push(i);
+        } else {
+            tails[k] = i;
+        }
+    }
+
+    let mut out_of_order = vec![true; items.len()];
+    let mut next = tails.last().copied();
+    while let Some(i) = next {
+        out_of_order[i] = false;
+        next = prev[i];
+    }
+    out_of_order
+}
+
+
+/// Returns (`new` - `old`, `old` - `new`), ignoring line numbers so that
+/// uses which merely shifted within a file are not reported.
+fn multiset_difference(
+    new: Vec<ReferenceSummary>,
+    old: Vec<ReferenceSummary>,
+) -> (Vec<ReferenceSummary>, Vec<ReferenceSummary>) {
+    let key = |r: &ReferenceSummary| (r.use_path.clone(), r.context.clone());
+
+    let mut counts: HashMap<(String, String), isize> = HashMap::new();
+    for r in &new { *counts.entry(key(r)).or_default() += 1; }
+    for r in &old { *counts.entry(key(r)).or_default() -= 1; }
+
+    let mut take = |list: Vec<ReferenceSummary>, sign: isize| {
+        let mut out: Vec<_> = list
+            .into_iter()
+            .filter(|r| {
+                let c = counts.get_mut(&key(r)).unwrap();
+                if *c * sign > 0 {
+                    *c -= sign;
+                    true
+                } else {
+                    false
+                }
+            })
+            .collect();
+        out.sort();
+        out
+    };
+    let gained = take(new, 1);
+    let lost = take(old, -1);
+    (gained, lost)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{multiset_difference, out_of_order, ReferenceSummary};
+
+    fn r(use_path: &str, line: u32, context: &str) -> ReferenceSummary {
+        ReferenceSummary { use_path: use_path.to_string(), line, context: context.to_string() }
+    }
+
+    #[test]
+    fn references_difference_ignores_shifted_lines() {
+        let old = vec![r("a.c", 3, "f"), r("b.c", 1, "g"), r("b.c", 5, "k")];
+        let new = vec![r("a.c", 4, "f"), r("b.c", 1, "g"), r("b.c", 9, "h")];
+
+        let (gained, lost) = multiset_difference(new, old);
+
+        assert_eq!(gained, vec![r("b.c", 9, "h")]);
+        assert_eq!(lost, vec![r("b.c", 5, "k")]);
+    }
+
+    #[test]
+    fn out_of_order_items() {
+        assert_eq!(out_of_order::<u32>(&[]), Vec::<bool>::new());
+        assert_eq!(out_of_order(&[1, 5, 9]), vec![false, false, false]);
+        // the first item moved to the end
+        assert_eq!(out_of_order(&[8, 2, 3, 4]), vec![true, false, false, false]);
+        // two adjacent items swapped
+        assert_eq!(out_of_order(&[1, 3, 2, 4]).iter().filter(|r| **r).count(), 1);
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+
+use prost::Message;
+use serde::Serialize;
+
+use crate::build_diff::BuildSource;
+use crate::slicemap_trie::slicemap_entries;
+use crate::territory::index::{self as pb, IndexItem, IndexItemKind, NodeKind};
+use crate::{pb_node_tokens, NodeID, TokenKind};
+
+
+/// Keywords opening function definitions in languages indexed from UIM input,
+/// whose index items carry no C-style function type.
+const FUNCTION_KEYWORDS: [&str; 3] = ["func", "def", "async"];
+
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    #[serde(with = "crate::ser::node_id")]
+    pub id: NodeID,
+    pub name: String,
+    pub path: String,
+    pub line: u32,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CallSite {
+    pub path: String,
+    pub line: u32,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    #[serde(with = "crate::ser::node_id")]
+    pub caller: NodeID,
+    #[serde(with = "crate::ser::node_id")]
+    pub callee: NodeID,
+    /// Definition context of the call sites, as recorded with the references.
+    pub context: String,
+    /// Set if any of the calls was linked through the callee's USR rather
+    /// than a direct definition lookup.
+    pub linked_via_sym: bool,
+    pub sites: Vec<CallSite>,
+}
+
+/// Caller to callee graph of the functions in a build.
+///
+/// Node IDs are serialized as `id:` hrefs, which can be passed back to the
+/// resolver or the territory-server `/node` endpoint.
+#[derive(Serialize, Debug, Default)]
+pub struct CallGraph {
+    pub functions: Vec<Function>,
+    pub calls: Vec<Call>,
+}
+
+impl CallGraph {
+    /// Builds the call graph from the calls among the references of a build.
+    ///
+    /// Functions are recognized by the search index items pointing at their
+    /// nodes: C and C++ functions, methods and constructors by their function
+    /// type, Go and Python definitions by their leading keyword.
+    pub fn extract(source: &dyn BuildSource, items: &[IndexItem]) -> Result<Self, Box<dyn Error>> {
+        let build = source.build();
+        let mut load = |loc| source.load_slice(loc);
+        let nodemap_root = build.nodemap_trie_root.ok_or("build has no nodemap trie")?;
+        let refs_root = build.references_trie_root.ok_or("build has no references trie")?;
+        let node_locations: BTreeMap<NodeID, _> = slicemap_entries(nodemap_root, &mut load)?
+            .into_iter()
+            .map(|e| (e.key, e.location))
+            .collect();
+
+        let mut functions = BTreeMap::new();
+        for item in items {
+            if item.kind() != IndexItemKind::IiSymbol {
+                continue;
+            }
+            let Some(pb::index_item::Href::NodeId(id)) = item.href else { continue };
+            if functions.contains_key(&id) {
+                continue;
+            }
+            let Some(loc) = node_locations.get(&id) else { continue };
+            let node = pb::Node::decode(&source.load_slice(*loc)?[..])?;
+            if !is_function(&node, item.r#type.as_deref()) {
+                continue;
+            }
+            functions.insert(id, Function {
+                id,
+                name: item.key.clone(),
+                path: node.path.clone(),
+                line: node.start.as_ref().map_or(0, |loc| loc.line),
+            });
+        }
+
+        let mut calls: BTreeMap<(NodeID, NodeID), Call> = BTreeMap::new();
+        for entry in slicemap_entries(refs_root, &mut load)? {
+            let callee = entry.key;
+            if !functions.contains_key(&callee) {
+                continue;
+            }
+            let refs = pb::References::decode(&source.load_slice(entry.location)?[..])?;
+            for r in refs.refs {
+                // references from UIM input are not classified
+                if !matches!(r.kind(), pb::ReferenceKind::RkCall | pb::ReferenceKind::RkUnknown) {
+                    continue;
+                }
+                let caller = match r.href {
+                    Some(pb::reference::Href::NodeId(id) | pb::reference::Href::DirectNodeLink(id)) => id,
+                    None => continue,
+                };
+                if !functions.contains_key(&caller) {
+                    continue;
+                }
+                let call = calls.entry((caller, callee)).or_insert_with(|| Call {
+                    caller,
+                    callee,
+                    context: r.context.clone(),
+                    linked_via_sym: false,
+                    sites: Vec::new(),
+                });
+                call.linked_via_sym |= r.linked_via_sym;
+                call.sites.push(CallSite {
+                    path: r.use_path,
+                    line: r.use_location.map_or(0, |loc| loc.line),
+                });
+            }
+        }
+
+        Ok(CallGraph {
+            functions: functions.into_values().collect(),
+            calls: calls.into_values().collect(),
+        })
+    }
+
+    pub fn write_dot(&self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        writeln!(out, "digraph calls {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        for f in &self.functions {
+            writeln!(
+                out,
+                "    \"id:{}\" [label=\"{}\\n{}:{}\"];",
+                f.id, dot_escape(&f.name), dot_escape(&f.path), f.line)?;
+        }
+        for c in &self.calls {
+            write!(out, "    \"id:{}\" -> \"id:{}\"", c.caller, c.callee)?;
+            if c.sites.len() > 1 {
+                write!(out, " [label=\"{}\"]", c.sites.len())?;
+            }
+            writeln!(out, ";")?;
+        }
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+}
+
+
+fn is_function(node: &pb::Node, ty: Option<&str>) -> bool {
+    if node.kind() != NodeKind::Definition {
+        return false;
+    }
+    if let Some(ty) = ty {
+        // "int (char *)", but not pointers to functions: "int (*)(char *)"
+        if ty.contains('(') && !ty.contains("(*") {
+            return true;
+        }
+    }
+    pb_node_tokens(node)
+        .into_iter()
+        .find(|tok| tok.type_ != TokenKind::WS)
+        .is_some_and(|tok| FUNCTION_KEYWORDS.contains(&tok.text.trim()))
+}
+
+
+pub(crate) fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::territory::index::{self as pb, NodeKind, TokenType};
+
+    use super::is_function;
+
+    fn definition(tokens: &[(&str, TokenType)]) -> pb::Node {
+        let mut node = pb::Node { kind: NodeKind::Definition.into(), ..Default::default() };
+        for (text, ty) in tokens {
+            node.tokens.push(pb::Token { offset: node.text.len() as u32, r#type: (*ty).into(), ..Default::default() });
+            node.text.push_str(text);
+        }
+        node
+    }
+
+    #[test]
+    fn function_detection() {
+        use TokenType::*;
+
+        let c_fn = definition(&[("int", Keyword), (" ", Ws), ("f", Identifier), ("() {}", Punctuation)]);
+        assert!(is_function(&c_fn, Some("int ()")));
+        assert!(!is_function(&c_fn, Some("int (*)(char *)")));
+        assert!(!is_function(&c_fn, Some("int")));
+
+        let go_fn = definition(&[("func", Keyword), (" ", Ws), ("f", Identifier), ("() {}", Punctuation)]);
+        assert!(is_function(&go_fn, None));
+
+        let py_fn = definition(&[("async", Keyword), (" ", Ws), ("def", Keyword), (" f():", Punctuation)]);
+        assert!(is_function(&py_fn, None));
+
+        let py_class = definition(&[("class", Keyword), (" ", Ws), ("C", Identifier), (":", Punctuation)]);
+        assert!(!is_function(&py_class, None));
+
+        let dir = pb::Node { kind: NodeKind::Directory.into(), ..go_fn };
+        assert!(!is_function(&dir, None));
+    }
+}
@@ -34,6 +34,54 @@ pub fn init_db(conn: &Connection) {
         )
     ", ()).unwrap();
 
+    conn.execute("
+        create table if not exists macromap (
+            node_id integer,
+            token_offset integer,
+            blob_id integer,
+            blob_start_offset integer,
+            blob_end_offset integer,
+            primary key (node_id, token_offset)
+        )
+    ", ()).unwrap();
+
+    conn.execute("
+        create table if not exists typemap (
+            node_id integer primary key,
+            blob_id integer,
+            blob_start_offset integer,
+            blob_end_offset integer
+        )
+    ", ()).unwrap();
+    // rewritten in full by every run
+    conn.execute("
+        delete from typemap
+    ", ()).unwrap();
+
+    conn.execute("
+        create table if not exists node_trigrams (
+            node_id integer,
+            trigram integer,
+            primary key (node_id, trigram)
+        ) without rowid
+    ", ()).unwrap();
+    conn.execute("
+        delete from node_trigrams where node_id not in (select node_id from nodemap)
+    ", ()).unwrap();
+
+    conn.execute("
+        create table if not exists trigrammap (
+            trigram integer primary key,
+            blob_id integer,
+            blob_start_offset integer,
+            blob_end_offset integer
+        )
+    ", ()).unwrap();
+    // rewritten in full by every run
+    conn.execute("
+        delete from trigrammap
+    ", ()).unwrap();
+
     conn.execute("
         create table if not exists paths (
             path_id integer primary key,
@@ -106,6 +154,60 @@ pub fn get_refs_location(conn: &Connection, token_location: &TokenLocation) -> O
 }
 
 
+pub fn get_type_hierarchy_location(conn: &Connection, node_id: NodeID) -> Option<BlobSliceLoc> {
+    let mut stmt = conn.prepare("
+        select blob_id, blob_start_offset, blob_end_offset
+        from typemap
+        where node_id=?1
+    ").unwrap();
+    stmt.query_row([node_id], |row| {
+            Ok(BlobSliceLoc {
+                blob_id: row.get(0)?,
+                start_offset: row.get(1)?,
+                end_offset: row.get(2)?,
+            })
+        })
+        .optional()
+        .unwrap()
+}
+
+
+pub fn get_macro_expansion_location(conn: &Connection, token_location: &TokenLocation) -> Option<BlobSliceLoc> {
+    let mut stmt = conn.prepare("
+        select blob_id, blob_start_offset, blob_end_offset
+        from macromap
+        where node_id=?1 and token_offset=?2
+    ").unwrap();
+    stmt.query_row((token_location.node_id, token_location.offset), |row| {
+            Ok(BlobSliceLoc {
+                blob_id: row.get(0)?,
+                start_offset: row.get(1)?,
+                end_offset: row.get(2)?,
+            })
+        })
+        .optional()
+        .unwrap()
+}
+
+
+pub fn get_trigram_location(conn: &Connection, trigram: u32) -> Option<BlobSliceLoc> {
+    let mut stmt = conn.prepare("
+        select blob_id, blob_start_offset, blob_end_offset
+        from trigrammap
+        where trigram=?1
+    ").unwrap();
+    stmt.query_row([trigram], |row| {
+            Ok(BlobSliceLoc {
+                blob_id: row.get(0)?,
+                start_offset: row.get(1)?,
+                end_offset: row.get(2)?,
+            })
+        })
+        .optional()
+        .unwrap()
+}
+
+
 pub fn get_node_for_path(conn: &Connection, path: &String) -> Option<NodeID> {
     let mut stmt = conn.prepare("
         select node_id
@@ -119,3 +221,20 @@ pub fn get_node_for_path(conn: &Connection, path: &String) -> Option<NodeID> {
         .optional()
         .unwrap()
 }
+
+
+pub fn get_sym_token_location(conn: &Connection, sym_id: SymID) -> Option<TokenLocation> {
+    let mut stmt = conn.prepare("
+        select node_id, offset
+        from sym
+        where sym_id = ?1
+    ").unwrap();
+    stmt.query_row(
+            (sym_id.0,),
+            |row| Ok(TokenLocation {
+                node_id: row.get(0).unwrap(),
+                offset: row.get(1).unwrap(),
+            }))
+        .optional()
+        .unwrap()
+}
@@ -0,0 +1,110 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::error::Error;
+use std::io::Write;
+
+use prost::Message;
+
+use crate::build_diff::BuildSource;
+use crate::call_graph::dot_escape;
+use crate::territory::index as pb;
+
+
+/// `#include` edges between the files of a build, merged over all
+/// translation units that were scanned.
+///
+/// Paths are relative to the repo root, system headers keep their absolute
+/// paths. Each edge records the directive spelling and the include path the
+/// header was found through, `""` for the repo root and `None` if the
+/// spelling does not name the tail of the resolved path (`"../foo.h"`).
+#[derive(Debug, Default)]
+pub struct IncludeGraph {
+    pub includes: Vec<pb::Include>,
+}
+
+impl IncludeGraph {
+    pub fn load(source: &dyn BuildSource) -> Result<Self, Box<dyn Error>> {
+        let loc = source.build().include_graph.ok_or("build has no include graph")?;
+        let graph = pb::IncludeGraph::decode(&source.load_slice(loc)?[..])?;
+        Ok(IncludeGraph { includes: graph.includes })
+    }
+
+    /// Files directly included by `path`.
+    pub fn includes(&self, path: &str) -> Vec<&pb::Include> {
+        self.includes.iter().filter(|i| i.includer == path).collect()
+    }
+
+    /// Files directly including `path`.
+    pub fn included_by(&self, path: &str) -> Vec<&pb::Include> {
+        self.includes.iter().filter(|i| i.included == path).collect()
+    }
+
+    /// All files including `path` directly or through other headers, that
+    /// is everything that needs rebuilding when `path` changes.
+    pub fn transitively_included_by(&self, path: &str) -> BTreeSet<&str> {
+        let mut seen = BTreeSet::new();
+        let mut queue = VecDeque::from([path]);
+        while let Some(p) = queue.pop_front() {
+            for include in self.included_by(p) {
+                if seen.insert(include.includer.as_str()) {
+                    queue.push_back(&include.includer);
+                }
+            }
+        }
+        seen.remove(path);
+        seen
+    }
+
+    pub fn write_dot(&self, out: &mut dyn Write) -> Result<(), std::io::Error> {
+        writeln!(out, "digraph includes {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        for i in &self.includes {
+            write!(out, "    \"{}\" -> \"{}\"", dot_escape(&i.includer), dot_escape(&i.included))?;
+            if i.tu_count > 1 {
+                write!(out, " [label=\"{}\"]", i.tu_count)?;
+            }
+            writeln!(out, ";")?;
+        }
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::territory::index as pb;
+
+    use super::IncludeGraph;
+
+    fn edge(includer: &str, included: &str) -> pb::Include {
+        pb::Include {
+            includer: includer.to_string(),
+            included: included.to_string(),
+            spelling: included.to_string(),
+            search_path: Some(String::new()),
+            tu_count: 1,
+        }
+    }
+
+    #[test]
+    fn included_by() {
+        let graph = IncludeGraph {
+            includes: vec![
+                edge("a.c", "a.h"),
+                edge("a.h", "common.h"),
+                edge("b.c", "common.h"),
+                edge("c.c", "c.h"),
+                edge("common.h", "a.h"),
+            ],
+        };
+
+        let direct: Vec<_> = graph.included_by("common.h").iter().map(|i| i.includer.as_str()).collect();
+        assert_eq!(direct, ["a.h", "b.c"]);
+        let includes: Vec<_> = graph.includes("a.h").iter().map(|i| i.included.as_str()).collect();
+        assert_eq!(includes, ["common.h"]);
+
+        let transitive: Vec<_> = graph.transitively_included_by("common.h").into_iter().collect();
+        // the cycle through a.h leads back to common.h, which is left out
+        assert_eq!(transitive, ["a.c", "a.h", "b.c"]);
+    }
+}
@@ -1,6 +1,7 @@
 mod wasm_bindings;
 mod ser;
 pub mod search;
+pub mod search_shards;
 pub mod token_writer;
 pub mod pblib;
 pub mod resolver;
@@ -8,6 +9,10 @@ pub mod strings_trie;
 pub mod slicemap_trie;
 pub mod pretty_print;
 pub mod node_diff;
+pub mod build_diff;
+pub mod call_graph;
+pub mod include_graph;
+pub mod text_search;
 
 #[cfg(feature = "db")]
 pub mod db;
@@ -26,7 +31,7 @@ pub use territory::index::BlobSliceLoc;
 use rusqlite::types::FromSqlError;
 
 use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashSet};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
@@ -433,6 +438,7 @@ impl<'a> Into<pb::Node> for &'a Node {
             pbtok.set_type(tok.type_.into());
             pbtok.href = tok.context.href.clone();
             pbtok.has_references = tok.context.references.is_set();
+            pbtok.has_macro_expansion = tok.context.has_macro_expansion;
             if tok.offset != real_offset {
                 pbtok.real_offset = Some(tok.offset);
                 real_offset = tok.offset;
@@ -499,6 +505,7 @@ pub fn pb_node_tokens(n: &pb::Node) -> Vec<Token> {
                 } else {
                     ReferencesLink::None
                 },
+                has_macro_expansion: pbtok.has_macro_expansion,
             },
         };
         real_offset += t.text.len() as u32;
@@ -589,12 +596,13 @@ pub struct HyperlinkedTokenContext {
     pub href: Option<pb::token::Href>,
     pub sym_id: Option<SymID>,
     pub references: ReferencesLink,
+    pub has_macro_expansion: bool,
 }
 
 impl Serialize for HyperlinkedTokenContext {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where S: serde::Serializer {
-        let mut state = serializer.serialize_struct("TokenContext", 4)?;
+        let mut state = serializer.serialize_struct("TokenContext", 5)?;
         let h = self.href.as_ref().map(|h| ser::gen_href::to_str(h));
         state.serialize_field("h", &h)?;
         let ht = match self.href {
@@ -604,6 +612,11 @@ impl Serialize for HyperlinkedTokenContext {
         state.serialize_field("ht", &ht)?;
         state.serialize_field("s", &self.sym_id)?;
         state.serialize_field("r", &self.references)?;
+        if self.has_macro_expansion {
+            state.serialize_field("m", &true)?;
+        } else {
+            state.skip_field("m")?;
+        }
         state.end()
     }
 }
@@ -614,6 +627,25 @@ pub type Token = GToken<HyperlinkedTokenContext>;
 pub struct Refs {
     pub token_location: TokenLocation,
     pub refs: HashSet<Ref>,
+    pub overridden_by: BTreeSet<Override>,
+    pub overrides: BTreeSet<Override>,
+}
+
+
+/// Virtual method on the other end of an override relation.
+#[derive(Serialize, PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
+pub struct Override {
+    pub location: TokenLocation,
+    pub name: String,
+}
+
+impl From<&Override> for pb::Implementation {
+    fn from(o: &Override) -> Self {
+        pb::Implementation {
+            href: Some(NodeIdWithOffsetHref { node_id: o.location.node_id, offset: o.location.offset }),
+            name: o.name.clone(),
+        }
+    }
 }
 
 
@@ -627,7 +659,7 @@ pub fn refs_url(token_location: &TokenLocation) -> String {
 
 impl Refs {
     pub fn new(token_location: TokenLocation) -> Refs {
-        Refs {token_location, refs: HashSet::new()}
+        Refs {token_location, refs: HashSet::new(), overridden_by: BTreeSet::new(), overrides: BTreeSet::new()}
     }
 }
 
@@ -638,6 +670,8 @@ impl Into<pb::References> for &Refs {
             node_id: self.token_location.node_id,
             offset: self.token_location.offset,
             refs: self.refs.iter().map(|r| r.into()).collect(),
+            overridden_by: self.overridden_by.iter().map(|o| o.into()).collect(),
+            overrides: self.overrides.iter().map(|o| o.into()).collect(),
         }
     }
 }
@@ -650,7 +684,8 @@ pub struct Ref {
     pub context: String,
     pub use_location: Location,
     pub use_path: RelativePath,
-    pub linked_via_sym: bool
+    pub linked_via_sym: bool,
+    pub kind: pb::ReferenceKind,
 }
 
 
@@ -662,6 +697,7 @@ impl Into<pb::Reference> for &Ref {
             use_location: Some(self.use_location.into()),
             linked_via_sym: self.linked_via_sym,
             use_path: self.use_path.to_string(),
+            kind: self.kind.into(),
         }
     }
 }
@@ -704,6 +740,21 @@ pub enum GenHref {
     RefsId(TokenLocation),
     Path(String),
     UniHref(String, Offset),
+    /// Supertypes and subtypes of the type defined in the node.
+    TypeHierarchy(NodeID),
+    /// Expansion of the macro invoked at the token.
+    MacroExpansion(TokenLocation),
+    /// Nodes containing a trigram, from the full-text index.
+    Trigram(u32),
+}
+impl GenHref {
+    pub fn from_url(url: &str) -> Option<GenHref> {
+        ser::gen_href::from_str(url)
+    }
+
+    pub fn to_url(&self) -> String {
+        ser::gen_href::to_str(self)
+    }
 }
 pub trait IntoGenHref {
     fn into_gen_href(&self) -> GenHref;
@@ -791,6 +842,7 @@ mod test {
                         href: Some(Href::DirectNodeLink(8888888888888u64)),
                         sym_id: Some(SymID(12345)),
                         references: ReferencesLink::TokenLocation(TokenLocation{ node_id: 76575756765765765u64, offset: 123 }),
+                        has_macro_expansion: false,
                     },
                 },
             ],
@@ -847,6 +899,7 @@ mod test {
                         href: Some(Href::DirectNodeLink(8888888888888u64)),
                         sym_id: None,
                         references: ReferencesLink::LegacyID(76575756765765765u64),
+                        has_macro_expansion: false,
                     },
                 },
             ],
@@ -903,6 +956,7 @@ mod test {
                         href: Some(Href::NodeIdWithOffsetRef(crate::territory::index::NodeIdWithOffsetHref { node_id: 8888888888888u64, offset: 9999 })),
                         sym_id: Some(SymID(12345)),
                         references: ReferencesLink::TokenLocation(TokenLocation{ node_id: 76575756765765765u64, offset: 123 }),
+                        has_macro_expansion: false,
                     },
                 },
             ],
@@ -959,6 +1013,7 @@ mod test {
                         href: Some(Href::DirectNodeLink(8888888888888u64)),
                         sym_id: Some(SymID(12345)),
                         references: ReferencesLink::TokenLocation(TokenLocation{ node_id: 76575756765765765u64, offset: 123 }),
+                        has_macro_expansion: false,
                     },
                 },
             ],
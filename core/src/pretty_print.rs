@@ -1,6 +1,7 @@
 use std::fmt::{Write as FWrite};
 use std::io::Write;
 
+use crate::build_diff::{BuildDiff, NodeSummary};
 use crate::territory::index::Node;
 use crate::ser::gen_href;
 use crate::GNode;
@@ -33,6 +34,7 @@ pub fn node(out: &mut dyn Write, n: &Node) -> Result<(), std::io::Error> {
         //     write!(&mut marker, "$sym:{}", sym_id).unwrap();
         // }
         if t.has_references { marker.push('#'); }
+        if t.has_macro_expansion { marker.push('!'); }
 
         if marker.is_empty() {
             write!(out, "{}", txt)?;
@@ -68,3 +70,59 @@ pub fn gnode<T, U>(out: &mut dyn Write, n: &GNode<T, U>) -> Result<(), std::io::
 
     Ok(())
 }
+
+
+pub fn build_diff(out: &mut dyn Write, diff: &BuildDiff) -> Result<(), std::io::Error> {
+    writeln!(out, "diff {} -> {}", diff.old_build, diff.new_build)?;
+    if diff.is_empty() {
+        writeln!(out, "no changes")?;
+        return Ok(());
+    }
+
+    let summary = |n: &NodeSummary| format!("{}:{} [{} id:{}] {}", n.path, n.line, n.kind, n.id, n.title);
+
+    let section = |out: &mut dyn Write, title: &str, count: usize| -> Result<(), std::io::Error> {
+        if count > 0 {
+            writeln!(out)?;
+            writeln!(out, "{} ({}):", title, count)?;
+        }
+        Ok(())
+    };
+
+    section(out, "nodes added", diff.nodes_added.len())?;
+    for n in &diff.nodes_added {
+        writeln!(out, "  + {}", summary(n))?;
+    }
+    section(out, "nodes removed", diff.nodes_removed.len())?;
+    for n in &diff.nodes_removed {
+        writeln!(out, "  - {}", summary(n))?;
+    }
+    section(out, "nodes changed", diff.nodes_changed.len())?;
+    for c in &diff.nodes_changed {
+        writeln!(out, "  ~ {}", summary(&c.new))?;
+    }
+    section(out, "symbols added", diff.symbols_added.len())?;
+    for s in &diff.symbols_added {
+        writeln!(out, "  + sym:{} {}", s.sym_id, summary(&s.node))?;
+    }
+    section(out, "symbols removed", diff.symbols_removed.len())?;
+    for s in &diff.symbols_removed {
+        writeln!(out, "  - sym:{} {}", s.sym_id, summary(&s.node))?;
+    }
+    section(out, "symbols moved", diff.symbols_moved.len())?;
+    for m in &diff.symbols_moved {
+        writeln!(out, "  > sym:{} {} {}:{} -> {}:{}", m.sym_id, m.new.title, m.old.path, m.old.line, m.new.path, m.new.line)?;
+    }
+    section(out, "references changed", diff.references.len())?;
+    for r in &diff.references {
+        writeln!(out, "  {} ({} id:{})", r.symbol, r.path, r.node_id)?;
+        for g in &r.gained {
+            writeln!(out, "    + {}:{} {}", g.use_path, g.line, g.context)?;
+        }
+        for l in &r.lost {
+            writeln!(out, "    - {}:{} {}", l.use_path, l.line, l.context)?;
+        }
+    }
+
+    Ok(())
+}
@@ -159,6 +159,21 @@ impl Resolver for DBResolver {
                     Ok(ConcreteLocation { path: legacy_refs_path(token_location), blob_bytes: None, token_offset: None, })
                 }
             },
+            GenHref::TypeHierarchy(id) => {
+                let conn = self.db_conn.lock().unwrap();
+                let result = crate::db::get_type_hierarchy_location(&conn, *id).ok_or(ResolutionFailure::NotFound)?;
+                Ok((&result).into())
+            },
+            GenHref::MacroExpansion(token_location) => {
+                let conn = self.db_conn.lock().unwrap();
+                let result = crate::db::get_macro_expansion_location(&conn, token_location).ok_or(ResolutionFailure::NotFound)?;
+                Ok((&result).into())
+            },
+            GenHref::Trigram(trigram) => {
+                let conn = self.db_conn.lock().unwrap();
+                let result = crate::db::get_trigram_location(&conn, *trigram).ok_or(ResolutionFailure::NotFound)?;
+                Ok((&result).into())
+            },
             GenHref::UniHref(_, _) => Err(ResolutionFailure::UnsupportedUrl)
         }
     }
@@ -218,6 +233,9 @@ pub struct TrieResolver<BR: Resolver> {
     nodemap: Arc<SlicemapReader>,
     symmap: Arc<SlicemapReader>,
     refmap: Arc<SlicemapReader>,
+    typemap: Option<Arc<SlicemapReader>>,
+    macromap: Option<Arc<SlicemapReader>>,
+    textmap: Option<Arc<SlicemapReader>>,
     repo_root_node_id: NodeID,
 }
 
@@ -234,10 +252,33 @@ impl<BR: Resolver> TrieResolver<BR> {
             nodemap: Arc::new(nodemap),
             symmap: Arc::new(symmap),
             refmap: Arc::new(refmap),
+            typemap: None,
+            macromap: None,
+            textmap: None,
             repo_root_node_id,
         }
     }
 
+    /// Enables `types:` hrefs. Builds written before the type hierarchy was
+    /// indexed don't have the trie.
+    pub fn with_type_hierarchy(mut self, typemap: SlicemapReader) -> Self {
+        self.typemap = Some(Arc::new(typemap));
+        self
+    }
+
+    /// Enables `macro:` hrefs, also missing from older builds.
+    pub fn with_macro_expansions(mut self, macromap: SlicemapReader) -> Self {
+        self.macromap = Some(Arc::new(macromap));
+        self
+    }
+
+    /// Enables `trigram:` hrefs, missing from builds indexed without the
+    /// full-text index.
+    pub fn with_text_index(mut self, textmap: SlicemapReader) -> Self {
+        self.textmap = Some(Arc::new(textmap));
+        self
+    }
+
     fn query_slicemap(slicemap: Arc<SlicemapReader>, key: u64, token_offset: Option<Offset>) -> ResolutionResult {
         for _ in 0..10 {
             let res = slicemap.get_by_number_with_offset(key, token_offset);
@@ -274,6 +315,18 @@ impl<BR: Resolver> Resolver for TrieResolver<BR> {
             GenHref::RefsId(TokenLocation { node_id, offset }) => {
                 Self::query_slicemap(Arc::clone(&self.refmap), *node_id, Some(*offset))
             }
+            GenHref::TypeHierarchy(id) => {
+                let typemap = self.typemap.as_ref().ok_or(ResolutionFailure::NotFound)?;
+                Self::query_slicemap(Arc::clone(typemap), *id, None)
+            }
+            GenHref::MacroExpansion(TokenLocation { node_id, offset }) => {
+                let macromap = self.macromap.as_ref().ok_or(ResolutionFailure::NotFound)?;
+                Self::query_slicemap(Arc::clone(macromap), *node_id, Some(*offset))
+            }
+            GenHref::Trigram(trigram) => {
+                let textmap = self.textmap.as_ref().ok_or(ResolutionFailure::NotFound)?;
+                Self::query_slicemap(Arc::clone(textmap), *trigram as u64, None)
+            }
             GenHref::Path(p) => {
                 if p == "" {
                     Self::query_slicemap(Arc::clone(&self.nodemap), self.repo_root_node_id, None)
@@ -1,7 +1,6 @@
 use std::collections::{BinaryHeap, HashMap};
 use std::error::Error;
 
-use prost::DecodeError;
 use prost::bytes::Buf;
 use serde::{Deserialize, Serialize};
 use prost::{Message, EncodeError, bytes::BytesMut};
@@ -16,6 +15,9 @@ pub enum Ranking {
     #[default]
     None,
     Length,
+    /// Scores matches with [`smart_score`]: word boundaries, exact and
+    /// prefix matches, symbol kind and path depth.
+    Smart,
 }
 
 #[derive(Deserialize)]
@@ -37,6 +39,115 @@ impl Default for Options {
 }
 
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MatchMode {
+    /// Query characters appear in the key in order, case-insensitive.
+    #[default]
+    Subsequence,
+    /// `^name`: the key starts with the query, case-insensitive.
+    Prefix,
+    /// `=name`: the key is the query, case-insensitive.
+    Exact,
+}
+
+/// A parsed search query: a name to match against item keys, narrowed by
+/// filters on the item's kind, path and type.
+///
+/// Filters are written as `kind:macro`, `path:drivers/net/` (a path prefix)
+/// and `type:int` (a substring of the type). Repeating a filter accepts any
+/// of its values, different filters must all match. Words without a known
+/// filter prefix make up the name, so `ns::f` is searched as is.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Query {
+    pub text: String,
+    pub mode: MatchMode,
+    pub kinds: Vec<IndexItemKind>,
+    pub paths: Vec<String>,
+    pub types: Vec<String>,
+}
+
+impl Query {
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let mut query = Query::default();
+        let mut words = Vec::new();
+        for word in raw.split_whitespace() {
+            if let Some(kind) = word.strip_prefix("kind:") {
+                query.kinds.push(parse_kind(kind)?);
+            } else if let Some(path) = word.strip_prefix("path:") {
+                query.paths.push(path.trim_start_matches('/').to_string());
+            } else if let Some(ty) = word.strip_prefix("type:") {
+                query.types.push(ty.to_string());
+            } else {
+                words.push(word);
+            }
+        }
+
+        let text = words.join(" ");
+        let (mode, text) = if let Some(t) = text.strip_prefix('=') {
+            (MatchMode::Exact, t)
+        } else if let Some(t) = text.strip_prefix('^') {
+            (MatchMode::Prefix, t)
+        } else {
+            (MatchMode::Subsequence, text.as_str())
+        };
+        query.mode = mode;
+        query.text = text.to_string();
+        Ok(query)
+    }
+
+    fn matches_key(&self, key: &str) -> bool {
+        let mut key = key.chars().map(fold_case);
+        let mut text = self.text.chars().map(fold_case);
+        match self.mode {
+            MatchMode::Subsequence => true,
+            MatchMode::Prefix => text.all(|c| key.next() == Some(c)),
+            MatchMode::Exact => key.eq(text),
+        }
+    }
+
+    fn matches_kind(&self, kind: IndexItemKind) -> bool {
+        self.kinds.is_empty() || self.kinds.contains(&kind)
+    }
+
+    fn matches_path(&self, path: Option<&str>) -> bool {
+        self.paths.is_empty() || path.is_some_and(|path| {
+            let path = path.trim_start_matches('/');
+            self.paths.iter().any(|prefix| path.starts_with(prefix.as_str()))
+        })
+    }
+
+    fn matches_type(&self, ty: Option<&str>) -> bool {
+        self.types.is_empty() || ty.is_some_and(|ty| self.types.iter().any(|t| ty.contains(t.as_str())))
+    }
+}
+
+
+fn parse_kind(kind: &str) -> Result<IndexItemKind, String> {
+    match kind {
+        "symbol" | "sym" => Ok(IndexItemKind::IiSymbol),
+        "macro" => Ok(IndexItemKind::IiMacro),
+        "file" => Ok(IndexItemKind::IiFile),
+        "directory" | "dir" => Ok(IndexItemKind::IiDirectory),
+        _ => Err(format!("unknown kind {kind:?}, expected symbol, macro, file or directory")),
+    }
+}
+
+
+/// Simple case folding of a character, for matching keys case-insensitively
+/// one character at a time. Characters whose lowercase form takes several
+/// characters are left as they are.
+pub fn fold_case(c: char) -> char {
+    if c.is_ascii() {
+        return c.to_ascii_lowercase();
+    }
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) => l,
+        _ => c,
+    }
+}
+
+
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, ::prost::Oneof)]
 pub enum Href {
     #[prost(uint64, tag = "3")]
@@ -89,6 +200,10 @@ pub struct TrieLocation {
     pub end: u64,
 }
 
+/// Version written to [`TrieIndexHeader::version`]. Version 0 indexes only
+/// have ASCII characters in their tries, 1 adds UTF-8 characters.
+pub const TRIE_INDEX_VERSION: u32 = 1;
+
 #[derive(Message)]
 pub struct TrieIndexHeader {
     #[prost(uint64, tag = "1")]
@@ -102,6 +217,9 @@ pub struct TrieIndexHeader {
 
     #[prost(uint64, tag = "4")]
     pub paths_trie_len: u64,
+
+    #[prost(uint32, tag = "5")]
+    pub version: u32,
 }
 
 
@@ -137,9 +255,14 @@ pub struct TrieIndex {
 
 
 impl TrieIndex {
-    pub fn load(buf: &[u8]) -> Result<Self, DecodeError> {
+    pub fn load(buf: &[u8]) -> Result<Self, Box<dyn Error>> {
         let mut bm = BytesMut::from(buf);
         let header = TrieIndexHeader::decode_length_delimited(&mut bm)?;
+        if header.version > TRIE_INDEX_VERSION {
+            return Err(format!(
+                "search index version {} is newer than the supported {TRIE_INDEX_VERSION}",
+                header.version).into());
+        }
 
         let mut entries = Vec::with_capacity(header.normalized_item_entries_count.try_into().unwrap());
         for _ in 0..header.normalized_item_entries_count {
@@ -177,6 +300,7 @@ impl TrieIndex {
             keys_trie_len: self.keys_data.len().try_into()?,
             types_trie_len: self.types_data.len().try_into()?,
             paths_trie_len: self.paths_data.len().try_into()?,
+            version: TRIE_INDEX_VERSION,
         };
         header.encode_length_delimited(buf).unwrap();
 
@@ -237,7 +361,7 @@ impl TrieIndex {
                 path_id: item.path.as_ref().and_then(|p| path_ids.get(p).copied()),
                 type_id: item.r#type.as_ref().and_then(|t| type_ids.get(t).copied()),
                 href,
-                kind: IndexItemKind::IiSymbol.into()
+                kind: item.kind,
             });
         }
 
@@ -251,46 +375,77 @@ impl TrieIndex {
         }
     }
 
+    /// Searches the index with a query in the syntax of [`Query::parse`],
+    /// returning nothing for a malformed query.
     pub fn search(&self, raw_query: &str, options: &Options) -> Vec<SearchResult> {
-        let query: &[u8] = raw_query.as_ref();
+        match Query::parse(raw_query) {
+            Ok(query) => self.search_query(&query, options),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Matches keys character by character with [`fold_case`]. Match
+    /// positions are character indices into the key.
+    pub fn search_query(&self, q: &Query, options: &Options) -> Vec<SearchResult> {
+        let query: Vec<char> = q.text.chars().map(fold_case).collect();
+        // resolve path and type filters once rather than per entry
+        let path_ids: Vec<bool> = self.paths.iter().map(|p| q.matches_path(Some(p))).collect();
+        let type_ids: Vec<bool> = self.types.iter().map(|t| q.matches_type(Some(t))).collect();
+        let entry_matches = |norm: &NormalizedItemEntry| {
+            q.matches_kind(norm.kind())
+                && (q.paths.is_empty() || norm.path_id.is_some_and(|id| path_ids[id as usize]))
+                && (q.types.is_empty() || norm.type_id.is_some_and(|id| type_ids[id as usize]))
+        };
         let mut r = TrieReader::new(&self.keys_data);
         let mut top_results = BinaryHeap::new();
 
         let mut key = String::new();
+        let mut key_len = 0;
         let mut matches = Vec::new();
         let mut idx = 0;
         loop {
             match r.read_symbol() {
                 Err(e) => { panic!("trie read error: {:?}", e); },
                 Ok(TrieSymbol::EOF) => { break; },
-                Ok(TrieSymbol::ASCIIChar(c)) => {
-                    let c = c as char;
-                    if (matches.len() < query.len()) &&
-                        (c.to_ascii_lowercase() == (query[matches.len()] as char).to_ascii_lowercase())
-                    {
-                        matches.push(key.len());
+                Ok(TrieSymbol::Char(c)) => {
+                    if (matches.len() < query.len()) && (fold_case(c) == query[matches.len()]) {
+                        matches.push(key_len);
                     }
                     key.push(c);
+                    key_len += 1;
                 }
                 Ok(TrieSymbol::Backspace(shift)) => {
                     for _ in 0..shift {
                         key.pop();
+                        key_len -= 1;
                         if let Some(lp) = matches.last() {
-                            if *lp == key.len() { matches.pop(); }
+                            if *lp == key_len { matches.pop(); }
                         }
                     }
                 }
                 Ok(TrieSymbol::Leaf(_)) => {
-                    if matches.len() == query.len() {
-                        let mut positions = matches.clone();
-                        let mut score = i64::MAX / key.len() as i64;
-
-                        if let Some(cont_match) = key.find(raw_query) {
-                            positions.clear();
-                            positions.extend(cont_match..cont_match+query.len());
-                        } else {
-                            score /= 2;
-                        }
+                    if matches.len() == query.len() && q.matches_key(&key) && entry_matches(&self.entries[idx]) {
+                        let (score, positions) = match options.ranking {
+                            Ranking::Smart => {
+                                let norm = &self.entries[idx];
+                                let path = norm.path_id.map(|id| self.paths[id as usize].as_str());
+                                let positions = match_positions(&key, &q.text).unwrap_or_else(|| matches.clone());
+                                (smart_score(&key, &q.text, &positions, norm.kind(), path), positions)
+                            },
+                            Ranking::None | Ranking::Length => {
+                                let mut positions = matches.clone();
+                                let mut score = i64::MAX / key_len as i64;
+
+                                if let Some(cont_match) = key.find(q.text.as_str()) {
+                                    let cont_match = key[..cont_match].chars().count();
+                                    positions.clear();
+                                    positions.extend(cont_match..cont_match+query.len());
+                                } else {
+                                    score /= 2;
+                                }
+                                (score, positions)
+                            },
+                        };
 
                         let rev = (-score, key.clone(), idx, positions);
                         top_results.push(rev);
@@ -329,10 +484,16 @@ impl TrieIndex {
 
 
 pub fn search<'a>(index: &'a Vec<IndexItem>, query: &str, options: &Options) -> Vec<&'a IndexItem> {
-    let query = query.to_lowercase();
+    let Ok(q) = Query::parse(query) else { return Vec::new() };
+    let query = q.text.to_lowercase();
     let mut results: Vec<_> = index
         .iter()
-        .filter(|item| item.key.to_lowercase().contains(&query))
+        .filter(|item| item.key.to_lowercase().contains(&query) && q.matches_key(&item.key))
+        .filter(|item| {
+            q.matches_kind(item.kind())
+                && q.matches_path(item.path.as_deref())
+                && q.matches_type(item.r#type.as_deref())
+        })
         .collect();
 
     match options.ranking {
@@ -340,6 +501,12 @@ pub fn search<'a>(index: &'a Vec<IndexItem>, query: &str, options: &Options) ->
         Ranking::Length => {
             results.sort_by(|a, b| a.key.len().cmp(&b.key.len()));
         }
+        Ranking::Smart => {
+            results.sort_by_cached_key(|item| {
+                let positions = match_positions(&item.key, &q.text).unwrap_or_default();
+                -smart_score(&item.key, &q.text, &positions, item.kind(), item.path.as_deref())
+            });
+        }
     }
 
     if let Some(limit) = options.limit {
@@ -350,13 +517,136 @@ pub fn search<'a>(index: &'a Vec<IndexItem>, query: &str, options: &Options) ->
 }
 
 
+const BOUNDARY_BONUS: i64 = 80;
+const CONTIGUOUS_BONUS: i64 = 200;
+const GAP_PENALTY: i64 = 20;
+const PREFIX_BONUS: i64 = 300;
+const EXACT_BONUS: i64 = 1000;
+const EXACT_CASE_BONUS: i64 = 100;
+const SYMBOL_BONUS: i64 = 150;
+const PATH_DEPTH_PENALTY: i64 = 10;
+const LENGTH_PENALTY: i64 = 2;
+
+
+/// Whether a word starts at character `i` of `key`: at the start, after a
+/// separator (`_`, `::`, `.`, `/`, `-`), at a lower to upper case change
+/// (`camelCase`) and at the start of a number.
+fn is_word_start(key: &[char], i: usize) -> bool {
+    if i == 0 {
+        return true;
+    }
+    let (prev, cur) = (key[i - 1], key[i]);
+    matches!(prev, '_' | ':' | '.' | '/' | '-' | ' ') && !matches!(cur, '_' | ':')
+        || prev.is_lowercase() && cur.is_uppercase()
+        || !prev.is_ascii_digit() && cur.is_ascii_digit()
+}
+
+
+/// Character positions in `key` of the characters of `query`,
+/// case-insensitive.
+///
+/// Prefers a contiguous match starting a word, then any contiguous match,
+/// then a subsequence whose characters start words wherever possible.
+fn match_positions(key: &str, query: &str) -> Option<Vec<usize>> {
+    let k: Vec<char> = key.chars().collect();
+    let q: Vec<char> = query.chars().map(fold_case).collect();
+    let k = &k[..];
+    if q.is_empty() {
+        return Some(Vec::new());
+    }
+    if q.len() > k.len() {
+        return None;
+    }
+    let eq = |i: usize, j: usize| fold_case(k[i]) == q[j];
+
+    let contiguous: Vec<usize> = (0..=k.len() - q.len())
+        .filter(|&i| (0..q.len()).all(|j| eq(i + j, j)))
+        .collect();
+    if let Some(&start) = contiguous.iter().find(|&&i| is_word_start(k, i)).or(contiguous.first()) {
+        return Some((start..start + q.len()).collect());
+    }
+
+    // greedy, jumping ahead to a word start when one still leaves room for the rest
+    let mut positions = Vec::with_capacity(q.len());
+    let mut i = 0;
+    for j in 0..q.len() {
+        let first = (i..k.len()).find(|&i| eq(i, j))?;
+        let at_word = (first..k.len())
+            .filter(|&i| eq(i, j) && is_word_start(k, i))
+            .find(|&i| is_subsequence(&k[i + 1..], &q[j + 1..]));
+        let pos = at_word.unwrap_or(first);
+        positions.push(pos);
+        i = pos + 1;
+    }
+    Some(positions)
+}
+
+
+/// Whether the folded `query` is a subsequence of `key`.
+fn is_subsequence(key: &[char], query: &[char]) -> bool {
+    let mut rest = query.iter();
+    let mut next = rest.next();
+    for &c in key {
+        match next {
+            Some(&q) if fold_case(c) == q => next = rest.next(),
+            Some(_) => {},
+            None => break,
+        }
+    }
+    next.is_none()
+}
+
+
+/// Score of `key` matching `query` at `positions` for [`Ranking::Smart`],
+/// higher is better.
+pub fn smart_score(key: &str, query: &str, positions: &[usize], kind: IndexItemKind, path: Option<&str>) -> i64 {
+    let k: Vec<char> = key.chars().collect();
+    let mut score = 0;
+
+    if key.chars().map(fold_case).eq(query.chars().map(fold_case)) {
+        score += EXACT_BONUS;
+        if key == query {
+            score += EXACT_CASE_BONUS;
+        }
+    } else if positions.first() == Some(&0) && positions.windows(2).all(|w| w[1] == w[0] + 1) {
+        score += PREFIX_BONUS;
+    }
+
+    let gaps = positions.windows(2).filter(|w| w[1] != w[0] + 1).count() as i64;
+    if gaps == 0 && !positions.is_empty() {
+        score += CONTIGUOUS_BONUS;
+    }
+    score -= gaps * GAP_PENALTY;
+
+    for (n, &i) in positions.iter().enumerate() {
+        // a contiguous run only counts the word start it begins with
+        let continues_run = n > 0 && positions[n - 1] + 1 == i;
+        if is_word_start(&k, i) && !continues_run {
+            score += BOUNDARY_BONUS;
+        }
+    }
+
+    if kind == IndexItemKind::IiSymbol {
+        score += SYMBOL_BONUS;
+    }
+    if let Some(path) = path {
+        score -= PATH_DEPTH_PENALTY * path.trim_matches('/').matches('/').count() as i64;
+    }
+    score -= LENGTH_PENALTY * (k.len() as i64 - query.chars().count() as i64).max(0);
+    score
+}
+
+
 #[cfg(test)]
 mod test {
+    use prost::Message;
+    use prost::bytes::BytesMut;
     use serde_json::to_string_pretty;
 
     use crate::territory::index::index_item::Href;
-    use crate::territory::index::{IndexItem, IndexItemKind::IiSymbol};
-    use crate::search::{Options, Ranking, TrieIndex, Href as THref, search};
+    use crate::territory::index::{IndexItem, IndexItemKind::{IiFile, IiMacro, IiSymbol}};
+
+    use crate::search::{match_positions, MatchMode, Options, Query, Ranking, TrieIndex, TrieIndexHeader, Href as THref, TRIE_INDEX_VERSION, search};
 
     fn ii_defaults() -> IndexItem {
         IndexItem {
@@ -432,6 +722,177 @@ mod test {
     }
 
 
+    #[test]
+    fn query_parse() {
+        let q = Query::parse("kind:macro  path:/drivers/net/ =init type:int").unwrap();
+        assert_eq!(q, Query {
+            text: "init".to_string(),
+            mode: MatchMode::Exact,
+            kinds: vec![IiMacro],
+            paths: vec!["drivers/net/".to_string()],
+            types: vec!["int".to_string()],
+        });
+
+        let q = Query::parse("^ns::f").unwrap();
+        assert_eq!((q.text.as_str(), q.mode), ("ns::f", MatchMode::Prefix));
+
+        assert!(Query::parse("kind:function").is_err());
+    }
+
+    #[test]
+    fn trie_search_filters() {
+        let mut index = vec![
+            IndexItem { key: "init".to_owned(), href: Some(Href::NodeId(1)), path: Some("drivers/net/e1000.c".to_owned()), r#type: Some("int (void)".to_owned()), ..ii_defaults() },
+            IndexItem { key: "init".to_owned(), href: Some(Href::NodeId(2)), path: Some("fs/ext4/super.c".to_owned()), r#type: Some("void (void)".to_owned()), ..ii_defaults() },
+            IndexItem { key: "INIT_LIST".to_owned(), href: Some(Href::NodeId(3)), path: Some("drivers/net/list.h".to_owned()), kind: IiMacro.into(), ..ii_defaults() },
+            IndexItem { key: "initrd.c".to_owned(), href: Some(Href::NodeId(4)), path: Some("init/initrd.c".to_owned()), kind: IiFile.into(), ..ii_defaults() },
+            IndexItem { key: "kinit".to_owned(), href: Some(Href::NodeId(5)), path: Some("drivers/net/kinit.c".to_owned()), ..ii_defaults() },
+        ];
+        let trie = TrieIndex::from_index_items(&mut index);
+        let ids = |query: &str| {
+            let mut ids: Vec<_> = trie.search(query, &Options::default())
+                .into_iter()
+                .map(|r| match r.item.href { THref::NodeId(id) | THref::DirectNodeLink(id) => id })
+                .collect();
+            ids.sort();
+            ids
+        };
+
+        assert_eq!(ids("init"), [1, 2, 3, 4, 5]);
+        assert_eq!(ids("init path:drivers/net/"), [1, 3, 5]);
+        assert_eq!(ids("init path:drivers/net/ kind:symbol"), [1, 5]);
+        assert_eq!(ids("init kind:macro kind:file"), [3, 4]);
+        assert_eq!(ids("init type:int"), [1]);
+        assert_eq!(ids("=init"), [1, 2]);
+        assert_eq!(ids("^init"), [1, 2, 3, 4]);
+        assert_eq!(ids("kind:macro"), [3]);
+        assert_eq!(ids("kind:bogus init"), Vec::<u64>::new());
+
+        let res = trie.search("^init kind:macro", &Options::default());
+        assert_eq!(res[0].item.kind, IiMacro);
+        assert_eq!(res[0].item.path.as_deref(), Some("drivers/net/list.h"));
+    }
+
+    #[test]
+    fn filters() {
+        let index = vec![
+            IndexItem { key: "foo".to_owned(), href: Some(Href::DirectNodeLink(1)), path: Some("a/foo.c".to_owned()), ..ii_defaults() },
+            IndexItem { key: "foobar".to_owned(), href: Some(Href::DirectNodeLink(2)), path: Some("b/foo.c".to_owned()), ..ii_defaults() },
+        ];
+
+        let keys = |query| search(&index, query, &Options::default()).into_iter().map(|ii| ii.key.as_str()).collect::<Vec<_>>();
+        assert_eq!(keys("foo path:b/"), ["foobar"]);
+        assert_eq!(keys("=foo"), ["foo"]);
+    }
+
+    #[test]
+    fn word_boundary_positions() {
+        assert_eq!(match_positions("fooBarBaz", "fbb"), Some(vec![0, 3, 6]));
+        assert_eq!(match_positions("foo_bar", "fb"), Some(vec![0, 4]));
+        assert_eq!(match_positions("ns::abcb_c", "bc"), Some(vec![5, 6]));
+        assert_eq!(match_positions("kinit_init", "init"), Some(vec![6, 7, 8, 9]));
+        assert_eq!(match_positions("Init", "INIT"), Some(vec![0, 1, 2, 3]));
+        assert_eq!(match_positions("foo", "fx"), None);
+    }
+
+    #[test]
+    fn trie_search_smart_ranking() {
+        let mut index = vec![
+            IndexItem { key: "kinit".to_owned(), href: Some(Href::NodeId(1)), path: Some("kinit.c".to_owned()), ..ii_defaults() },
+            IndexItem { key: "INIT_LIST".to_owned(), href: Some(Href::NodeId(2)), path: Some("include/linux/list.h".to_owned()), kind: IiMacro.into(), ..ii_defaults() },
+            IndexItem { key: "module_init".to_owned(), href: Some(Href::NodeId(3)), path: Some("kernel/module.c".to_owned()), ..ii_defaults() },
+            IndexItem { key: "InitCache".to_owned(), href: Some(Href::NodeId(4)), path: Some("cache.c".to_owned()), ..ii_defaults() },
+            IndexItem { key: "init".to_owned(), href: Some(Href::NodeId(5)), path: Some("init/main.c".to_owned()), ..ii_defaults() },
+            IndexItem { key: "i_nit".to_owned(), href: Some(Href::NodeId(6)), path: Some("a.c".to_owned()), ..ii_defaults() },
+        ];
+        let trie = TrieIndex::from_index_items(&mut index);
+
+        let options = Options { ranking: Ranking::Smart, ..Options::default() };
+        let res = trie.search("init", &options);
+        let keys: Vec<_> = res.iter().map(|r| r.item.key.as_str()).collect();
+        assert_eq!(keys, ["init", "InitCache", "INIT_LIST", "module_init", "kinit", "i_nit"]);
+        assert_eq!(res[3].positions, vec![7, 8, 9, 10]);
+        assert_eq!(res[5].positions, vec![0, 2, 3, 4]);
+
+        let res = trie.search("init", &Options { limit: Some(2), ..options });
+        assert_eq!(res.iter().map(|r| r.item.key.as_str()).collect::<Vec<_>>(), ["init", "InitCache"]);
+    }
+
+    #[test]
+    fn trie_search_unicode() {
+        let mut index = vec![
+            IndexItem { key: "Größe".to_owned(), href: Some(Href::NodeId(1)), path: Some("pkg/maße.go".to_owned()), ..ii_defaults() },
+            IndexItem { key: "ÜberSetzer".to_owned(), href: Some(Href::NodeId(2)), ..ii_defaults() },
+            IndexItem { key: "größer".to_owned(), href: Some(Href::NodeId(3)), ..ii_defaults() },
+            IndexItem { key: "σύνολο".to_owned(), href: Some(Href::NodeId(4)), ..ii_defaults() },
+        ];
+        let trie = TrieIndex::from_index_items(&mut index);
+
+        let res = trie.search("GRÖSSE", &Options::default());
+        assert!(res.is_empty(), "ß only folds to itself");
+
+        let res = trie.search("=gröẞe", &Options::default());
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].item.key, "Größe");
+        assert_eq!(res[0].item.path.as_deref(), Some("pkg/maße.go"));
+
+        let res = trie.search("=ΣΎΝΟΛΟ", &Options::default());
+        assert_eq!(res.len(), 1);
+
+        let res = trie.search("^größ", &Options::default());
+        let keys: Vec<_> = res.iter().map(|r| r.item.key.as_str()).collect();
+        assert_eq!(keys, ["größer", "Größe"]);
+
+        // positions count characters, not bytes
+        let res = trie.search("üset", &Options { ranking: Ranking::Smart, ..Options::default() });
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].positions, vec![0, 4, 5, 6]);
+        let res = trie.search("ße", &Options::default());
+        assert_eq!(res.iter().map(|r| r.positions.clone()).collect::<Vec<_>>(), [vec![3, 4], vec![3, 4]]);
+    }
+
+    #[test]
+    fn trie_index_versions() {
+        let mut index = vec![
+            IndexItem { key: "foo".to_owned(), href: Some(Href::NodeId(1)), ..ii_defaults() },
+        ];
+        let trie = TrieIndex::from_index_items(&mut index);
+
+        // indexes written before the header had a version decode as version 0
+        let mut buf = BytesMut::new();
+        let header = TrieIndexHeader {
+            normalized_item_entries_count: 1,
+            keys_trie_len: trie.keys_data.len() as u64,
+            types_trie_len: 0,
+            paths_trie_len: 0,
+            version: 0,
+        };
+        header.encode_length_delimited(&mut buf).unwrap();
+        trie.normalized_entries_proto(&mut buf).unwrap();
+        buf.extend_from_slice(&trie.keys_data);
+        let old = TrieIndex::load(&buf).unwrap();
+        assert_eq!(old.search("fo", &Options::default()).len(), 1);
+
+        let mut buf = BytesMut::new();
+        TrieIndexHeader { version: TRIE_INDEX_VERSION + 1, ..header }.encode_length_delimited(&mut buf).unwrap();
+        assert!(TrieIndex::load(&buf).is_err());
+    }
+
+    #[test]
+    fn smart_ranking() {
+        let index = vec![
+            IndexItem { key: "get_value".to_owned(), href: Some(Href::DirectNodeLink(1)), path: Some("a/b/c/d.c".to_owned()), ..ii_defaults() },
+            IndexItem { key: "getValue".to_owned(), href: Some(Href::DirectNodeLink(2)), path: Some("d.c".to_owned()), ..ii_defaults() },
+            IndexItem { key: "GET_VALUE".to_owned(), href: Some(Href::DirectNodeLink(3)), path: Some("d.h".to_owned()), kind: IiMacro.into(), ..ii_defaults() },
+        ];
+
+        let keys: Vec<_> = search(&index, "value", &Options { ranking: Ranking::Smart, ..Options::default() })
+            .into_iter()
+            .map(|ii| ii.key.as_str())
+            .collect();
+        assert_eq!(keys, ["getValue", "get_value", "GET_VALUE"]);
+    }
+
     #[test]
     fn empty_query() {
         let index = vec![
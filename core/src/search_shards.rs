@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::iter::once;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use prost::Message;
+
+use crate::resolver::{ConcreteLocation, NeedData, ResolutionFailure};
+use crate::search::{fold_case, MatchMode, Options, Query, SearchResult, TrieIndex};
+use crate::territory::index::{self as pb, BlobSliceLoc, IndexItem};
+
+
+/// Case-folded form of a key, the order the search index is sharded in.
+pub fn shard_key(key: &str) -> String {
+    key.chars().map(fold_case).collect()
+}
+
+
+/// Splits `items` into runs of about `max_items` in [`shard_key`] order,
+/// each with the shard key of its first item. Items with the same shard key
+/// are kept in one run, so a run may be longer.
+pub fn split_shards(items: Vec<IndexItem>, max_items: usize) -> Vec<(String, Vec<IndexItem>)> {
+    let mut keyed: Vec<_> = items.into_iter().map(|item| (shard_key(&item.key), item)).collect();
+    keyed.sort_by(|l, r| l.0.cmp(&r.0));
+
+    let mut shards: Vec<(String, Vec<IndexItem>)> = Vec::new();
+    let mut last_key = String::new();
+    for (key, item) in keyed {
+        let full = shards.last().is_none_or(|(_, items)| items.len() >= max_items);
+        if full && (shards.is_empty() || key != last_key) {
+            shards.push((key.clone(), Vec::new()));
+        }
+        shards.last_mut().unwrap().1.push(item);
+        last_key = key;
+    }
+    shards
+}
+
+
+/// Bits in `SearchShard.pair_bits`.
+const PAIR_BITS: usize = 4096;
+
+
+fn pair_bit(first: char, second: char) -> usize {
+    (first as usize * 131 + second as usize) % PAIR_BITS
+}
+
+
+/// Bitset of the ordered pairs of characters in the shard keys of `items`,
+/// with `'\0'` standing before every key so that single characters count
+/// too. A subsequence query can only match in a shard that has each pair of
+/// consecutive query characters.
+pub fn pair_bits(items: &[IndexItem]) -> Vec<u8> {
+    let mut bits = vec![0; PAIR_BITS / 8];
+    let mut set = |bit: usize| bits[bit / 8] |= 1 << (bit % 8);
+    for item in items {
+        // distinct characters with their first and last position
+        let mut chars: Vec<(char, usize, usize)> = Vec::new();
+        for (pos, c) in shard_key(&item.key).chars().enumerate() {
+            match chars.iter_mut().find(|(d, _, _)| *d == c) {
+                Some((_, _, last)) => *last = pos,
+                None => chars.push((c, pos, pos)),
+            }
+        }
+        for &(first, first_pos, _) in &chars {
+            set(pair_bit('\0', first));
+            for &(second, _, last_pos) in &chars {
+                if first_pos < last_pos {
+                    set(pair_bit(first, second));
+                }
+            }
+        }
+    }
+    bits
+}
+
+
+/// Whether a shard with `bits` from [`pair_bits`] can hold keys with `text`
+/// as a subsequence. Shards without the bitset always can.
+fn may_have_subsequence(bits: &[u8], text: &str) -> bool {
+    if bits.len() != PAIR_BITS / 8 {
+        return true;
+    }
+    let text = shard_key(text);
+    once('\0').chain(text.chars()).zip(text.chars()).all(|(first, second)| {
+        let bit = pair_bit(first, second);
+        bits[bit / 8] & (1 << (bit % 8)) != 0
+    })
+}
+
+
+/// Shards that can hold matches of `query`. Prefix and exact queries only
+/// need the shards whose key range overlaps the keys starting with the
+/// query, a subsequence can match anywhere its character pairs are found.
+fn shards_for(shards: &[pb::SearchShard], query: &Query) -> Vec<usize> {
+    if query.mode == MatchMode::Subsequence {
+        return (0..shards.len())
+            .filter(|&i| may_have_subsequence(&shards[i].pair_bits, &query.text))
+            .collect();
+    }
+    let prefix = shard_key(&query.text);
+    (0..shards.len())
+        .filter(|&i| {
+            let first = &shards[i].first_key;
+            let starts_in_range = *first <= prefix || first.starts_with(&prefix);
+            let ends_in_range = shards.get(i + 1).is_none_or(|next| next.first_key > prefix);
+            starts_in_range && ends_in_range
+        })
+        .collect()
+}
+
+
+/// Search index of a build split into shards by key, fetched as queries
+/// need them.
+///
+/// Like [`crate::resolver::TrieResolver`], searching returns
+/// [`ResolutionFailure::NeedData`] for the manifest, then for each shard the
+/// query needs that is not loaded yet, one at a time. Callers fetch the
+/// location, pass the bytes to the continuation and search again. Loaded
+/// shards are kept for later queries.
+pub struct ShardedIndex {
+    root: BlobSliceLoc,
+    manifest: Arc<OnceLock<Vec<pb::SearchShard>>>,
+    loaded: Arc<Mutex<HashMap<usize, Arc<TrieIndex>>>>,
+}
+
+impl ShardedIndex {
+    /// `root` is the location of the [`pb::SearchShards`] manifest, as in
+    /// `Build.search_shards`.
+    pub fn new(root: BlobSliceLoc) -> Self {
+        Self {
+            root,
+            manifest: Arc::new(OnceLock::new()),
+            loaded: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn manifest(&self) -> Result<&[pb::SearchShard], ResolutionFailure> {
+        if let Some(shards) = self.manifest.get() {
+            return Ok(shards);
+        }
+        let manifest = Arc::clone(&self.manifest);
+        let cont = Box::new(move |data: &[u8]| {
+            let _ = manifest.set(pb::SearchShards::decode(data)?.shards);
+            Ok(())
+        });
+        Err(ResolutionFailure::NeedData(NeedData((&self.root).into(), cont)))
+    }
+
+    fn need_shard(&self, idx: usize, shard: &pb::SearchShard) -> Result<NeedData, ResolutionFailure> {
+        let Some(loc) = shard.location else {
+            return Err(ResolutionFailure::Error(format!("search shard {idx} has no location").into()));
+        };
+        let loaded = Arc::clone(&self.loaded);
+        let cont = Box::new(move |data: &[u8]| {
+            let index = TrieIndex::load(data)?;
+            loaded.lock().unwrap().insert(idx, Arc::new(index));
+            Ok(())
+        });
+        Ok(NeedData(ConcreteLocation::from(&loc), cont))
+    }
+
+    /// Number of shards the manifest lists and how many of them are loaded.
+    pub fn loaded_shards(&self) -> Option<(usize, usize)> {
+        let total = self.manifest.get()?.len();
+        Some((total, self.loaded.lock().unwrap().len()))
+    }
+
+    /// Searches with a query in the syntax of [`Query::parse`].
+    pub fn search(&self, raw_query: &str, options: &Options) -> Result<Vec<SearchResult>, ResolutionFailure> {
+        let query = Query::parse(raw_query).map_err(|e| ResolutionFailure::Error(e.into()))?;
+        self.search_query(&query, options)
+    }
+
+    /// Merges the results of the shards `query` needs, ranked as a single
+    /// [`TrieIndex`] would rank them.
+    pub fn search_query(&self, query: &Query, options: &Options) -> Result<Vec<SearchResult>, ResolutionFailure> {
+        let shards = self.manifest()?;
+        let indexes: Vec<Arc<TrieIndex>> = {
+            let loaded = self.loaded.lock().unwrap();
+            let mut indexes = Vec::new();
+            for idx in shards_for(shards, query) {
+                match loaded.get(&idx) {
+                    Some(index) => indexes.push(Arc::clone(index)),
+                    None => return Err(ResolutionFailure::NeedData(self.need_shard(idx, &shards[idx])?)),
+                }
+            }
+            indexes
+        };
+
+        let mut results: Vec<SearchResult> = indexes.iter()
+            .flat_map(|index| index.search_query(query, options))
+            .collect();
+        results.sort_by(|l, r| r.score.cmp(&l.score).then_with(|| l.item.key.cmp(&r.item.key)));
+        if let Some(limit) = options.limit {
+            results.truncate(limit);
+        }
+        Ok(results)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use prost::Message;
+    use prost::bytes::BytesMut;
+
+    use crate::resolver::{NeedData, ResolutionFailure};
+    use crate::search::{Options, Ranking, SearchResult, TrieIndex};
+    use crate::territory::index::{self as pb, index_item::Href, BlobSliceLoc, IndexItem, IndexItemKind};
+
+    use super::{pair_bits, split_shards, ShardedIndex};
+
+    fn item(key: &str, id: u64) -> IndexItem {
+        IndexItem {
+            key: key.to_string(),
+            href: Some(Href::NodeId(id)),
+            kind: IndexItemKind::IiSymbol.into(),
+            path: Some(format!("{key}.c")),
+            r#type: None,
+        }
+    }
+
+    fn items() -> Vec<IndexItem> {
+        ["alpha", "Alpha", "alphabet", "beta", "BETA", "gamma", "get_beta", "zeta"]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| item(key, i as u64))
+            .collect()
+    }
+
+    /// Writes the shards and manifest into blob 0, as the indexer does.
+    fn write_shards(items: Vec<IndexItem>, max_items: usize) -> (Vec<u8>, BlobSliceLoc) {
+        let mut blob = Vec::new();
+        let mut slice = |data: &[u8]| {
+            let start_offset = blob.len() as u64;
+            blob.extend_from_slice(data);
+            BlobSliceLoc { blob_id: 0, start_offset, end_offset: blob.len() as u64 }
+        };
+        let mut manifest = pb::SearchShards::default();
+        for (first_key, mut shard_items) in split_shards(items, max_items) {
+            let mut buf = BytesMut::new();
+            TrieIndex::from_index_items(&mut shard_items).dump(&mut buf).unwrap();
+            manifest.shards.push(pb::SearchShard {
+                first_key,
+                location: Some(slice(&buf)),
+                item_count: shard_items.len() as u64,
+                pair_bits: pair_bits(&shard_items),
+            });
+        }
+        let root = slice(&manifest.encode_to_vec());
+        (blob, root)
+    }
+
+    /// Searches, feeding the index what it asks for. Returns the results
+    /// and the number of round trips.
+    fn search(index: &ShardedIndex, blob: &[u8], query: &str, options: &Options) -> (Vec<SearchResult>, usize) {
+        let mut fetches = 0;
+        loop {
+            let NeedData(loc, cont) = match index.search(query, options) {
+                Ok(results) => return (results, fetches),
+                Err(ResolutionFailure::NeedData(nd)) => nd,
+                Err(e) => panic!("search failed: {e:?}"),
+            };
+            let (start, end) = loc.blob_bytes.unwrap();
+            cont(&blob[start as usize..end as usize]).unwrap();
+            fetches += 1;
+        }
+    }
+
+    fn keys(results: &[SearchResult]) -> Vec<&str> {
+        results.iter().map(|r| r.item.key.as_str()).collect()
+    }
+
+    #[test]
+    fn split_keeps_folded_keys_together() {
+        let shards = split_shards(items(), 1);
+        let first_keys: Vec<_> = shards.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(first_keys, ["alpha", "alphabet", "beta", "gamma", "get_beta", "zeta"]);
+        let counts: HashMap<_, _> = shards.iter().map(|(k, items)| (k.as_str(), items.len())).collect();
+        assert_eq!(counts["alpha"], 2);
+        assert_eq!(counts["beta"], 2);
+    }
+
+    #[test]
+    fn prefix_query_loads_one_shard() {
+        let (blob, root) = write_shards(items(), 2);
+        let index = ShardedIndex::new(root);
+
+        let (results, fetches) = search(&index, &blob, "^get", &Options::default());
+        assert_eq!(keys(&results), ["get_beta"]);
+        // the manifest and the shard starting at "gamma"
+        assert_eq!(fetches, 2);
+        assert_eq!(index.loaded_shards(), Some((4, 1)));
+
+        let (results, fetches) = search(&index, &blob, "=beta", &Options::default());
+        assert_eq!(keys(&results), ["beta", "BETA"]);
+        assert_eq!(fetches, 1);
+
+        // everything needed is loaded by now
+        let (_, fetches) = search(&index, &blob, "^ge", &Options::default());
+        assert_eq!(fetches, 0);
+    }
+
+    #[test]
+    fn subsequence_query_loads_matching_shards() {
+        let (blob, root) = write_shards(items(), 1);
+        let index = ShardedIndex::new(root);
+
+        let (results, fetches) = search(&index, &blob, "eta", &Options::default());
+        assert_eq!(keys(&results), ["beta", "zeta", "BETA", "get_beta"]);
+        // the manifest, then the shards at "beta", "get_beta" and "zeta";
+        // "alphabet" has no "a" after the "t"
+        assert_eq!(fetches, 4);
+        assert_eq!(index.loaded_shards(), Some((6, 3)));
+
+        let (results, fetches) = search(&index, &blob, "gtb", &Options::default());
+        assert_eq!(keys(&results), ["get_beta"]);
+        assert_eq!(fetches, 0);
+    }
+
+    #[test]
+    fn same_results_as_unsharded() {
+        let (blob, root) = write_shards(items(), 1);
+        let index = ShardedIndex::new(root);
+        let whole = TrieIndex::from_index_items(&mut items());
+
+        for query in ["a", "beta", "^al", "=alpha", "eta"] {
+            for ranking in [Ranking::None, Ranking::Smart] {
+                let options = Options { limit: Some(3), ranking };
+                let (results, _) = search(&index, &blob, query, &options);
+                let expected = whole.search(query, &options);
+                assert_eq!(keys(&results), keys(&expected), "query {query:?}");
+            }
+        }
+    }
+}
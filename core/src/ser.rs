@@ -125,11 +125,15 @@ pub mod gen_href {
             },
             GenHref::Path(path) => format!("path:{}", path),
             GenHref::UniHref(path, offset) => format!("path:{}#token-{}", path, offset),
+            GenHref::TypeHierarchy(id) => format!("types:{}", id),
+            GenHref::MacroExpansion(TokenLocation { node_id, offset }) => format!("macro:{}/{}", node_id, offset),
+            GenHref::Trigram(trigram) => format!("trigram:{}", trigram),
         }
     }
     pub fn from_str(url: &str) -> Option<GenHref> {
         let file_slice_re = Regex::new(r"^slice:f/([0-9]+)\[([0-9]+):([0-9]+)\]$").unwrap();
         let refs_re = Regex::new(r"^refs:([0-9]+)/([0-9]+)$").unwrap();
+        let macro_re = Regex::new(r"^macro:([0-9]+)/([0-9]+)$").unwrap();
 
         if url.starts_with("id:") {
             let id: NodeID = url[3..].parse().ok()?;
@@ -137,6 +141,12 @@ pub mod gen_href {
         } else if url.starts_with("sym:") {
             let id: u64 = url[4..].parse().ok()?;
             Some(GenHref::SymId(SymID(id)))
+        } else if url.starts_with("types:") {
+            let id: NodeID = url[6..].parse().ok()?;
+            Some(GenHref::TypeHierarchy(id))
+        } else if url.starts_with("trigram:") {
+            let trigram: u32 = url[8..].parse().ok()?;
+            Some(GenHref::Trigram(trigram))
         } else if url.starts_with("path:") {
             Some(GenHref::Path(url[5..].into()))
         } else if url.starts_with("cur/") {
@@ -148,6 +158,12 @@ pub mod gen_href {
                 offset: caps[2].parse().ok()?,
             };
             Some(GenHref::RefsId(token_location))
+        } else if let Some(caps) = macro_re.captures(url) {
+            let token_location = TokenLocation {
+                node_id: caps[1].parse().ok()?,
+                offset: caps[2].parse().ok()?,
+            };
+            Some(GenHref::MacroExpansion(token_location))
         } else if let Some(caps) = file_slice_re.captures(url) {
             let floc = BlobSliceLoc {
                 blob_id: caps[1].parse().ok()?,
@@ -191,6 +207,24 @@ pub mod gen_href {
             assert_eq!(Some(path.clone()), from_str(dbg!(&to_str(&path))));
         }
 
+        #[test]
+        fn type_hierarchy_roundtrip() {
+            let id = GenHref::TypeHierarchy(4321);
+            assert_eq!(Some(id.clone()), from_str(dbg!(&to_str(&id))));
+        }
+
+        #[test]
+        fn macro_expansion_roundtrip() {
+            let id = GenHref::MacroExpansion(TokenLocation { node_id: 98765, offset: 1234 });
+            assert_eq!(Some(id.clone()), from_str(dbg!(&to_str(&id))));
+        }
+
+        #[test]
+        fn trigram_roundtrip() {
+            let id = GenHref::Trigram(0x616263);
+            assert_eq!(Some(id.clone()), from_str(dbg!(&to_str(&id))));
+        }
+
         #[test]
         fn refs_roundtrip() {
             let refs_id = GenHref::RefsId(TokenLocation { node_id: 98765, offset: 1234 });
@@ -375,3 +375,55 @@ impl SlicemapReader {
         Ok(())
     }
 }
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SlicemapEntry {
+    pub key: u64,
+    pub token_offset: Option<Offset>,
+    pub location: BlobSliceLoc,
+}
+
+/// Lists every leaf of the trie rooted at `root_loc`, loading trie nodes with
+/// `load` (which must return decompressed slice bytes).
+pub fn slicemap_entries(
+    root_loc: BlobSliceLoc,
+    load: &mut dyn FnMut(BlobSliceLoc) -> Result<Vec<u8>, Box<dyn Error>>,
+) -> Result<Vec<SlicemapEntry>, Box<dyn Error>> {
+    let mut entries = Vec::new();
+    let mut stack = vec![(root_loc, 0u64)];
+
+    while let Some((loc, key_prefix)) = stack.pop() {
+        let node = TrieNode::decode(&load(loc)?[..])?;
+        for branch in &node.branches {
+            let key = key_prefix | (branch.prefix << node.bit_offset);
+            let location = branch.location.ok_or("location missing from trie branch")?;
+            if branch.is_inner_node {
+                stack.push((location, key));
+            } else {
+                entries.push(SlicemapEntry { key, token_offset: branch.token_offset, location });
+            }
+        }
+    }
+
+    entries.sort_by_key(|e| (e.key, e.token_offset));
+    Ok(entries)
+}
+
+/// Looks up `key` in the trie rooted at `root_loc`, loading the trie nodes on
+/// its path with `load` (which must return decompressed slice bytes).
+pub fn slicemap_get(
+    root_loc: BlobSliceLoc,
+    key: u64,
+    load: &mut dyn FnMut(BlobSliceLoc) -> Result<Vec<u8>, Box<dyn Error>>,
+) -> Result<Option<BlobSliceLoc>, Box<dyn Error>> {
+    let mut loc = root_loc;
+    loop {
+        let node = TrieNode::decode(&load(loc)?[..])?;
+        let Some(branch) = node.find_key(key) else { return Ok(None) };
+        let location = branch.location.ok_or("location missing from trie branch")?;
+        if !branch.is_inner_node {
+            return Ok(Some(location));
+        }
+        loc = location;
+    }
+}
@@ -5,10 +5,15 @@ pub type TrieValue = u64;
 
 const CODE_BACKSPACE: u8 = 1;
 const CODE_LEAF: u8 = 0;
+/// Followed by the 2 to 4 UTF-8 bytes of a non-ASCII character.
+const CODE_UTF8: u8 = 2;
 
+/// Bytes up to 127 are ASCII characters, anything above starts a code
+/// (`1ccc nnnn`). Backspaces count characters, not bytes, so a trie of ASCII
+/// keys is the same as before non-ASCII characters were supported.
 pub enum TrieSymbol {
     EOF,
-    ASCIIChar(u8),
+    Char(char),
     Backspace(u8),
     Leaf(TrieValue),
 }
@@ -16,7 +21,7 @@ pub enum TrieSymbol {
 
 pub struct TrieWriter {
     data: Vec<u8>,
-    key: Vec<u8>,
+    key: Vec<char>,
 }
 
 
@@ -26,7 +31,7 @@ impl TrieWriter {
     }
 
     pub fn push(&mut self, key_str: &str, value: TrieValue) {
-        let key: &[u8] = key_str.as_ref();
+        let key: Vec<char> = key_str.chars().collect();
         let mut common_prefix_len = 0;
         while common_prefix_len < key.len() &&
               common_prefix_len < self.key.len() &&
@@ -46,17 +51,20 @@ impl TrieWriter {
             shift -= ds as usize;
         }
 
-        for c in &key[common_prefix_len..] {
-            if *c < 32 {
-                println!("skipping control character {} in key {:?}", c, key_str);
+        for &c in &key[common_prefix_len..] {
+            if c.is_control() {
+                println!("skipping control character {:?} in key {:?}", c, key_str);
                 continue;
             }
-            if *c > 127 {
-                println!("skipping non-ASCII character {} in key {:?}", c, key_str);
-                continue;
+            self.key.push(c);
+            if c.is_ascii() {
+                self.data.push(c as u8);
+            } else {
+                let mut buf = [0; 4];
+                let encoded = c.encode_utf8(&mut buf);
+                self.data.push(1 << 7 | CODE_UTF8 << 4 | encoded.len() as u8);
+                self.data.extend_from_slice(encoded.as_bytes());
             }
-            self.key.push(*c);
-            self.data.push(*c);
         }
 
         let value_bytes = value.to_be_bytes();
@@ -87,6 +95,7 @@ pub struct TrieReader<'a> {
 pub enum TrieError {
     UnexpectedEOF,
     UnknownCode { code: u8, offset: usize },
+    BadUtf8 { offset: usize },
 }
 
 impl<'a> TrieReader<'a> {
@@ -105,7 +114,7 @@ impl<'a> TrieReader<'a> {
         self.offset += 1;
 
         if byte <= 127 {
-            return Ok(ASCIIChar(byte));
+            return Ok(Char(byte as char));
         } else {
             let code = (byte >> 4) & 0b111;
             let shift = byte & 0b1111;
@@ -122,6 +131,18 @@ impl<'a> TrieReader<'a> {
                     }
                     return Ok(Leaf(value));
                 }
+                CODE_UTF8 => {
+                    let start = self.offset;
+                    let Some(bytes) = self.data.get(start..start + shift as usize) else {
+                        return Err(TrieError::UnexpectedEOF);
+                    };
+                    self.offset += shift as usize;
+                    let mut chars = std::str::from_utf8(bytes).map_err(|_| TrieError::BadUtf8 { offset: start })?.chars();
+                    return match (chars.next(), chars.next()) {
+                        (Some(c), None) => Ok(Char(c)),
+                        _ => Err(TrieError::BadUtf8 { offset: start }),
+                    };
+                }
                 _ => {
                     return Err(TrieError::UnknownCode { code: byte, offset: self.offset-1 });
                 }
@@ -148,8 +169,8 @@ impl<'a> Iterator for TrieItems<'a> {
             match self.reader.read_symbol() {
                 Err(e) => { panic!("trie read error: {:?}", e); },
                 Ok(TrieSymbol::EOF) => { return None; },
-                Ok(TrieSymbol::ASCIIChar(c)) => {
-                    self.key.push(c as char);
+                Ok(TrieSymbol::Char(c)) => {
+                    self.key.push(c);
                 }
                 Ok(TrieSymbol::Backspace(n)) => {
                     for _ in 0..n { self.key.pop(); }
@@ -189,4 +210,34 @@ mod test {
             .collect::<Vec<_>>();
         assert_eq!(data, decoded);
     }
+
+    #[test]
+    fn unicode_items() {
+        let data = vec![
+            ("Größe".to_string(), 1),
+            ("Grün".to_string(), 2),
+            ("dir/café.c".to_string(), 3),
+            ("dir/naïve.c".to_string(), 4),
+            ("日本語".to_string(), 5),
+            ("日本𝒳".to_string(), 6),
+        ];
+
+        let mut w = TrieWriter::new();
+        for (k, v) in &data { w.push(k, *v); }
+
+        let bytes = w.data();
+        let decoded = TrieReader::new(&bytes)
+            .items()
+            .collect::<Vec<_>>();
+        assert_eq!(data, decoded);
+    }
+
+    #[test]
+    fn ascii_encoding_unchanged() {
+        let mut w = TrieWriter::new();
+        w.push("ab", 1);
+        w.push("ac", 2);
+        // a b leaf(1) backspace(1) c leaf(2)
+        assert_eq!(w.data(), [b'a', b'b', 0x81, 1, 0x91, b'c', 0x81, 2]);
+    }
 }
@@ -0,0 +1,457 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+
+use prost::Message;
+use regex::{Regex, RegexBuilder};
+use regex_syntax::hir::{Class, Hir, HirKind};
+use serde::{Deserialize, Serialize};
+
+use crate::build_diff::BuildSource;
+use crate::slicemap_trie::slicemap_get;
+use crate::territory::index::{self as pb, NodeKind};
+use crate::NodeID;
+
+
+/// Three bytes of node text, ASCII letters lowercased, as the key of the
+/// text index trie.
+pub type Trigram = u32;
+
+/// Above this many alternatives, exact strings of a regex are reduced to the
+/// trigrams they all need.
+const MAX_EXACT_STRINGS: usize = 16;
+
+/// Character classes up to this size are expanded into exact strings.
+const MAX_CLASS_CHARS: u32 = 8;
+
+
+fn trigram(bytes: &[u8]) -> Trigram {
+    bytes.iter().fold(0, |t, b| (t << 8) | b.to_ascii_lowercase() as Trigram)
+}
+
+/// Distinct trigrams of `text`, sorted.
+pub fn trigrams(text: &str) -> Vec<Trigram> {
+    let mut trigrams: Vec<_> = text.as_bytes().windows(3).map(trigram).collect();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    trigrams
+}
+
+pub fn encode_postings(node_ids: &[NodeID]) -> pb::TrigramPostings {
+    let mut prev = 0;
+    let node_id_deltas = node_ids.iter().map(|&id| { let d = id - prev; prev = id; d }).collect();
+    pb::TrigramPostings { node_id_deltas }
+}
+
+pub fn decode_postings(postings: &pb::TrigramPostings) -> Vec<NodeID> {
+    postings.node_id_deltas.iter().scan(0, |id, d| { *id += d; Some(*id) }).collect()
+}
+
+
+#[derive(Deserialize, Default, Debug)]
+pub struct TextSearchOptions {
+    pub limit: Option<usize>,
+
+    /// Treat the pattern as a regex rather than a literal string
+    #[serde(default)]
+    pub regex: bool,
+
+    #[serde(default)]
+    pub case_sensitive: bool,
+}
+
+
+/// Trigrams a text must contain to possibly match a pattern.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum TrigramQuery {
+    /// No constraint, any text may match.
+    All,
+    Trigram(Trigram),
+    And(Vec<TrigramQuery>),
+    Or(Vec<TrigramQuery>),
+}
+
+impl TrigramQuery {
+    fn and(self, other: TrigramQuery) -> TrigramQuery {
+        match (self, other) {
+            (TrigramQuery::All, q) | (q, TrigramQuery::All) => q,
+            (TrigramQuery::And(mut l), TrigramQuery::And(r)) => { l.extend(r); TrigramQuery::And(l) },
+            (TrigramQuery::And(mut l), q) | (q, TrigramQuery::And(mut l)) => { l.push(q); TrigramQuery::And(l) },
+            (l, r) => TrigramQuery::And(vec![l, r]),
+        }
+    }
+
+    fn or(self, other: TrigramQuery) -> TrigramQuery {
+        match (self, other) {
+            (TrigramQuery::All, _) | (_, TrigramQuery::All) => TrigramQuery::All,
+            (TrigramQuery::Or(mut l), TrigramQuery::Or(r)) => { l.extend(r); TrigramQuery::Or(l) },
+            (TrigramQuery::Or(mut l), q) | (q, TrigramQuery::Or(mut l)) => { l.push(q); TrigramQuery::Or(l) },
+            (l, r) => TrigramQuery::Or(vec![l, r]),
+        }
+    }
+
+    /// Matches texts containing any of `strings`.
+    fn any_of(strings: &BTreeSet<Vec<u8>>) -> TrigramQuery {
+        let mut query = None;
+        for s in strings {
+            let all_trigrams = s.windows(3)
+                .map(|t| TrigramQuery::Trigram(trigram(t)))
+                .fold(TrigramQuery::All, TrigramQuery::and);
+            query = Some(match query {
+                None => all_trigrams,
+                Some(q) => TrigramQuery::or(q, all_trigrams),
+            });
+        }
+        query.unwrap_or(TrigramQuery::All)
+    }
+
+    /// All trigrams the query refers to.
+    pub fn trigrams(&self) -> BTreeSet<Trigram> {
+        let mut trigrams = BTreeSet::new();
+        let mut stack = vec![self];
+        while let Some(q) = stack.pop() {
+            match q {
+                TrigramQuery::All => {},
+                TrigramQuery::Trigram(t) => { trigrams.insert(*t); },
+                TrigramQuery::And(qs) | TrigramQuery::Or(qs) => stack.extend(qs),
+            }
+        }
+        trigrams
+    }
+
+    /// Nodes satisfying the query given the postings of its trigrams, `None`
+    /// for all nodes.
+    pub fn eval(&self, postings: &BTreeMap<Trigram, Vec<NodeID>>) -> Option<BTreeSet<NodeID>> {
+        match self {
+            TrigramQuery::All => None,
+            TrigramQuery::Trigram(t) => Some(postings.get(t).into_iter().flatten().copied().collect()),
+            TrigramQuery::And(qs) => qs.iter()
+                .filter_map(|q| q.eval(postings))
+                .reduce(|l, r| l.intersection(&r).copied().collect()),
+            TrigramQuery::Or(qs) => qs.iter()
+                .map(|q| q.eval(postings))
+                .try_fold(BTreeSet::new(), |mut acc, nodes| { acc.extend(nodes?); Some(acc) }),
+        }
+    }
+}
+
+
+/// What a regex needs: either exactly one of a small set of (lowercased)
+/// strings, or some trigrams.
+struct Requirement {
+    exact: Option<BTreeSet<Vec<u8>>>,
+    query: TrigramQuery,
+}
+
+impl Requirement {
+    fn exact(strings: impl IntoIterator<Item = Vec<u8>>) -> Self {
+        Requirement { exact: Some(strings.into_iter().collect()), query: TrigramQuery::All }
+    }
+
+    fn any() -> Self {
+        Requirement { exact: None, query: TrigramQuery::All }
+    }
+
+    fn into_query(self) -> TrigramQuery {
+        match self.exact {
+            Some(strings) => self.query.and(TrigramQuery::any_of(&strings)),
+            None => self.query,
+        }
+    }
+}
+
+
+fn requirement(hir: &Hir) -> Requirement {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => Requirement::exact([Vec::new()]),
+        HirKind::Literal(lit) => Requirement::exact([lit.0.to_ascii_lowercase()]),
+        HirKind::Class(class) => match class_strings(class) {
+            Some(strings) => Requirement::exact(strings),
+            None => Requirement::any(),
+        },
+        HirKind::Capture(cap) => requirement(&cap.sub),
+        HirKind::Repetition(rep) => {
+            if rep.min == 0 {
+                Requirement::any()
+            } else if rep.min == 1 && rep.max == Some(1) {
+                requirement(&rep.sub)
+            } else {
+                Requirement { exact: None, query: requirement(&rep.sub).into_query() }
+            }
+        },
+        HirKind::Concat(subs) => {
+            let mut query = TrigramQuery::All;
+            let mut exact = Some(BTreeSet::from([Vec::new()]));
+            let mut broken = false;
+            for sub in subs {
+                let req = requirement(sub);
+                query = query.and(req.query);
+                exact = match (exact, req.exact) {
+                    (Some(prefixes), Some(suffixes)) if prefixes.len() * suffixes.len() <= MAX_EXACT_STRINGS => {
+                        Some(prefixes.iter()
+                            .flat_map(|p| suffixes.iter().map(move |s| [&p[..], &s[..]].concat()))
+                            .collect())
+                    },
+                    (prefixes, suffixes) => {
+                        broken = true;
+                        if let Some(prefixes) = prefixes {
+                            query = query.and(TrigramQuery::any_of(&prefixes));
+                        }
+                        suffixes
+                    },
+                };
+            }
+            if broken {
+                Requirement { exact: None, query: Requirement { exact, query }.into_query() }
+            } else {
+                Requirement { exact, query }
+            }
+        },
+        HirKind::Alternation(subs) => {
+            let reqs: Vec<_> = subs.iter().map(requirement).collect();
+            let total: usize = reqs.iter().map(|r| r.exact.as_ref().map_or(usize::MAX, |e| e.len())).fold(0, usize::saturating_add);
+            if total <= MAX_EXACT_STRINGS && reqs.iter().all(|r| r.query == TrigramQuery::All) {
+                Requirement::exact(reqs.into_iter().flat_map(|r| r.exact.unwrap_or_default()))
+            } else {
+                let query = reqs.into_iter()
+                    .map(Requirement::into_query)
+                    .reduce(TrigramQuery::or)
+                    .unwrap_or(TrigramQuery::All);
+                Requirement { exact: None, query }
+            }
+        },
+    }
+}
+
+/// Expands a small character class into its (lowercased) characters.
+fn class_strings(class: &Class) -> Option<BTreeSet<Vec<u8>>> {
+    let mut strings = BTreeSet::new();
+    match class {
+        Class::Unicode(class) => {
+            let size: u32 = class.iter().map(|r| r.end() as u32 - r.start() as u32 + 1).sum();
+            if size > MAX_CLASS_CHARS {
+                return None;
+            }
+            for c in class.iter().flat_map(|r| r.start()..=r.end()) {
+                strings.insert(c.to_string().into_bytes().to_ascii_lowercase());
+            }
+        },
+        Class::Bytes(class) => {
+            let size: u32 = class.iter().map(|r| r.end() as u32 - r.start() as u32 + 1).sum();
+            if size > MAX_CLASS_CHARS {
+                return None;
+            }
+            for b in class.iter().flat_map(|r| r.start()..=r.end()) {
+                strings.insert(vec![b.to_ascii_lowercase()]);
+            }
+        },
+    }
+    Some(strings)
+}
+
+
+/// A line of node text matching a full-text query.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TextMatch {
+    #[serde(with = "crate::ser::node_id")]
+    pub node_id: NodeID,
+    pub path: String,
+    pub line: u32,
+    /// Byte offset of the match in the node text.
+    pub offset: u32,
+    /// The line the match starts on.
+    pub text: String,
+    /// Byte range of the match in `text`, cut at the end of the line.
+    pub positions: (usize, usize),
+}
+
+
+/// A compiled full-text query: the regex and the trigrams a node must
+/// contain to be worth matching against it.
+///
+/// Clients with a resolver look up the postings of `trigram_query().trigrams()`
+/// through `trigram:` hrefs, pass them to `candidates` and run `matches` on
+/// the candidate nodes.
+#[derive(Debug)]
+pub struct TextQuery {
+    regex: Regex,
+    trigram_query: TrigramQuery,
+}
+
+impl TextQuery {
+    pub fn new(pattern: &str, options: &TextSearchOptions) -> Result<Self, Box<dyn Error>> {
+        let pattern = if options.regex { pattern.to_string() } else { regex::escape(pattern) };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(!options.case_sensitive)
+            .multi_line(true)
+            .build()?;
+        // trigrams only fold ASCII case, so parse without Unicode case folding
+        // where the pattern allows it: "k" would also match the Kelvin sign
+        let parse = |unicode| regex_syntax::ParserBuilder::new()
+            .case_insensitive(!options.case_sensitive)
+            .multi_line(true)
+            .unicode(unicode)
+            .utf8(unicode)
+            .build()
+            .parse(&pattern)
+            .map_err(Box::new);
+        let hir = parse(false).or_else(|_| parse(true))?;
+        let trigram_query = requirement(&hir).into_query();
+        if trigram_query == TrigramQuery::All {
+            return Err(format!("pattern {pattern:?} needs a run of at least 3 literal characters").into());
+        }
+        Ok(TextQuery { regex, trigram_query })
+    }
+
+    pub fn trigram_query(&self) -> &TrigramQuery {
+        &self.trigram_query
+    }
+
+    /// Nodes that may match, given the postings of the query's trigrams.
+    /// Trigrams missing from `postings` are not in the index.
+    pub fn candidates(&self, postings: &BTreeMap<Trigram, Vec<NodeID>>) -> BTreeSet<NodeID> {
+        self.trigram_query.eval(postings).unwrap_or_default()
+    }
+
+    pub fn matches(&self, node: &pb::Node) -> Vec<TextMatch> {
+        if node.kind() == NodeKind::Directory {
+            return Vec::new();
+        }
+        let start_line = node.start.as_ref().map_or(1, |l| l.line);
+        let mut line = start_line;
+        let mut counted = 0;
+        let mut matches = Vec::new();
+        for m in self.regex.find_iter(&node.text) {
+            line += node.text[counted..m.start()].matches('\n').count() as u32;
+            counted = m.start();
+            let line_start = node.text[..m.start()].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = node.text[m.start()..].find('\n').map_or(node.text.len(), |i| m.start() + i);
+            // one match per line is enough to show it
+            if matches.last().is_some_and(|prev: &TextMatch| prev.line == line) {
+                continue;
+            }
+            matches.push(TextMatch {
+                node_id: node.id,
+                path: node.path.clone(),
+                line,
+                offset: m.start() as u32,
+                text: node.text[line_start..line_end].to_string(),
+                positions: (m.start() - line_start, m.end().min(line_end) - line_start),
+            });
+        }
+        matches
+    }
+}
+
+
+/// Runs a full-text query against the text index of a build.
+pub fn search_text(
+    source: &dyn BuildSource,
+    pattern: &str,
+    options: &TextSearchOptions,
+) -> Result<Vec<TextMatch>, Box<dyn Error>> {
+    let build = source.build();
+    let text_root = build.text_index_trie_root.ok_or("build has no text index")?;
+    let nodemap_root = build.nodemap_trie_root.ok_or("build has no nodemap trie")?;
+    let mut load = |loc| source.load_slice(loc);
+
+    let query = TextQuery::new(pattern, options)?;
+    let mut postings = BTreeMap::new();
+    for t in query.trigram_query().trigrams() {
+        if let Some(loc) = slicemap_get(text_root, t as u64, &mut load)? {
+            let p = pb::TrigramPostings::decode(&source.load_slice(loc)?[..])?;
+            postings.insert(t, decode_postings(&p));
+        }
+    }
+
+    let mut matches = Vec::new();
+    for node_id in query.candidates(&postings) {
+        if options.limit.is_some_and(|limit| matches.len() >= limit) {
+            break;
+        }
+        let Some(loc) = slicemap_get(nodemap_root, node_id, &mut load)? else { continue };
+        let node = pb::Node::decode(&source.load_slice(loc)?[..])?;
+        matches.extend(query.matches(&node));
+    }
+    if let Some(limit) = options.limit {
+        matches.truncate(limit);
+    }
+    matches.sort_by(|l, r| (&l.path, l.line).cmp(&(&r.path, r.line)));
+    Ok(matches)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::territory::index::{self as pb, NodeKind};
+
+    use super::{decode_postings, encode_postings, trigram, trigrams, TextQuery, TextSearchOptions, TrigramQuery};
+
+    fn t(s: &str) -> TrigramQuery {
+        TrigramQuery::Trigram(trigram(s.as_bytes()))
+    }
+
+    fn query(pattern: &str, regex: bool) -> TrigramQuery {
+        let options = TextSearchOptions { regex, ..Default::default() };
+        TextQuery::new(pattern, &options).unwrap().trigram_query().clone()
+    }
+
+    #[test]
+    fn text_trigrams() {
+        assert_eq!(trigrams("abAB"), vec![trigram(b"aba"), trigram(b"bab")]);
+        assert!(trigrams("ab").is_empty());
+        assert_eq!(decode_postings(&encode_postings(&[3, 10, 11])), vec![3, 10, 11]);
+        assert_eq!(encode_postings(&[3, 10, 11]).node_id_deltas, vec![3, 7, 1]);
+    }
+
+    #[test]
+    fn literal_query() {
+        assert_eq!(query("Hello", false), TrigramQuery::And(vec![t("hel"), t("ell"), t("llo")]));
+        // regex metacharacters are escaped
+        assert_eq!(query("a.*b", false), TrigramQuery::And(vec![t("a.*"), t(".*b")]));
+    }
+
+    #[test]
+    fn regex_query() {
+        assert_eq!(query("foo.*bar", true), TrigramQuery::And(vec![t("foo"), t("bar")]));
+        assert_eq!(query("ab(c|d)e", true), TrigramQuery::Or(vec![
+            TrigramQuery::And(vec![t("abc"), t("bce")]),
+            TrigramQuery::And(vec![t("abd"), t("bde")]),
+        ]));
+        assert_eq!(query(r"\bprintk\(", true), TrigramQuery::And(vec![t("pri"), t("rin"), t("int"), t("ntk"), t("tk(")]));
+        assert_eq!(query("(foo|bar)_?x+", true), TrigramQuery::Or(vec![t("bar"), t("foo")]));
+
+        let options = TextSearchOptions { regex: true, ..Default::default() };
+        assert!(TextQuery::new("a.b", &options).is_err());
+        // "xy" alone can't be looked up
+        assert!(TextQuery::new("(foo|xy)z*", &options).is_err());
+        assert!(TextQuery::new("[a-z]+_init", &options).is_ok());
+    }
+
+    #[test]
+    fn candidates_and_matches() {
+        let q = TextQuery::new("lock", &TextSearchOptions::default()).unwrap();
+        let postings = BTreeMap::from([
+            (trigram(b"loc"), vec![1, 2, 3]),
+            (trigram(b"ock"), vec![2, 3, 4]),
+        ]);
+        assert_eq!(q.candidates(&postings).into_iter().collect::<Vec<_>>(), vec![2, 3]);
+
+        let node = pb::Node {
+            id: 2,
+            kind: NodeKind::Definition.into(),
+            path: "a.c".to_string(),
+            start: Some(pb::Location { line: 10, column: 1, offset: 0 }),
+            text: "void f() {\n    spin_lock(&l); LOCK();\n    unlock();\n}".to_string(),
+            ..Default::default()
+        };
+        let matches = q.matches(&node);
+        assert_eq!(matches.len(), 2);
+        assert_eq!((matches[0].line, matches[0].text.as_str(), matches[0].positions), (11, "    spin_lock(&l); LOCK();", (9, 13)));
+        assert_eq!((matches[1].line, matches[1].positions), (12, (6, 10)));
+
+        let q = TextQuery::new("LOCK", &TextSearchOptions { case_sensitive: true, ..Default::default() }).unwrap();
+        assert_eq!(q.matches(&node).len(), 1);
+    }
+}
@@ -51,6 +51,7 @@ impl<'a> TokenWriter<'a> {
                 href: href.map(|h| Href::NodeIdRef(h)),
                 sym_id,
                 references: reflink,
+                has_macro_expansion: false,
             },
         };
 
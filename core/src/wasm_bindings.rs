@@ -6,10 +6,12 @@ use gloo_utils::format::JsValueSerdeExt;
 use prost::Message;
 
 use crate::resolver::{ConcreteLocation, NeedData, ResolutionFailure};
-use crate::Node;
+use crate::{GenHref, Node};
 use crate::search::{Options, search, TrieIndex};
+use crate::search_shards::ShardedIndex;
 use crate::slicemap_trie::{SlicemapReader, SharedCache};
 use crate::territory::index as pb;
+use crate::text_search::{decode_postings, TextSearchOptions};
 
 
 #[wasm_bindgen]
@@ -55,17 +57,61 @@ pub struct Build {
 impl Build {
     pub fn resolver(&self, max_mem: usize) -> Resolver {
         let cache = SharedCache::new(max_mem);
+        let mut inner = crate::resolver::TrieResolver::new(
+            crate::resolver::BasicResolver,
+            SlicemapReader::new(self.data.nodemap_trie_root.unwrap(), SharedCache::new_handle(&cache, "nodemap")),
+            SlicemapReader::new(self.data.symmap_trie_root.unwrap(), SharedCache::new_handle(&cache, "symmap")),
+            SlicemapReader::new(self.data.references_trie_root.unwrap(), SharedCache::new_handle(&cache, "refmap")),
+            self.data.repo_root_node_id,
+        );
+        if let Some(root) = self.data.type_hierarchy_trie_root {
+            inner = inner.with_type_hierarchy(SlicemapReader::new(root, SharedCache::new_handle(&cache, "typemap")));
+        }
+        if let Some(root) = self.data.macro_expansion_trie_root {
+            inner = inner.with_macro_expansions(SlicemapReader::new(root, SharedCache::new_handle(&cache, "macromap")));
+        }
+        if let Some(root) = self.data.text_index_trie_root {
+            inner = inner.with_text_index(SlicemapReader::new(root, SharedCache::new_handle(&cache, "textmap")));
+        }
         Resolver {
             pending_fetches: Mutex::new(HashMap::new()),
-            inner: Box::new(crate::resolver::TrieResolver::new(
-                crate::resolver::BasicResolver,
-                SlicemapReader::new(self.data.nodemap_trie_root.unwrap(), SharedCache::new_handle(&cache, "nodemap")),
-                SlicemapReader::new(self.data.symmap_trie_root.unwrap(), SharedCache::new_handle(&cache, "symmap")),
-                SlicemapReader::new(self.data.references_trie_root.unwrap(), SharedCache::new_handle(&cache, "refmap")),
-                self.data.repo_root_node_id,
-            )),
+            inner: Box::new(inner),
         }
     }
+
+    /// Sharded search index of the build, `undefined` for builds written
+    /// before the index was sharded.
+    pub fn search_index(&self) -> Option<ShardedSearchIndex> {
+        let root = self.data.search_shards?;
+        Some(ShardedSearchIndex {
+            inner: ShardedIndex::new(root),
+            pending_fetches: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[wasm_bindgen]
+pub struct ShardedSearchIndex {
+    inner: ShardedIndex,
+    pending_fetches: PendingFetches,
+}
+
+#[wasm_bindgen]
+impl ShardedSearchIndex {
+    /// Like `Resolver.resolve_url`, calls `more` with the location of each
+    /// shard the query needs that isn't loaded yet.
+    pub async fn search(&self, query: JsValue, options: JsValue, more: &js_sys::Function) -> Result<JsValue, JsValue> {
+        let query_str: String = serde_wasm_bindgen::from_value(query)?;
+        let options: Options = serde_wasm_bindgen::from_value(options)?;
+        let results = with_fetches(&self.pending_fetches, more, || self.inner.search(&query_str, &options)).await?;
+        Ok(JsValue::from_serde(&results).unwrap())
+    }
+
+    /// `[total, loaded]` shard counts, once the manifest is loaded.
+    pub fn loaded_shards(&self) -> Option<Vec<usize>> {
+        let (total, loaded) = self.inner.loaded_shards()?;
+        Some(vec![total, loaded])
+    }
 }
 
 enum SearchIndexImpl {
@@ -154,49 +200,112 @@ pub fn decode_trie_index(raw_value: JsValue) -> Result<SearchIndex, JsValue> {
 }
 
 
+#[wasm_bindgen]
+pub struct TextQuery {
+    inner: crate::text_search::TextQuery,
+}
+
+#[wasm_bindgen]
+impl TextQuery {
+    /// `trigram:` urls whose postings `candidates` needs.
+    pub fn trigram_urls(&self) -> Result<JsValue, JsValue> {
+        let urls: Vec<String> = self.inner.trigram_query().trigrams()
+            .into_iter()
+            .map(|t| GenHref::Trigram(t).to_url())
+            .collect();
+        Ok(serde_wasm_bindgen::to_value(&urls)?)
+    }
+
+    /// Takes `[url, bytes]` pairs for the trigram urls that resolved and
+    /// returns the `id:` urls of the nodes to pass to `matches`.
+    pub fn candidates(&self, postings: JsValue) -> Result<JsValue, JsValue> {
+        let raw: Vec<(String, serde_bytes::ByteBuf)> = serde_wasm_bindgen::from_value(postings)?;
+        let mut postings = std::collections::BTreeMap::new();
+        for (url, bytes) in raw {
+            let Some(GenHref::Trigram(t)) = GenHref::from_url(&url) else {
+                return Err(format!("not a trigram url: {url}").into());
+            };
+            let p = pb::TrigramPostings::decode(&bytes[..]).map_err(|e| format!("decode error: {:?}", e))?;
+            postings.insert(t, decode_postings(&p));
+        }
+        let urls: Vec<String> = self.inner.candidates(&postings)
+            .into_iter()
+            .map(|id| GenHref::NodeId(id).to_url())
+            .collect();
+        Ok(serde_wasm_bindgen::to_value(&urls)?)
+    }
+
+    pub fn matches(&self, raw_value: JsValue) -> Result<JsValue, JsValue> {
+        let raw: serde_bytes::ByteBuf = serde_wasm_bindgen::from_value(raw_value)?;
+        let node = pb::Node::decode(&raw[..]).map_err(|e| format!("decode error: {:?}", e))?;
+        Ok(serde_wasm_bindgen::to_value(&self.inner.matches(&node))?)
+    }
+}
+
+#[wasm_bindgen]
+pub fn compile_text_query(pattern: &str, options: JsValue) -> Result<TextQuery, JsValue> {
+    let options: TextSearchOptions = serde_wasm_bindgen::from_value(options)?;
+    let inner = crate::text_search::TextQuery::new(pattern, &options).map_err(|e| e.to_string())?;
+    Ok(TextQuery { inner })
+}
+
+
+type PendingFetches = Mutex<HashMap<ConcreteLocation, (js_sys::Promise, NeedData)>>;
+
 #[wasm_bindgen]
 pub struct Resolver {
     inner: Box<dyn crate::resolver::Resolver>,
-    pending_fetches: Mutex<HashMap<ConcreteLocation, (js_sys::Promise, NeedData)>>,
+    pending_fetches: PendingFetches,
 }
 
 
 #[wasm_bindgen]
 impl Resolver {
     pub async fn resolve_url(&self, url: &str, more: &js_sys::Function) -> Result<JsValue, JsValue> {
-        loop {
-            let result = self.inner.resolve_url(url);
-            match result {
-                Ok(loc) => {
-                    return Ok(JsValue::from_serde(&loc).unwrap());
-                },
-                Err(ResolutionFailure::NeedData(nd)) => {
-                    let loc = nd.0.clone();
-
-                    let more_prom: js_sys::Promise  = {
-                        let mut pf = self.pending_fetches.lock().unwrap();
-                        if let Some((prom, _nd)) = pf.get(&loc) {
-                            prom.clone()
-                        } else {
-                            let prom: js_sys::Promise = more.call1(&JsValue::null(), &JsValue::from_serde(&loc).unwrap())?.try_into().unwrap();
-                            pf.insert(loc.clone(), (prom.clone(), nd));
-                            prom
-                        }
-                    };
-
-                    let more_js = wasm_bindgen_futures::JsFuture::from(more_prom).await?;
-                    let more_bytes: serde_bytes::ByteBuf = serde_wasm_bindgen::from_value(more_js)?;
-                    {
-                        let mut pf = self.pending_fetches.lock().unwrap();
-                        if let Some((_prom, NeedData(_loc, cont))) = pf.remove(&loc) {
-                            cont(&more_bytes)
-                                .map_err(|e| format!("index decode error: {:?}", e))?;
-                        }
+        let loc = with_fetches(&self.pending_fetches, more, || self.inner.resolve_url(url)).await?;
+        Ok(JsValue::from_serde(&loc).unwrap())
+    }
+}
+
+
+/// Calls `f` until it stops asking for data, fetching what it needs with
+/// `more`. Concurrent calls needing the same location share one fetch.
+async fn with_fetches<T>(
+    pending_fetches: &PendingFetches,
+    more: &js_sys::Function,
+    f: impl Fn() -> Result<T, ResolutionFailure>,
+) -> Result<T, JsValue> {
+    loop {
+        match f() {
+            Ok(result) => {
+                return Ok(result);
+            },
+            Err(ResolutionFailure::NeedData(nd)) => {
+                let loc = nd.0.clone();
+
+                let more_prom: js_sys::Promise  = {
+                    let mut pf = pending_fetches.lock().unwrap();
+                    if let Some((prom, _nd)) = pf.get(&loc) {
+                        prom.clone()
+                    } else {
+                        let prom: js_sys::Promise = more.call1(&JsValue::null(), &JsValue::from_serde(&loc).unwrap())?.try_into().unwrap();
+                        pf.insert(loc.clone(), (prom.clone(), nd));
+                        prom
+                    }
+                };
+
+                let more_js = wasm_bindgen_futures::JsFuture::from(more_prom).await?;
+                let more_bytes: serde_bytes::ByteBuf = serde_wasm_bindgen::from_value(more_js)?;
+                {
+                    let mut pf = pending_fetches.lock().unwrap();
+                    if let Some((_prom, NeedData(_loc, cont))) = pf.remove(&loc) {
+                        cont(&more_bytes)
+                            .map_err(|e| format!("index decode error: {:?}", e))?;
                     }
-                },
-                Err(e) => {
-                    return Err(format!("resolve error: {:?}", e).into());
                 }
+            },
+            Err(e) => {
+                return Err(format!("resolve error: {:?}", e).into());
             }
         }
     }
@@ -0,0 +1,346 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+use memmap2::Mmap;
+use prost::Message;
+
+use territory_core::resolver::{
+    BasicResolver,
+    ConcreteLocation,
+    NeedData,
+    ResolutionFailure,
+    ResolutionResult,
+    Resolver,
+    TrieResolver,
+};
+use territory_core::slicemap_trie::{slicemap_entries, SharedCache, SlicemapReader};
+use territory_core::territory::index::{ArchiveEntry, ArchiveToc, Build};
+use territory_core::{BlobSliceLoc, GenHref};
+
+use crate::args::Args;
+use crate::index_reader::{maybe_decompress, read_raw};
+
+
+const MAGIC: &[u8; 8] = b"TTYARC01";
+const HEADER_LEN: u64 = 24;
+const MAX_RESOLVE_ATTEMPTS: usize = 10;
+
+pub const BUILD_ENTRY: &str = "build";
+pub const SEARCH_TRIE_ENTRY: &str = "search/trie";
+pub const SEARCH_ITEMS_ENTRY: &str = "search/all";
+
+
+/// Writes a single-file archive of one build.
+///
+/// Layout: the magic, the offset and length of the table of contents (both
+/// u64 LE), the entries back to back and finally the `ArchiveToc`. Entries
+/// are stored exactly as the file backend writes them, so blob slices keep
+/// their `BlobSliceLoc` offsets relative to the start of the blob entry.
+pub struct ArchiveWriter<W: Write + Seek> {
+    out: W,
+    pos: u64,
+    toc: ArchiveToc,
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub fn new(mut out: W, repo_id: &str, build_id: &str) -> std::io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[0; (HEADER_LEN as usize) - MAGIC.len()])?;
+        Ok(Self {
+            out,
+            pos: HEADER_LEN,
+            toc: ArchiveToc {
+                repo_id: repo_id.to_string(),
+                build_id: build_id.to_string(),
+                entries: Vec::new(),
+            },
+        })
+    }
+
+    pub fn add(&mut self, path: &str, data: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(data)?;
+        self.toc.entries.push(ArchiveEntry {
+            path: path.to_string(),
+            offset: self.pos,
+            length: data.len() as u64,
+        });
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let toc = self.toc.encode_to_vec();
+        self.out.write_all(&toc)?;
+        self.out.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        self.out.write_all(&self.pos.to_le_bytes())?;
+        self.out.write_all(&(toc.len() as u64).to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+
+/// Packs the build written by the file backend to `outdir` into `archive_path`:
+/// the `Build`, the search index and every blob the slicemap tries point to.
+pub fn pack(outdir: &Path, repo_id: &str, build_id: &str, archive_path: &Path) -> Result<(), Box<dyn Error>> {
+    let blobs_dir = outdir.join("nodes").join(repo_id);
+    let search_dir = outdir.join("search").join(repo_id).join(build_id);
+    let raw_build = read_raw(&outdir.join("builds").join(repo_id).join(build_id))?;
+    let build = Build::decode(&maybe_decompress(&raw_build)?[..])?;
+
+    let required_roots = [build.nodemap_trie_root, build.symmap_trie_root, build.references_trie_root];
+    if required_roots.iter().any(Option::is_none) {
+        return Err(format!("build {build_id} is missing a trie root").into());
+    }
+    let mut blobs: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    for blob_id in build_blobs(&blobs_dir, &build)? {
+        blobs.insert(blob_id, read_raw(&blobs_dir.join(format!("f/{blob_id}")))?);
+    }
+
+    let tmp_path = archive_path.with_extension("tmp");
+    let mut writer = ArchiveWriter::new(BufWriter::new(File::create(&tmp_path)?), repo_id, build_id)?;
+    writer.add(BUILD_ENTRY, &raw_build)?;
+    writer.add(SEARCH_TRIE_ENTRY, &read_raw(&search_dir.join("trie"))?)?;
+    writer.add(SEARCH_ITEMS_ENTRY, &read_raw(&search_dir.join("all"))?)?;
+    for (blob_id, data) in &blobs {
+        writer.add(&format!("f/{blob_id}"), data)?;
+    }
+    writer.finish()?;
+    std::fs::rename(&tmp_path, archive_path)?;
+
+    info!("packed build {} with {} blobs into {:?}", build_id, blobs.len(), archive_path);
+    Ok(())
+}
+
+
+/// IDs of the blobs under `blobs_dir` (`nodes/<repo_id>`) that `build`
+/// reads: those holding trie nodes along with those holding slices, the
+/// include graph and the search shards.
+pub fn build_blobs(blobs_dir: &Path, build: &Build) -> Result<BTreeSet<u64>, Box<dyn Error>> {
+    let mut blobs = BTreeSet::new();
+    let roots = [
+        build.nodemap_trie_root,
+        build.symmap_trie_root,
+        build.references_trie_root,
+        build.type_hierarchy_trie_root,
+        build.macro_expansion_trie_root,
+        build.text_index_trie_root,
+    ];
+    for root in roots.into_iter().flatten() {
+        let entries = slicemap_entries(root, &mut |loc: BlobSliceLoc| {
+            blobs.insert(loc.blob_id);
+            let blob = read_raw(&blobs_dir.join(format!("f/{}", loc.blob_id)))?;
+            let bytes = blob
+                .get(loc.start_offset as usize..loc.end_offset as usize)
+                .ok_or_else(|| format!("trie node {loc:?} out of bounds"))?;
+            Ok(maybe_decompress(bytes)?)
+        })?;
+        blobs.extend(entries.iter().map(|e| e.location.blob_id));
+    }
+    blobs.extend(build.include_graph.map(|loc| loc.blob_id));
+    blobs.extend(build.search_shards.map(|loc| loc.blob_id));
+    Ok(blobs)
+}
+
+
+pub fn pack_from_args(args: &Args, archive_path: &Path) -> Result<(), Box<dyn Error>> {
+    if args.storage_mode != "file" {
+        return Err("--archive requires file storage".into());
+    }
+    pack(&args.outdir, &args.repo_id, &args.build_id, archive_path)
+}
+
+
+/// Memory-mapped archive written by [`ArchiveWriter`].
+pub struct Archive {
+    mmap: Mmap,
+    repo_id: String,
+    build_id: String,
+    entries: HashMap<String, (usize, usize)>,
+}
+
+impl Archive {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path).map_err(|e| format!("error opening {path:?}: {e}"))?;
+        // archives are written to a temporary file and renamed into place,
+        // never modified afterwards
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        if mmap.len() < HEADER_LEN as usize || &mmap[..MAGIC.len()] != MAGIC {
+            return Err(format!("{path:?} is not a territory archive").into());
+        }
+        let toc_offset = u64::from_le_bytes(mmap[8..16].try_into()?) as usize;
+        let toc_len = u64::from_le_bytes(mmap[16..24].try_into()?) as usize;
+        let toc_bytes = toc_offset.checked_add(toc_len)
+            .and_then(|toc_end| mmap.get(toc_offset..toc_end))
+            .ok_or_else(|| format!("table of contents out of bounds in {path:?}"))?;
+        let toc = ArchiveToc::decode(toc_bytes)?;
+
+        let mut entries = HashMap::new();
+        for entry in toc.entries {
+            let start = entry.offset as usize;
+            let end = entry.offset.checked_add(entry.length).and_then(|end| usize::try_from(end).ok());
+            match end {
+                Some(end) if start <= end && end <= toc_offset => {
+                    entries.insert(entry.path, (start, end));
+                },
+                _ => return Err(format!("entry {} out of bounds in {path:?}", entry.path).into()),
+            }
+        }
+
+        Ok(Self { mmap, repo_id: toc.repo_id, build_id: toc.build_id, entries })
+    }
+
+    pub fn repo_id(&self) -> &str {
+        &self.repo_id
+    }
+
+    pub fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    /// Raw bytes of the entry at `path`, as the file backend would have
+    /// written them.
+    pub fn get(&self, path: &str) -> Result<&[u8], Box<dyn Error>> {
+        let (start, end) = self.entries.get(path).ok_or_else(|| format!("not in archive: {path}"))?;
+        Ok(&self.mmap[*start..*end])
+    }
+
+    /// Raw bytes at `loc`, still compressed if the build was.
+    pub fn slice(&self, loc: &ConcreteLocation) -> Result<&[u8], Box<dyn Error>> {
+        let entry = self.get(&loc.path)?;
+        match loc.blob_bytes {
+            Some((l, r)) => entry
+                .get(l as usize..r as usize)
+                .ok_or_else(|| format!("slice {l}:{r} out of bounds in {}", loc.path).into()),
+            None => Ok(entry),
+        }
+    }
+
+    pub fn build(&self) -> Result<Build, Box<dyn Error>> {
+        Ok(Build::decode(&maybe_decompress(self.get(BUILD_ENTRY)?)?[..])?)
+    }
+}
+
+
+/// Resolver over the slicemap tries of an archive. Trie nodes are read from
+/// the mapped file as needed, so resolution never returns `NeedData`.
+pub struct ArchiveResolver {
+    archive: Arc<Archive>,
+    inner: TrieResolver<BasicResolver>,
+}
+
+impl ArchiveResolver {
+    pub fn new(archive: Arc<Archive>) -> Result<Self, Box<dyn Error>> {
+        let build = archive.build()?;
+        let cache = SharedCache::new(1024);
+        let trie = |root: Option<BlobSliceLoc>, name: &str| -> Result<SlicemapReader, Box<dyn Error>> {
+            let root = root.ok_or_else(|| format!("build {} has no {name} trie", build.id))?;
+            let handle = SharedCache::new_handle(&cache, &format!("{}/{name}", archive.repo_id()));
+            Ok(SlicemapReader::new(root, handle))
+        };
+        let mut inner = TrieResolver::new(
+            BasicResolver,
+            trie(build.nodemap_trie_root, "nodes")?,
+            trie(build.symmap_trie_root, "syms")?,
+            trie(build.references_trie_root, "refs")?,
+            build.repo_root_node_id,
+        );
+        if build.type_hierarchy_trie_root.is_some() {
+            inner = inner.with_type_hierarchy(trie(build.type_hierarchy_trie_root, "types")?);
+        }
+        if build.macro_expansion_trie_root.is_some() {
+            inner = inner.with_macro_expansions(trie(build.macro_expansion_trie_root, "macros")?);
+        }
+        if build.text_index_trie_root.is_some() {
+            inner = inner.with_text_index(trie(build.text_index_trie_root, "text")?);
+        }
+        Ok(Self { archive, inner })
+    }
+
+    /// Reads and decompresses the bytes at `loc`.
+    pub fn load_bytes(&self, loc: &ConcreteLocation) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(maybe_decompress(self.archive.slice(loc)?)?)
+    }
+}
+
+impl Resolver for ArchiveResolver {
+    fn resolve_href(&self, href: &GenHref) -> ResolutionResult {
+        for _ in 0..MAX_RESOLVE_ATTEMPTS {
+            match self.inner.resolve_href(href) {
+                Err(ResolutionFailure::NeedData(NeedData(loc, cont))) => {
+                    let data = self.load_bytes(&loc)?;
+                    cont(&data)?;
+                },
+                res => return res,
+            }
+        }
+        Err(ResolutionFailure::Error(format!("failed to resolve {} in {MAX_RESOLVE_ATTEMPTS} attempts", href.to_url()).into()))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use prost::Message;
+
+    use territory_core::resolver::ConcreteLocation;
+    use territory_core::territory::index::{ArchiveEntry, ArchiveToc};
+
+    use super::{Archive, ArchiveWriter, HEADER_LEN, MAGIC};
+
+    #[test]
+    fn write_and_map() {
+        let dir = testdir::testdir!();
+        let path = dir.join("build.tty");
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), "repo", "b1").unwrap();
+        writer.add("f/1", b"hello world").unwrap();
+        writer.add("build", b"").unwrap();
+        writer.add("f/2", b"abc").unwrap();
+        std::fs::write(&path, writer.finish().unwrap().into_inner()).unwrap();
+
+        let archive = Archive::open(&path).unwrap();
+        assert_eq!((archive.repo_id(), archive.build_id()), ("repo", "b1"));
+        assert_eq!(archive.get("f/2").unwrap(), b"abc");
+        assert_eq!(archive.get("build").unwrap(), b"");
+        assert!(archive.get("f/3").is_err());
+
+        let loc = ConcreteLocation { path: "f/1".into(), blob_bytes: Some((6, 11)), token_offset: None };
+        assert_eq!(archive.slice(&loc).unwrap(), b"world");
+        let loc = ConcreteLocation { blob_bytes: Some((6, 12)), ..loc };
+        assert!(archive.slice(&loc).is_err());
+
+        std::fs::write(&path, b"not an archive at all, really").unwrap();
+        assert!(Archive::open(&path).is_err());
+    }
+
+    #[test]
+    fn entries_past_the_end_are_rejected() {
+        let path = testdir::testdir!().join("build.tty");
+        let open_with = |toc_offset: u64, offset: u64, length: u64| {
+            let entry = ArchiveEntry { path: "f/1".into(), offset, length };
+            let toc = ArchiveToc { repo_id: "repo".into(), build_id: "b1".into(), entries: vec![entry] };
+            let mut data = MAGIC.to_vec();
+            data.extend(toc_offset.to_le_bytes());
+            data.extend((toc.encoded_len() as u64).to_le_bytes());
+            data.extend(toc.encode_to_vec());
+            std::fs::write(&path, data).unwrap();
+            Archive::open(&path).err().unwrap().to_string()
+        };
+
+        let error = open_with(HEADER_LEN, u64::MAX - 1, 10);
+        assert!(error.starts_with("entry f/1 out of bounds"), "{}", error);
+        let error = open_with(HEADER_LEN, HEADER_LEN, 1);
+        assert!(error.starts_with("entry f/1 out of bounds"), "{}", error);
+        let error = open_with(u64::MAX, 0, 0);
+        assert!(error.starts_with("table of contents out of bounds"), "{}", error);
+    }
+}
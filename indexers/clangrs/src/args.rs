@@ -12,14 +12,6 @@ pub enum CompressionMode {
 }
 
 
-#[derive(ValueEnum, Debug, Clone)]
-pub enum StorageMode {
-    None,
-    File,
-    Cloud,
-}
-
-
 #[derive(ValueEnum, Debug, Clone)]
 pub enum Stage {
     Parse,
@@ -28,14 +20,29 @@ pub enum Stage {
 }
 
 
+/// How the driver gets its `--par` scanners.
+#[derive(ValueEnum, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScannerMode {
+    /// Start `--scanner-bin` processes and restart them when they fail
+    Process,
+    /// Run the scanners in threads of the indexer. libclang allows one
+    /// instance per process, so they scan one at a time.
+    Thread,
+    /// Wait for scanners started separately to connect to
+    /// `--scanner-socket-path`
+    External,
+}
+
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[arg(short, long, default_value=".")]
     pub repo: PathBuf,
 
-    #[arg(short = 'm', long, default_value="file")]
-    pub storage_mode: StorageMode,
+    /// One of the backends in `storage::BACKENDS`
+    #[arg(short = 'm', long, default_value="file", value_parser=crate::storage::parse_backend_name)]
+    pub storage_mode: String,
 
     #[arg(short = 'o', long, default_value=".territory/index")]
     pub outdir: PathBuf,
@@ -67,6 +74,8 @@ pub struct Args {
     #[arg(long)]
     pub remove_path_prefix: Option<String>,
 
+    /// Inherited file descriptor to write progress to as JSON lines: stage
+    /// transitions, translation units with an ETA, blobs and storage uploads
     #[arg(long)]
     pub status_fd: Option<std::os::fd::RawFd>,
 
@@ -91,18 +100,56 @@ pub struct Args {
     #[arg(long, default_value_t=false)]
     pub no_references: bool,
 
+    /// Items per shard of the search index loaded on demand by clients
+    #[arg(long, default_value_t=20000)]
+    pub search_shard_items: usize,
+
+    /// Skip building the trigram index used for full-text search.
+    #[arg(long, default_value_t=false)]
+    pub no_text_index: bool,
+
     #[arg(long)]
     pub clang_extra_args: Option<Vec<String>>,
 
     #[arg(long)]
     pub fatal_missing_spans: bool,
 
+    #[arg(long, value_enum, default_value="process")]
+    pub scanners: ScannerMode,
+
+    /// Socket of the driver. Scanners started by the driver get their own,
+    /// with the scanner number appended.
     #[arg(long, default_value="/tmp/territory.sock")]
     pub scanner_socket_path: PathBuf,
 
+    /// Seconds to wait for a scanner to connect
     #[arg(long, default_value="5")]
     pub scanner_socket_timeout: usize,
 
+    /// Times a failed scanner is restarted before its slot is given up
+    #[arg(long, default_value_t=3)]
+    pub scanner_restarts: usize,
+
+    /// Seconds a started scanner may stay silent before it is considered
+    /// stuck and killed
+    #[arg(long, default_value_t=900)]
+    pub scanner_idle_timeout: u64,
+
+    /// Failures in a row while scanning translation units, with none
+    /// finished in between, after which a scanner's slot is given up
+    #[arg(long, default_value_t=10)]
+    pub scanner_tu_failures: usize,
+
+    /// Times a translation unit is retried after its scanner failed on it
+    /// before it is quarantined and left out of the build
+    #[arg(long, default_value_t=1)]
+    pub tu_retries: usize,
+
+    /// Percentage of the translation units that may be quarantined before
+    /// the run fails
+    #[arg(long, default_value_t=5)]
+    pub max_quarantined_percent: usize,
+
     #[arg(short = 'l', long)]
     pub log_dir: Option<PathBuf>,
 
@@ -111,6 +158,45 @@ pub struct Args {
 
     #[arg(long, default_value_t=100_000)]
     pub max_node_len: usize,
+
+    /// Re-parse only translation units that include one of the files listed
+    /// (one per line, relative to the repo) and reuse the rest of the previous
+    /// run on the same db_path and intermediate_path
+    #[arg(long, conflicts_with = "git_diff")]
+    pub changed_files: Option<PathBuf>,
+
+    /// Like --changed-files, with the list from `git diff --name-only <RANGE>`
+    #[arg(long)]
+    pub git_diff: Option<String>,
+
+    /// Continue a run of the same BUILD_ID that was killed, on the same
+    /// db_path, intermediate_path and storage: finished stages and parsed
+    /// translation units are skipped. Starts over if there is nothing to
+    /// resume.
+    #[arg(long, default_value_t=false, conflicts_with_all = ["stage", "changed_files", "git_diff", "uim_input", "watch"])]
+    pub resume: bool,
+
+    /// Keep running after the first build and re-index the files changed in
+    /// the repo, publishing each result as BUILD_ID.
+    #[arg(long, default_value_t=false)]
+    pub watch: bool,
+
+    /// Quiet period after the last change before a watch cycle starts
+    #[arg(long, default_value_t=500)]
+    pub watch_debounce_ms: u64,
+
+    /// Scanner executable started with `--scanners process`
+    #[arg(long, default_value="cscanner")]
+    pub scanner_bin: PathBuf,
+
+    /// Directory with the compile_commands.json scanners started by the
+    /// driver read, the repo if not given
+    #[arg(long)]
+    pub compile_commands_dir: Option<PathBuf>,
+
+    /// Also pack the finished build into a single archive file at PATH
+    #[arg(long, value_name="PATH")]
+    pub archive: Option<PathBuf>,
 }
 
 #[derive(Default)]
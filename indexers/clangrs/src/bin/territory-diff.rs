@@ -0,0 +1,62 @@
+use std::io::stdout;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+
+use clangrs::index_reader::IndexReader;
+use territory_core::build_diff::diff_builds;
+use territory_core::pretty_print;
+
+
+/// Compare two builds of a repository: nodes added, removed and changed,
+/// moved symbol definitions and references gained or lost per symbol.
+///
+/// Exits with 1 if the builds differ and 2 on errors.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct DiffArgs {
+    #[arg(short = 'o', long, default_value=".territory/index")]
+    outdir: PathBuf,
+
+    #[arg(long)]
+    repo_id: String,
+
+    old_build_id: String,
+
+    new_build_id: String,
+
+    /// Print the diff as JSON instead of text
+    #[arg(long)]
+    json: bool,
+}
+
+
+fn main() -> ExitCode {
+    let args = DiffArgs::parse();
+
+    let diff = IndexReader::open(&args.outdir, &args.repo_id, &args.old_build_id)
+        .and_then(|old| {
+            let new = IndexReader::open(&args.outdir, &args.repo_id, &args.new_build_id)?;
+            diff_builds(&old, &new)
+        });
+    let diff = match diff {
+        Ok(diff) => diff,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        },
+    };
+
+    let res = if args.json {
+        serde_json::to_writer_pretty(stdout().lock(), &diff).map_err(|e| e.into())
+    } else {
+        pretty_print::build_diff(&mut stdout().lock(), &diff)
+    };
+    if let Err(e) = res {
+        eprintln!("error: {e}");
+        return ExitCode::from(2);
+    }
+
+    if diff.is_empty() { ExitCode::SUCCESS } else { ExitCode::from(1) }
+}
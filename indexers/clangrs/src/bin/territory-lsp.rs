@@ -0,0 +1,143 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use clap::Parser;
+use log::{info, warn};
+use lsp_server::{Connection, Message, Request, RequestId, Response};
+use lsp_types::request::{
+    DocumentSymbolRequest,
+    GotoDefinition,
+    References,
+    WorkspaceSymbolRequest,
+};
+use lsp_types::{
+    DocumentSymbolResponse,
+    GotoDefinitionResponse,
+    OneOf,
+    ServerCapabilities,
+    WorkspaceSymbolResponse,
+};
+use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
+
+use clangrs::index_reader::IndexReader;
+use clangrs::lsp::LspBackend;
+
+
+/// Language server (stdio) answering go-to-definition, references and symbol
+/// queries from a finished build.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct LspArgs {
+    /// Repository checkout the build was made from
+    #[arg(short, long, default_value=".")]
+    repo: PathBuf,
+
+    #[arg(short = 'o', long, default_value=".territory/index")]
+    outdir: PathBuf,
+
+    /// Intermediate DB of the build, used to resolve `path:` hrefs directly
+    #[arg(short = 'd', long)]
+    db_path: Option<PathBuf>,
+
+    #[arg(long)]
+    repo_id: String,
+
+    #[arg(long)]
+    build_id: String,
+}
+
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let args = LspArgs::parse();
+    // stdout carries the protocol, log to stderr only
+    TermLogger::init(
+            LevelFilter::Info,
+            simplelog::Config::default(),
+            TerminalMode::Stderr,
+            ColorChoice::Never)
+        .unwrap();
+
+    let repo = args.repo.canonicalize()?;
+    let reader = match &args.db_path {
+        Some(db_path) => IndexReader::open_with_db(&args.outdir, &args.repo_id, &args.build_id, db_path),
+        None => IndexReader::open(&args.outdir, &args.repo_id, &args.build_id),
+    };
+    let backend = LspBackend::new(&repo, reader.map_err(|e| e.to_string())?);
+
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        workspace_symbol_provider: Some(OneOf::Left(true)),
+        ..ServerCapabilities::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    info!("serving {}/{} for {:?}", args.repo_id, args.build_id, repo);
+
+    for msg in &connection.receiver {
+        let Message::Request(req) = msg else { continue };
+        if connection.handle_shutdown(&req)? {
+            break;
+        }
+        let response = handle_request(&backend, req);
+        connection.sender.send(Message::Response(response))?;
+    }
+
+    drop(connection);
+    io_threads.join()?;
+    Ok(())
+}
+
+
+fn handle_request(backend: &LspBackend, req: Request) -> Response {
+    let id = req.id.clone();
+    let result = match req.method.as_str() {
+        "textDocument/definition" => dispatch::<GotoDefinition>(req, |params| {
+            let pos = params.text_document_position_params;
+            let locations = backend.definition(&pos.text_document.uri, pos.position)?;
+            Ok(Some(GotoDefinitionResponse::Array(locations)))
+        }),
+        "textDocument/references" => dispatch::<References>(req, |params| {
+            let pos = params.text_document_position;
+            let locations = backend.references(
+                &pos.text_document.uri, pos.position, params.context.include_declaration)?;
+            Ok(Some(locations))
+        }),
+        "textDocument/documentSymbol" => dispatch::<DocumentSymbolRequest>(req, |params| {
+            let symbols = backend.document_symbols(&params.text_document.uri)?;
+            Ok(Some(DocumentSymbolResponse::Flat(symbols)))
+        }),
+        "workspace/symbol" => dispatch::<WorkspaceSymbolRequest>(req, |params| {
+            let symbols = backend.workspace_symbols(&params.query)?;
+            Ok(Some(WorkspaceSymbolResponse::Flat(symbols)))
+        }),
+        method => {
+            return error_response(id, lsp_server::ErrorCode::MethodNotFound, format!("unsupported method: {method}"));
+        },
+    };
+
+    match result {
+        Ok(value) => Response::new_ok(id, value),
+        Err(e) => {
+            warn!("request {id} failed: {e}");
+            error_response(id, lsp_server::ErrorCode::RequestFailed, e.to_string())
+        },
+    }
+}
+
+
+fn dispatch<R>(
+    req: Request,
+    f: impl FnOnce(R::Params) -> Result<R::Result, Box<dyn Error>>,
+) -> Result<serde_json::Value, Box<dyn Error>>
+    where R: lsp_types::request::Request
+{
+    let params = serde_json::from_value(req.params)?;
+    Ok(serde_json::to_value(f(params)?)?)
+}
+
+
+fn error_response(id: RequestId, code: lsp_server::ErrorCode, message: String) -> Response {
+    Response::new_err(id, code as i32, message)
+}
@@ -0,0 +1,60 @@
+use std::error::Error;
+use std::io::stdout;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Parser;
+
+use clangrs::index_reader::IndexReader;
+use clangrs::query::{self, Command};
+
+
+/// Inspect a finished build from the command line.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct QueryArgs {
+    #[arg(short = 'o', long, default_value=".territory/index")]
+    outdir: PathBuf,
+
+    #[arg(long, required_unless_present = "archive")]
+    repo_id: Option<String>,
+
+    #[arg(long, required_unless_present = "archive")]
+    build_id: Option<String>,
+
+    /// Read the build from an archive written with `--archive` instead of OUTDIR
+    #[arg(short = 'a', long, conflicts_with = "db_path")]
+    archive: Option<PathBuf>,
+
+    /// Intermediate DB of the build, used to resolve `path:` hrefs directly
+    #[arg(short = 'd', long)]
+    db_path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+
+fn main() -> ExitCode {
+    let args = QueryArgs::parse();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+
+fn run(args: &QueryArgs) -> Result<(), Box<dyn Error>> {
+    let reader = match (&args.archive, &args.repo_id, &args.build_id) {
+        (Some(archive), _, _) => IndexReader::open_archive(archive),
+        (None, Some(repo_id), Some(build_id)) => match &args.db_path {
+            Some(db_path) => IndexReader::open_with_db(&args.outdir, repo_id, build_id, db_path),
+            None => IndexReader::open(&args.outdir, repo_id, build_id),
+        },
+        _ => unreachable!("clap requires repo_id and build_id without an archive"),
+    }?;
+    query::run(&reader, &args.command, &mut stdout().lock())
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+use clap::Parser;
+use log::{info, warn};
+use prost::Message;
+use serde::Serialize;
+use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use clangrs::index_reader::IndexReader;
+use territory_core::resolver::ConcreteLocation;
+use territory_core::search::{Options, Query, Ranking};
+use territory_core::territory::index::{Node, References};
+use territory_core::text_search::{TextQuery, TextSearchOptions};
+use territory_core::GenHref;
+
+
+/// Serve a finished build over HTTP/JSON.
+///
+/// GET /node?url=<href>        decoded node for an `id:`, `sym:` or `path:` href
+/// GET /references?url=<href>  decoded references for a `refs:` href
+/// GET /resolve?url=<href>     concrete blob location of any href
+/// GET /search?q=<query>       search index results (`limit`, `ranking` optional)
+/// GET /grep?q=<pattern>       full-text matches (`limit`, `regex`, `case_sensitive` optional)
+/// GET /build                  build metadata
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct ServerArgs {
+    #[arg(short = 'o', long, default_value=".territory/index")]
+    outdir: PathBuf,
+
+    #[arg(long)]
+    repo_id: String,
+
+    #[arg(long)]
+    build_id: String,
+
+    /// Intermediate DB of the build, used to resolve `path:` hrefs directly
+    #[arg(short = 'd', long)]
+    db_path: Option<PathBuf>,
+
+    #[arg(short, long, default_value="127.0.0.1:8411")]
+    listen: String,
+
+    #[arg(short, long, default_value_t=4)]
+    threads: usize,
+}
+
+
+#[derive(Serialize)]
+struct BuildInfo<'a> {
+    repo_id: &'a str,
+    build_id: &'a str,
+    repo_root_node_id: u64,
+}
+
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+
+enum HandlerError {
+    BadRequest(String),
+    /// No such endpoint, or the href is not in the build
+    NotFound(String),
+    Internal(String),
+}
+
+impl<E: Into<Box<dyn Error>>> From<E> for HandlerError {
+    fn from(e: E) -> Self {
+        HandlerError::Internal(e.into().to_string())
+    }
+}
+
+
+type HandlerResult = Result<String, HandlerError>;
+
+
+fn main() {
+    let args = ServerArgs::parse();
+    TermLogger::init(
+            LevelFilter::Info,
+            simplelog::Config::default(),
+            TerminalMode::Mixed,
+            ColorChoice::Auto)
+        .unwrap();
+
+    let reader = match &args.db_path {
+        Some(db_path) => IndexReader::open_with_db(&args.outdir, &args.repo_id, &args.build_id, db_path),
+        None => IndexReader::open(&args.outdir, &args.repo_id, &args.build_id),
+    }.expect("failed to open build");
+    let reader = Arc::new(reader);
+
+    let server = Arc::new(Server::http(&args.listen).expect("failed to start HTTP server"));
+    info!("serving {}/{} from {:?} on http://{}", args.repo_id, args.build_id, args.outdir, args.listen);
+
+    let workers: Vec<_> = (0..args.threads.max(1)).map(|_| {
+        let server = Arc::clone(&server);
+        let reader = Arc::clone(&reader);
+        let args = args.clone();
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                handle(&args, &reader, request);
+            }
+        })
+    }).collect();
+
+    for w in workers {
+        w.join().unwrap();
+    }
+}
+
+
+fn handle(args: &ServerArgs, reader: &IndexReader, request: Request) {
+    let (path, query) = match request.url().split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (request.url().to_string(), String::new()),
+    };
+    let params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let result = match (request.method(), path.as_str()) {
+        (Method::Get, "/node") => get_node(reader, &params),
+        (Method::Get, "/references") => get_references(reader, &params),
+        (Method::Get, "/resolve") => get_location(reader, &params),
+        (Method::Get, "/search") => get_search(reader, &params),
+        (Method::Get, "/grep") => get_grep(reader, &params),
+        (Method::Get, "/build") => get_build(args, reader),
+        _ => Err(HandlerError::NotFound(format!("no such endpoint: {path}"))),
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err(HandlerError::BadRequest(error)) => (400, error_body(error)),
+        Err(HandlerError::NotFound(error)) => (404, error_body(error)),
+        Err(HandlerError::Internal(error)) => (500, error_body(error)),
+    };
+    if status != 200 {
+        warn!("{} {} -> {}", request.method(), request.url(), status);
+    }
+
+    let response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+        .with_header(header("Access-Control-Allow-Origin", "*"));
+    if let Err(e) = request.respond(response) {
+        warn!("failed to send response: {e}");
+    }
+}
+
+
+fn get_node(reader: &IndexReader, params: &HashMap<String, String>) -> HandlerResult {
+    let href = href_param(params)?;
+    if let GenHref::RefsId(_) = href {
+        return Err(HandlerError::BadRequest("refs: hrefs are served by /references".to_string()));
+    }
+    let loc = resolve(reader, &href)?;
+    to_json(&Node::decode(&reader.load_bytes(&loc)?[..])?)
+}
+
+
+fn get_references(reader: &IndexReader, params: &HashMap<String, String>) -> HandlerResult {
+    let href = href_param(params)?;
+    let GenHref::RefsId(_) = href else {
+        return Err(HandlerError::BadRequest("expected a refs: href".to_string()));
+    };
+    let loc = resolve(reader, &href)?;
+    to_json(&References::decode(&reader.load_bytes(&loc)?[..])?)
+}
+
+
+fn get_location(reader: &IndexReader, params: &HashMap<String, String>) -> HandlerResult {
+    let href = href_param(params)?;
+    to_json(&resolve(reader, &href)?)
+}
+
+
+fn get_search(reader: &IndexReader, params: &HashMap<String, String>) -> HandlerResult {
+    let query = params.get("q").ok_or(HandlerError::BadRequest("missing q parameter".to_string()))?;
+    Query::parse(query).map_err(|e| HandlerError::BadRequest(format!("bad query: {e}")))?;
+    let limit = params.get("limit")
+        .map(|l| l.parse())
+        .transpose()
+        .map_err(|e| HandlerError::BadRequest(format!("bad limit: {e}")))?;
+    let ranking: Ranking = params.get("ranking")
+        .map(|r| serde_json::from_value(serde_json::Value::String(r.clone())))
+        .transpose()
+        .map_err(|e| HandlerError::BadRequest(format!("bad ranking: {e}")))?
+        .unwrap_or_default();
+
+    to_json(&reader.search(query, &Options { limit, ranking })?)
+}
+
+
+fn get_grep(reader: &IndexReader, params: &HashMap<String, String>) -> HandlerResult {
+    let pattern = params.get("q").ok_or(HandlerError::BadRequest("missing q parameter".to_string()))?;
+    let flag = |name: &str| params.get(name)
+        .map(|v| v.parse())
+        .transpose()
+        .map_err(|e| HandlerError::BadRequest(format!("bad {name}: {e}")))
+        .map(Option::unwrap_or_default);
+    let limit = params.get("limit")
+        .map(|l| l.parse())
+        .transpose()
+        .map_err(|e| HandlerError::BadRequest(format!("bad limit: {e}")))?;
+    let options = TextSearchOptions { limit, regex: flag("regex")?, case_sensitive: flag("case_sensitive")? };
+    TextQuery::new(pattern, &options).map_err(|e| HandlerError::BadRequest(format!("bad pattern: {e}")))?;
+
+    to_json(&reader.search_text(pattern, &options)?)
+}
+
+
+fn get_build(args: &ServerArgs, reader: &IndexReader) -> HandlerResult {
+    to_json(&BuildInfo {
+        repo_id: &args.repo_id,
+        build_id: &args.build_id,
+        repo_root_node_id: reader.build().repo_root_node_id,
+    })
+}
+
+
+fn href_param(params: &HashMap<String, String>) -> Result<GenHref, HandlerError> {
+    let url = params.get("url").ok_or(HandlerError::BadRequest("missing url parameter".to_string()))?;
+    GenHref::from_url(url).ok_or_else(|| HandlerError::BadRequest(format!("malformed href: {url}")))
+}
+
+
+fn resolve(reader: &IndexReader, href: &GenHref) -> Result<ConcreteLocation, HandlerError> {
+    reader.try_resolve_href(href)?
+        .ok_or_else(|| HandlerError::NotFound(format!("not found: {}", href.to_url())))
+}
+
+
+fn to_json<T: Serialize>(value: &T) -> HandlerResult {
+    Ok(serde_json::to_string(value)?)
+}
+
+
+fn error_body(error: String) -> String {
+    serde_json::to_string(&ErrorBody { error }).unwrap()
+}
+
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
@@ -1,13 +1,31 @@
+use std::path::PathBuf;
+
 use log::info;
+use prost::Message;
 
-use territory_core::territory::index::Build;
-use territory_core::RelativePath;
+use territory_core::territory::index::{Build, BlobSliceLoc};
+use territory_core::{BlobID, RelativePath};
+use territory_core::text_search::encode_postings;
 
 use crate::args::CompressionMode;
-use crate::intermediate_model::sqlite::{Paths, OutputMap};
+use crate::intermediate_model::sqlite::{Paths, OutputMap, TuIncludes};
 use crate::slicemap_trie_writer;
 use crate::storage::StorageChannel;
-use crate::writer::NodeWriter;
+use crate::writer::{apply_compression, NodeWriter};
+
+
+const TEXT_INDEX_BLOB_SIZE: usize = 16 << 20;
+
+
+/// What the tries of a build are written from.
+pub struct TrieInputs<'a> {
+    pub output_map: &'a OutputMap,
+    pub paths: &'a Paths,
+    pub tu_includes: &'a TuIncludes,
+    pub search_shards: BlobSliceLoc,
+    /// Also write the trigram postings and their trie.
+    pub text_index: bool,
+}
 
 
 pub async fn write_slicemap_tries(
@@ -15,10 +33,10 @@ pub async fn write_slicemap_tries(
     build_id: &str,
     compression_mode: CompressionMode,
     node_writer: &mut NodeWriter,
-    output_map: &OutputMap,
-    paths: &Paths,
+    inputs: TrieInputs<'_>,
     storage_channel: StorageChannel,
 ) {
+    let TrieInputs { output_map, paths, tu_includes, search_shards, text_index } = inputs;
     info!("writing nodemap trie");
     let node_locations = output_map.node_locations();
     let nodemap_trie_root = slicemap_trie_writer::write_slicemap(
@@ -40,6 +58,39 @@ pub async fn write_slicemap_tries(
         refs_locations, &output_map, storage_channel.clone()
     ).await;
 
+    info!("writing type hierarchy trie");
+    let type_hierarchy_locations = output_map.type_hierarchy_locations().into_iter();
+    let type_hierarchy_trie_root = slicemap_trie_writer::write_slicemap(
+        repo_id, compression_mode,
+        type_hierarchy_locations, &output_map, storage_channel.clone()
+    ).await;
+
+    info!("writing macro expansion trie");
+    let macro_expansion_locations = output_map.macro_expansion_locations().into_iter();
+    let macro_expansion_trie_root = slicemap_trie_writer::write_slicemap(
+        repo_id, compression_mode,
+        macro_expansion_locations, &output_map, storage_channel.clone()
+    ).await;
+
+    info!("writing include graph");
+    let include_graph = write_include_graph(
+        repo_id, compression_mode, tu_includes, output_map, storage_channel.clone()
+    ).await;
+
+    let text_index_trie_root = if text_index {
+        info!("writing text index trie");
+        write_text_index(repo_id, compression_mode, output_map, storage_channel.clone()).await;
+        let trigram_locations = output_map.trigram_locations()
+            .into_iter()
+            .map(|(trigram, loc)| (trigram as u64, loc));
+        Some(slicemap_trie_writer::write_slicemap(
+            repo_id, compression_mode,
+            trigram_locations, &output_map, storage_channel.clone()
+        ).await)
+    } else {
+        None
+    };
+
     let root_node_id = paths
         .get(&RelativePath::repo_root())
         .and_then(|p| paths.get_node_for_path(p))
@@ -50,9 +101,76 @@ pub async fn write_slicemap_tries(
         symmap_trie_root: Some(symmap_trie_root),
         references_trie_root: Some(references_trie_root),
         repo_root_node_id: root_node_id,
+        type_hierarchy_trie_root: Some(type_hierarchy_trie_root),
+        macro_expansion_trie_root: Some(macro_expansion_trie_root),
+        include_graph: Some(include_graph),
+        text_index_trie_root,
+        search_shards: Some(search_shards),
     };
     info!("created build: {build:?}");
     node_writer.submit_build(build);
 }
 
 
+
+/// Writes the postings list of every trigram, packed into blobs of up to
+/// `TEXT_INDEX_BLOB_SIZE` bytes, and records their locations in the trigrammap.
+/// Each blob is submitted as soon as it is full.
+async fn write_text_index(
+    repo_id: &str,
+    compression_mode: CompressionMode,
+    output_map: &OutputMap,
+    storage_channel: StorageChannel,
+) {
+    let blobs_dir = PathBuf::from("nodes").join(repo_id).join("f");
+    let output_map = output_map.clone();
+    // the postings are read synchronously, so blobs are submitted blocking
+    tokio::task::spawn_blocking(move || {
+        let submit = |blob_id: BlobID, buf: Vec<u8>| {
+            storage_channel.submit_blob_blocking(blobs_dir.join(blob_id.0.to_string()), buf);
+        };
+        let mut blob_count = 1;
+        let mut locations = Vec::new();
+        let mut buf = Vec::new();
+        let mut blob_id = output_map.new_blob_id();
+        output_map.for_each_trigram_posting(|trigram, node_ids| {
+            if buf.len() >= TEXT_INDEX_BLOB_SIZE {
+                submit(blob_id, std::mem::take(&mut buf));
+                blob_id = output_map.new_blob_id();
+                blob_count += 1;
+            }
+            let mut slice = apply_compression(compression_mode, encode_postings(node_ids).encode_to_vec());
+            let start_offset = buf.len().try_into().unwrap();
+            buf.append(&mut slice);
+            let end_offset = buf.len().try_into().unwrap();
+            locations.push((trigram, BlobSliceLoc { blob_id: blob_id.0, start_offset, end_offset }));
+        });
+        submit(blob_id, buf);
+        info!("text index has {} trigrams in {} blobs", locations.len(), blob_count);
+
+        output_map.store_trigram_locations(&locations);
+    }).await.unwrap();
+}
+
+
+async fn write_include_graph(
+    repo_id: &str,
+    compression_mode: CompressionMode,
+    tu_includes: &TuIncludes,
+    output_map: &OutputMap,
+    storage_channel: StorageChannel,
+) -> BlobSliceLoc {
+    let graph = tu_includes.graph();
+    info!("include graph has {} edges", graph.includes.len());
+    let buf = apply_compression(compression_mode, graph.encode_to_vec());
+
+    let blob_id = output_map.new_blob_id();
+    let loc = BlobSliceLoc {
+        blob_id: blob_id.0,
+        start_offset: 0,
+        end_offset: buf.len().try_into().unwrap(),
+    };
+    let path = PathBuf::from("nodes").join(repo_id).join("f").join(blob_id.0.to_string());
+    storage_channel.submit_blob(path, buf).await;
+    loc
+}
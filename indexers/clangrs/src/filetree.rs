@@ -129,6 +129,7 @@ impl<'a> FileTree<'a> {
                         href: Some(Href::NodeIdRef(child_href)),
                         sym_id: None,
                         references: territory_core::ReferencesLink::None,
+                        has_macro_expansion: false,
                     },
                 };
                 off += c.text.len() as u32;
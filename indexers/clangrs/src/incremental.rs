@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::info;
+
+use territory_core::{AbsolutePath, NodeID, RelativePath};
+use cscanner::ast::ClangCommand;
+
+use crate::args::Args;
+use crate::intermediate_model::SemFile;
+use crate::writer::{IntermediateNodeFileReader, IntermediateNodeFileWriter};
+
+
+/// Files changed since the previous run, from `--changed-files` or
+/// `--git-diff`. `None` if neither was given and everything is re-parsed.
+pub fn changed_files_from_args(args: &Args) -> Result<Option<HashSet<RelativePath>>, Box<dyn Error>> {
+    let listing = if let Some(list_path) = &args.changed_files {
+        std::fs::read_to_string(list_path)
+            .map_err(|e| format!("failed to read changed files list {:?}: {}", list_path, e))?
+    } else if let Some(range) = &args.git_diff {
+        let out = Command::new("git")
+            .arg("-C").arg(&args.repo)
+            .args(["diff", "--name-only", "--relative", range])
+            .output()
+            .map_err(|e| format!("failed to run git diff {}: {}", range, e))?;
+        if !out.status.success() {
+            return Err(format!(
+                "git diff {} failed: {}", range, String::from_utf8_lossy(&out.stderr)).into());
+        }
+        String::from_utf8(out.stdout)?
+    } else {
+        return Ok(None);
+    };
+
+    let repo = args.repo.canonicalize().unwrap_or(args.repo.clone());
+    let changed = listing
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            let p = PathBuf::from(l);
+            if p.is_absolute() {
+                AbsolutePath::from(p).to_relative(&repo)
+            } else {
+                RelativePath::from(p)
+            }
+        })
+        .collect();
+    Ok(Some(changed))
+}
+
+
+/// Key of a translation unit in `tu_sources`.
+pub fn tu_key(command: &ClangCommand) -> PathBuf {
+    command.directory.join(&command.file)
+}
+
+
+/// Decides which translation units to re-parse and which nodes of the
+/// previous run's semfile are still valid.
+pub struct IncrementalRun {
+    changed: HashSet<RelativePath>,
+    previous: HashMap<PathBuf, HashSet<RelativePath>>,
+    seen: HashSet<PathBuf>,
+    scanned: HashSet<PathBuf>,
+}
+
+impl IncrementalRun {
+    pub fn new(changed: HashSet<RelativePath>, previous: HashMap<PathBuf, HashSet<RelativePath>>) -> Self {
+        Self {
+            changed,
+            previous,
+            seen: HashSet::new(),
+            scanned: HashSet::new(),
+        }
+    }
+
+    fn is_affected(&self, tu: &Path) -> bool {
+        match self.previous.get(tu) {
+            Some(source_set) => !source_set.is_disjoint(&self.changed),
+            None => true,
+        }
+    }
+
+    /// Drops compile commands of translation units not touched by the change.
+    pub fn retain_commands(&mut self, commands: &mut Vec<ClangCommand>) {
+        let total = commands.len();
+        commands.retain(|cmd| {
+            let tu = tu_key(cmd);
+            self.seen.insert(tu.clone());
+            if self.is_affected(&tu) {
+                self.scanned.insert(tu);
+                true
+            } else {
+                false
+            }
+        });
+        info!("incremental run: re-parsing {} of {} translation units", commands.len(), total);
+    }
+
+    /// Translation units indexed previously that are no longer in the
+    /// compile commands.
+    pub fn vanished(&self) -> Vec<PathBuf> {
+        self.previous.keys().filter(|tu| !self.seen.contains(*tu)).cloned().collect()
+    }
+
+    /// Paths whose nodes from the previous run must not be reused: the changed
+    /// files and all files of translation units that were re-parsed or
+    /// removed, as they were before and after the change.
+    pub fn stale_paths(&self, current: &HashMap<PathBuf, HashSet<RelativePath>>) -> HashSet<String> {
+        let mut stale: HashSet<String> = self.changed.iter().map(|p| p.to_string()).collect();
+        for tu in self.scanned.iter().chain(self.vanished().iter()) {
+            for source_set in [self.previous.get(tu), current.get(tu)].into_iter().flatten() {
+                stale.extend(source_set.iter().map(|p| p.to_string()));
+            }
+        }
+        stale
+    }
+}
+
+
+/// Copies nodes of the previous semfile outside of `stale_paths` to the
+/// current one, returning their IDs.
+pub fn carry_over_nodes(
+    previous_semfile: PathBuf,
+    stale_paths: &HashSet<String>,
+    writer: &mut IntermediateNodeFileWriter,
+) -> Vec<NodeID> {
+    let mut kept = Vec::new();
+    let mut reader = IntermediateNodeFileReader::new(previous_semfile);
+    for mut semfile in &mut reader {
+        semfile.nodes.retain(|n| !stale_paths.contains(&n.path));
+        if semfile.nodes.is_empty() {
+            continue;
+        }
+        kept.extend(semfile.nodes.iter().map(|n| n.id));
+        writer.append(&SemFile { nodes: semfile.nodes });
+    }
+    kept
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, HashSet};
+    use std::path::PathBuf;
+
+    use territory_core::RelativePath;
+    use cscanner::ast::ClangCommand;
+
+    use super::IncrementalRun;
+
+    fn rel(p: &str) -> RelativePath {
+        RelativePath::from(PathBuf::from(p))
+    }
+
+    fn command(file: &str) -> ClangCommand {
+        ClangCommand { index: 0, file: file.into(), directory: "/repo".into(), args: vec![] }
+    }
+
+    #[test]
+    fn affected_translation_units() {
+        let previous = HashMap::from([
+            (PathBuf::from("/repo/a.c"), HashSet::from([rel("a.c"), rel("common.h")])),
+            (PathBuf::from("/repo/b.c"), HashSet::from([rel("b.c")])),
+            (PathBuf::from("/repo/gone.c"), HashSet::from([rel("gone.c")])),
+        ]);
+        let mut run = IncrementalRun::new(HashSet::from([rel("common.h")]), previous);
+
+        let mut commands = vec![command("a.c"), command("b.c"), command("new.c")];
+        run.retain_commands(&mut commands);
+        let files: Vec<_> = commands.iter().map(|c| c.file.to_str().unwrap()).collect();
+        assert_eq!(files, vec!["a.c", "new.c"]);
+        assert_eq!(run.vanished(), vec![PathBuf::from("/repo/gone.c")]);
+
+        let current = HashMap::from([
+            (PathBuf::from("/repo/a.c"), HashSet::from([rel("a.c"), rel("common.h"), rel("extra.h")])),
+            (PathBuf::from("/repo/new.c"), HashSet::from([rel("new.c")])),
+        ]);
+        let mut stale: Vec<_> = run.stale_paths(&current).into_iter().collect();
+        stale.sort();
+        assert_eq!(stale, vec!["a.c", "common.h", "extra.h", "gone.c", "new.c"]);
+    }
+}
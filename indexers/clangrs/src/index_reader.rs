@@ -0,0 +1,353 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use flate2::read::GzDecoder;
+use prost::Message;
+use rusqlite::Connection;
+
+use territory_core::resolver::{
+    BasicResolver,
+    ConcreteLocation,
+    DBResolver,
+    NeedData,
+    ResolutionFailure,
+    ResolutionResult,
+    Resolver,
+    TrieResolver,
+};
+use territory_core::build_diff::BuildSource;
+use territory_core::search::{Options, Query, SearchResult, TrieIndex};
+use territory_core::search_shards::ShardedIndex;
+use territory_core::slicemap_trie::{SharedCache, SlicemapReader};
+use territory_core::text_search::{search_text, TextMatch, TextSearchOptions};
+use territory_core::pblib::decode_many;
+use territory_core::territory::index::{Build, IndexItem, Node, References, TypeHierarchy};
+use territory_core::{pb_node_tokens, BlobSliceLoc, GenHref, IntoGenHref, NodeID};
+
+use crate::archive::{Archive, BUILD_ENTRY, SEARCH_ITEMS_ENTRY, SEARCH_TRIE_ENTRY};
+
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const MAX_RESOLVE_ATTEMPTS: usize = 10;
+
+
+/// Resolver used for hrefs the slicemap tries can't answer.
+///
+/// Builds don't carry a path index, so without the intermediate DB `path:`
+/// hrefs are resolved by walking directory nodes from the repo root.
+pub enum BackupResolver {
+    Basic(BasicResolver),
+    DB(DBResolver),
+}
+
+impl Resolver for BackupResolver {
+    fn resolve_href(&self, href: &GenHref) -> ResolutionResult {
+        match self {
+            BackupResolver::Basic(r) => r.resolve_href(href),
+            BackupResolver::DB(r) => r.resolve_href(href),
+        }
+    }
+}
+
+
+/// Where the blobs, build and search index of an [`IndexReader`] are read from.
+enum Source {
+    /// Output directory of the file storage backend.
+    Dir(PathBuf),
+    /// Single-file archive written with `--archive`.
+    Archive(Arc<Archive>),
+}
+
+impl Source {
+    /// Raw bytes of `entry`, named as in the archive: `f/<blob_id>` or one of
+    /// the `archive::*_ENTRY` names.
+    fn read_raw(&self, repo_id: &str, build_id: &str, entry: &str) -> Result<Cow<'_, [u8]>, Box<dyn Error>> {
+        let outdir = match self {
+            Source::Archive(archive) => return Ok(Cow::Borrowed(archive.get(entry)?)),
+            Source::Dir(outdir) => outdir,
+        };
+        let path = match entry {
+            BUILD_ENTRY => outdir.join("builds").join(repo_id).join(build_id),
+            SEARCH_TRIE_ENTRY => outdir.join("search").join(repo_id).join(build_id).join("trie"),
+            SEARCH_ITEMS_ENTRY => outdir.join("search").join(repo_id).join(build_id).join("all"),
+            blob => outdir.join("nodes").join(repo_id).join(blob),
+        };
+        Ok(Cow::Owned(read_raw(&path)?))
+    }
+}
+
+
+/// Read-only view of a build written by the file storage backend, or packed
+/// into an archive.
+pub struct IndexReader {
+    source: Source,
+    repo_id: String,
+    build_id: String,
+    build: Build,
+    resolver: TrieResolver<BackupResolver>,
+    search_index: OnceLock<TrieIndex>,
+    search_shards: Option<ShardedIndex>,
+    index_items: OnceLock<Vec<IndexItem>>,
+}
+
+impl IndexReader {
+    pub fn open(outdir: &Path, repo_id: &str, build_id: &str) -> Result<Self, Box<dyn Error>> {
+        Self::open_with_backup(outdir, repo_id, build_id, BackupResolver::Basic(BasicResolver))
+    }
+
+    pub fn open_with_db(
+        outdir: &Path,
+        repo_id: &str,
+        build_id: &str,
+        db_path: &Path,
+    ) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(db_path)?;
+        let backup = BackupResolver::DB(DBResolver::new(Arc::new(Mutex::new(conn))));
+        Self::open_with_backup(outdir, repo_id, build_id, backup)
+    }
+
+    pub fn open_with_backup(
+        outdir: &Path,
+        repo_id: &str,
+        build_id: &str,
+        backup: BackupResolver,
+    ) -> Result<Self, Box<dyn Error>> {
+        let build_path = outdir.join("builds").join(repo_id).join(build_id);
+        let build = Build::decode(&read_file(&build_path)?[..])?;
+        Self::new(Source::Dir(outdir.to_path_buf()), repo_id, build_id, build, backup)
+    }
+
+    /// Opens an archive written with `--archive`. The file is mapped into
+    /// memory rather than read.
+    pub fn open_archive(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let archive = Archive::open(path)?;
+        let build = archive.build()?;
+        let repo_id = archive.repo_id().to_string();
+        let build_id = archive.build_id().to_string();
+        let backup = BackupResolver::Basic(BasicResolver);
+        Self::new(Source::Archive(Arc::new(archive)), &repo_id, &build_id, build, backup)
+    }
+
+    fn new(
+        source: Source,
+        repo_id: &str,
+        build_id: &str,
+        build: Build,
+        backup: BackupResolver,
+    ) -> Result<Self, Box<dyn Error>> {
+
+        let cache = SharedCache::new(1024);
+        let trie = |root: Option<BlobSliceLoc>, name: &str| -> Result<SlicemapReader, Box<dyn Error>> {
+            let root = root.ok_or_else(|| format!("build {build_id} has no {name} trie"))?;
+            let handle = SharedCache::new_handle(&cache, &format!("{repo_id}/{name}"));
+            Ok(SlicemapReader::new(root, handle))
+        };
+        let mut resolver = TrieResolver::new(
+            backup,
+            trie(build.nodemap_trie_root, "nodes")?,
+            trie(build.symmap_trie_root, "syms")?,
+            trie(build.references_trie_root, "refs")?,
+            build.repo_root_node_id,
+        );
+        if build.type_hierarchy_trie_root.is_some() {
+            resolver = resolver.with_type_hierarchy(trie(build.type_hierarchy_trie_root, "types")?);
+        }
+        if build.macro_expansion_trie_root.is_some() {
+            resolver = resolver.with_macro_expansions(trie(build.macro_expansion_trie_root, "macros")?);
+        }
+        if build.text_index_trie_root.is_some() {
+            resolver = resolver.with_text_index(trie(build.text_index_trie_root, "text")?);
+        }
+        let search_shards = build.search_shards.map(ShardedIndex::new);
+
+        Ok(Self {
+            source,
+            repo_id: repo_id.to_string(),
+            build_id: build_id.to_string(),
+            build,
+            resolver,
+            search_index: OnceLock::new(),
+            search_shards,
+            index_items: OnceLock::new(),
+        })
+    }
+
+    pub fn build(&self) -> &Build {
+        &self.build
+    }
+
+    pub fn resolve_href(&self, href: &GenHref) -> Result<ConcreteLocation, Box<dyn Error>> {
+        self.try_resolve_href(href)?.ok_or_else(|| format!("not found: {}", href.to_url()).into())
+    }
+
+    /// Like `resolve_href`, with `None` for hrefs not in the build.
+    pub fn try_resolve_href(&self, href: &GenHref) -> Result<Option<ConcreteLocation>, Box<dyn Error>> {
+        for _ in 0..MAX_RESOLVE_ATTEMPTS {
+            match self.resolver.resolve_href(href) {
+                Ok(loc) => return Ok(Some(loc)),
+                Err(ResolutionFailure::NeedData(NeedData(loc, cont))) => {
+                    let data = self.load_bytes(&loc)?;
+                    cont(&data)?;
+                },
+                Err(ResolutionFailure::UnsupportedUrl) => return self.resolve_by_walking(href),
+                Err(ResolutionFailure::NotFound) => return Ok(None),
+                Err(e) => return Err(format!("failed to resolve {}: {e:?}", href.to_url()).into()),
+            }
+        }
+        Err(format!("failed to resolve {} in {MAX_RESOLVE_ATTEMPTS} attempts", href.to_url()).into())
+    }
+
+    fn resolve_by_walking(&self, href: &GenHref) -> Result<Option<ConcreteLocation>, Box<dyn Error>> {
+        match href {
+            GenHref::Path(path) => {
+                let Some(node) = self.walk_path(path)? else { return Ok(None) };
+                self.resolve_href(&GenHref::NodeId(node.id)).map(Some)
+            },
+            GenHref::UniHref(path, offset) => {
+                let Some(node) = self.walk_path(path)? else { return Ok(None) };
+                let loc = self.resolve_href(&GenHref::NodeId(node.id))?;
+                Ok(Some(ConcreteLocation { token_offset: Some(*offset), ..loc }))
+            },
+            _ => Err(format!("unsupported href: {}", href.to_url()).into()),
+        }
+    }
+
+    /// Node of the file or directory at `path`, `None` if there is none.
+    fn walk_path(&self, path: &str) -> Result<Option<Node>, Box<dyn Error>> {
+        let mut node: Node = self.load(&GenHref::Path("".into()))?;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let Some(tok) = pb_node_tokens(&node)
+                .into_iter()
+                .find(|tok| tok.text.trim_end_matches('\n').trim_end_matches('/') == component)
+            else {
+                return Ok(None);
+            };
+            let href = tok.context.href.ok_or_else(|| format!("directory entry not linked: {component}"))?;
+            node = self.load(&href.into_gen_href())?;
+        }
+        Ok(Some(node))
+    }
+
+    pub fn load<T>(&self, href: &GenHref) -> Result<T, Box<dyn Error>> where T: Message + Default {
+        let loc = self.resolve_href(href)?;
+        let bytes = self.load_bytes(&loc)?;
+        Ok(T::decode(&bytes[..])?)
+    }
+
+    pub fn node(&self, href: &GenHref) -> Result<Node, Box<dyn Error>> {
+        self.load(href)
+    }
+
+    pub fn references(&self, href: &GenHref) -> Result<References, Box<dyn Error>> {
+        self.load(href)
+    }
+
+    /// Supertypes and subtypes of the type defined in node `node_id`. Types
+    /// without any recorded relations get an empty hierarchy.
+    pub fn type_hierarchy(&self, node_id: NodeID) -> Result<TypeHierarchy, Box<dyn Error>> {
+        if self.build.type_hierarchy_trie_root.is_none() {
+            return Err(format!("build {} has no type hierarchy", self.build_id).into());
+        }
+        match self.try_resolve_href(&GenHref::TypeHierarchy(node_id))? {
+            Some(loc) => Ok(TypeHierarchy::decode(&self.load_bytes(&loc)?[..])?),
+            None => Ok(TypeHierarchy { node_id, ..Default::default() }),
+        }
+    }
+
+    /// Reads the bytes at `loc`, decompressing them if the build was written
+    /// with gzip compression.
+    pub fn load_bytes(&self, loc: &ConcreteLocation) -> Result<Vec<u8>, Box<dyn Error>> {
+        let buf = self.read_raw(&loc.path)?;
+        let bytes = match loc.blob_bytes {
+            Some((l, r)) => buf
+                .get(l as usize..r as usize)
+                .ok_or_else(|| format!("slice {l}:{r} out of bounds in {}", loc.path))?,
+            None => &buf[..],
+        };
+        Ok(maybe_decompress(bytes)?)
+    }
+
+    pub fn search_index(&self) -> Result<&TrieIndex, Box<dyn Error>> {
+        if let Some(index) = self.search_index.get() {
+            return Ok(index);
+        }
+        let index = TrieIndex::load(&maybe_decompress(&self.read_raw(SEARCH_TRIE_ENTRY)?)?)?;
+        Ok(self.search_index.get_or_init(|| index))
+    }
+
+    /// All items of the inverted index, as written to `search/<repo>/<build>/all`.
+    pub fn index_items(&self) -> Result<&[IndexItem], Box<dyn Error>> {
+        if let Some(items) = self.index_items.get() {
+            return Ok(items);
+        }
+        let items = decode_many(&maybe_decompress(&self.read_raw(SEARCH_ITEMS_ENTRY)?)?)?;
+        Ok(self.index_items.get_or_init(|| items))
+    }
+
+    /// Searches the sharded index, loading only the shards the query needs.
+    /// Builds written before the index was sharded search the whole trie.
+    pub fn search(&self, query: &str, options: &Options) -> Result<Vec<SearchResult>, Box<dyn Error>> {
+        let query = Query::parse(query)?;
+        let Some(shards) = &self.search_shards else {
+            return Ok(self.search_index()?.search_query(&query, options));
+        };
+        loop {
+            match shards.search_query(&query, options) {
+                Ok(results) => return Ok(results),
+                Err(ResolutionFailure::NeedData(NeedData(loc, cont))) => cont(&self.load_bytes(&loc)?)?,
+                Err(e) => return Err(format!("search failed: {e:?}").into()),
+            }
+        }
+    }
+
+    /// Matches of `pattern` in the node text, narrowed down with the build's
+    /// trigram index.
+    pub fn search_text(&self, pattern: &str, options: &TextSearchOptions) -> Result<Vec<TextMatch>, Box<dyn Error>> {
+        search_text(self, pattern, options)
+    }
+
+    fn read_raw(&self, entry: &str) -> Result<Cow<'_, [u8]>, Box<dyn Error>> {
+        self.source.read_raw(&self.repo_id, &self.build_id, entry)
+    }
+}
+
+
+impl BuildSource for IndexReader {
+    fn build(&self) -> &Build {
+        &self.build
+    }
+
+    fn load_slice(&self, loc: BlobSliceLoc) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.load_bytes(&ConcreteLocation::from(&loc))
+    }
+}
+
+
+pub(crate) fn read_raw(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .map_err(|e| format!("error opening {path:?}: {e}"))?
+        .read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+
+// Blobs are compressed slice by slice, so only whole-file objects (builds,
+// search indexes) may be decompressed on read.
+pub(crate) fn read_file(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(maybe_decompress(&read_raw(path)?)?)
+}
+
+
+pub(crate) fn maybe_decompress(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        return Ok(bytes.to_vec());
+    }
+    let mut out = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut out)?;
+    Ok(out)
+}
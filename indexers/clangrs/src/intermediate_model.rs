@@ -6,10 +6,10 @@ use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 
 use territory_core::{
-    GBlob, GNode, Location, NodeID, Offset, PathID, Ref, Refs, RelativePath, SymID, TokenLocation
+    GBlob, GNode, Location, NodeID, Offset, Override, PathID, Ref, Refs, RelativePath, SymID, TokenLocation
 };
 
-use cscanner::ast::Sem;
+use cscanner::ast::{BaseClass, MacroExpansion, OverriddenMethod, Sem};
 
 use crate::writer::NodeWriter;
 
@@ -73,6 +73,9 @@ pub struct SemTokenContext {
     pub local_definition: Option<TokenLocation>,
     #[serde(rename="e")]
     pub elided: Option<TokenLocation>,
+    /// Set on the name token of a macro invocation
+    #[serde(rename="x", default)]
+    pub macro_expansion: Option<SemMacroExpansion>,
 }
 
 
@@ -92,6 +95,31 @@ pub struct SemNodeContext {
     pub nest_level: usize,
     #[serde(rename="E")]
     pub end_offset: Offset,
+    #[serde(rename="b", default)]
+    pub bases: Vec<BaseClass>,
+    #[serde(rename="o", default)]
+    pub overrides: Vec<SemOverride>,
+}
+
+
+/// Method overridden by the virtual method defined in a node, with its
+/// declaration resolved to a token.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SemOverride {
+    #[serde(rename="m")]
+    pub method: OverriddenMethod,
+    #[serde(rename="d")]
+    pub declaration: Option<TokenLocation>,
+}
+
+
+/// Macro expansion with the definition of each step resolved.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SemMacroExpansion {
+    #[serde(rename="e")]
+    pub expansion: MacroExpansion,
+    #[serde(rename="d")]
+    pub definitions: Vec<Option<TokenLocation>>,
 }
 
 
@@ -110,6 +138,8 @@ pub trait GlobalSymbolMapReader {
 
 pub trait UsesMap {
     fn record_use(&mut self, loc: TokenLocation, use_: Ref);
+    /// Records that the method defined at `method` overrides `base`.
+    fn record_override(&mut self, method: Override, base: Override);
 }
 pub trait UsesMapQuery {
     fn has_uses(&self, loc: &TokenLocation) -> bool;
@@ -124,6 +154,17 @@ impl UsesMap for UsesHashmap {
         let refs = self.entry(loc).or_insert_with(|| Refs::new(loc));
         refs.refs.insert(use_);
     }
+
+    fn record_override(&mut self, method: Override, base: Override) {
+        self.entry(base.location)
+            .or_insert_with(|| Refs::new(base.location))
+            .overridden_by
+            .insert(method.clone());
+        self.entry(method.location)
+            .or_insert_with(|| Refs::new(method.location))
+            .overrides
+            .insert(base);
+    }
 }
 
 
@@ -216,7 +257,7 @@ impl<'a> LocalSpanIndex<'a> {
 
 
 pub mod sqlite {
-    use std::{cell::RefCell, collections::HashMap, error::Error, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
+    use std::{cell::RefCell, collections::{BTreeMap, HashMap, HashSet}, error::Error, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::Duration};
 
     use itertools::Itertools;
     use ring::digest::Digest;
@@ -229,14 +270,19 @@ pub mod sqlite {
         NodeID,
         NodeKind,
         Offset,
+        Override,
         PathID,
         Ref,
         Refs,
         RelativePath,
         SymID,
         db::init_db,
+        text_search::Trigram,
     };
 
+    use territory_core::territory::index::{self as pb, Implementation, NodeIdWithOffsetHref, ReferenceKind, TypeHierarchy};
+    use cscanner::ipc::Include;
+
     use crate::{args::{get_debug_cfg, Args}, writer::ReferencesBlob};
     use super::TokenLocation;
 
@@ -342,7 +388,8 @@ pub mod sqlite {
                     use_location_col,
                     use_location_off,
                     linked_via_sym,
-                    path
+                    path,
+                    kind
                 from use, spans, paths
                 where spans.path_id = ?1
                     and use.node_id = spans.location_id
@@ -367,6 +414,7 @@ pub mod sqlite {
                     },
                     linked_via_sym: row.get(7).unwrap(),
                     use_path: row.get(8).unwrap(),
+                    kind: ReferenceKind::from_i32(row.get(9).unwrap()).unwrap_or_default(),
                 };
                 match &mut refs {
                     Some(Refs { token_location, refs, .. }) if *token_location == row_token_location => {
@@ -391,6 +439,56 @@ pub mod sqlite {
 
             file_refs
         }
+
+        /// Override relations grouped by the file of the token they attach
+        /// to, in the shape `write` merges them into the uses.
+        fn get_overrides(conn: &Connection) -> BTreeMap<PathID, BTreeMap<TokenLocation, Refs>> {
+            let mut stmt = conn.prepare("
+                select
+                    overrides.node_id,
+                    overrides.offset,
+                    name,
+                    spans.path_id,
+                    base_node_id,
+                    base_offset,
+                    base_name,
+                    base_spans.path_id
+                from overrides, spans, spans as base_spans
+                where overrides.node_id = spans.node_id
+                    and overrides.base_node_id = base_spans.node_id
+            ").unwrap();
+
+            let mut res: BTreeMap<PathID, BTreeMap<TokenLocation, Refs>> = BTreeMap::new();
+            let rows = stmt.query_map([], |row| {
+                let method = Override {
+                    location: TokenLocation { node_id: row.get::<_, i64>(0)? as u64, offset: row.get(1)? },
+                    name: row.get(2)?,
+                };
+                let base = Override {
+                    location: TokenLocation { node_id: row.get::<_, i64>(4)? as u64, offset: row.get(5)? },
+                    name: row.get(6)?,
+                };
+                Ok((method, PathID(row.get(3)?), base, PathID(row.get(7)?)))
+            }).unwrap();
+
+            for row in rows {
+                let (method, method_path_id, base, base_path_id) = row.unwrap();
+                res.entry(base_path_id)
+                    .or_default()
+                    .entry(base.location)
+                    .or_insert_with(|| Refs::new(base.location))
+                    .overridden_by
+                    .insert(method.clone());
+                res.entry(method_path_id)
+                    .or_default()
+                    .entry(method.location)
+                    .or_insert_with(|| Refs::new(method.location))
+                    .overrides
+                    .insert(base);
+            }
+
+            res
+        }
     }
 
     impl SqliteUMWriter {
@@ -409,12 +507,30 @@ pub mod sqlite {
                     use_location_off integer,
                     linked_via_sym bool,
                     use_path_id integer,
+                    kind integer,
                     PRIMARY KEY (node_id, offset, href, use_location_off)
                 ) without rowid
             ", ()).unwrap();
             let _ = conn.execute("
                 alter table use add column use_path_id integer
             ", ());
+            conn.execute("
+                drop table if exists overrides
+            ", ()).unwrap();
+            conn.execute("
+                create table overrides (
+                    node_id integer,
+                    offset integer,
+                    name string,
+                    base_node_id integer,
+                    base_offset integer,
+                    base_name string,
+                    PRIMARY KEY (node_id, offset, base_node_id, base_offset)
+                ) without rowid
+            ", ()).unwrap();
+            conn.execute("
+                create index overrides_base on overrides (base_node_id, base_offset)
+            ", ()).unwrap();
         }
     }
 
@@ -434,10 +550,12 @@ pub mod sqlite {
                 use_location_col,
                 use_location_off,
                 linked_via_sym,
-                use_path_id
+                use_path_id,
+                kind
             ) values (
                 ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8,
-                (select path_id from paths where path = ?9)
+                (select path_id from paths where path = ?9),
+                ?10
             )", (
                 loc.node_id as i64,
                 loc.offset,
@@ -448,6 +566,30 @@ pub mod sqlite {
                 use_.use_location.off,
                 use_.linked_via_sym,
                 use_.use_path.to_string(),
+                use_.kind as i32,
+            )).unwrap();
+        }
+
+        fn record_override(&mut self, method: Override, base: Override) {
+            if get_debug_cfg().print_references {
+                println!("override {:?} --> {:?}", method, base);
+            }
+
+            let conn = self.conn.lock().unwrap();
+            conn.execute("insert or ignore into overrides (
+                node_id,
+                offset,
+                name,
+                base_node_id,
+                base_offset,
+                base_name
+            ) values (?1, ?2, ?3, ?4, ?5, ?6)", (
+                method.location.node_id as i64,
+                method.location.offset,
+                method.name,
+                base.location.node_id as i64,
+                base.location.offset,
+                base.name,
             )).unwrap();
         }
     }
@@ -456,7 +598,12 @@ pub mod sqlite {
         fn has_uses(&self, token_location: &TokenLocation) -> bool {
             let conn = self.conn.lock().unwrap();
             let mut existence_query = conn.prepare("
-                select 1 from use where node_id=?1 and offset=?2 limit 1
+                select 1 from use where node_id=?1 and offset=?2
+                union all
+                select 1 from overrides where node_id=?1 and offset=?2
+                union all
+                select 1 from overrides where base_node_id=?1 and base_offset=?2
+                limit 1
             ").unwrap();
             let res = existence_query.query_row((
                 token_location.node_id as i64,
@@ -467,6 +614,7 @@ pub mod sqlite {
 
         fn write(self, nw: &mut crate::writer::NodeWriter) {
             let conn = self.conn.lock().unwrap();
+            let mut overrides = Self::get_overrides(&conn);
             let mut get_by_usr_stmt = conn.prepare("
                 select
                     spans.node_id,
@@ -478,7 +626,8 @@ pub mod sqlite {
                     use_location_off,
                     linked_via_sym,
                     spans.path_id,
-                    path
+                    path,
+                    kind
                 from use, spans, paths
                 where use.node_id = spans.node_id and paths.path_id = use_path_id
                 order by spans.path_id, spans.node_id, offset
@@ -502,6 +651,7 @@ pub mod sqlite {
                             },
                             linked_via_sym: row.get(7).unwrap(),
                             use_path: row.get(9).unwrap(),
+                            kind: ReferenceKind::from_i32(row.get(10).unwrap()).unwrap_or_default(),
                         };
 
                         Ok((path_id, row_token_location, new_ref))
@@ -510,21 +660,24 @@ pub mod sqlite {
                 .map(|res| res.unwrap())
                 .group_by(|(path_id, _, _)| *path_id);
 
-            let res = res.into_iter()
-                .map(|(_path_id, group)| {
-                    let file_references = group
-                        .group_by(|(_, row_token_location, _)| *row_token_location)
-                        .into_iter()
-                        .map(|(token_location, group)| Refs {
-                            token_location,
-                            refs: group.into_iter().map(|(_, _, r)| r).collect(),
-                        })
-                        .collect();
-                    ReferencesBlob(file_references)
-                });
+            for (path_id, group) in res.into_iter() {
+                let mut file_overrides = overrides.remove(&path_id).unwrap_or_default();
+                let mut file_references: Vec<Refs> = group
+                    .group_by(|(_, row_token_location, _)| *row_token_location)
+                    .into_iter()
+                    .map(|(token_location, group)| {
+                        let mut refs = file_overrides.remove(&token_location).unwrap_or_else(|| Refs::new(token_location));
+                        refs.refs.extend(group.into_iter().map(|(_, _, r)| r));
+                        refs
+                    })
+                    .collect();
+                file_references.extend(file_overrides.into_values());
+                nw.submit_refs_file(ReferencesBlob(file_references));
+            }
 
-            for refs_file in res {
-                nw.submit_refs_file(refs_file);
+            // overridden methods without any other references
+            for file_overrides in overrides.into_values() {
+                nw.submit_refs_file(ReferencesBlob(file_overrides.into_values().collect()));
             }
 
         }
@@ -664,6 +817,21 @@ pub mod sqlite {
 
             result
         }
+
+        /// Marks spans of nodes carried over from a previous run as fresh.
+        pub fn refresh(&self, node_ids: impl Iterator<Item=NodeID>) {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            {
+                let mut stmt = tx.prepare_cached("
+                    update spans set fresh=true where node_id=?1
+                ").unwrap();
+                for node_id in node_ids {
+                    stmt.execute((node_id as i64,)).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
     }
 
     pub struct Paths {
@@ -866,6 +1034,385 @@ pub mod sqlite {
     }
 
 
+    /// Files each translation unit consisted of in the last run that parsed
+    /// it, as reported by the scanner with `Control::TUDone`.
+    pub struct TuSources {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    impl TuSources {
+        fn create_table(conn: &Connection) {
+            conn.execute("
+                 create table if not exists tu_sources (
+                    tu string,
+                    path string,
+                    primary key (tu, path)
+                ) without rowid
+            ", ()).unwrap();
+        }
+
+        pub fn replace(&self, tu: &Path, source_set: &HashSet<RelativePath>) {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            let tu = tu.to_string_lossy();
+            tx.execute("delete from tu_sources where tu=?1", (&tu,)).unwrap();
+            {
+                let mut stmt = tx.prepare_cached("
+                    insert into tu_sources (tu, path) values (?1, ?2)
+                ").unwrap();
+                for path in source_set {
+                    stmt.execute((&tu, path.to_string())).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
+
+        pub fn remove(&self, tu: &Path) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("delete from tu_sources where tu=?1", (tu.to_string_lossy(),)).unwrap();
+        }
+
+        pub fn clear(&self) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("delete from tu_sources", ()).unwrap();
+        }
+
+        pub fn all(&self) -> HashMap<PathBuf, HashSet<RelativePath>> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("select tu, path from tu_sources").unwrap();
+            let mut result: HashMap<PathBuf, HashSet<RelativePath>> = HashMap::new();
+            let rows = stmt.query_map(
+                    (),
+                    |row| Ok((PathBuf::from(row.get::<_, String>(0)?), row.get::<_, RelativePath>(1)?)))
+                .unwrap();
+            for row in rows {
+                let (tu, path) = row.unwrap();
+                result.entry(tu).or_default().insert(path);
+            }
+            result
+        }
+    }
+
+
+    /// `#include` directives of each translation unit in the last run that
+    /// parsed it, kept like `TuSources`.
+    pub struct TuIncludes {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    impl TuIncludes {
+        fn create_table(conn: &Connection) {
+            conn.execute("
+                 create table if not exists tu_includes (
+                    tu string,
+                    includer string,
+                    included string,
+                    spelling string,
+                    search_path string,
+                    primary key (tu, includer, included, spelling)
+                ) without rowid
+            ", ()).unwrap();
+        }
+
+        pub fn replace(&self, tu: &Path, includes: &[Include]) {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            let tu = tu.to_string_lossy();
+            tx.execute("delete from tu_includes where tu=?1", (&tu,)).unwrap();
+            {
+                let mut stmt = tx.prepare_cached("
+                    insert or ignore into tu_includes (tu, includer, included, spelling, search_path)
+                    values (?1, ?2, ?3, ?4, ?5)
+                ").unwrap();
+                for include in includes {
+                    stmt.execute((
+                        &tu,
+                        include.includer.to_string(),
+                        include.included.to_string(),
+                        &include.spelling,
+                        include.search_path.as_ref().map(|p| p.to_string()),
+                    )).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
+
+        pub fn remove(&self, tu: &Path) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("delete from tu_includes where tu=?1", (tu.to_string_lossy(),)).unwrap();
+        }
+
+        pub fn clear(&self) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("delete from tu_includes", ()).unwrap();
+        }
+
+        /// Edges merged over all TUs, ordered by includer.
+        pub fn graph(&self) -> pb::IncludeGraph {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("
+                select includer, included, spelling, search_path, count(distinct tu)
+                from tu_includes
+                group by includer, included, spelling, search_path
+                order by 1, 2, 3
+            ").unwrap();
+            let includes = stmt.query_map(
+                    (),
+                    |row| Ok(pb::Include {
+                        includer: row.get(0)?,
+                        included: row.get(1)?,
+                        spelling: row.get(2)?,
+                        search_path: row.get(3)?,
+                        tu_count: row.get(4)?,
+                    }))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect();
+            pb::IncludeGraph { includes }
+        }
+    }
+
+
+    /// Translation units left out of the build because the scanner kept
+    /// failing on them, with the last failure.
+    pub struct Quarantine {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    impl Quarantine {
+        fn create_table(conn: &Connection) {
+            conn.execute("
+                 create table if not exists quarantine (
+                    tu string primary key,
+                    reason string,
+                    attempts integer
+                ) without rowid
+            ", ()).unwrap();
+        }
+
+        pub fn add(&self, tu: &Path, reason: &str, attempts: usize) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("
+                insert or replace into quarantine (tu, reason, attempts) values (?1, ?2, ?3)
+            ", (tu.to_string_lossy(), reason, attempts)).unwrap();
+        }
+
+        pub fn remove(&self, tu: &Path) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("delete from quarantine where tu=?1", (tu.to_string_lossy(),)).unwrap();
+        }
+
+        pub fn clear(&self) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("delete from quarantine", ()).unwrap();
+        }
+
+        /// Quarantined TUs with their last failure, ordered by TU.
+        pub fn all(&self) -> Vec<(PathBuf, String)> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("select tu, reason from quarantine order by tu").unwrap();
+            stmt.query_map(
+                    (),
+                    |row| Ok((PathBuf::from(row.get::<_, String>(0)?), row.get(1)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        }
+    }
+
+
+    /// How far the run of a build got, for `--resume`: the stages that
+    /// finished and the translation units whose nodes made it to the
+    /// semfile, with the semfile length after them.
+    #[derive(Clone)]
+    pub struct RunProgress {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    impl RunProgress {
+        fn create_table(conn: &Connection) {
+            conn.execute("
+                 create table if not exists run_progress (
+                    key string primary key,
+                    value string
+                ) without rowid
+            ", ()).unwrap();
+            conn.execute("
+                 create table if not exists tu_done (
+                    tu string primary key,
+                    semfile_end integer
+                ) without rowid
+            ", ()).unwrap();
+        }
+
+        /// Forgets the progress of the previous run and records a new one.
+        pub fn start(&self, build_id: &str) {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            tx.execute("delete from run_progress", ()).unwrap();
+            tx.execute("delete from tu_done", ()).unwrap();
+            tx.execute("insert into run_progress (key, value) values ('build_id', ?1)", (build_id,)).unwrap();
+            tx.commit().unwrap();
+        }
+
+        fn get(&self, key: &str) -> Option<String> {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row("select value from run_progress where key=?1", (key,), |row| row.get(0))
+                .optional()
+                .unwrap()
+        }
+
+        fn set(&self, key: &str, value: &str) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("insert or replace into run_progress (key, value) values (?1, ?2)", (key, value)).unwrap();
+        }
+
+        /// Build of the last run started on this database.
+        pub fn build_id(&self) -> Option<String> {
+            // asked before create_tables, on a db that may be new
+            Self::create_table(&self.conn.lock().unwrap());
+            self.get("build_id")
+        }
+
+        pub fn finish_stage(&self, stage: &str) {
+            self.set(&format!("stage:{}", stage), "done");
+        }
+
+        pub fn is_stage_done(&self, stage: &str) -> bool {
+            self.get(&format!("stage:{}", stage)).is_some()
+        }
+
+        /// Last blob ID allocated before the output stage started. Blobs after
+        /// it may not have been stored if the run was killed.
+        pub fn set_blob_mark(&self, blob_id: u64) {
+            self.set("blob_mark", &blob_id.to_string());
+        }
+
+        pub fn blob_mark(&self) -> Option<u64> {
+            self.get("blob_mark").and_then(|v| v.parse().ok())
+        }
+
+        pub fn tu_done(&self, tu: &Path, semfile_end: u64) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("
+                insert or replace into tu_done (tu, semfile_end) values (?1, ?2)
+            ", (tu.to_string_lossy(), semfile_end as i64)).unwrap();
+        }
+
+        pub fn done_tus(&self) -> HashSet<PathBuf> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("select tu from tu_done").unwrap();
+            stmt.query_map((), |row| Ok(PathBuf::from(row.get::<_, String>(0)?)))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        }
+
+        /// Length of the semfile after the last finished translation unit.
+        pub fn semfile_end(&self) -> u64 {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row("select coalesce(max(semfile_end), 0) from tu_done", (), |row| row.get::<_, i64>(0))
+                .unwrap() as u64
+        }
+    }
+
+
+    /// Edges of the type hierarchy, collected in the uses stage and written
+    /// as one `TypeHierarchy` per type in the output stage.
+    #[derive(Clone)]
+    pub struct TypeHierarchyMap {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    impl TypeHierarchyMap {
+        fn create_table(conn: &Connection) {
+            conn.execute("
+                drop table if exists type_hierarchy
+            ", ()).unwrap();
+            conn.execute("
+                 create table type_hierarchy (
+                    sub_node_id integer,
+                    sub_offset integer,
+                    sub_name string,
+                    super_node_id integer,
+                    super_offset integer,
+                    super_name string,
+                    unique (sub_node_id, sub_offset, super_name)
+                )
+            ", ()).unwrap();
+        }
+
+        /// Records that `subtype` derives from or implements `supertype`.
+        /// `supertype` may lack an href if it is defined outside the index.
+        pub fn record(&self, subtype: TokenLocation, subtype_name: &str, supertype: Option<TokenLocation>, supertype_name: &str) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("
+                insert or ignore into type_hierarchy (
+                    sub_node_id, sub_offset, sub_name, super_node_id, super_offset, super_name
+                ) values (?1, ?2, ?3, ?4, ?5, ?6)
+            ", (
+                subtype.node_id as i64,
+                subtype.offset,
+                subtype_name,
+                supertype.map(|l| l.node_id as i64),
+                supertype.map(|l| l.offset),
+                supertype_name,
+            )).unwrap();
+        }
+
+        pub fn get(&self) -> Vec<TypeHierarchy> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("
+                select sub_node_id, sub_offset, sub_name, super_node_id, super_offset, super_name
+                from type_hierarchy
+                order by sub_node_id, sub_offset, super_name
+            ").unwrap();
+
+            let mut types: BTreeMap<NodeID, TypeHierarchy> = BTreeMap::new();
+            let rows = stmt.query_map((), |row| {
+                let sub_href = NodeIdWithOffsetHref {
+                    node_id: row.get::<_, i64>(0)? as u64,
+                    offset: row.get(1)?,
+                };
+                let super_href = match (row.get::<_, Option<i64>>(3)?, row.get(4)?) {
+                    (Some(node_id), Some(offset)) => Some(NodeIdWithOffsetHref { node_id: node_id as u64, offset }),
+                    _ => None,
+                };
+                Ok((
+                    Implementation { href: Some(sub_href), name: row.get(2)? },
+                    Implementation { href: super_href, name: row.get(5)? },
+                ))
+            }).unwrap();
+
+            for row in rows {
+                let (subtype, supertype) = row.unwrap();
+                let sub_node_id = subtype.href.as_ref().unwrap().node_id;
+                if let Some(super_node_id) = supertype.href.as_ref().map(|h| h.node_id) {
+                    types
+                        .entry(super_node_id)
+                        .or_insert_with(|| TypeHierarchy { node_id: super_node_id, ..Default::default() })
+                        .subtypes
+                        .push(subtype);
+                }
+                types
+                    .entry(sub_node_id)
+                    .or_insert_with(|| TypeHierarchy { node_id: sub_node_id, ..Default::default() })
+                    .supertypes
+                    .push(supertype);
+            }
+
+            types.into_values().collect()
+        }
+
+        pub fn write(self, nw: &mut crate::writer::NodeWriter) {
+            let types = self.get();
+            if !types.is_empty() {
+                nw.submit_type_hierarchy(types);
+            }
+        }
+    }
+
+
     #[derive(Clone)]
     pub struct OutputMap {
         conn: Arc<Mutex<Connection>>,
@@ -965,6 +1512,129 @@ pub mod sqlite {
             ", (token_location.node_id, token_location.offset, loc.blob_id, loc.start_offset, loc.end_offset)).unwrap();
         }
 
+        pub fn store_type_hierarchy_location(&self, node_id: NodeID, loc: &BlobSliceLoc) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("
+                insert or replace into typemap (
+                    node_id,
+                    blob_id,
+                    blob_start_offset,
+                    blob_end_offset
+                ) values (?1, ?2, ?3, ?4)
+            ", (node_id, loc.blob_id, loc.start_offset, loc.end_offset)).unwrap();
+        }
+
+        pub fn store_macro_expansion_location(&self, token_location: TokenLocation, loc: &BlobSliceLoc) {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("
+                insert or replace into macromap (
+                    node_id,
+                    token_offset,
+                    blob_id,
+                    blob_start_offset,
+                    blob_end_offset
+                ) values (?1, ?2, ?3, ?4, ?5)
+            ", (token_location.node_id, token_location.offset, loc.blob_id, loc.start_offset, loc.end_offset)).unwrap();
+        }
+
+        /// Replaces the trigrams indexed for the text of `node_id`.
+        pub fn store_node_trigrams(&self, node_id: NodeID, trigrams: &[Trigram]) {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            tx.execute("delete from node_trigrams where node_id = ?1", (node_id,)).unwrap();
+            {
+                let mut stmt = tx.prepare("
+                    insert into node_trigrams (node_id, trigram) values (?1, ?2)
+                ").unwrap();
+                for trigram in trigrams {
+                    stmt.execute((node_id, trigram)).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
+
+        pub fn has_node_trigrams(&self, node_id: NodeID) -> bool {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row("
+                select exists(select 1 from node_trigrams where node_id = ?1)
+            ", (node_id,), |row| row.get(0)).unwrap()
+        }
+
+        /// Calls `f` with the sorted ids of the fresh nodes containing each
+        /// trigram, in trigram order.
+        pub fn for_each_trigram_posting(&self, mut f: impl FnMut(Trigram, &[NodeID])) {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("
+                select node_trigrams.trigram, node_trigrams.node_id
+                from node_trigrams, nodemap
+                where node_trigrams.node_id = nodemap.node_id
+                    and nodemap.fresh=true
+                order by 1, 2
+            ").unwrap();
+            let mut rows = stmt.query(()).unwrap();
+            let mut current = None;
+            let mut node_ids = Vec::new();
+            while let Some(row) = rows.next().unwrap() {
+                let trigram: Trigram = row.get(0).unwrap();
+                if current.is_some_and(|t| t != trigram) {
+                    f(current.unwrap(), &node_ids);
+                    node_ids.clear();
+                }
+                current = Some(trigram);
+                node_ids.push(row.get(1).unwrap());
+            }
+            if let Some(trigram) = current {
+                f(trigram, &node_ids);
+            }
+        }
+
+        pub fn store_trigram_locations(&self, locations: &[(Trigram, BlobSliceLoc)]) {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            {
+                let mut stmt = tx.prepare("
+                    insert or replace into trigrammap (
+                        trigram,
+                        blob_id,
+                        blob_start_offset,
+                        blob_end_offset
+                    ) values (?1, ?2, ?3, ?4)
+                ").unwrap();
+                for (trigram, loc) in locations {
+                    stmt.execute((trigram, loc.blob_id, loc.start_offset, loc.end_offset)).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
+
+        pub fn last_blob_id(&self) -> u64 {
+            let conn = self.conn.lock().unwrap();
+            conn.query_row("select coalesce(max(blob_id), 0) from blobs", (), |row| row.get(0)).unwrap()
+        }
+
+        pub fn blob_ids_after(&self, blob_id: u64) -> Vec<u64> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("select blob_id from blobs where blob_id > ?1").unwrap();
+            stmt.query_map((blob_id,), |row| row.get(0))
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        }
+
+        /// Drops all locations in the given blobs, so that their contents are
+        /// written again instead of reused.
+        pub fn forget_blobs(&self, blob_ids: &[u64]) {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction().unwrap();
+            for table in ["nodemap", "refmap", "macromap", "typemap", "trigrammap", "slices_by_hash"] {
+                let mut stmt = tx.prepare_cached(&format!("delete from {} where blob_id=?1", table)).unwrap();
+                for blob_id in blob_ids {
+                    stmt.execute((blob_id,)).unwrap();
+                }
+            }
+            tx.commit().unwrap();
+        }
+
         pub fn new_blob_id(&self) -> BlobID {
             let conn = self.conn.lock().unwrap();
             conn.execute("
@@ -1049,6 +1719,80 @@ pub mod sqlite {
                 .collect()
         }
 
+        pub fn macro_expansion_locations(&self) -> Vec<(TokenLocation, BlobSliceLoc)> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("
+                select
+                    node_id,
+                    token_offset,
+                    blob_id,
+                    blob_start_offset,
+                    blob_end_offset
+                from macromap
+                order by 1, 2
+            ").unwrap();
+            stmt.query_map(
+                (),
+                |row| {
+                    let tokl = TokenLocation {
+                        node_id: row.get(0)?,
+                        offset: row.get(1)?,
+                    };
+                    Ok((tokl, BlobSliceLoc {
+                        blob_id: row.get(2)?,
+                        start_offset: row.get(3)?,
+                        end_offset: row.get(4)?,
+                    }))
+                })
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        }
+
+        pub fn trigram_locations(&self) -> Vec<(Trigram, BlobSliceLoc)> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("
+                select trigram, blob_id, blob_start_offset, blob_end_offset
+                from trigrammap
+                order by 1
+            ").unwrap();
+            stmt.query_map(
+                (),
+                |row| {
+                    let trigram: Trigram = row.get(0)?;
+                    Ok((trigram, BlobSliceLoc {
+                        blob_id: row.get(1)?,
+                        start_offset: row.get(2)?,
+                        end_offset: row.get(3)?,
+                    }))
+                })
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        }
+
+        pub fn type_hierarchy_locations(&self) -> Vec<(NodeID, BlobSliceLoc)> {
+            let conn = self.conn.lock().unwrap();
+            let mut stmt = conn.prepare("
+                select node_id, blob_id, blob_start_offset, blob_end_offset
+                from typemap
+                order by 1
+            ").unwrap();
+            stmt.query_map(
+                (),
+                |row| {
+                    let node_id: NodeID = row.get(0)?;
+                    Ok((node_id, BlobSliceLoc {
+                        blob_id: row.get(1)?,
+                        start_offset: row.get(2)?,
+                        end_offset: row.get(3)?,
+                    }))
+                })
+                .unwrap()
+                .map(|r| r.unwrap())
+                .collect()
+        }
+
     }
 
 
@@ -1058,6 +1802,11 @@ pub mod sqlite {
         pub span_store: SpanStore,
         pub paths: Paths,
         pub queue: Queue,
+        pub tu_sources: TuSources,
+        pub tu_includes: TuIncludes,
+        pub quarantine: Quarantine,
+        pub progress: RunProgress,
+        pub type_hierarchy: TypeHierarchyMap,
         pub output_map: OutputMap,
         pub conn: Arc<Mutex<Connection>>,
     }
@@ -1070,9 +1819,22 @@ pub mod sqlite {
             SpanStore::create_table(&conn);
             Paths::create_table(&conn);
             Queue::create_table(&conn);
+            TuSources::create_table(&conn);
+            TuIncludes::create_table(&conn);
+            Quarantine::create_table(&conn);
+            RunProgress::create_table(&conn);
+            TypeHierarchyMap::create_table(&conn);
             OutputMap::create_table(&conn);
         }
 
+        /// Empties the tables filled by the uses stage before it runs again
+        /// on a resumed build.
+        pub fn reset_uses(&self) {
+            let conn = self.conn.lock().unwrap();
+            SqliteUMWriter::create_table(&conn);
+            TypeHierarchyMap::create_table(&conn);
+        }
+
         pub fn delete_expired(&self) {
         }
     }
@@ -1104,6 +1866,11 @@ pub mod sqlite {
             span_store: SpanStore { conn: Arc::clone(&conn) },
             paths: Paths::new(&conn),
             queue: Queue { conn: Arc::clone(&conn) },
+            tu_sources: TuSources { conn: Arc::clone(&conn) },
+            tu_includes: TuIncludes { conn: Arc::clone(&conn) },
+            quarantine: Quarantine { conn: Arc::clone(&conn) },
+            progress: RunProgress { conn: Arc::clone(&conn) },
+            type_hierarchy: TypeHierarchyMap { conn: Arc::clone(&conn) },
             output_map: OutputMap {  conn: Arc::clone(&conn) },
             conn,
         }
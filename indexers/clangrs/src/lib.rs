@@ -14,7 +14,17 @@ pub mod uses_stage;
 pub mod timers;
 pub mod slicemap_trie_writer;
 pub mod scanner_driver;
+pub mod scanner_pool;
 pub(crate) mod locks_agent;
 pub mod uim;
 pub(crate) mod buildroot;
 pub(crate) mod unparsed_listing;
+pub mod index_reader;
+pub mod query;
+pub mod lsp;
+pub mod incremental;
+pub mod report;
+pub mod resume;
+pub mod status;
+pub mod watch;
+pub mod archive;
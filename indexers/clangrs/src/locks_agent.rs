@@ -37,6 +37,15 @@ impl LocksAgent {
         }
     }
 
+    /// Agent for a run that continues an interrupted one, where `processed`
+    /// files were already scanned.
+    pub fn with_processed(thread_count: usize, processed: HashSet<RelativePath>) -> Self {
+        LocksAgent {
+            processed,
+            ..Self::new(thread_count)
+        }
+    }
+
     pub fn release_thread(&mut self, thread: usize) {
         // thread = idle
         //
@@ -69,6 +78,38 @@ impl LocksAgent {
         }
     }
 
+    /// Releases the files of a thread whose scanner failed. Unlike
+    /// `release_thread`, the files it held are not marked processed, so the
+    /// threads waiting for them scan them instead.
+    pub fn release_failed_thread(&mut self, thread: usize) {
+        let mut notify_candidates: HashSet<usize> = HashSet::new();
+        match std::mem::replace(&mut self.thread_holds[thread], ThreadHold::Idle) {
+            ThreadHold::Holding(held_paths) => {
+                for path in held_paths {
+                    let fh = self.file_holds.remove(&path).expect("held path not in file_holds");
+                    if !fh.waiting.is_empty() {
+                        notify_candidates.extend(fh.waiting.iter().copied());
+                        self.file_holds.insert(path, FileHold { holder: None, waiting: fh.waiting });
+                    }
+                }
+            },
+            ThreadHold::Waiting(paths, _) => {
+                for path in paths {
+                    let Some(fh) = self.file_holds.get_mut(&path) else { continue; };
+                    fh.waiting.remove(&thread);
+                    if fh.holder.is_none() && fh.waiting.is_empty() {
+                        self.file_holds.remove(&path);
+                    }
+                }
+            },
+            ThreadHold::Idle => {},
+        }
+
+        for thread in notify_candidates {
+            self.try_acquire_all(thread);
+        }
+    }
+
     pub fn handle_message(
         &mut self,
         thread: usize,
@@ -259,6 +300,39 @@ mod test {
         })));
     }
 
+    #[test]
+    fn failed_thread() {
+        let mut la = LocksAgent::new(3);
+
+        let (resp_sender_1, _resp_rcv_1) = sync_channel(1);
+        la.handle_message(
+            1,
+            lock_all(&["p1", "p2"]),
+            resp_sender_1);
+
+        let (resp_sender_2, resp_rcv_2) = sync_channel(1);
+        la.handle_message(
+            2,
+            lock_all(&["p1"]),
+            resp_sender_2);
+        assert_eq!(resp_rcv_2.try_recv(), Err(std::sync::mpsc::TryRecvError::Empty));
+
+        la.release_failed_thread(1);
+        assert_eq!(resp_rcv_2.try_recv(), Ok(DriverSays::LockResponse(LockGrant {
+            already_processed: HashSet::new(),
+        })));
+        assert!(la.is_held(2, &relpath("p1")));
+
+        let (resp_sender_3, resp_rcv_3) = sync_channel(1);
+        la.handle_message(
+            3,
+            lock_all(&["p2"]),
+            resp_sender_3);
+        assert_eq!(resp_rcv_3.try_recv(), Ok(DriverSays::LockResponse(LockGrant {
+            already_processed: HashSet::new(),
+        })));
+    }
+
     #[test]
     fn is_held() {
         let mut la = LocksAgent::new(3);
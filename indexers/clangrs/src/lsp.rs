@@ -0,0 +1,355 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use log::warn;
+use lsp_types::{Location, Position, Range, SymbolInformation, SymbolKind, Url};
+
+use territory_core::search::Options;
+use territory_core::territory::index::{self as pb, IndexItemKind};
+use territory_core::{
+    pb_node_tokens,
+    AbsolutePath,
+    GenHref,
+    IntoGenHref,
+    NodeID,
+    Offset,
+    ReferencesLink,
+    RelativePath,
+    SymID,
+    Token,
+    TokenKind,
+    TokenLocation,
+};
+
+use crate::index_reader::IndexReader;
+
+
+const WORKSPACE_SYMBOLS_LIMIT: usize = 100;
+
+
+/// Line start offsets of a source file, for converting between byte offsets
+/// stored in the index and LSP (line, UTF-16 column) positions.
+pub struct LineIndex {
+    text: String,
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(text: String) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+        Self { text, line_starts }
+    }
+
+    pub fn offset(&self, pos: Position) -> Option<Offset> {
+        let start = *self.line_starts.get(pos.line as usize)?;
+        let end = self.line_starts.get(pos.line as usize + 1).copied().unwrap_or(self.text.len());
+        let mut col = 0;
+        for (i, c) in self.text[start..end].char_indices() {
+            if col >= pos.character {
+                return Some((start + i) as Offset);
+            }
+            col += c.len_utf16() as u32;
+        }
+        Some(end as Offset)
+    }
+
+    pub fn position(&self, offset: Offset) -> Position {
+        let offset = (offset as usize).min(self.text.len());
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let start = self.line_starts[line];
+        let character = self.text[start..offset].chars().map(|c| c.len_utf16() as u32).sum();
+        Position { line: line as u32, character }
+    }
+}
+
+
+/// Answers LSP queries from the nodes of a build alone, positions in source
+/// files are mapped to tokens through their real offsets.
+pub struct LspBackend {
+    repo: PathBuf,
+    reader: IndexReader,
+    line_indexes: RefCell<HashMap<String, Rc<LineIndex>>>,
+}
+
+impl LspBackend {
+    pub fn new(repo: &Path, reader: IndexReader) -> Self {
+        Self {
+            repo: repo.to_path_buf(),
+            reader,
+            line_indexes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn definition(&self, uri: &Url, pos: Position) -> Result<Vec<Location>, Box<dyn Error>> {
+        let Some((_, tok)) = self.token_at(uri, pos)? else {
+            return Ok(vec![]);
+        };
+        let target = match &tok.context.href {
+            Some(href) => self.href_target(href)?,
+            None => match tok.context.references {
+                // definitions link to their references, not to themselves
+                ReferencesLink::TokenLocation(_) => Some((self.relative_path(uri)?, tok.offset)),
+                _ => None,
+            },
+        };
+
+        Ok(target
+            .and_then(|(path, offset)| self.location(&path, offset, token_name(&tok).len()))
+            .into_iter()
+            .collect())
+    }
+
+    pub fn references(&self, uri: &Url, pos: Position, include_declaration: bool) -> Result<Vec<Location>, Box<dyn Error>> {
+        let Some((_, tok)) = self.token_at(uri, pos)? else {
+            return Ok(vec![]);
+        };
+        let name_len = token_name(&tok).len();
+
+        let definition = match (&tok.context.references, &tok.context.href) {
+            (ReferencesLink::TokenLocation(loc), _) => Some(*loc),
+            (_, Some(pb::token::Href::NodeIdWithOffsetRef(pb::NodeIdWithOffsetHref { node_id, offset }))) => {
+                Some(TokenLocation { node_id: *node_id, offset: *offset })
+            },
+            (_, Some(pb::token::Href::SymIdRef(id))) => self.sym_token_location(SymID(*id))?,
+            _ => None,
+        };
+        let Some(definition) = definition else {
+            return Ok(vec![]);
+        };
+
+        let mut locations = Vec::new();
+        if include_declaration {
+            let node = self.reader.node(&GenHref::NodeId(definition.node_id))?;
+            locations.extend(self.location(&node.path, definition.offset, name_len));
+        }
+
+        let refs = match self.reader.references(&GenHref::RefsId(definition)) {
+            Ok(refs) => refs,
+            Err(e) => {
+                warn!("no references for {definition:?}: {e}");
+                return Ok(locations);
+            },
+        };
+        for r in refs.refs {
+            let Some(use_location) = r.use_location else { continue };
+            locations.extend(self.location(&r.use_path, use_location.offset, name_len));
+        }
+
+        Ok(locations)
+    }
+
+    pub fn document_symbols(&self, uri: &Url) -> Result<Vec<SymbolInformation>, Box<dyn Error>> {
+        let path = self.relative_path(uri)?.to_string();
+        let items = self.reader.index_items()?;
+        Ok(items
+            .iter()
+            .filter(|item| item.path.as_deref() == Some(path.as_str()))
+            .filter_map(|item| self.symbol_information(
+                &item.key,
+                item.kind(),
+                item.r#type.as_deref(),
+                item.href.into_gen_href()))
+            .collect())
+    }
+
+    pub fn workspace_symbols(&self, query: &str) -> Result<Vec<SymbolInformation>, Box<dyn Error>> {
+        let options = Options { limit: Some(WORKSPACE_SYMBOLS_LIMIT), ..Options::default() };
+        let results = self.reader.search(query, &options)?;
+        Ok(results
+            .iter()
+            .filter_map(|res| self.symbol_information(
+                &res.item.key,
+                res.item.kind,
+                res.item.ty.as_deref(),
+                res.item.href.into_gen_href()))
+            .collect())
+    }
+
+    #[allow(deprecated)]
+    fn symbol_information(
+        &self,
+        name: &str,
+        kind: IndexItemKind,
+        ty: Option<&str>,
+        href: GenHref,
+    ) -> Option<SymbolInformation> {
+        let kind = match (kind, ty) {
+            (IndexItemKind::IiDirectory, _) => return None,
+            (IndexItemKind::IiFile, _) => SymbolKind::FILE,
+            (IndexItemKind::IiMacro, _) => SymbolKind::CONSTANT,
+            (IndexItemKind::IiSymbol, Some(ty)) if ty.contains('(') => SymbolKind::FUNCTION,
+            (IndexItemKind::IiSymbol, _) => SymbolKind::VARIABLE,
+        };
+        let node = self.reader.node(&href)
+            .map_err(|e| warn!("failed to load node for {name}: {e}"))
+            .ok()?;
+        let start = node.start.as_ref().map_or(0, |loc| loc.offset);
+        let end = start + node.text.len() as Offset;
+        let line_index = self.line_index(&node.path)?;
+
+        Some(SymbolInformation {
+            name: name.to_string(),
+            kind,
+            tags: None,
+            deprecated: None,
+            location: Location {
+                uri: self.uri(&node.path)?,
+                range: Range { start: line_index.position(start), end: line_index.position(end) },
+            },
+            container_name: None,
+        })
+    }
+
+    /// Token at `pos`, found by descending from the file node through the
+    /// elided nodes holding the position.
+    fn token_at(&self, uri: &Url, pos: Position) -> Result<Option<(pb::Node, Token)>, Box<dyn Error>> {
+        let path = self.relative_path(uri)?.to_string();
+        let line_index = self.line_index(&path).ok_or("source file not readable")?;
+        let Some(offset) = line_index.offset(pos) else {
+            return Ok(None);
+        };
+
+        let mut node = match self.reader.node(&GenHref::Path(path)) {
+            Ok(node) => node,
+            Err(e) => {
+                warn!("no file node for {uri}: {e}");
+                return Ok(None);
+            },
+        };
+        loop {
+            let mut tokens = pb_node_tokens(&node);
+            // tokens span the file up to the real offset of the next one
+            let Some(i) = tokens.iter().rposition(|tok| tok.offset <= offset) else {
+                return Ok(None);
+            };
+            if let Some(nested_id) = elided_node(&node.tokens[i]) {
+                node = self.reader.node(&GenHref::NodeId(nested_id))?;
+                continue;
+            }
+            let tok = tokens.swap_remove(i);
+            if tok.type_ == TokenKind::WS || offset >= tok.offset + tok.text.len() as Offset {
+                return Ok(None);
+            }
+            return Ok(Some((node, tok)));
+        }
+    }
+
+    fn href_target(&self, href: &pb::token::Href) -> Result<Option<(RelativePath, Offset)>, Box<dyn Error>> {
+        let (node_id, offset) = match href {
+            pb::token::Href::NodeIdWithOffsetRef(pb::NodeIdWithOffsetHref { node_id, offset }) => (*node_id, Some(*offset)),
+            pb::token::Href::NodeIdRef(node_id) => (*node_id, None),
+            pb::token::Href::SymIdRef(id) => match self.sym_token_location(SymID(*id))? {
+                Some(TokenLocation { node_id, offset }) => (node_id, Some(offset)),
+                None => return Ok(None),
+            },
+            pb::token::Href::UniHref(pb::UniHref { path, offset }) => {
+                return Ok(Some((PathBuf::from(path).into(), *offset)));
+            },
+            pb::token::Href::DirectNodeLink(_) => return Ok(None),
+        };
+        let node = self.reader.node(&GenHref::NodeId(node_id))?;
+        let offset = offset.unwrap_or_else(|| node.start.as_ref().map_or(0, |loc| loc.offset));
+        Ok(Some((PathBuf::from(&node.path).into(), offset)))
+    }
+
+    /// Location of the token defining `sym_id`, in the node the symmap
+    /// points to.
+    fn sym_token_location(&self, sym_id: SymID) -> Result<Option<TokenLocation>, Box<dyn Error>> {
+        let node = match self.reader.node(&GenHref::SymId(sym_id)) {
+            Ok(node) => node,
+            Err(e) => {
+                warn!("no definition for {sym_id:?}: {e}");
+                return Ok(None);
+            },
+        };
+        // the node may use the symbol before the token defining it, which
+        // is the one with references
+        let defining: Vec<_> = pb_node_tokens(&node)
+            .into_iter()
+            .filter(|tok| tok.context.sym_id == Some(sym_id))
+            .collect();
+        let tok = defining
+            .iter()
+            .find(|tok| matches!(tok.context.references, ReferencesLink::TokenLocation(_)))
+            .or(defining.first());
+        Ok(tok.map(|tok| TokenLocation { node_id: node.id, offset: tok.offset }))
+    }
+
+    fn location(&self, path: &impl ToString, offset: Offset, len: usize) -> Option<Location> {
+        let path = path.to_string();
+        let line_index = self.line_index(&path)?;
+        Some(Location {
+            uri: self.uri(&path)?,
+            range: Range {
+                start: line_index.position(offset),
+                end: line_index.position(offset + len as Offset),
+            },
+        })
+    }
+
+    fn relative_path(&self, uri: &Url) -> Result<RelativePath, Box<dyn Error>> {
+        let abs_path = uri.to_file_path().map_err(|_| format!("not a file URI: {uri}"))?;
+        if !abs_path.starts_with(&self.repo) {
+            return Err(format!("{abs_path:?} is outside of the repository").into());
+        }
+        Ok(AbsolutePath::from(abs_path).to_relative(&self.repo))
+    }
+
+    fn uri(&self, path: &str) -> Option<Url> {
+        Url::from_file_path(self.repo.join(path)).ok()
+    }
+
+    fn line_index(&self, path: &str) -> Option<Rc<LineIndex>> {
+        if let Some(line_index) = self.line_indexes.borrow().get(path) {
+            return Some(Rc::clone(line_index));
+        }
+        let text = read_to_string(self.repo.join(path))
+            .map_err(|e| warn!("can't read {path}: {e}"))
+            .ok()?;
+        let line_index = Rc::new(LineIndex::new(text));
+        self.line_indexes.borrow_mut().insert(path.to_string(), Rc::clone(&line_index));
+        Some(line_index)
+    }
+}
+
+
+/// Node shown folded at `tok`, in place of its text.
+fn elided_node(tok: &pb::Token) -> Option<NodeID> {
+    match &tok.href {
+        Some(pb::token::Href::NodeIdRef(node_id)) => Some(*node_id),
+        Some(pb::token::Href::NodeIdWithOffsetRef(href)) if tok.uim_elided == Some(true) => Some(href.node_id),
+        _ => None,
+    }
+}
+
+
+fn token_name(tok: &Token) -> &str {
+    tok.text.trim()
+}
+
+
+#[cfg(test)]
+mod test {
+    use lsp_types::Position;
+
+    use super::LineIndex;
+
+    #[test]
+    fn line_index_roundtrip() {
+        let li = LineIndex::new("int x;\nint żółw;\n\nvoid f() {}".to_string());
+
+        assert_eq!(li.offset(Position { line: 0, character: 4 }), Some(4));
+        assert_eq!(li.offset(Position { line: 1, character: 6 }), Some(15));
+        assert_eq!(li.offset(Position { line: 3, character: 5 }), Some(26));
+        assert_eq!(li.offset(Position { line: 7, character: 0 }), None);
+
+        assert_eq!(li.position(4), Position { line: 0, character: 4 });
+        assert_eq!(li.position(15), Position { line: 1, character: 6 });
+        assert_eq!(li.position(20), Position { line: 2, character: 0 });
+    }
+}
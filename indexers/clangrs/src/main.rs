@@ -1,9 +1,8 @@
 use std::fs::{create_dir_all, File};
-use std::os::fd::FromRawFd;
 use std::sync::Arc;
 
 use clap::Parser;
-use log::info;
+use log::{error, info};
 use simplelog::{ColorChoice, CombinedLogger, LevelFilter, SharedLogger, TermLogger, TerminalMode, WriteLogger};
 
 use clangrs::args::{Args, Stage};
@@ -12,13 +11,19 @@ use clangrs::serial_stage::serial_stage_with_stores;
 use clangrs::uses_stage::{uses_stage, uses_stage_with_store};
 use clangrs::output_stage::{output_stage, output_stage_with_stores};
 use clangrs::intermediate_model::sqlite;
+use clangrs::archive::pack_from_args;
+use clangrs::incremental::changed_files_from_args;
+use clangrs::report::RunReport;
+use clangrs::resume::{prepare_output_stage, prepare_run};
+use clangrs::status::{self, StageState};
+use clangrs::watch::RepoWatcher;
 
 
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
     if let Some(fd) = args.status_fd {
-        let mut _file = unsafe { File::from_raw_fd(fd) };
+        status::open(fd);
     }
 
     let mut loggers: Vec<Box<dyn SharedLogger>> = vec![
@@ -45,10 +50,18 @@ async fn main() {
 
     if let Some(uim_input) = &args.uim_input {
         let p = uim_input.clone();
-        clangrs::uim::index_uim(args, &p).await;
+        clangrs::uim::index_uim(args.clone(), &p).await;
+        pack_archive(&args);
         return;
     }
 
+    if args.watch {
+        watch(&args).await;
+        return;
+    }
+
+    check_changed_files(&args);
+
     match args.stage {
         None => {
             run_stages(&args).await;
@@ -72,33 +85,108 @@ async fn main() {
 pub async fn run_stages(args: &Args) {
     let mut t = clangrs::timers::Timers::new();
 
-    let mut store = t.timed("create tables", || {
+    let (mut store, args) = t.timed("create tables", || {
         info!("opening db: {:?}", args.db_path);
         let store = sqlite::new_from_args::<sqlite::SqliteGSMWriter, sqlite::SqliteUMWriter>(args);
-        info!("preparing tables");
-        store.create_tables();
-        store
-    });
-
-    t.timed("parse stage", || {
-        parse_stage_with_stores(args, &mut store)
+        let args = prepare_run(args, &store);
+        (store, args)
     });
+    let args = &args;
+    let progress = store.progress.clone();
+    let mut report = RunReport::new(args);
+
+    if begin_stage(&progress, "parse") {
+        report.parse = t.timed("parse stage", || {
+            parse_stage_with_stores(args, &mut store)
+        });
+        end_stage(&progress, "parse");
+    }
 
     let mut store = sqlite::new_with_conn(store.conn);
-    t.async_timed("uses stage", async {
-        uses_stage_with_store(&args, &mut store);
-    }).await;
+    if begin_stage(&progress, "uses") {
+        if args.resume {
+            store.reset_uses();
+        }
+        t.async_timed("uses stage", async {
+            uses_stage_with_store(args, &mut store);
+        }).await;
+        end_stage(&progress, "uses");
+    }
 
     let conn = Arc::clone(&store.conn);
     let store = sqlite::new_with_conn(Arc::clone(&conn));
-    t.async_timed("output stage", async {
-        output_stage_with_stores(args, store).await;
-    }).await;
+    if begin_stage(&progress, "output") {
+        prepare_output_stage(args, &progress, &store.output_map).await;
+        let blobs = t.async_timed("output stage", async {
+            output_stage_with_stores(args, store).await
+        }).await;
+        report.add_blobs(&blobs);
+        end_stage(&progress, "output");
+    }
 
     let store = sqlite::new_with_conn(Arc::clone(&conn));
-    t.async_timed("serial stage", async {
-        serial_stage_with_stores(args, store).await;
-    }).await;
+    if begin_stage(&progress, "serial") {
+        let blobs = t.async_timed("serial stage", async {
+            serial_stage_with_stores(args, store).await
+        }).await;
+        report.add_blobs(&blobs);
+        end_stage(&progress, "serial");
+    }
+
+    t.timed("pack archive", || pack_archive(args));
 
     t.dump();
+    report.timings = t.seconds();
+    report.write(args);
+}
+
+
+fn begin_stage(progress: &sqlite::RunProgress, stage: &str) -> bool {
+    if progress.is_stage_done(stage) {
+        info!("{} stage finished in the interrupted run, skipping", stage);
+        status::report_stage(stage, StageState::Skipped);
+        return false;
+    }
+    status::report_stage(stage, StageState::Started);
+    true
+}
+
+
+fn end_stage(progress: &sqlite::RunProgress, stage: &str) {
+    progress.finish_stage(stage);
+    status::report_stage(stage, StageState::Finished);
+}
+
+
+/// Exits before any stage starts if the files of an incremental run can't be
+/// listed.
+fn check_changed_files(args: &Args) {
+    if let Err(e) = changed_files_from_args(args) {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+
+fn pack_archive(args: &Args) {
+    let Some(archive_path) = &args.archive else { return };
+    if let Err(e) = pack_from_args(args, archive_path) {
+        error!("failed to pack archive {:?}: {}", archive_path, e);
+        std::process::exit(1);
+    }
+}
+
+
+async fn watch(args: &Args) {
+    let mut watcher = RepoWatcher::new(args).expect("failed to watch the repo");
+    let mut changed = None;
+    loop {
+        let cycle_args = watcher.next_cycle(changed.as_ref()).unwrap();
+        info!("indexing build {}", cycle_args.build_id);
+
+        run_stages(&cycle_args).await;
+        watcher.publish(&cycle_args).unwrap();
+
+        changed = watcher.wait_for_changes().unwrap();
+    }
 }
@@ -1,6 +1,6 @@
 use cscanner::ast::ClangCurKind;
 use territory_core::territory::index::token::Href;
-use territory_core::territory::index::NodeIdWithOffsetHref;
+use territory_core::territory::index::{self as pb, NodeIdWithOffsetHref};
 use territory_core::{
     Node,
     TokenLocation,
@@ -11,7 +11,8 @@ use territory_core::{
 
 use log::info;
 
-use crate::intermediate_model::sqlite::{SqliteGSMReader, SqliteServices, SqliteUMQuery};
+use crate::intermediate_model::sqlite::{SqliteGSMReader, SqliteServices, SqliteUMQuery, TypeHierarchyMap};
+use crate::report::BlobsByKind;
 use crate::storage::start_from_args;
 use crate::writer::{NodeWriter, IntermediateNodeFileReader};
 use crate::args::Args;
@@ -19,6 +20,7 @@ use crate::intermediate_model::{
     sqlite,
     GlobalSymbolMapReader,
     SemFile,
+    SemMacroExpansion,
     SemNode,
     SemTokenContext,
     UsesMapQuery,
@@ -37,14 +39,17 @@ impl OutputStage {
         Self { node_writer, references_enabled }
     }
 
+    /// Links the tokens of `sem_node`, adding the expansions of the macros
+    /// it invokes to `macro_expansions`.
     fn make_hyperlinked_node(
         &mut self,
         global_defs: &mut impl GlobalSymbolMapReader,
         uses: &impl UsesMapQuery,
         sem_node: SemNode,
+        macro_expansions: &mut Vec<pb::MacroExpansion>,
     ) -> Node {
         let node_id = sem_node.id;
-        sem_node
+        let node = sem_node
             .map_tokens(&mut |offset, _text, kind, ctx| {
                 let sym_id = (*kind == TokenKind::Identifier).then_some(())
                 .and_then(|_| ctx.sem.as_ref())
@@ -72,11 +77,19 @@ impl OutputStage {
                     ReferencesLink::None
                 };
 
-                HyperlinkedTokenContext { href, sym_id, references }
+                let has_macro_expansion = if let Some(expansion) = ctx.macro_expansion {
+                    macro_expansions.push(pb_macro_expansion(token_location, expansion));
+                    true
+                } else {
+                    false
+                };
+
+                HyperlinkedTokenContext { href, sym_id, references, has_macro_expansion }
             })
             .replace_context(HyperlinkedNodeContext {
                 references: None,
-            })
+            });
+        node
     }
 
     pub fn generate_hyperlinked_graph<'tu>(
@@ -86,22 +99,28 @@ impl OutputStage {
         files: impl Iterator<Item=SemFile>,
     ) {
         for sem_file in files {
+            let mut macro_expansions = Vec::new();
             let file = sem_file.map_nodes(&mut |sem_node: SemNode|
-                self.make_hyperlinked_node(global_defs, uses, sem_node));
+                self.make_hyperlinked_node(global_defs, uses, sem_node, &mut macro_expansions));
             self.node_writer.submit_blob(file);
+            if !macro_expansions.is_empty() {
+                self.node_writer.submit_macro_expansions(macro_expansions);
+            }
         }
     }
 
-    pub fn finalize(mut self, uses: impl UsesMapQuery) {
+    pub fn finalize(mut self, uses: impl UsesMapQuery, type_hierarchy: TypeHierarchyMap) -> BlobsByKind {
         if self.references_enabled {
             uses.write(&mut self.node_writer);
         }
+        type_hierarchy.write(&mut self.node_writer);
 
         let writer_stats = self.node_writer.join();
 
         info!("translation unit done, wrote {} nodes", writer_stats.total_written);
         info!("total PB bytes written:    {}", writer_stats.pb_bytes_count);
         info!("total PB bytes reused:     {}", writer_stats.pb_bytes_reused);
+        writer_stats.by_kind
     }
 }
 
@@ -124,17 +143,38 @@ fn get_href(
 }
 
 
-pub async fn output_stage(args: &Args) {
+fn pb_macro_expansion(token_location: TokenLocation, sem_expansion: SemMacroExpansion) -> pb::MacroExpansion {
+    let SemMacroExpansion { expansion, definitions } = sem_expansion;
+    pb::MacroExpansion {
+        node_id: token_location.node_id,
+        offset: token_location.offset,
+        name: expansion.steps.first().map(|step| step.name.clone()).unwrap_or_default(),
+        text: expansion.text,
+        steps: expansion.steps
+            .into_iter()
+            .zip(definitions)
+            .map(|(step, definition)| pb::MacroExpansionStep {
+                name: step.name,
+                depth: step.depth,
+                definition: definition.map(|tl| NodeIdWithOffsetHref { node_id: tl.node_id, offset: tl.offset }),
+            })
+            .collect(),
+    }
+}
+
+
+pub async fn output_stage(args: &Args) -> BlobsByKind {
     let stores = sqlite::new_from_args(args);
-    output_stage_with_stores(args, stores).await;
+    output_stage_with_stores(args, stores).await
 }
 
-pub async fn output_stage_with_stores(args: &Args, stores: SqliteServices<SqliteGSMReader, SqliteUMQuery>) {
+pub async fn output_stage_with_stores(args: &Args, stores: SqliteServices<SqliteGSMReader, SqliteUMQuery>) -> BlobsByKind {
     let mut timers = Timers::new();
 
     let SqliteServices {
         global_symbol_map: mut global_defs,
         uses_map,
+        type_hierarchy,
         output_map,
         ..
     } = stores;
@@ -151,13 +191,14 @@ pub async fn output_stage_with_stores(args: &Args, stores: SqliteServices<Sqlite
         output_stage_.generate_hyperlinked_graph(&mut global_defs, &uses_map, &mut node_file_reader);
     });
 
-    timers.timed("finalize", move || {
-        output_stage_.finalize(uses_map);
+    let blobs = timers.timed("finalize", move || {
+        output_stage_.finalize(uses_map, type_hierarchy)
     });
 
     timers.async_timed("storage.join", async {
-        storage_done.await.unwrap();
+        storage_done.await.unwrap().expect("failed to store blobs");
     }).await;
 
     timers.dump();
+    blobs
 }
@@ -1,13 +1,15 @@
 use std::collections::{HashMap, HashSet};
 use std::fs::{create_dir_all, File};
+use std::path::{Path, PathBuf};
 
 use itertools::Itertools;
-use log::{warn, debug};
+use log::{debug, info, warn};
 
 use territory_core::{
     GToken, Location, NodeID, NodeKind, RelativePath, TokenKind, TokenLocation
 };
-use cscanner::ast::{Block, ClangCurKind, ClangTokenContext, LocalDefinitionLocation, Sem, TransportID};
+use cscanner::ast::{Block, ClangCommand, ClangCurKind, ClangTokenContext, LocalDefinitionLocation, Sem, TransportID};
+use cscanner::ipc::Include;
 
 use crate::intermediate_model::sqlite::{
     SpanStore,
@@ -17,12 +19,14 @@ use crate::intermediate_model::sqlite::{
     Paths,
 };
 use crate::looks::write_elision_tokens;
-use crate::scanner_driver::driver_loop;
-use crate::writer::IntermediateNodeFileWriter;
+use crate::scanner_driver::{driver_loop, DriverCallbacks};
+use crate::incremental::{carry_over_nodes, changed_files_from_args, tu_key, IncrementalRun};
+use crate::writer::{semfile_path, IntermediateNodeFileWriter};
 use crate::args::{Args, get_debug_cfg};
 use crate::intermediate_model::{
-    sqlite, GlobalSymbolMapWriter, LocalSpanIndex, SemFile, SemNode, SemNodeContext, SemTokenContext
+    sqlite, GlobalSymbolMapWriter, LocalSpanIndex, SemFile, SemNode, SemMacroExpansion, SemNodeContext, SemOverride, SemTokenContext
 };
+use crate::report::{NodeReport, ParseReport};
 use crate::timers::Timers;
 
 
@@ -36,7 +40,8 @@ struct Indexer<'a> {
     args: Args,
     pub paths: &'a Paths,
     span_store: &'a mut SpanStore,
-    slice_states: Vec<SliceState>
+    slice_states: Vec<SliceState>,
+    report: &'a mut ParseReport,
 }
 
 impl<'a> Indexer<'a> {
@@ -44,6 +49,7 @@ impl<'a> Indexer<'a> {
         args: &Args,
         paths: &'a Paths,
         span_store: &'a mut SpanStore,
+        report: &'a mut ParseReport,
     ) -> Self {
         debug!("writing output to {:?}", args.outdir);
 
@@ -54,7 +60,7 @@ impl<'a> Indexer<'a> {
             slice_states: (0..args.par)
                 .map(|_i| SliceState { sem_nodes: vec![], blocks: HashMap::new() })
                 .collect_vec(),
-
+            report,
         }
     }
 
@@ -111,6 +117,11 @@ impl<'a> Indexer<'a> {
                 sem: Some(block.context.root.clone()),
                 nest_level,
                 end_offset: block.end.off,
+                bases: block.context.bases,
+                overrides: block.context.overrides
+                    .into_iter()
+                    .map(|method| SemOverride { method, declaration: None })
+                    .collect(),
             },
             text: self.write_sem_toks(slice, id, results, &mut block.sems, block.text),
         };
@@ -145,6 +156,7 @@ impl<'a> Indexer<'a> {
                             .clone()),
                         local_definition: None,  // set later
                         elided: None,
+                        macro_expansion: None,  // set later
                     };
                     let sem_tok = GToken { context: tc, offset, line, text, type_ };
                     sem_toks.push(sem_tok);
@@ -155,6 +167,7 @@ impl<'a> Indexer<'a> {
                             sem: None,
                             local_definition: None,
                             elided: None,
+                            macro_expansion: None,
                         };
                         let sem_tok = GToken { context: tc, offset, line, text, type_ };
                         sem_toks.push(sem_tok);
@@ -187,6 +200,7 @@ impl<'a> Indexer<'a> {
                                     node_id: nested_node.id,
                                     offset,
                                 }),
+                                macro_expansion: None,
                             };
                             let sem_tok = GToken { context: tc, offset, line, text: text.into(), type_ };
                             sem_toks.push(sem_tok);
@@ -228,6 +242,31 @@ impl<'a> Indexer<'a> {
                             }
                         }
                     }));
+
+                // tokens within the invocation share the cursor, the name comes first
+                let expansion = tok.context.sem.as_mut()
+                    .filter(|sem| sem.cur_start_offset == Some(tok.offset))
+                    .and_then(|sem| sem.macro_expansion.take());
+                tok.context.macro_expansion = expansion.map(|expansion| SemMacroExpansion {
+                    definitions: expansion.steps.iter().map(|step| {
+                        step.definition.as_ref().and_then(|ldl| {
+                            self.ldl_to_tl(&lsi, ldl).unwrap_or_else(|e| {
+                                warn!("failed to resolve definition of macro {}: {}", step.name, e);
+                                None
+                            })
+                        })
+                    }).collect(),
+                    expansion,
+                });
+            }
+
+            for ovr in &mut node.context.overrides {
+                ovr.declaration = ovr.method.declaration.as_ref().and_then(|ldl| {
+                    self.ldl_to_tl(&lsi, ldl).unwrap_or_else(|e| {
+                        warn!("failed to resolve declaration of overridden method {}: {}", ovr.method.name, e);
+                        None
+                    })
+                });
             }
         }
     }
@@ -334,12 +373,12 @@ fn dump_sems(sems: &Vec<SemNode>) {
 }
 
 
-pub fn parse_stage(args: &Args) {
+pub fn parse_stage(args: &Args) -> ParseReport {
     let mut stores = sqlite::new_from_args(args);
     parse_stage_with_stores(args, &mut stores)
 }
 
-pub fn parse_stage_with_stores(args: &Args, stores: &mut SqliteServices<SqliteGSMWriter, SqliteUMWriter>) {
+pub fn parse_stage_with_stores(args: &Args, stores: &mut SqliteServices<SqliteGSMWriter, SqliteUMWriter>) -> ParseReport {
     std::fs::create_dir_all(&args.intermediate_path).unwrap();
 
     let SqliteServices {
@@ -347,12 +386,61 @@ pub fn parse_stage_with_stores(args: &Args, stores: &mut SqliteServices<SqliteGS
         uses_map: _uses,
         span_store,
         paths,
+        tu_sources,
+        tu_includes,
+        quarantine,
+        progress,
         ..
     } = stores;
 
-    let indexer = crate::parse_stage::Indexer::new(&args, paths, span_store);
+    let changed_files = changed_files_from_args(args).expect("failed to list changed files");
+    let previous_semfile = semfile_path(args, args.slice).with_extension("prev");
+    let previous_tus = tu_sources.all();
+    let mut incremental = match changed_files {
+        Some(changed) if !previous_tus.is_empty() && semfile_path(args, args.slice).exists() => {
+            std::fs::rename(semfile_path(args, args.slice), &previous_semfile).unwrap();
+            Some(IncrementalRun::new(changed, previous_tus))
+        },
+        Some(_) => {
+            warn!("no previous run found in {:?}, indexing everything", args.db_path);
+            tu_sources.clear();
+            tu_includes.clear();
+            None
+        },
+        None if args.resume => None,
+        None => {
+            tu_sources.clear();
+            tu_includes.clear();
+            quarantine.clear();
+            None
+        },
+    };
+
+    // translation units parsed before the run was interrupted are in the
+    // semfile already, along with the files they covered, and quarantined
+    // ones stay out
+    let (skipped_tus, processed): (HashSet<PathBuf>, HashSet<RelativePath>) = if args.resume {
+        let done_tus = progress.done_tus();
+        info!("resuming after {} parsed translation units", done_tus.len());
+        let processed = tu_sources.all()
+            .into_iter()
+            .filter(|(tu, _)| done_tus.contains(tu))
+            .flat_map(|(_, source_set)| source_set)
+            .collect();
+        let quarantined = quarantine.all().into_iter().map(|(tu, _)| tu);
+        (done_tus.into_iter().chain(quarantined).collect(), processed)
+    } else {
+        (HashSet::new(), HashSet::new())
+    };
 
-    let mut node_file_writer = IntermediateNodeFileWriter::new_from_args(args);
+    let mut report = ParseReport::default();
+    let indexer = crate::parse_stage::Indexer::new(args, paths, span_store, &mut report);
+
+    let mut node_file_writer = if args.resume {
+        IntermediateNodeFileWriter::resume(&semfile_path(args, args.slice), progress.semfile_end())
+    } else {
+        IntermediateNodeFileWriter::new_from_args(args)
+    };
 
     let mut scan_log_file = args.log_dir.as_ref().map(|log_dir| {
         create_dir_all(log_dir).unwrap();
@@ -361,8 +449,14 @@ pub fn parse_stage_with_stores(args: &Args, stores: &mut SqliteServices<SqliteGS
 
     let mut timers = Timers::new();
 
-    driver_loop(args, indexer, scan_log_file.as_mut(),
-    &mut |indexer: &mut Indexer, block: Block, slice: usize| {
+    let tu_reports = driver_loop(args, indexer, scan_log_file.as_mut(), processed, DriverCallbacks {
+    on_block: &mut |indexer: &mut Indexer, block: Block, slice: usize| {
+        indexer.report.add_node(NodeReport {
+            path: block.context.relative_path.clone(),
+            line: block.start.line,
+            bytes: block.end.off.saturating_sub(block.start.off) as usize,
+        }, block.is_truncated());
+
         if block.context.nest_level > 0 {
             indexer.blocks(slice).insert(block.transport_key, block);
             return Ok(());
@@ -397,7 +491,14 @@ pub fn parse_stage_with_stores(args: &Args, stores: &mut SqliteServices<SqliteGS
 
         Ok(())
     },
-    &mut |indexer: &mut Indexer, slice: usize, source_set: &HashSet<RelativePath>| {
+    on_tu_done: &mut |indexer: &mut Indexer, slice: usize, tu: &Path, source_set: &HashSet<RelativePath>, includes: &[Include]| {
+        tu_sources.replace(tu, source_set);
+        tu_includes.replace(tu, includes);
+        quarantine.remove(tu);
+        if !args.index_system {
+            indexer.report.add_skipped_files(source_set, includes);
+        }
+
         let mut sem_nodes = std::mem::replace(&mut indexer.slice_states[slice-1].sem_nodes, Vec::new());
 
         timers.timed("resolve local definitions", || {
@@ -418,14 +519,51 @@ pub fn parse_stage_with_stores(args: &Args, stores: &mut SqliteServices<SqliteGS
                 node_file_writer.append(&sf);
             }
         });
+        progress.tu_done(tu, node_file_writer.flushed_len());
 
         Ok(())
+    },
+    on_tu_failed: &mut |indexer: &mut Indexer, slice: usize, tu: &Path, reason: &str, quarantined: bool| {
+        let state = &mut indexer.slice_states[slice-1];
+        state.sem_nodes.clear();
+        state.blocks.clear();
+        if quarantined {
+            quarantine.add(tu, reason, args.tu_retries + 1);
+        }
+    },
+    filter_commands: &mut |commands: &mut Vec<ClangCommand>| {
+        if let Some(incremental) = incremental.as_mut() {
+            incremental.retain_commands(commands);
+        }
+        commands.retain(|cmd| !skipped_tus.contains(&tu_key(cmd)));
+    },
     });
 
+    if let Some(incremental) = incremental {
+        for tu in incremental.vanished() {
+            tu_sources.remove(&tu);
+            tu_includes.remove(&tu);
+        }
+        let stale_paths = incremental.stale_paths(&tu_sources.all());
+        let kept = timers.timed("carry over unchanged nodes", || {
+            carry_over_nodes(previous_semfile.clone(), &stale_paths, &mut node_file_writer)
+        });
+        info!("reused {} nodes from the previous run", kept.len());
+        span_store.refresh(kept.into_iter());
+        std::fs::remove_file(&previous_semfile).unwrap();
+    }
+
+    report.tus = tu_reports;
+    for (tu, reason) in quarantine.all() {
+        warn!("quarantined {:?}: {}", tu, reason);
+        report.quarantined.insert(tu, reason);
+    }
+
     if get_debug_cfg().print_global_defs {
         // println!("global desfs: {:#?}", global_defs);
         todo!();
     }
 
     timers.dump();
+    report
 }
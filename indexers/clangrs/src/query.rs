@@ -0,0 +1,307 @@
+//! Subcommands of `territory-query`, printing what they find in a build.
+
+use std::error::Error;
+use std::io::Write;
+
+use clap::Subcommand;
+
+use territory_core::call_graph::CallGraph;
+use territory_core::include_graph::IncludeGraph;
+use territory_core::search::{Options, Ranking};
+use territory_core::text_search::TextSearchOptions;
+use territory_core::territory::index::{Implementation, MacroExpansion, Node, NodeKind, ReferenceKind};
+use territory_core::{pb_node_tokens, pretty_print, GenHref, IntoGenHref, Offset, TokenLocation};
+
+use crate::index_reader::IndexReader;
+
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Search the symbol index
+    Search {
+        /// Name to look for, `=name` for an exact and `^name` for a prefix
+        /// match, narrowed with `kind:macro`, `path:dir/` or `type:int`
+        query: String,
+
+        #[arg(short, long, default_value_t=20)]
+        limit: usize,
+
+        /// Rank shorter keys first
+        #[arg(long)]
+        by_length: bool,
+
+        /// Rank word boundary, exact and prefix matches first
+        #[arg(long, conflicts_with = "by_length")]
+        smart: bool,
+    },
+    /// Search the text of all nodes, using the trigram index
+    Grep {
+        pattern: String,
+
+        /// Treat the pattern as a regex rather than a literal string
+        #[arg(short = 'e', long)]
+        regex: bool,
+
+        #[arg(short = 's', long)]
+        case_sensitive: bool,
+
+        #[arg(short, long, default_value_t=20)]
+        limit: usize,
+    },
+    /// Pretty-print a node given as a node id, a path or any href
+    Show {
+        node: String,
+    },
+    /// List references of the token at `offset` in `node_id`, and the
+    /// overrides of a virtual method defined there
+    Refs {
+        node_id: u64,
+        offset: Offset,
+
+        /// Only list references of this kind: read, write, call, address-of,
+        /// type-use, macro or include
+        #[arg(short, long, value_parser = parse_reference_kind)]
+        kind: Option<ReferenceKind>,
+    },
+    /// Show the expansion of the macro invoked at `offset` in `node_id`,
+    /// followed by the macros expanded along the way
+    Macro {
+        node_id: u64,
+        offset: Offset,
+    },
+    /// List a directory
+    Ls {
+        #[arg(default_value="")]
+        dir: String,
+    },
+    /// Print the whole directory tree of the build
+    Tree,
+    /// List direct base classes or implemented interfaces of the type defined in a node
+    Supertypes {
+        node: String,
+    },
+    /// List types directly deriving from or implementing the type defined in a node
+    Subtypes {
+        node: String,
+    },
+    /// Export the function call graph as Graphviz DOT
+    Calls {
+        /// Print JSON instead of DOT
+        #[arg(long)]
+        json: bool,
+    },
+    /// List files included by a file, with the include path each was found through
+    Includes {
+        path: String,
+    },
+    /// List files including a file
+    IncludedBy {
+        path: String,
+
+        /// Follow includes through other headers
+        #[arg(long)]
+        transitive: bool,
+    },
+    /// Export the include graph as Graphviz DOT
+    IncludeGraph,
+}
+
+
+/// Runs `command` against the build in `reader`, writing the results to `out`.
+pub fn run(reader: &IndexReader, command: &Command, out: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Search { query, limit, by_length, smart } => {
+            let ranking = match (*by_length, *smart) {
+                (true, _) => Ranking::Length,
+                (_, true) => Ranking::Smart,
+                _ => Ranking::None,
+            };
+            let options = Options { limit: Some(*limit), ranking };
+            for res in reader.search(query, &options)? {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    res.item.key,
+                    res.item.ty.as_deref().unwrap_or("-"),
+                    res.item.path.as_deref().unwrap_or("-"),
+                    res.item.href.into_gen_href().to_url())?;
+            }
+        },
+        Command::Grep { pattern, regex, case_sensitive, limit } => {
+            let options = TextSearchOptions { limit: Some(*limit), regex: *regex, case_sensitive: *case_sensitive };
+            for m in reader.search_text(pattern, &options)? {
+                writeln!(out, "{}:{}:\t{}", m.path, m.line, m.text)?;
+            }
+        },
+        Command::Show { node } => {
+            let node = reader.node(&parse_node_arg(node)?)?;
+            pretty_print::node(out, &node)?;
+        },
+        Command::Refs { node_id, offset, kind } => {
+            let href = GenHref::RefsId(TokenLocation { node_id: *node_id, offset: *offset });
+            let references = reader.references(&href)?;
+            for r in references.refs.iter().filter(|r| kind.is_none_or(|k| r.kind() == k)) {
+                let location = match &r.use_location {
+                    Some(loc) => format!("{}:{}:{}", r.use_path, loc.line, loc.column),
+                    None => r.use_path.clone(),
+                };
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    location,
+                    r.context,
+                    r.href.into_gen_href().to_url(),
+                    reference_kind_name(r.kind()))?;
+            }
+            for (relation, methods) in [("overrides", references.overrides), ("overridden by", references.overridden_by)] {
+                for m in methods {
+                    let href = m.href.map_or("-".to_string(), |h| GenHref::NodeId(h.node_id).to_url());
+                    writeln!(out, "{}\t{}\t{}", relation, m.name, href)?;
+                }
+            }
+        },
+        Command::Macro { node_id, offset } => {
+            let href = GenHref::MacroExpansion(TokenLocation { node_id: *node_id, offset: *offset });
+            let expansion: MacroExpansion = reader.load(&href)?;
+            writeln!(out, "{}", expansion.text)?;
+            for step in expansion.steps {
+                let href = step.definition.map_or("-".to_string(), |h| GenHref::NodeId(h.node_id).to_url());
+                writeln!(out, "{}{}\t{}", "  ".repeat(step.depth as usize), step.name, href)?;
+            }
+        },
+        Command::Ls { dir } => {
+            let node = reader.node(&GenHref::Path(dir.clone()))?;
+            if node.kind() != NodeKind::Directory {
+                return Err(format!("not a directory: {dir}").into());
+            }
+            for (name, href) in dir_entries(&node) {
+                writeln!(out, "{}\t{}", name, href.map_or("-".to_string(), |h| h.to_url()))?;
+            }
+        },
+        Command::Tree => {
+            let root = reader.node(&GenHref::Path("".into()))?;
+            print_tree(out, reader, &root, 0)?;
+        },
+        Command::Supertypes { node } => {
+            let node = reader.node(&parse_node_arg(node)?)?;
+            print_types(out, &reader.type_hierarchy(node.id)?.supertypes)?;
+        },
+        Command::Subtypes { node } => {
+            let node = reader.node(&parse_node_arg(node)?)?;
+            print_types(out, &reader.type_hierarchy(node.id)?.subtypes)?;
+        },
+        Command::Calls { json } => {
+            let graph = CallGraph::extract(reader, reader.index_items()?)?;
+            if *json {
+                serde_json::to_writer_pretty(&mut *out, &graph)?;
+                writeln!(out)?;
+            } else {
+                graph.write_dot(out)?;
+            }
+        },
+        Command::Includes { path } => {
+            let graph = IncludeGraph::load(reader)?;
+            for include in graph.includes(path) {
+                let search_path = match include.search_path.as_deref() {
+                    Some("") => ".",
+                    Some(p) => p,
+                    None => "-",
+                };
+                writeln!(out, "{}\t{}\t{}", include.included, include.spelling, search_path)?;
+            }
+        },
+        Command::IncludedBy { path, transitive } => {
+            let graph = IncludeGraph::load(reader)?;
+            if *transitive {
+                for includer in graph.transitively_included_by(path) {
+                    writeln!(out, "{includer}")?;
+                }
+            } else {
+                for include in graph.included_by(path) {
+                    writeln!(out, "{}\t{}", include.includer, include.spelling)?;
+                }
+            }
+        },
+        Command::IncludeGraph => {
+            IncludeGraph::load(reader)?.write_dot(out)?;
+        },
+    }
+
+    Ok(())
+}
+
+
+const REFERENCE_KIND_NAMES: &[(ReferenceKind, &str)] = &[
+    (ReferenceKind::RkUnknown, "-"),
+    (ReferenceKind::RkRead, "read"),
+    (ReferenceKind::RkWrite, "write"),
+    (ReferenceKind::RkCall, "call"),
+    (ReferenceKind::RkAddressOf, "address-of"),
+    (ReferenceKind::RkTypeUse, "type-use"),
+    (ReferenceKind::RkMacro, "macro"),
+    (ReferenceKind::RkInclude, "include"),
+];
+
+
+fn reference_kind_name(kind: ReferenceKind) -> &'static str {
+    REFERENCE_KIND_NAMES.iter().find(|(k, _)| *k == kind).map_or("-", |(_, name)| name)
+}
+
+
+fn parse_reference_kind(arg: &str) -> Result<ReferenceKind, String> {
+    REFERENCE_KIND_NAMES
+        .iter()
+        .find(|(k, name)| *name == arg && *k != ReferenceKind::RkUnknown)
+        .map(|(k, _)| *k)
+        .ok_or_else(|| format!("unknown reference kind: {arg}"))
+}
+
+
+/// Accepts a bare node id, an href (`id:`, `sym:`, `path:`, ...) or a
+/// repo-relative path.
+fn parse_node_arg(arg: &str) -> Result<GenHref, Box<dyn Error>> {
+    if let Ok(id) = arg.parse() {
+        return Ok(GenHref::NodeId(id));
+    }
+    if arg.contains(':') {
+        return GenHref::from_url(arg).ok_or_else(|| format!("malformed href: {arg}").into());
+    }
+    Ok(GenHref::Path(arg.trim_start_matches('/').to_string()))
+}
+
+
+fn dir_entries(node: &Node) -> Vec<(String, Option<GenHref>)> {
+    pb_node_tokens(node)
+        .into_iter()
+        .filter(|tok| !tok.text.trim().is_empty())
+        .map(|tok| (
+            tok.text.trim_end_matches('\n').to_string(),
+            tok.context.href.map(|h| h.into_gen_href()),
+        ))
+        .collect()
+}
+
+
+fn print_types(out: &mut dyn Write, types: &[Implementation]) -> Result<(), Box<dyn Error>> {
+    for ty in types {
+        let href = ty.href.as_ref().map_or("-".to_string(), |h| GenHref::NodeId(h.node_id).to_url());
+        writeln!(out, "{}\t{}", ty.name, href)?;
+    }
+    Ok(())
+}
+
+
+fn print_tree(out: &mut dyn Write, reader: &IndexReader, dir: &Node, depth: usize) -> Result<(), Box<dyn Error>> {
+    for (name, href) in dir_entries(dir) {
+        writeln!(out, "{}{}", "  ".repeat(depth), name)?;
+        if !name.ends_with('/') {
+            continue;
+        }
+        let Some(href) = href else { continue };
+        let child = reader.node(&href)?;
+        if child.kind() == NodeKind::Directory {
+            print_tree(out, reader, &child, depth + 1)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{create_dir_all, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use log::info;
+use serde::Serialize;
+
+use cscanner::ipc::{DiagnosticCounts, Include};
+use territory_core::RelativePath;
+
+use crate::args::Args;
+
+
+/// Nodes kept in `ParseReport::largest_nodes`.
+const LARGEST_NODES: usize = 50;
+
+
+/// What went into a build, written as JSON next to its output to compare
+/// builds with each other.
+#[derive(Serialize, Default, Debug)]
+pub struct RunReport {
+    pub build_id: String,
+    /// Seconds spent in each stage
+    pub timings: BTreeMap<String, f64>,
+    pub parse: ParseReport,
+    /// Bytes of blobs per kind, from both writing stages
+    pub blobs: BlobsByKind,
+}
+
+impl RunReport {
+    pub fn new(args: &Args) -> Self {
+        Self { build_id: args.build_id.clone(), ..Default::default() }
+    }
+
+    pub fn add_blobs(&mut self, blobs: &BlobsByKind) {
+        for (kind, bytes) in blobs {
+            self.blobs.entry(kind).or_default().add(bytes);
+        }
+    }
+
+    pub fn write(&self, args: &Args) {
+        let path = report_path(args);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        let file = BufWriter::new(File::create(&path).unwrap());
+        serde_json::to_writer_pretty(file, self).unwrap();
+        info!("wrote run report to {:?}", path);
+    }
+}
+
+
+pub fn report_path(args: &Args) -> PathBuf {
+    args.outdir
+        .join("reports")
+        .join(&args.repo_id)
+        .join(format!("{}.json", args.build_id))
+}
+
+
+/// Bytes written and reused by a `NodeWriter`, by kind of blob.
+pub type BlobsByKind = BTreeMap<&'static str, BlobBytes>;
+
+
+#[derive(Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobBytes {
+    pub written: usize,
+    /// Bytes of nodes found in blobs of previous builds instead of written
+    pub reused: usize,
+}
+
+impl BlobBytes {
+    pub fn add(&mut self, other: &BlobBytes) {
+        self.written += other.written;
+        self.reused += other.reused;
+    }
+}
+
+
+#[derive(Serialize, Default, Debug)]
+pub struct ParseReport {
+    /// Translation units parsed in this run, in the order they finished
+    pub tus: Vec<TuReport>,
+    /// Quarantined translation units with the last failure
+    pub quarantined: BTreeMap<PathBuf, String>,
+    /// Largest nodes by source length, largest first
+    pub largest_nodes: Vec<NodeReport>,
+    /// Nodes cut at `--max-node-len` tokens
+    pub truncated_nodes: Vec<NodeReport>,
+    /// Files included by translation units whose nodes were left out
+    pub skipped_files: BTreeMap<String, SkipReason>,
+}
+
+impl ParseReport {
+    pub fn add_node(&mut self, node: NodeReport, truncated: bool) {
+        if truncated {
+            self.truncated_nodes.push(node.clone());
+        }
+        if self.largest_nodes.len() == LARGEST_NODES
+            && self.largest_nodes.last().is_some_and(|n| n.bytes >= node.bytes) {
+            return;
+        }
+        let at = self.largest_nodes.partition_point(|n| n.bytes >= node.bytes);
+        self.largest_nodes.insert(at, node);
+        self.largest_nodes.truncate(LARGEST_NODES);
+    }
+
+    /// Records the files of a translation unit that are not indexed because
+    /// they are outside the repo. Ones found through an include path outside
+    /// the repo are taken for system headers.
+    pub fn add_skipped_files<'a>(
+        &mut self,
+        source_set: impl IntoIterator<Item=&'a RelativePath>,
+        includes: &[Include],
+    ) {
+        let system: HashSet<&RelativePath> = includes.iter()
+            .filter(|incl| incl.search_path.as_ref().is_some_and(|sp| !sp.is_in_repo()))
+            .map(|incl| &incl.included)
+            .collect();
+        for path in source_set {
+            if path.is_in_repo() {
+                continue;
+            }
+            let reason = self.skipped_files.entry(path.to_string()).or_insert(SkipReason::OutOfRepo);
+            if system.contains(path) {
+                *reason = SkipReason::System;
+            }
+        }
+    }
+}
+
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TuReport {
+    pub tu: PathBuf,
+    /// Seconds from handing the command to a scanner to it finishing the TU
+    pub parse_secs: f64,
+    pub diagnostics: DiagnosticCounts,
+}
+
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeReport {
+    pub path: RelativePath,
+    pub line: u32,
+    pub bytes: usize,
+}
+
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    System,
+    OutOfRepo,
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    use cscanner::ipc::Include;
+    use territory_core::{AbsolutePath, RelativePath};
+
+    use super::{NodeReport, ParseReport, SkipReason, LARGEST_NODES};
+
+    fn rel(p: &str) -> RelativePath {
+        RelativePath::from(PathBuf::from(p))
+    }
+
+    fn outside(p: &str) -> RelativePath {
+        AbsolutePath::from(PathBuf::from(p)).to_relative(Path::new("/repo"))
+    }
+
+    fn node(bytes: usize) -> NodeReport {
+        NodeReport { path: rel("a.c"), line: bytes as u32, bytes }
+    }
+
+    #[test]
+    fn largest_nodes() {
+        let mut report = ParseReport::default();
+        for bytes in (0..LARGEST_NODES * 2).rev().chain(0..LARGEST_NODES * 2) {
+            report.add_node(node(bytes), bytes == 7);
+        }
+
+        let sizes: Vec<_> = report.largest_nodes.iter().map(|n| n.bytes).collect();
+        assert_eq!(sizes.len(), LARGEST_NODES);
+        assert_eq!(sizes[0], LARGEST_NODES * 2 - 1);
+        assert_eq!(sizes[1], LARGEST_NODES * 2 - 1);
+        assert!(sizes.windows(2).all(|w| w[0] >= w[1]));
+        assert_eq!(report.truncated_nodes, vec![node(7), node(7)]);
+    }
+
+    #[test]
+    fn skipped_files() {
+        let include = |included: &str, search_path: Option<&str>| Include {
+            includer: rel("a.c"),
+            included: outside(included),
+            spelling: String::new(),
+            search_path: search_path.map(outside),
+        };
+
+        let mut report = ParseReport::default();
+        report.add_skipped_files(
+            &[rel("a.c"), outside("/usr/include/stdio.h"), outside("/home/lib/lib.h")],
+            &[include("/usr/include/stdio.h", Some("/usr/include")), include("/home/lib/lib.h", None)]);
+
+        assert_eq!(report.skipped_files, BTreeMap::from([
+            ("/home/lib/lib.h".to_string(), SkipReason::OutOfRepo),
+            ("/usr/include/stdio.h".to_string(), SkipReason::System),
+        ]));
+    }
+}
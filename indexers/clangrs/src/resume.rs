@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+
+use log::{info, warn};
+
+use crate::args::Args;
+use crate::intermediate_model::sqlite::{OutputMap, RunProgress, SqliteGSM, SqliteServices, SqliteUM};
+use crate::storage::{StorageBackend, StorageError};
+
+
+/// Decides whether the run continues an interrupted one (see `--resume`) and
+/// prepares the tables if it doesn't. The returned args have `resume` unset
+/// when starting over.
+pub fn prepare_run<GSM: SqliteGSM, UM: SqliteUM>(args: &Args, store: &SqliteServices<GSM, UM>) -> Args {
+    if args.resume {
+        match store.progress.build_id() {
+            Some(build_id) if build_id == args.build_id => {
+                info!("resuming build {}", args.build_id);
+                return args.clone();
+            },
+            Some(build_id) => {
+                warn!("last run in {:?} was of build {}, starting over", args.db_path, build_id);
+            },
+            None => {
+                warn!("no run to resume in {:?}, starting over", args.db_path);
+            },
+        }
+    }
+
+    info!("preparing tables");
+    store.create_tables();
+    store.progress.start(&args.build_id);
+    Args { resume: false, ..args.clone() }
+}
+
+
+/// Before the output stage runs, remembers where its blobs start. If it ran
+/// before and was interrupted, drops the locations in blobs that did not make
+/// it to storage so that they are written again.
+pub async fn prepare_output_stage(
+    args: &Args,
+    progress: &RunProgress,
+    output_map: &OutputMap,
+) {
+    let Some(mark) = progress.blob_mark() else {
+        progress.set_blob_mark(output_map.last_blob_id());
+        return;
+    };
+    let backend = crate::storage::backend_from_args(args).expect("failed to open storage");
+    let forgotten = forget_unstored_blobs(backend.as_ref(), &args.repo_id, output_map, mark)
+        .await
+        .expect("failed to check stored blobs");
+    info!("{} blobs of the interrupted output stage were not stored", forgotten);
+}
+
+
+pub async fn forget_unstored_blobs(
+    backend: &dyn StorageBackend,
+    repo_id: &str,
+    output_map: &OutputMap,
+    mark: u64,
+) -> Result<usize, StorageError> {
+    let blobs_dir = PathBuf::from("nodes").join(repo_id).join("f");
+    let mut missing = Vec::new();
+    for blob_id in output_map.blob_ids_after(mark) {
+        if !backend.exists(&blobs_dir.join(blob_id.to_string())).await? {
+            missing.push(blob_id);
+        }
+    }
+    output_map.forget_blobs(&missing);
+    Ok(missing.len())
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use ring::digest::{digest, SHA256};
+    use testdir::testdir;
+
+    use territory_core::territory::index::BlobSliceLoc;
+
+    use crate::intermediate_model::{sqlite, SemFile};
+    use crate::storage::{file::FileBackend, StorageBackend};
+    use crate::writer::{IntermediateNodeFileReader, IntermediateNodeFileWriter};
+    use super::forget_unstored_blobs;
+
+    #[test]
+    fn unstored_blobs_are_forgotten() {
+        let store = sqlite::new_mem::<sqlite::SqliteGSMWriter, sqlite::SqliteUMWriter>();
+        let output_map = &store.output_map;
+        let old = output_map.new_blob_id();
+        let mark = output_map.last_blob_id();
+        let stored = output_map.new_blob_id();
+        let lost = output_map.new_blob_id();
+        for (node_id, blob_id) in [(1, old), (2, stored), (3, lost)] {
+            let loc = BlobSliceLoc { blob_id: blob_id.0, start_offset: 0, end_offset: 1 };
+            output_map.store_node_location(node_id, &loc, digest(&SHA256, &[node_id as u8]));
+        }
+
+        let backend = FileBackend::new(testdir!());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let forgotten = rt.block_on(async {
+            backend.put(Path::new(&format!("nodes/r/f/{}", stored.0)), &[1]).await.unwrap();
+            forget_unstored_blobs(&backend, "r", output_map, mark).await.unwrap()
+        });
+
+        assert_eq!(forgotten, 1);
+        let mut nodes: Vec<_> = output_map.node_locations().into_iter().map(|(id, _)| id).collect();
+        nodes.sort();
+        assert_eq!(nodes, vec![1, 2]);
+    }
+
+    #[test]
+    fn semfile_is_cut_after_last_parsed_tu() {
+        let path = testdir!().join("semfile.1");
+        let mut writer = IntermediateNodeFileWriter::new(&path);
+        writer.append(&SemFile { nodes: vec![] });
+        let end = writer.flushed_len();
+        writer.append(&SemFile { nodes: vec![] });
+        drop(writer);
+
+        let mut writer = IntermediateNodeFileWriter::resume(&path, end);
+        writer.append(&SemFile { nodes: vec![] });
+        assert_eq!(writer.flushed_len(), 2 * end);
+        drop(writer);
+
+        assert_eq!((&mut IntermediateNodeFileReader::new(path)).count(), 2);
+    }
+}
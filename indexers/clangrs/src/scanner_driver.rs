@@ -1,6 +1,7 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::os::unix::net::UnixStream;
 use std::{
     os::unix::net::UnixListener,
@@ -8,72 +9,147 @@ use std::{
     thread
 };
 use std::sync::mpsc::{sync_channel, RecvTimeoutError, SyncSender};
+use std::thread::JoinHandle;
+use std::time::Instant;
 
 use serde_json::to_writer;
-use log::{info, warn};
+use log::{error, info, warn};
 
 use crate::locks_agent::LocksAgent;
-use crate::args::{Args, get_scanner_ipc_timeout};
+use crate::args::{Args, ScannerMode, get_scanner_ipc_timeout};
+use crate::incremental::tu_key;
+use crate::scanner_pool::{supervise, ScannerError, Served};
+use crate::report::TuReport;
+use crate::status::TuProgress;
 
 use territory_core::RelativePath;
 use cscanner::ast::{ClangCommand, Block};
-use cscanner::ipc::{Control, DriverSays, ScanCommandsArgs, ScanOpts, ScannerSays};
+use cscanner::ipc::{Control, DriverSays, Include, ScanCommandsArgs, ScanOpts, ScannerSays};
+
+
+enum ScannerEvent {
+    Says(usize, Box<ScannerSays>, SyncSender<DriverSays>),
+    /// The scanner died, stopped responding or exited with an error and may
+    /// be restarted.
+    Failed(usize, String),
+}
 
 
 enum ScanCommandsState {
     NotStarted,
-    Scanning(Vec<SyncSender<DriverSays>>),
+    Scanning(usize, Vec<SyncSender<DriverSays>>),
     GotCommands(Vec<ClangCommand>, usize),
 }
 
+
+/// Translation units scanners failed on. Each is queued again up to
+/// `--tu-retries` times, then quarantined.
+#[derive(Default)]
+struct TuFailures {
+    failures: HashMap<PathBuf, usize>,
+    quarantined: usize,
+}
+
+impl TuFailures {
+    /// Counts a failure on `cmd` and queues it again unless it is
+    /// quarantined now. Returns the unit and whether it was quarantined.
+    fn failed(&mut self, args: &Args, cmd: ClangCommand, commands: &mut ScanCommandsState) -> (PathBuf, bool) {
+        let tu = tu_key(&cmd);
+        let failures = self.failures.entry(tu.clone()).or_default();
+        *failures += 1;
+        let quarantined = *failures > args.tu_retries;
+        if quarantined {
+            self.quarantined += 1;
+        } else if let ScanCommandsState::GotCommands(commands, _count) = commands {
+            commands.insert(0, cmd);
+        }
+        (tu, quarantined)
+    }
+
+    /// Fails if more than `--max-quarantined-percent` of the `total` units
+    /// were quarantined.
+    fn check(&self, args: &Args, total: usize) -> Result<(), String> {
+        if self.quarantined * 100 > total * args.max_quarantined_percent {
+            return Err(format!(
+                "{} of {} translation units were quarantined, more than {}%",
+                self.quarantined, total, args.max_quarantined_percent));
+        }
+        Ok(())
+    }
+}
+
+pub type OnBlock<'a, State> = dyn FnMut(&mut State, Block, usize) -> Result<(), Box<dyn Error>> + 'a;
+pub type OnTuDone<'a, State> =
+    dyn FnMut(&mut State, usize, &Path, &HashSet<RelativePath>, &[Include]) -> Result<(), Box<dyn Error>> + 'a;
+
+/// What `driver_loop` does with the scanners' results. Callbacks are passed
+/// the scanner number along with the loop's state.
+pub struct DriverCallbacks<'a, State> {
+    /// A block of a translation unit was scanned.
+    pub on_block: &'a mut OnBlock<'a, State>,
+    /// All blocks of a translation unit were sent, with its source set and
+    /// includes.
+    pub on_tu_done: &'a mut OnTuDone<'a, State>,
+    /// The scanner failed on a translation unit, with the reason and whether
+    /// the unit was quarantined rather than queued for a retry.
+    pub on_tu_failed: &'a mut dyn FnMut(&mut State, usize, &Path, &str, bool),
+    /// Narrows down the compile commands before they are handed out.
+    pub filter_commands: &'a mut dyn FnMut(&mut Vec<ClangCommand>),
+}
+
 pub fn driver_loop<State>(
     args: &Args,
     mut state: State,
     mut log_file: Option<&mut File>,
-    mut on_block: impl FnMut(&mut State, Block, usize) -> Result<(), Box<dyn Error>>,
-    mut on_tu_done: impl FnMut(&mut State, usize, &HashSet<RelativePath>) -> Result<(), Box<dyn Error>>,
-) {
-    let sock = UnixListener::bind(&args.scanner_socket_path).expect("failed to open socket file");
-    sock.set_nonblocking(true).unwrap();
-
+    processed: HashSet<RelativePath>,
+    callbacks: DriverCallbacks<State>,
+) -> Vec<TuReport> {
     let (send, recv) = sync_channel(args.par);
 
-    let mut la = LocksAgent::new(args.par);
+    let mut la = LocksAgent::with_processed(args.par, processed);
 
-    info!("waiting for scanners");
-    let started_waiting = std::time::Instant::now();
-    let mut threads = Vec::new();
-    for i in 1..=args.par {
-        let con = 'accept: loop {
-            match sock.accept() {
-                Ok((con, _addr)) => {
-                    info!("scanner {} connected", i);
-                    break 'accept con;
-                }
-                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    if started_waiting.elapsed() > std::time::Duration::from_secs(args.scanner_socket_timeout as u64) {
-                        panic!("timed out waiting for scanner");
-                    }
-                    thread::sleep(std::time::Duration::from_millis(50));
-                }
-                Err(e) => {
-                    panic!("Error accepting connection: {}", e);
-                }
-            }
-        };
-        let mut args = args.clone();
-        args.slice = i;
-        let send = send.clone();
-        let t = thread::spawn(move || handle_connection(&args, con, send, i));
-        threads.push(t);
-    }
+    let threads = match args.scanners {
+        ScannerMode::External => accept_external(args, &send),
+        _ => spawn_supervisors(args, &send),
+    };
     drop(send);
 
     let mut commands = ScanCommandsState::NotStarted;
     let mut pending_commands = 0;
+    let mut thread_tus: HashMap<usize, (ClangCommand, Instant)> = HashMap::new();
+    let mut tu_reports = Vec::new();
+    let mut tu_failures = TuFailures::default();
+    let mut progress: Option<TuProgress> = None;
     loop {
         let (thread, msg, responder) = match recv.recv_timeout(get_scanner_ipc_timeout()) {
-            Ok(rcvd) => rcvd,
+            Ok(ScannerEvent::Says(thread, msg, responder)) => (thread, *msg, responder),
+            Ok(ScannerEvent::Failed(thread, reason)) => {
+                if let Some((cmd, _started)) = thread_tus.remove(&thread) {
+                    let (tu, quarantined) = tu_failures.failed(args, cmd, &mut commands);
+                    if quarantined {
+                        error!("quarantining {:?}: scanner {} failed on it {} times, last: {}", tu, thread, tu_failures.failures[&tu], reason);
+                    } else {
+                        warn!("scanner {} failed while scanning {:?}, will retry: {}", thread, tu, reason);
+                        if let ScanCommandsState::GotCommands(commands, _count) = &commands {
+                            pending_commands = commands.len();
+                        }
+                    }
+                    (callbacks.on_tu_failed)(&mut state, thread, &tu, &reason, quarantined);
+                    if let Some(progress) = progress.as_mut() {
+                        progress.failed(quarantined);
+                    }
+                }
+                la.release_failed_thread(thread);
+                if let ScanCommandsState::Scanning(scanning_thread, waitlist) = &mut commands {
+                    if *scanning_thread == thread {
+                        for sender in waitlist.drain(..) {
+                            let _ = sender.send(DriverSays::Again);
+                        }
+                        commands = ScanCommandsState::NotStarted;
+                    }
+                }
+                continue;
+            },
             Err(RecvTimeoutError::Timeout) => {
                 panic!("scanner ipc TIMEOUT");
             },
@@ -94,6 +170,7 @@ pub fn driver_loop<State>(
                             continue;
                         };
                         pending_commands = commands.len();
+                        thread_tus.insert(thread, (cmd.clone(), Instant::now()));
                         responder.send(DriverSays::ClangCommand {
                             command: cmd,
                             opts: ScanOpts {
@@ -110,9 +187,9 @@ pub fn driver_loop<State>(
                             remove_path_prefix: args.remove_path_prefix.clone(),
                         };
                         responder.send(DriverSays::ScanCommands(scan_commands_args)).unwrap();
-                        commands = ScanCommandsState::Scanning(Vec::new());
+                        commands = ScanCommandsState::Scanning(thread, Vec::new());
                     },
-                    ScanCommandsState::Scanning(ref mut waitlist) => {
+                    ScanCommandsState::Scanning(_, ref mut waitlist) => {
                         waitlist.push(responder);
                     }
                 }
@@ -130,21 +207,33 @@ pub fn driver_loop<State>(
                         block.context.relative_path, thread);
                 }
 
-                on_block(&mut state, block, thread).unwrap();
+                (callbacks.on_block)(&mut state, block, thread).unwrap();
                 responder.send(DriverSays::BlockReceived).unwrap();
             }
-            ScannerSays::Control(Control::TUDone { source_set }) => {
-                on_tu_done(&mut state, thread, &source_set).unwrap();
+            ScannerSays::Control(Control::TUDone { source_set, includes, diagnostics }) => {
+                let (cmd, started) = thread_tus.remove(&thread).expect("TUDone without a command");
+                let tu = tu_key(&cmd);
+                (callbacks.on_tu_done)(&mut state, thread, &tu, &source_set, &includes).unwrap();
+                tu_reports.push(TuReport {
+                    tu,
+                    parse_secs: started.elapsed().as_secs_f64(),
+                    diagnostics,
+                });
+                if let Some(progress) = progress.as_mut() {
+                    progress.done();
+                }
                 la.release_thread(thread);
                 responder.send(DriverSays::Continue).unwrap();
             }
             ScannerSays::Control(Control::GotCommands { commands: mut c }) => {
-                let ScanCommandsState::Scanning(waitlist) = commands else {
+                let ScanCommandsState::Scanning(_, waitlist) = commands else {
                     panic!("GotCommands but ScanCommandsState is not Scanning");
                 };
+                (callbacks.filter_commands)(&mut c);
                 c.reverse();
                 let commands_count = c.len();
                 pending_commands = commands_count;
+                progress = Some(TuProgress::start(commands_count));
                 for sender in waitlist {
                     sender.send(DriverSays::Again).unwrap();
                 }
@@ -164,38 +253,140 @@ pub fn driver_loop<State>(
 
     la.dump_state();
 
+    let mut failures = Vec::new();
     for t in threads {
-        t.join().unwrap();
+        if let Err(e) = t.join().unwrap() {
+            error!("{}", e);
+            failures.push(e.to_string());
+        }
+    }
+    if args.scanners == ScannerMode::External {
+        let _ = std::fs::remove_file(&args.scanner_socket_path);
     }
-    let _ = std::fs::remove_file(&args.scanner_socket_path);
 
+    if !matches!(commands, ScanCommandsState::GotCommands(..)) {
+        panic!("no scanner got to read the compile commands: {}", failures.join(", "));
+    }
     if pending_commands > 0 {
         panic!("scanners terminated with {} pending commands", pending_commands);
     }
+    if let ScanCommandsState::GotCommands(_, commands_count) = commands {
+        if let Err(e) = tu_failures.check(args, commands_count) {
+            panic!("{}", e);
+        }
+    }
     info!("scanner done");
+    tu_reports
+}
+
+
+/// Waits for `--par` scanners started separately to connect to
+/// `--scanner-socket-path`.
+fn accept_external(
+    args: &Args,
+    send: &SyncSender<ScannerEvent>,
+) -> Vec<JoinHandle<Result<(), ScannerError>>> {
+    let sock = UnixListener::bind(&args.scanner_socket_path).expect("failed to open socket file");
+    sock.set_nonblocking(true).unwrap();
+
+    info!("waiting for scanners");
+    let started_waiting = std::time::Instant::now();
+    let mut threads = Vec::new();
+    for i in 1..=args.par {
+        let con = 'accept: loop {
+            match sock.accept() {
+                Ok((con, _addr)) => {
+                    info!("scanner {} connected", i);
+                    break 'accept con;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if started_waiting.elapsed() > std::time::Duration::from_secs(args.scanner_socket_timeout as u64) {
+                        panic!("timed out waiting for scanner");
+                    }
+                    thread::sleep(std::time::Duration::from_millis(50));
+                }
+                Err(e) => {
+                    panic!("Error accepting connection: {}", e);
+                }
+            }
+        };
+        con.set_nonblocking(false).unwrap();
+        let mut args = args.clone();
+        args.slice = i;
+        let send = send.clone();
+        let t = thread::spawn(move || {
+            handle_connection(&args, con, send, i);
+            Ok(())
+        });
+        threads.push(t);
+    }
+    threads
+}
+
+
+/// Starts a supervisor thread for each of the `--par` scanners the driver
+/// runs itself.
+fn spawn_supervisors(
+    args: &Args,
+    send: &SyncSender<ScannerEvent>,
+) -> Vec<JoinHandle<Result<(), ScannerError>>> {
+    (1..=args.par).map(|i| {
+        let mut args = args.clone();
+        args.slice = i;
+        let send = send.clone();
+        thread::spawn(move || {
+            supervise(
+                &args,
+                i,
+                |con| handle_connection(&args, con, send.clone(), i),
+                |e| send.send(ScannerEvent::Failed(i, e.to_string())).unwrap(),
+            )
+        })
+    }).collect()
 }
 
 
+/// Passes the messages of a scanner to the driver loop until it disconnects.
 fn handle_connection(
     _args: &Args,
     con: UnixStream,
-    send: SyncSender<(usize, ScannerSays, SyncSender<DriverSays>)>,
+    send: SyncSender<ScannerEvent>,
     thread_num: usize,
-) {
-    con.set_nonblocking(false).unwrap();
+) -> Served {
     let reader = std::io::BufReader::new(con.try_clone().unwrap());
     let mut de = serde_json::de::Deserializer::from_reader(reader).into_iter();
 
     let scanner_ipc_timeout = get_scanner_ipc_timeout();
 
+    let mut served = Served::default();
     while let Some(req) = de.next() {
-        let req = req.unwrap();
+        let req: ScannerSays = match req {
+            Ok(req) => req,
+            Err(e) if e.is_io() => {
+                warn!("scanner {} stopped responding: {}", thread_num, e);
+                break;
+            }
+            Err(e) => {
+                warn!("bad message from scanner {}: {}", thread_num, e);
+                break;
+            }
+        };
+        if let ScannerSays::Control(Control::TUDone { .. }) = req {
+            served.scanning = false;
+            served.finished_tu = true;
+        }
 
         let (responder, response_recv) = sync_channel(10);
-        send.send((thread_num, req, responder)).unwrap();
+        send.send(ScannerEvent::Says(thread_num, Box::new(req), responder)).unwrap();
         match response_recv.recv_timeout(scanner_ipc_timeout) {
             Ok(response) =>  {
-                to_writer(&con, &response).unwrap();
+                if let DriverSays::ClangCommand { .. } = response {
+                    served.scanning = true;
+                }
+                if let Err(e) = to_writer(&con, &response) {
+                    warn!("failed to respond to scanner {}: {}", thread_num, e);
+                    break;
+                }
             }
             Err(RecvTimeoutError::Timeout) => {
                 panic!("failed to receive driver response: TIMEOUT (waiting thread {})", thread_num);
@@ -205,9 +396,49 @@ fn handle_connection(
             }
         };
     }
-    drop(send);
+    served
+}
+
 
-    // if !locks.is_empty() {
-    //     warn!("got {} leftover locks", locks.len());
-    // }
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use cscanner::ast::ClangCommand;
+
+    use crate::testlib::defaut_args;
+    use super::{ScanCommandsState, TuFailures};
+
+    fn command(file: &str) -> ClangCommand {
+        ClangCommand { index: 0, file: file.into(), directory: "/repo".into(), args: vec![] }
+    }
+
+    fn pop(commands: &mut ScanCommandsState) -> Option<ClangCommand> {
+        let ScanCommandsState::GotCommands(commands, _count) = commands else { panic!("no commands") };
+        commands.pop()
+    }
+
+    #[test]
+    fn failed_tus_are_retried_then_quarantined() {
+        let mut args = defaut_args();
+        args.tu_retries = 1;
+        args.max_quarantined_percent = 25;
+        let mut commands = ScanCommandsState::GotCommands(vec![command("b.c"), command("a.c")], 2);
+        let mut failures = TuFailures::default();
+
+        let a = pop(&mut commands).unwrap();
+        assert_eq!(failures.failed(&args, a, &mut commands), (PathBuf::from("/repo/a.c"), false));
+        // retried after the others
+        assert_eq!(pop(&mut commands), Some(command("b.c")));
+        let a = pop(&mut commands).unwrap();
+        assert_eq!(a, command("a.c"));
+
+        assert_eq!(failures.failed(&args, a, &mut commands), (PathBuf::from("/repo/a.c"), true));
+        assert_eq!(pop(&mut commands), None);
+
+        assert_eq!(failures.check(&args, 4), Ok(()));
+        assert_eq!(
+            failures.check(&args, 2),
+            Err("1 of 2 translation units were quarantined, more than 25%".to_string()));
+    }
 }
@@ -0,0 +1,324 @@
+use std::error::Error;
+use std::fs::remove_file;
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use log::{error, info, warn};
+
+use crate::args::{Args, ScannerMode};
+
+
+pub type ScannerError = Box<dyn Error + Send + Sync>;
+
+
+/// Socket the scanner in `slot` connects to when the driver starts its own
+/// scanners. Each slot has its own so connections can't be mixed up.
+pub fn slot_socket_path(args: &Args, slot: usize) -> PathBuf {
+    let mut path = args.scanner_socket_path.clone().into_os_string();
+    path.push(format!(".{}", slot));
+    PathBuf::from(path)
+}
+
+
+/// A scanner started by the driver, as a `--scanner-bin` process or as a
+/// thread of the indexer.
+pub enum ScannerWorker {
+    Process(Child),
+    Thread(Option<JoinHandle<Result<(), String>>>),
+}
+
+impl ScannerWorker {
+    pub fn launch(args: &Args, slot: usize, sock: &Path) -> io::Result<Self> {
+        let compile_commands_dir = args.compile_commands_dir.as_ref().unwrap_or(&args.repo);
+        match args.scanners {
+            ScannerMode::Process => {
+                let mut child = Command::new(&args.scanner_bin)
+                    .arg("--repo-path").arg(&args.repo)
+                    .arg("--compile-commands-dir").arg(compile_commands_dir)
+                    .arg("--sock").arg(sock)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+                forward_output(slot, child.stdout.take());
+                forward_output(slot, child.stderr.take());
+                info!("started scanner {} (pid {})", slot, child.id());
+                Ok(Self::Process(child))
+            },
+            ScannerMode::Thread => {
+                let scanner_args = cscanner::Args {
+                    compile_commands_dir: compile_commands_dir.clone(),
+                    repo_path: args.repo.clone(),
+                    sock: sock.to_path_buf(),
+                    chroot: None,
+                    setuid: None,
+                    setgid: None,
+                    socket_timeout: args.scanner_socket_timeout as u64,
+                    dump_ccs: false,
+                };
+                Ok(Self::Thread(Some(thread::spawn(move || {
+                    let conn = cscanner::connect(&scanner_args).map_err(|e| e.to_string())?;
+                    cscanner::scanner_loop(&conn, &scanner_args).map_err(|e| e.to_string())
+                }))))
+            },
+            ScannerMode::External => {
+                Err(io::Error::other("external scanners are not started by the driver"))
+            },
+        }
+    }
+
+    /// Exit status if the scanner has exited.
+    fn try_wait(&mut self) -> Option<Result<(), ScannerError>> {
+        match self {
+            Self::Process(child) => match child.try_wait() {
+                Ok(Some(status)) => Some(exit_result(status)),
+                Ok(None) => None,
+                Err(e) => Some(Err(e.into())),
+            },
+            Self::Thread(handle) if handle.as_ref().is_some_and(|h| h.is_finished()) => {
+                handle.take().map(join_result)
+            },
+            Self::Thread(_) => None,
+        }
+    }
+
+    pub fn wait(self) -> Result<(), ScannerError> {
+        match self {
+            Self::Process(mut child) => exit_result(child.wait()?),
+            Self::Thread(handle) => handle.map_or(Ok(()), join_result),
+        }
+    }
+
+    /// Waits for the scanner to exit after its connection ended, killing it
+    /// if it is still running after `grace`. Threads can't be killed and are
+    /// waited for.
+    fn wait_or_kill(mut self, grace: Duration) -> Result<(), ScannerError> {
+        if let Self::Process(_) = self {
+            let started_waiting = Instant::now();
+            while started_waiting.elapsed() < grace {
+                if let Some(res) = self.try_wait() {
+                    return res;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            self.kill();
+            return Err("scanner stopped responding and was killed".into());
+        }
+        self.wait()
+    }
+
+    /// Threads can't be stopped, they end when their connection fails.
+    fn kill(&mut self) {
+        if let Self::Process(child) = self {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+
+fn exit_result(status: std::process::ExitStatus) -> Result<(), ScannerError> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("scanner exited with {}", status).into())
+    }
+}
+
+
+fn join_result(handle: JoinHandle<Result<(), String>>) -> Result<(), ScannerError> {
+    match handle.join() {
+        Ok(res) => res.map_err(|e| e.into()),
+        Err(_) => Err("scanner thread panicked".into()),
+    }
+}
+
+
+/// Logs the lines a scanner prints, prefixed with its slot like the logs it
+/// sends over the socket.
+fn forward_output(slot: usize, output: Option<impl Read + Send + 'static>) {
+    let Some(output) = output else { return };
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) => info!("<{}> {}", slot, line),
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+
+fn accept(
+    listener: &UnixListener,
+    worker: &mut ScannerWorker,
+    timeout: Duration,
+    idle_timeout: Duration,
+) -> Result<UnixStream, ScannerError> {
+    let started_waiting = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((con, _addr)) => {
+                con.set_nonblocking(false)?;
+                con.set_read_timeout(Some(idle_timeout))?;
+                return Ok(con);
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(res) = worker.try_wait() {
+                    let reason = res.err().map(|e| e.to_string()).unwrap_or("exited".to_string());
+                    return Err(format!("scanner did not connect: {}", reason).into());
+                }
+                if started_waiting.elapsed() > timeout {
+                    return Err(format!("scanner did not connect within {:?}", timeout).into());
+                }
+                thread::sleep(Duration::from_millis(50));
+            },
+            Err(e) => {
+                return Err(e.into());
+            },
+        }
+    }
+}
+
+
+/// How the connection of a scanner went, as told by the `serve` callback of
+/// [`supervise`].
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Served {
+    /// Whether the scanner finished any translation unit
+    pub finished_tu: bool,
+    /// Whether it was in the middle of a translation unit at the end
+    pub scanning: bool,
+}
+
+
+/// Runs a scanner until it exits. On failure, also returns how far it got.
+fn run_worker(
+    args: &Args,
+    slot: usize,
+    listener: &UnixListener,
+    sock: &Path,
+    serve: &mut impl FnMut(UnixStream) -> Served,
+) -> Result<(), (ScannerError, Served)> {
+    let mut worker = ScannerWorker::launch(args, slot, sock).map_err(|e| (e.into(), Served::default()))?;
+    let timeout = Duration::from_secs(args.scanner_socket_timeout as u64);
+    let idle_timeout = Duration::from_secs(args.scanner_idle_timeout);
+    let served = match accept(listener, &mut worker, timeout, idle_timeout) {
+        Ok(con) => serve(con),
+        Err(e) => {
+            worker.kill();
+            return Err((e, Served::default()));
+        },
+    };
+    worker.wait_or_kill(timeout).map_err(|e| (e, served))
+}
+
+
+/// Starts the scanner of `slot` and passes its connection to `serve`.
+/// `on_failure` is called after every failed run, before the scanner is
+/// restarted.
+///
+/// A scanner is restarted up to `--scanner-restarts` times. Failures while
+/// scanning a translation unit are counted separately, the driver retries or
+/// quarantines the unit, so a few bad files can't use up the restarts. The
+/// slot is given up after `--scanner-tu-failures` of them in a row without a
+/// finished unit in between.
+pub fn supervise(
+    args: &Args,
+    slot: usize,
+    mut serve: impl FnMut(UnixStream) -> Served,
+    mut on_failure: impl FnMut(&ScannerError),
+) -> Result<(), ScannerError> {
+    let sock = slot_socket_path(args, slot);
+    let _ = remove_file(&sock);
+    let listener = UnixListener::bind(&sock)?;
+    listener.set_nonblocking(true)?;
+
+    let mut restarts = 0;
+    let mut tu_failures = 0;
+    let result = loop {
+        match run_worker(args, slot, &listener, &sock, &mut serve) {
+            Ok(()) => {
+                info!("scanner {} finished", slot);
+                break Ok(());
+            },
+            Err((e, served)) => {
+                error!("scanner {} failed: {}", slot, e);
+                on_failure(&e);
+                if served.finished_tu {
+                    tu_failures = 0;
+                }
+                if served.scanning {
+                    tu_failures += 1;
+                    if tu_failures >= args.scanner_tu_failures {
+                        break Err(format!("scanner {} failed on {} translation units in a row", slot, tu_failures).into());
+                    }
+                    warn!("restarting scanner {}", slot);
+                    continue;
+                }
+                if restarts >= args.scanner_restarts {
+                    break Err(format!("scanner {} failed {} times", slot, restarts + 1).into());
+                }
+                restarts += 1;
+                warn!("restarting scanner {} ({}/{})", slot, restarts, args.scanner_restarts);
+            },
+        }
+    };
+
+    let _ = remove_file(&sock);
+    result
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use testdir::testdir;
+
+    use crate::args::ScannerMode;
+    use crate::testlib::defaut_args;
+    use super::{slot_socket_path, supervise, ScannerWorker};
+
+    #[test]
+    fn failed_scanners_are_restarted() {
+        let mut args = defaut_args();
+        args.scanners = ScannerMode::Process;
+        args.scanner_bin = PathBuf::from("false");
+        args.scanner_socket_path = testdir!().join("driver.sock");
+        args.scanner_restarts = 2;
+
+        let mut failures = Vec::new();
+        let res = supervise(&args, 1, |_| panic!("no scanner should connect"), |e| failures.push(e.to_string()));
+
+        assert_eq!(res.unwrap_err().to_string(), "scanner 1 failed 3 times");
+        assert_eq!(failures.len(), 3);
+        assert!(failures[0].contains("exit status: 1"), "{}", failures[0]);
+        assert!(!slot_socket_path(&args, 1).exists());
+    }
+
+    #[test]
+    fn scanners_read_the_given_compile_commands() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = testdir!();
+        let scanner = dir.join("scanner.sh");
+        std::fs::write(&scanner, format!("#!/bin/sh\necho \"$@\" > {:?}\n", dir.join("argv"))).unwrap();
+        std::fs::set_permissions(&scanner, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut args = defaut_args();
+        args.scanners = ScannerMode::Process;
+        args.scanner_bin = scanner;
+        args.compile_commands_dir = Some(dir.join("build"));
+
+        let mut worker = ScannerWorker::launch(&args, 0, &dir.join("scanner.sock")).unwrap();
+        while worker.try_wait().is_none() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let argv = std::fs::read_to_string(dir.join("argv")).unwrap();
+        assert!(argv.contains(&format!("--compile-commands-dir {}", dir.join("build").display())), "{argv}");
+    }
+}
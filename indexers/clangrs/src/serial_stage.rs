@@ -7,9 +7,10 @@ use log::info;
 use territory_core::{
     GToken, HNBlob, HyperlinkedNodeContext, HyperlinkedTokenContext, Location, Node, NodeID, NodeKind, RelativePath, TokenKind
 };
-use territory_core::territory::index::{IndexItemKind, IndexItem, index_item};
+use territory_core::territory::index::{BlobSliceLoc, IndexItemKind, IndexItem, index_item};
 
 use crate::intermediate_model::sqlite::{Paths, self, SqliteServices, SqliteGSMReader, SqliteUMQuery};
+use crate::report::BlobsByKind;
 use crate::storage::start_from_args;
 use crate::writer::{NodeWriter, InvertedIndexWriter, IntermediateNodeFileReader};
 use crate::args::Args;
@@ -19,7 +20,7 @@ use crate::looks::{
     inverted_index_entries,
 };
 use crate::filetree::FileTree;
-use crate::buildroot::write_slicemap_tries;
+use crate::buildroot::{write_slicemap_tries, TrieInputs};
 use crate::unparsed_listing::scan_file_listing;
 
 
@@ -119,7 +120,7 @@ impl<'a> SerialStage<'a> {
         }
     }
 
-    pub async fn finalize(mut self) {
+    pub async fn finalize(mut self) -> (BlobSliceLoc, BlobsByKind) {
         self.file_tree.write_dir_nodes(
             &mut self.node_writer,
             Some(&mut self.inverted_index_writer));
@@ -131,29 +132,30 @@ impl<'a> SerialStage<'a> {
             self.node_writer.submit_blob(blob);
         }
 
-        self.inverted_index_writer.join().await;
+        let search_shards = self.inverted_index_writer.join().await;
 
         let writer_stats = self.node_writer.join();
 
         info!("translation unit done, wrote {} nodes", writer_stats.total_written);
         info!("total PB bytes written:    {}", writer_stats.pb_bytes_count);
+        (search_shards, writer_stats.by_kind)
     }
 }
 
-pub async fn serial_stage(args: &Args) {
+pub async fn serial_stage(args: &Args) -> BlobsByKind {
     let stores = sqlite::new_from_args::<SqliteGSMReader, SqliteUMQuery>(args);
-    serial_stage_with_stores(args, stores).await;
+    serial_stage_with_stores(args, stores).await
 }
 
 
-pub async fn serial_stage_with_stores(args: &Args, mut stores: SqliteServices<SqliteGSMReader, SqliteUMQuery>) {
+pub async fn serial_stage_with_stores(args: &Args, mut stores: SqliteServices<SqliteGSMReader, SqliteUMQuery>) -> BlobsByKind {
     let (storage_done, storage_channel) = start_from_args(args).await;
     let node_writer = NodeWriter::start(
         &args,
         args.writer_concurrency,
         storage_channel.clone(),
         stores.output_map.clone());
-    let inverted_index_writer = InvertedIndexWriter::start(&args, storage_channel.clone());
+    let inverted_index_writer = InvertedIndexWriter::start(&args, storage_channel.clone(), stores.output_map.clone());
     let mut stage = SerialStage::new(args, node_writer, inverted_index_writer, &mut stores.paths);
 
     let mut node_file_reader = IntermediateNodeFileReader::new_with_slice(args, 1);
@@ -165,7 +167,7 @@ pub async fn serial_stage_with_stores(args: &Args, mut stores: SqliteServices<Sq
 
     scan_file_listing(&mut stage.file_tree, &mut stage.node_writer, &mut stage.inverted_index_writer, &args.repo);
 
-    stage.finalize().await;
+    let (search_shards, mut blobs) = stage.finalize().await;
 
     // need to wait for the original InvertedIndexWriter to fill the output map
     let mut node_writer = NodeWriter::start(
@@ -175,12 +177,23 @@ pub async fn serial_stage_with_stores(args: &Args, mut stores: SqliteServices<Sq
         stores.output_map.clone());
     write_slicemap_tries(
         &args.repo_id, &args.build_id, args.compression,
-        &mut node_writer, &stores.output_map, &stores.paths, storage_channel
+        &mut node_writer,
+        TrieInputs {
+            output_map: &stores.output_map,
+            paths: &stores.paths,
+            tu_includes: &stores.tu_includes,
+            search_shards,
+            text_index: !args.no_text_index,
+        },
+        storage_channel
     ).await;
-    node_writer.join();
+    for (kind, bytes) in node_writer.join().by_kind {
+        blobs.entry(kind).or_default().add(&bytes);
+    }
 
     info!("delete expired rows");
     stores.delete_expired();
 
-    storage_done.await.unwrap();
+    storage_done.await.unwrap().expect("failed to store blobs");
+    blobs
 }
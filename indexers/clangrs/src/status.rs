@@ -0,0 +1,155 @@
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::warn;
+use serde::Serialize;
+
+
+static STATUS_FILE: OnceLock<Mutex<File>> = OnceLock::new();
+
+
+/// Progress reported on `--status-fd`, one JSON object per line with the
+/// kind in `event`.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Status<'a> {
+    Stage {
+        stage: &'a str,
+        state: StageState,
+    },
+    /// Translation units of the parse stage. `failed` counts scanner
+    /// failures, including the ones retried later.
+    Tus {
+        queued: usize,
+        done: usize,
+        failed: usize,
+        quarantined: usize,
+        eta_secs: Option<u64>,
+    },
+    /// Totals of a `NodeWriter` once it finished.
+    Blobs {
+        written: usize,
+        bytes_written: usize,
+        bytes_reused: usize,
+    },
+    /// Counters of the storage driver.
+    Storage {
+        accepted: usize,
+        in_flight: usize,
+        done: usize,
+        failed: usize,
+    },
+}
+
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StageState {
+    Started,
+    Finished,
+    Skipped,
+}
+
+
+/// Sends status lines to `fd` from now on.
+pub fn open(fd: RawFd) {
+    let file = unsafe { File::from_raw_fd(fd) };
+    if STATUS_FILE.set(Mutex::new(file)).is_err() {
+        panic!("status fd opened twice");
+    }
+}
+
+
+pub fn report(status: Status) {
+    let Some(file) = STATUS_FILE.get() else { return };
+    let mut line = serde_json::to_vec(&status).unwrap();
+    line.push(b'\n');
+    if let Err(e) = file.lock().unwrap().write_all(&line) {
+        warn!("failed to write status: {}", e);
+    }
+}
+
+
+pub fn report_stage(stage: &str, state: StageState) {
+    report(Status::Stage { stage, state });
+}
+
+
+/// Counts translation units as the scanners go through them.
+pub struct TuProgress {
+    started: Instant,
+    queued: usize,
+    done: usize,
+    failed: usize,
+    quarantined: usize,
+}
+
+impl TuProgress {
+    pub fn start(queued: usize) -> Self {
+        let progress = Self { started: Instant::now(), queued, done: 0, failed: 0, quarantined: 0 };
+        progress.report();
+        progress
+    }
+
+    pub fn done(&mut self) {
+        self.done += 1;
+        self.report();
+    }
+
+    pub fn failed(&mut self, quarantined: bool) {
+        self.failed += 1;
+        if quarantined {
+            self.quarantined += 1;
+        }
+        self.report();
+    }
+
+    fn report(&self) {
+        report(self.status(self.started.elapsed()));
+    }
+
+    /// The ETA assumes the remaining units take as long as the finished
+    /// ones did on average.
+    fn status(&self, elapsed: Duration) -> Status<'static> {
+        let remaining = self.queued.saturating_sub(self.done + self.quarantined);
+        let eta_secs = (self.done > 0)
+            .then(|| (elapsed.as_secs_f64() / self.done as f64 * remaining as f64).round() as u64);
+        Status::Tus {
+            queued: self.queued,
+            done: self.done,
+            failed: self.failed,
+            quarantined: self.quarantined,
+            eta_secs,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Status, TuProgress};
+
+    #[test]
+    fn tu_eta() {
+        let mut progress = TuProgress::start(10);
+        assert_eq!(progress.status(Duration::from_secs(5)), Status::Tus {
+            queued: 10, done: 0, failed: 0, quarantined: 0, eta_secs: None,
+        });
+
+        progress.done();
+        progress.done();
+        progress.failed(false);
+        progress.failed(true);
+        assert_eq!(progress.status(Duration::from_secs(4)), Status::Tus {
+            queued: 10, done: 2, failed: 2, quarantined: 1, eta_secs: Some(14),
+        });
+
+        let line = serde_json::to_string(&progress.status(Duration::from_secs(4))).unwrap();
+        assert_eq!(line, r#"{"event":"tus","queued":10,"done":2,"failed":2,"quarantined":1,"eta_secs":14}"#);
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+
+#[derive(Debug)]
+pub enum StorageError {
+    NotFound(PathBuf),
+    /// Temporary failure (rate limits, timeouts); the storage driver retries
+    /// these with backoff.
+    Retriable(String),
+    Fatal(String),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound(path) => write!(f, "not found: {}", path.to_string_lossy()),
+            StorageError::Retriable(msg) => write!(f, "temporary storage error: {}", msg),
+            StorageError::Fatal(msg) => write!(f, "storage error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::Interrupted | std::io::ErrorKind::TimedOut => StorageError::Retriable(e.to_string()),
+            _ => StorageError::Fatal(e.to_string()),
+        }
+    }
+}
+
+
+/// Blob store the index is written to, addressed by paths relative to the
+/// output root (`nodes/<repo_id>/f/<blob_id>`, `builds/<repo_id>/<build_id>`).
+///
+/// Writes from the indexer go through [`super::StorageChannel`], which runs
+/// them concurrently and retries [`StorageError::Retriable`] failures. The
+/// other methods can be called on the backend directly.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn put(&self, path: &Path, data: &[u8]) -> Result<(), StorageError>;
+
+    async fn get(&self, path: &Path) -> Result<Vec<u8>, StorageError>;
+
+    async fn exists(&self, path: &Path) -> Result<bool, StorageError>;
+
+    /// Paths of all blobs under `prefix`, in no particular order.
+    async fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>, StorageError>;
+
+    async fn delete(&self, path: &Path) -> Result<(), StorageError>;
+}
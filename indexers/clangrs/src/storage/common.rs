@@ -3,10 +3,14 @@ use std::sync::Arc;
 
 use tokio::sync::oneshot;
 
+use super::StorageError;
+
 
 pub type StoreRequest = (PathBuf, Vec<u8>);
 
-pub type Done = oneshot::Receiver<()>;
+/// Fires once the storage driver finished, with an error if some requests
+/// failed for good.
+pub type Done = oneshot::Receiver<Result<(), StorageError>>;
 
 
 #[derive(Clone)]
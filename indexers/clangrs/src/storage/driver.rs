@@ -0,0 +1,293 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::error;
+use tokio::select;
+use tokio::sync::oneshot;
+use tokio::task::{JoinError, JoinSet};
+
+use crate::status::{self, Status};
+
+use super::backend::{StorageBackend, StorageError};
+use super::common::{StorageChannel, StoreRequest, Done};
+
+
+const BASE_CHILL_TIME: Duration = Duration::from_secs(1);
+const MAX_CHILL_TIME: Duration = Duration::from_secs(64);
+/// Attempts at storing a blob before giving up on it.
+const MAX_STORE_ATTEMPTS: usize = 8;
+
+
+#[derive(Default, PartialEq)]
+enum Stage {
+    #[default]
+    Running,
+    Terminating,
+    Done,
+}
+
+
+#[derive(Default)]
+struct State {
+    stage: Stage,
+
+    joinset: JoinSet<StoreResult>,
+
+    running_count: usize,
+    accepted_ctr: usize,
+    done_ctr: usize,
+    failed_ctr: usize,
+}
+
+
+/// Runs store requests from the returned channel against `backend`, at most
+/// `store_concurrency` at a time. Each request retries temporary failures
+/// with exponential backoff, up to `MAX_STORE_ATTEMPTS` times. `Done` fires
+/// once the channel is dropped and all requests have finished, with an error
+/// if any of them failed.
+pub async fn start(
+    backend: Arc<dyn StorageBackend>,
+    store_concurrency: usize,
+) -> (Done, StorageChannel) {
+    let store_concurrency = store_concurrency.max(1);
+    let (sender, mut rcv) = StorageChannel::new_owned(store_concurrency * 2);
+    let (done_sender, done_rcv) = oneshot::channel();
+
+    tokio::spawn(async move {
+        let mut state = State::default();
+
+        let mut last_print = Instant::now();
+        while state.stage != Stage::Done {
+            if last_print.elapsed() > Duration::from_secs(10) {
+                print_status(&state).await;
+                report_status(&state);
+                last_print = Instant::now();
+            }
+
+            if state.running_count == store_concurrency {
+                // busy
+                let res = state.joinset.join_next().await;
+                on_join(&mut state, res).await;
+            } else if state.stage == Stage::Terminating {
+                // terminating
+                let res = state.joinset.join_next().await;
+                match res {
+                    None      => { state.stage = Stage::Done; },
+                    Some(res) => { on_result(&mut state, res.unwrap()).await },
+                }
+            } else if state.running_count == 0 {
+                // idle
+                let rcv_result = rcv.recv().await;
+                on_receive(&mut state, &backend, rcv_result).await;
+            } else {
+                // have capacity
+                select! {
+                    rcv_result = rcv.recv() => {
+                        on_receive(&mut state, &backend, rcv_result).await;
+                    },
+                    res = state.joinset.join_next() => {
+                        on_join(&mut state, res).await;
+                    },
+                };
+            }
+        }
+
+        let result = if state.failed_ctr > 0 {
+            print_status(&state).await;
+            Err(StorageError::Fatal(format!(
+                "{} of {} blobs could not be stored", state.failed_ctr, state.accepted_ctr)))
+        } else {
+            Ok(())
+        };
+        report_status(&state);
+        done_sender.send(result).unwrap();
+    });
+
+    (done_rcv, sender)
+}
+
+
+async fn on_result(state: &mut State, res: StoreResult) {
+    state.running_count -= 1;
+
+    match res {
+        StoreResult::Ok => { state.done_ctr += 1; },
+        StoreResult::Fail => { state.failed_ctr += 1; },
+    }
+}
+
+async fn on_join(state: &mut State, res: Option<Result<StoreResult, JoinError>>) {
+    let res = res.expect("empty joinset").unwrap();
+    on_result(state, res).await;
+}
+
+
+async fn on_receive(state: &mut State, backend: &Arc<dyn StorageBackend>, rcv_result: Option<StoreRequest>) {
+    match rcv_result {
+        None      => {
+            state.stage = Stage::Terminating;
+        },
+        Some(req) => {
+            state.accepted_ctr += 1;
+            on_request(state, Arc::clone(backend), req).await;
+        },
+    }
+}
+
+
+async fn on_request(state: &mut State, backend: Arc<dyn StorageBackend>, req: StoreRequest) {
+    state.running_count += 1;
+
+    state.joinset.spawn(async move {
+        let (path, data) = req;
+        let mut chill_time = BASE_CHILL_TIME;
+        let mut attempt = 1;
+        loop {
+            match backend.put(&path, &data).await {
+                Ok(()) => return StoreResult::Ok,
+                Err(StorageError::Retriable(msg)) if attempt < MAX_STORE_ATTEMPTS => {
+                    println!("[Storage] store error (retrying in {:?}): {}", chill_time, msg);
+                    tokio::time::sleep(chill_time).await;
+                    chill_time = (chill_time * 2).min(MAX_CHILL_TIME);
+                    attempt += 1;
+                },
+                Err(e) => {
+                    error!("[Storage] storing {} failed after {} attempts: {}", path.to_string_lossy(), attempt, e);
+                    return StoreResult::Fail;
+                },
+            }
+        }
+    });
+}
+
+async fn print_status(state: &State) {
+    println!(
+        "[Storage] accepted: {}  in flight: {}  done: {}  failed: {}",
+        state.accepted_ctr,
+        state.running_count,
+        state.done_ctr,
+        state.failed_ctr,
+    );
+}
+
+fn report_status(state: &State) {
+    status::report(Status::Storage {
+        accepted: state.accepted_ctr,
+        in_flight: state.running_count,
+        done: state.done_ctr,
+        failed: state.failed_ctr,
+    });
+}
+
+enum StoreResult {
+    Ok,
+    Fail,
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+
+    use super::super::backend::{StorageBackend, StorageError};
+
+    /// Fails the first put with a retriable error, and the ones to `broken`
+    /// for good. Puts to `unavailable` always fail with a retriable error.
+    #[derive(Default)]
+    struct FlakyBackend {
+        broken: Option<PathBuf>,
+        unavailable: Option<PathBuf>,
+        attempts: Mutex<usize>,
+        stored: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl StorageBackend for FlakyBackend {
+        async fn put(&self, path: &Path, data: &[u8]) -> Result<(), StorageError> {
+            let attempt = {
+                let mut attempts = self.attempts.lock().unwrap();
+                *attempts += 1;
+                *attempts
+            };
+            if attempt == 1 || self.unavailable.as_deref() == Some(path) {
+                return Err(StorageError::Retriable("try again".to_string()));
+            }
+            if self.broken.as_deref() == Some(path) {
+                return Err(StorageError::Fatal("broken".to_string()));
+            }
+            self.stored.lock().unwrap().insert(path.to_path_buf(), data.to_vec());
+            Ok(())
+        }
+
+        async fn get(&self, path: &Path) -> Result<Vec<u8>, StorageError> {
+            self.stored.lock().unwrap().get(path).cloned().ok_or(StorageError::NotFound(path.to_path_buf()))
+        }
+
+        async fn exists(&self, path: &Path) -> Result<bool, StorageError> {
+            Ok(self.stored.lock().unwrap().contains_key(path))
+        }
+
+        async fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>, StorageError> {
+            Ok(self.stored.lock().unwrap().keys().filter(|p| p.starts_with(prefix)).cloned().collect())
+        }
+
+        async fn delete(&self, path: &Path) -> Result<(), StorageError> {
+            self.stored.lock().unwrap().remove(path);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn retries_temporary_failures() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let backend = Arc::new(FlakyBackend::default());
+            let (done, channel) = super::start(backend.clone(), 2).await;
+            channel.submit_blob(PathBuf::from("nodes/a"), vec![1]).await;
+            channel.submit_blob(PathBuf::from("nodes/b"), vec![2]).await;
+            drop(channel);
+            done.await.unwrap().unwrap();
+
+            assert_eq!(backend.get(Path::new("nodes/a")).await.unwrap(), vec![1]);
+            assert_eq!(backend.get(Path::new("nodes/b")).await.unwrap(), vec![2]);
+            assert_eq!(backend.list(Path::new("nodes")).await.unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn reports_failed_requests() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let backend = Arc::new(FlakyBackend { broken: Some(PathBuf::from("nodes/b")), ..Default::default() });
+            let (done, channel) = super::start(backend.clone(), 2).await;
+            channel.submit_blob(PathBuf::from("nodes/a"), vec![1]).await;
+            channel.submit_blob(PathBuf::from("nodes/b"), vec![2]).await;
+            drop(channel);
+
+            let err = done.await.unwrap().unwrap_err();
+            assert_eq!(err.to_string(), "storage error: 1 of 2 blobs could not be stored");
+            assert_eq!(backend.list(Path::new("nodes")).await.unwrap(), vec![PathBuf::from("nodes/a")]);
+        });
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().start_paused(true).build().unwrap();
+        rt.block_on(async {
+            let backend = Arc::new(FlakyBackend { unavailable: Some(PathBuf::from("nodes/b")), ..Default::default() });
+            let (done, channel) = super::start(backend.clone(), 2).await;
+            channel.submit_blob(PathBuf::from("nodes/b"), vec![2]).await;
+            channel.submit_blob(PathBuf::from("nodes/a"), vec![1]).await;
+            drop(channel);
+
+            let err = done.await.unwrap().unwrap_err();
+            assert_eq!(err.to_string(), "storage error: 1 of 2 blobs could not be stored");
+            assert_eq!(backend.list(Path::new("nodes")).await.unwrap(), vec![PathBuf::from("nodes/a")]);
+            assert_eq!(*backend.attempts.lock().unwrap(), super::MAX_STORE_ATTEMPTS + 1);
+        });
+    }
+}
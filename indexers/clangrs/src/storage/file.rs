@@ -1,46 +1,114 @@
-use std::fs::{File, create_dir_all};
-use std::io::Write;
-use std::thread;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use tokio::sync::oneshot;
+use async_trait::async_trait;
 
 use crate::args::Args;
 
-pub use super::common::{StoreRequest, StorageChannel, Done};
+use super::backend::{StorageBackend, StorageError};
 
-pub async fn start(args: Args) -> (Done, StorageChannel) {
-    let (sender, receiver) = StorageChannel::new(args.store_concurrency * 2);
 
-    let (done_sender, done_rcv) = oneshot::channel();
+/// Stores blobs as files under the output directory.
+pub struct FileBackend {
+    root: PathBuf,
+}
+
+impl FileBackend {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+pub fn open(args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    Ok(Arc::new(FileBackend::new(args.outdir.clone())))
+}
+
+fn not_found_as(path: &Path, e: std::io::Error) -> StorageError {
+    if e.kind() == ErrorKind::NotFound {
+        StorageError::NotFound(path.to_path_buf())
+    } else {
+        e.into()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for FileBackend {
+    async fn put(&self, path: &Path, data: &[u8]) -> Result<(), StorageError> {
+        let abs_path = self.root.join(path);
+        tokio::fs::create_dir_all(abs_path.parent().unwrap_or(&self.root)).await?;
+        // a killed run must not leave a truncated blob that looks stored
+        let mut part_path = abs_path.clone().into_os_string();
+        part_path.push(".part");
+        tokio::fs::write(&part_path, data).await?;
+        tokio::fs::rename(&part_path, &abs_path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, path: &Path) -> Result<Vec<u8>, StorageError> {
+        tokio::fs::read(self.root.join(path)).await.map_err(|e| not_found_as(path, e))
+    }
 
-    let join_handles: Vec<thread::JoinHandle<()>> = (0..args.store_concurrency).map(|_thread_num| {
-        let t_receiver = receiver.clone();
-        let t_args = args.clone();
-        thread::spawn(move || {
-            loop {
-                let rcv_res = { let mut rcv_hold = t_receiver.blocking_lock(); rcv_hold.blocking_recv() };
-                match rcv_res {
-                    Some(req) => do_work(&t_args, req),
-                    None => { break; },
+    async fn exists(&self, path: &Path) -> Result<bool, StorageError> {
+        Ok(tokio::fs::try_exists(self.root.join(path)).await?)
+    }
+
+    async fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>, StorageError> {
+        let mut result = Vec::new();
+        let mut dirs = vec![self.root.join(prefix)];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    dirs.push(entry.path());
+                } else {
+                    let path = entry.path();
+                    result.push(path.strip_prefix(&self.root).unwrap_or(&path).to_path_buf());
                 }
             }
-        })
-    }).collect();
-
-    thread::spawn(move || {
-        for jh in join_handles {
-            jh.join().unwrap();
         }
-        done_sender.send(()).expect("\"storage done\" receiver closed");
-    });
+        Ok(result)
+    }
 
-    (done_rcv, sender)
+    async fn delete(&self, path: &Path) -> Result<(), StorageError> {
+        tokio::fs::remove_file(self.root.join(path)).await.map_err(|e| not_found_as(path, e))
+    }
 }
 
-fn do_work(args: &Args, (path, data): StoreRequest) {
-    let abs_path = args.outdir.join(path);
-    create_dir_all(abs_path.parent().unwrap_or(&args.outdir)).unwrap();
 
-    let mut f = File::create(&abs_path).expect(&format!("creating file: {}", abs_path.to_string_lossy()));
-    f.write_all(&data).expect(&format!("writing to file: {}", abs_path.to_string_lossy()));
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use testdir::testdir;
+
+    use super::super::backend::{StorageBackend, StorageError};
+    use super::FileBackend;
+
+    #[test]
+    fn read_back_and_list() {
+        let backend = FileBackend::new(testdir!());
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            backend.put(Path::new("nodes/r/f/1"), &[1, 2]).await.unwrap();
+            backend.put(Path::new("nodes/r/f/2"), &[3]).await.unwrap();
+            backend.put(Path::new("builds/r/b"), &[4]).await.unwrap();
+
+            assert_eq!(backend.get(Path::new("nodes/r/f/1")).await.unwrap(), vec![1, 2]);
+            assert!(backend.exists(Path::new("builds/r/b")).await.unwrap());
+            assert!(!backend.exists(Path::new("builds/r/c")).await.unwrap());
+
+            let mut listed = backend.list(Path::new("nodes/r")).await.unwrap();
+            listed.sort();
+            assert_eq!(listed, vec![PathBuf::from("nodes/r/f/1"), PathBuf::from("nodes/r/f/2")]);
+            assert_eq!(backend.list(Path::new("search")).await.unwrap(), Vec::<PathBuf>::new());
+
+            backend.delete(Path::new("nodes/r/f/1")).await.unwrap();
+            assert!(matches!(backend.get(Path::new("nodes/r/f/1")).await, Err(StorageError::NotFound(_))));
+        });
+    }
 }
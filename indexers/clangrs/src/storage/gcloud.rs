@@ -1,232 +1,153 @@
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
 
+use async_trait::async_trait;
 use google_cloud_storage::client::{ClientConfig, Client};
 use google_cloud_storage::http::Error as GCSError;
 use google_cloud_storage::http::objects::Object;
+use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
+use google_cloud_storage::http::objects::download::Range;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+use google_cloud_storage::http::objects::list::ListObjectsRequest;
 use google_cloud_storage::http::objects::upload::{UploadObjectRequest, UploadType};
-use tokio::select;
-use tokio::sync::oneshot;
-use tokio::task::{JoinError, JoinSet};
+use tokio::sync::OnceCell;
 
-use crate::args::CompressionMode;
-use super::common::{StorageChannel, StoreRequest, Done};
+use crate::args::{Args, CompressionMode};
+use super::backend::{StorageBackend, StorageError};
 
 
-const BASE_CHILL_TIME: Duration = Duration::from_secs(1);
-const MAX_CHILL_TIME: Duration = Duration::from_secs(64);
-
-
-#[derive(Default, PartialEq)]
-enum Stage {
-    #[default]
-    Running,
-    Terminating,
-    Done,
-}
-
-
-#[derive(Default)]
-struct State {
-    stage: Stage,
-
-    joinset: JoinSet<StoreResult>,
-    retries: Vec<StoreRequest>,
-
-    running_count: usize,
-    accepted_ctr: usize,
-    done_ctr: usize,
-    failed_ctr: usize,
-
-    chill_time: Duration,
-}
-
-
-pub async fn start(
+/// Stores blobs as objects in a Google Cloud Storage bucket.
+pub struct CloudBackend {
     bucket_name: String,
     compression: CompressionMode,
-    store_concurrency: usize,
-) -> (Done, StorageChannel) {
-    let (sender, mut rcv) = StorageChannel::new_owned(store_concurrency * 2);
-    let (done_sender, done_rcv) = oneshot::channel();
-
-    tokio::spawn(async move {
-        let mut state = State {
-            chill_time: BASE_CHILL_TIME,
-            ..Default::default()
-        };
-
-        let actuator = Arc::new(Actuator::new(bucket_name, compression).await);
-
-        let mut last_print = Instant::now();
-        while state.stage != Stage::Done {
-            if last_print.elapsed() > Duration::from_secs(10) {
-                print_status(&state).await;
-                last_print = Instant::now();
-            }
-
-            if state.running_count == store_concurrency {
-                // busy
-                let res = state.joinset.join_next().await;
-                on_join(&mut state, res).await;
-            } else if let Some(r) = state.retries.pop() {
-                // retry
-                on_request(&mut state, Arc::clone(&actuator), r).await;
-            } else if state.stage == Stage::Terminating {
-                // terminating
-                let res = state.joinset.join_next().await;
-                match res {
-                    None      => { state.stage = Stage::Done; },
-                    Some(res) => { on_result(&mut state, res.unwrap()).await },
-                }
-            } else if state.running_count == 0 {
-                // idle
-                let rcv_result = rcv.recv().await;
-                on_receive(&mut state, &actuator, rcv_result).await;
-            } else {
-                // have capacity
-                select! {
-                    rcv_result = rcv.recv() => {
-                        on_receive(&mut state, &actuator, rcv_result).await;
-                    },
-                    res = state.joinset.join_next() => {
-                        on_join(&mut state, res).await;
-                    },
-                };
-            }
-        }
-
-        print_status(&state).await;
-        done_sender.send(()).unwrap();
-    });
-
-    (done_rcv, sender)
+    client: OnceCell<Client>,
 }
 
+pub fn open(args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    Ok(Arc::new(CloudBackend::new(args.bucket.clone(), args.compression)))
+}
 
-async fn on_result(state: &mut State, res: StoreResult) {
-    state.running_count -= 1;
+impl CloudBackend {
+    pub fn new(bucket_name: String, compression: CompressionMode) -> Self {
+        CloudBackend { bucket_name, compression, client: OnceCell::new() }
+    }
 
-    match res {
-        StoreResult::Ok => {
-            state.done_ctr += 1;
-            state.chill_time = BASE_CHILL_TIME;
-        },
-        StoreResult::Fail => { state.failed_ctr += 1; },
-        StoreResult::Retry(request) => {
-            println!("[CloudStorage] chill for {:?}", state.chill_time);
-            tokio::time::sleep(state.chill_time).await;
-            state.chill_time *= 2;
-            if state.chill_time > MAX_CHILL_TIME {
-                state.chill_time = MAX_CHILL_TIME;
-            }
-            state.retries.push(request);
-        }
+    async fn client(&self) -> Result<&Client, StorageError> {
+        self.client.get_or_try_init(|| async {
+            let config = ClientConfig { ..ClientConfig::default() }
+                .with_auth()
+                .await
+                .map_err(|e| StorageError::Fatal(format!("cloud storage auth: {:?}", e)))?;
+            Ok(Client::new(config))
+        }).await
     }
-}
 
-async fn on_join(state: &mut State, res: Option<Result<StoreResult, JoinError>>) {
-    let res = res.expect("empty joinset").unwrap();
-    on_result(state, res).await;
+    fn object_name(path: &Path) -> String {
+        path.to_str().unwrap().to_owned()
+    }
 }
 
-
-async fn on_receive(state: &mut State, actuator: &Arc<Actuator>, rcv_result: Option<StoreRequest>) {
-    match rcv_result {
-        None      => {
-            state.stage = Stage::Terminating;
-        },
-        Some(req) => {
-            state.accepted_ctr += 1;
-            on_request(state, Arc::clone(&actuator), req).await;
+fn storage_error(path: &Path, e: GCSError) -> StorageError {
+    match e {
+        GCSError::Response(resp) if resp.code == 404 => StorageError::NotFound(path.to_path_buf()),
+        GCSError::Response(resp) if resp.is_retriable() => {
+            if resp.code == 429 {
+                StorageError::Retriable("rate limited".to_string())
+            } else {
+                StorageError::Retriable(format!("{:?}", resp))
+            }
         },
+        e => StorageError::Fatal(format!("{:?}", e)),
     }
 }
 
-
-async fn on_request(state: &mut State, actuator: Arc<Actuator>, req: StoreRequest) {
-    state.running_count += 1;
-
-    let actuator = Arc::clone(&actuator);
-    state.joinset.spawn(async move {
-        actuator.store(req).await
-    });
-}
-
-async fn print_status(state: &State) {
-    println!(
-        "[CloudStorage] accepted: {}  in flight: {}  done: {}  failed: {}",
-        state.accepted_ctr,
-        state.running_count,
-        state.done_ctr,
-        state.failed_ctr,
-    );
-}
-
-enum StoreResult {
-    Ok,
-    Fail,
-    Retry(StoreRequest),
-}
-
-
-struct Actuator {
-    bucket_name: String,
-    compression: CompressionMode,
-    client: Client,
-}
-
-impl Actuator {
-    async fn new(bucket_name: String, compression: CompressionMode) -> Self {
-        let config = ClientConfig { ..ClientConfig::default() }
-            .with_auth()
-            .await.unwrap();
-        let client = Client::new(config);
-        Actuator { bucket_name, compression, client }
-    }
-
-    async fn store(&self, (path, data): StoreRequest) -> StoreResult {
-        let path_str = path.to_str().unwrap().to_owned();
+#[async_trait]
+impl StorageBackend for CloudBackend {
+    async fn put(&self, path: &Path, data: &[u8]) -> Result<(), StorageError> {
         let content_encoding = match self.compression {
             CompressionMode::None => None,
             CompressionMode::Gzip => Some("gzip".to_string()),
         };
 
         let upload_type = UploadType::Multipart(Box::new(Object {
-            name: path_str,
+            name: Self::object_name(path),
             bucket: self.bucket_name.clone(),
             content_encoding,
             ..Default::default()
         }));
-        let result = self.client
+        self.client().await?
             .upload_object(
                 &UploadObjectRequest {
                     bucket: self.bucket_name.to_owned(),
                     ..Default::default()
                 },
-                data.clone(),
+                data.to_vec(),
                 &upload_type)
+            .await
+            .map_err(|e| storage_error(path, e))?;
+        Ok(())
+    }
+
+    async fn get(&self, path: &Path) -> Result<Vec<u8>, StorageError> {
+        self.client().await?
+            .download_object(
+                &GetObjectRequest {
+                    bucket: self.bucket_name.clone(),
+                    object: Self::object_name(path),
+                    ..Default::default()
+                },
+                &Range::default())
+            .await
+            .map_err(|e| storage_error(path, e))
+    }
+
+    async fn exists(&self, path: &Path) -> Result<bool, StorageError> {
+        let res = self.client().await?
+            .get_object(&GetObjectRequest {
+                bucket: self.bucket_name.clone(),
+                object: Self::object_name(path),
+                ..Default::default()
+            })
             .await;
+        match res.map_err(|e| storage_error(path, e)) {
+            Ok(_) => Ok(true),
+            Err(StorageError::NotFound(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
 
-        match result {
-            Ok(_) => {
-                return StoreResult::Ok;
-            }
-            Err(e) => {
-                match e {
-                    GCSError::Response(resp) if resp.is_retriable() => {
-                        if resp.code != 429 {
-                            println!("[CloudStorage] upload error (retrying): {:?}", resp);
-                        }
-                        return StoreResult::Retry((path, data));
-                    }
-                    _ => {
-                        println!("[CloudStorage] upload failed: {:?}", e);
-                        return StoreResult::Fail;
-                    }
-                }
+    async fn list(&self, prefix: &Path) -> Result<Vec<PathBuf>, StorageError> {
+        let client = self.client().await?;
+        let mut result = Vec::new();
+        let mut page_token = None;
+        loop {
+            let resp = client
+                .list_objects(&ListObjectsRequest {
+                    bucket: self.bucket_name.clone(),
+                    prefix: Some(Self::object_name(prefix)),
+                    page_token,
+                    ..Default::default()
+                })
+                .await
+                .map_err(|e| storage_error(prefix, e))?;
+            result.extend(resp.items.unwrap_or_default().into_iter().map(|o| PathBuf::from(o.name)));
+            page_token = resp.next_page_token;
+            if page_token.is_none() {
+                break;
             }
-        };
+        }
+        Ok(result)
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), StorageError> {
+        self.client().await?
+            .delete_object(&DeleteObjectRequest {
+                bucket: self.bucket_name.clone(),
+                object: Self::object_name(path),
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| storage_error(path, e))
     }
 }
 
@@ -257,16 +178,16 @@ mod test {
 
         let rt = tokio::runtime::Runtime::new().unwrap();
         let result = rt.block_on(async {
-            let (done, channel) = super::start(
+            let backend = std::sync::Arc::new(super::CloudBackend::new(
                 "territory-index-scrap".to_string(),
-                CompressionMode::None,
-                1).await;
+                CompressionMode::None));
+            let (done, channel) = crate::storage::driver::start(backend, 1).await;
 
             let data: Vec<u8> = vec![1, 2, 3, 4, 5];
             channel.submit_blob(PathBuf::from("test").join(&rand_string), data).await;
 
             drop(channel);
-            done.await.unwrap();
+            done.await.unwrap().unwrap();
 
 
             println!("checking result");
@@ -1,32 +1,79 @@
 mod common;
+pub mod backend;
+pub mod driver;
 #[cfg(feature = "gcloud")]
 pub mod gcloud;
 pub mod file;
 pub mod mem;
 pub mod null;
+#[cfg(feature = "s3")]
+pub mod s3;
 
-use crate::args::{Args, StorageMode};
+use std::sync::Arc;
 
+use crate::args::Args;
+
+pub use backend::{StorageBackend, StorageError};
 pub use common::{StoreRequest, StorageChannel, Done};
 pub use mem::MemStorage;
 
 
+pub type OpenBackend = fn(&Args) -> Result<Arc<dyn StorageBackend>, StorageError>;
+
+/// Backends selectable with `--storage-mode`.
+pub const BACKENDS: &[(&str, OpenBackend)] = &[
+    ("none", null::open),
+    ("file", file::open),
+    ("cloud", open_cloud),
+    ("s3", open_s3),
+];
+
+
 #[cfg(feature = "gcloud")]
-pub async fn init_cloud_storage(args: &Args) -> (Done, StorageChannel) {
-    gcloud::start(args.bucket.clone(), args.compression, args.store_concurrency).await
+fn open_cloud(args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    gcloud::open(args)
 }
 
 
 #[cfg(not(feature = "gcloud"))]
-pub async fn init_cloud_storage(_args: &Args) -> (Done, StorageChannel) {
-    unimplemented!("cloud storage feature not enabled");
+fn open_cloud(_args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    Err(StorageError::Fatal("cloud storage feature not enabled".to_string()))
 }
 
 
-pub async fn start_from_args(args: &Args) -> (Done, StorageChannel) {
-    match args.storage_mode {
-        StorageMode::None  => null::start().await,
-        StorageMode::File  => file::start(args.clone()).await,
-        StorageMode::Cloud => init_cloud_storage(args).await,
+#[cfg(feature = "s3")]
+fn open_s3(args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    s3::open(args)
+}
+
+
+#[cfg(not(feature = "s3"))]
+fn open_s3(_args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    Err(StorageError::Fatal("s3 storage feature not enabled".to_string()))
+}
+
+
+/// `--storage-mode` value parser.
+pub fn parse_backend_name(name: &str) -> Result<String, String> {
+    if BACKENDS.iter().any(|(n, _)| *n == name) {
+        Ok(name.to_string())
+    } else {
+        let names: Vec<_> = BACKENDS.iter().map(|(n, _)| *n).collect();
+        Err(format!("unknown storage backend, expected one of: {}", names.join(", ")))
     }
 }
+
+
+pub fn backend_from_args(args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    let (_, open) = BACKENDS
+        .iter()
+        .find(|(name, _)| *name == args.storage_mode)
+        .ok_or_else(|| StorageError::Fatal(format!("unknown storage backend: {}", args.storage_mode)))?;
+    open(args)
+}
+
+
+pub async fn start_from_args(args: &Args) -> (Done, StorageChannel) {
+    let backend = backend_from_args(args).expect("failed to open storage");
+    driver::start(backend, args.store_concurrency).await
+}
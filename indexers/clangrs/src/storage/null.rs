@@ -1,16 +1,39 @@
-use tokio::sync::oneshot;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use super::common::{Done, StorageChannel};
+use async_trait::async_trait;
 
+use crate::args::Args;
 
-pub async fn start() -> (Done, StorageChannel) {
-    let (sender, mut rcv) = StorageChannel::new_owned(2);
-    let (done_sender, done_rcv) = oneshot::channel();
+use super::backend::{StorageBackend, StorageError};
 
-    tokio::spawn(async move {
-        while let Some(_) = rcv.recv().await { }
-        done_sender.send(()).unwrap();
-    });
 
-    (done_rcv, sender)
+/// Discards everything written to it.
+pub struct NullBackend;
+
+pub fn open(_args: &Args) -> Result<Arc<dyn StorageBackend>, StorageError> {
+    Ok(Arc::new(NullBackend))
+}
+
+#[async_trait]
+impl StorageBackend for NullBackend {
+    async fn put(&self, _path: &Path, _data: &[u8]) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn get(&self, path: &Path) -> Result<Vec<u8>, StorageError> {
+        Err(StorageError::NotFound(path.to_path_buf()))
+    }
+
+    async fn exists(&self, _path: &Path) -> Result<bool, StorageError> {
+        Ok(false)
+    }
+
+    async fn list(&self, _prefix: &Path) -> Result<Vec<PathBuf>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    async fn delete(&self, path: &Path) -> Result<(), StorageError> {
+        Err(StorageError::NotFound(path.to_path_buf()))
+    }
 }
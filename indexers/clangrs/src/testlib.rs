@@ -7,7 +7,6 @@ use std::io::{Read, Write};
 use std::path::{PathBuf, Path};
 use std::env::var;
 use std::sync::{Arc, Mutex};
-use std::thread::JoinHandle;
 
 use rusqlite::Connection;
 use prost::Message;
@@ -18,9 +17,9 @@ use rand::random;
 
 use territory_core::pblib::decode_many;
 use territory_core::resolver::{BasicResolver, ConcreteLocation, NeedData, ResolutionFailure, Resolver, TrieResolver};
-use territory_core::territory::index::{Node, References, Build, IndexItem, IndexItemKind};
-use territory_core::{pb_node_tokens, GenHref, IntoGenHref, ReferencesLink, Token};
-use crate::args::{Args, CompressionMode};
+use territory_core::territory::index::{Node, References, Build, IndexItem, IndexItemKind, MacroExpansion, TypeHierarchy};
+use territory_core::{pb_node_tokens, GenHref, IntoGenHref, ReferencesLink, Token, TokenLocation};
+use crate::args::{Args, CompressionMode, ScannerMode};
 use crate::intermediate_model::sqlite;
 
 lazy_static! {
@@ -44,24 +43,6 @@ pub struct InspectResult {
     pub conn: Arc<Mutex<Connection>>,
 }
 
-pub fn run_scanner_thread(repo_path: PathBuf, socket_path: PathBuf) -> JoinHandle<()> {
-    std::thread::spawn(|| {
-        let scanner_args = cscanner::Args {
-            compile_commands_dir: repo_path.clone(),
-            repo_path,
-            sock: socket_path,
-            chroot: None,
-            setuid: None,
-            setgid: None,
-            socket_timeout: 30,
-            dump_ccs: false,
-        };
-        let conn = cscanner::connect(&scanner_args).unwrap();
-        cscanner::scanner_loop(&conn, &scanner_args).unwrap();
-    })
-}
-
-
 pub fn init_logging() {
     let _ = simplelog::TestLogger::init(
         simplelog::LevelFilter::max(),
@@ -75,13 +56,8 @@ pub fn inspect_repo(args: &Args) -> InspectResult {
     let mut stores_with_writer = sqlite::new_from_args(args);
     stores_with_writer.create_tables();
 
-    let ta = args.clone();
-    let scanner_thread = run_scanner_thread(ta.repo, ta.scanner_socket_path);
-
     crate::parse_stage::parse_stage_with_stores(args, &mut stores_with_writer);
 
-    scanner_thread.join().unwrap();
-
     let mut stores_with_reader = sqlite::new_with_conn(stores_with_writer.conn);
     crate::uses_stage::uses_stage_with_store(args, &mut stores_with_reader);
 
@@ -95,6 +71,10 @@ pub fn inspect_repo(args: &Args) -> InspectResult {
         crate::serial_stage::serial_stage_with_stores(args, stores_with_uses).await;
     });
 
+    if let Some(archive_path) = &args.archive {
+        crate::archive::pack_from_args(args, archive_path).unwrap();
+    }
+
     InspectResult { conn }
 }
 
@@ -135,13 +115,28 @@ impl<'a> GraphWalker {
         let refmap = territory_core::slicemap_trie::SlicemapReader::new(
             build.references_trie_root.unwrap(),
             territory_core::slicemap_trie::SharedCache::new_handle(&trie_cache, "test_repo/refs"));
-        let trie_resolver = TrieResolver::new(
+        let mut trie_resolver = TrieResolver::new(
             BasicResolver,
             nodemap,
             symmap,
             refmap,
             build.repo_root_node_id,
         );
+        if let Some(root) = build.type_hierarchy_trie_root {
+            trie_resolver = trie_resolver.with_type_hierarchy(territory_core::slicemap_trie::SlicemapReader::new(
+                root,
+                territory_core::slicemap_trie::SharedCache::new_handle(&trie_cache, "test_repo/types")));
+        }
+        if let Some(root) = build.macro_expansion_trie_root {
+            trie_resolver = trie_resolver.with_macro_expansions(territory_core::slicemap_trie::SlicemapReader::new(
+                root,
+                territory_core::slicemap_trie::SharedCache::new_handle(&trie_cache, "test_repo/macros")));
+        }
+        if let Some(root) = build.text_index_trie_root {
+            trie_resolver = trie_resolver.with_text_index(territory_core::slicemap_trie::SlicemapReader::new(
+                root,
+                territory_core::slicemap_trie::SharedCache::new_handle(&trie_cache, "test_repo/text")));
+        }
 
         let mut gw = GraphWalker { index_path, current_node: Node::default(), history: vec![], resolver: trie_resolver };
         gw.go_to_node(gw.root_ref());
@@ -225,6 +220,16 @@ impl<'a> GraphWalker {
         }
     }
 
+    pub fn macro_expansion(&mut self, text: &str) -> MacroExpansion {
+        let tok = self.find_token(text).expect(&format!("token not found: {:?}", text));
+        assert!(tok.context.has_macro_expansion, "token {:?} is not a macro invocation", text);
+        self.load(GenHref::MacroExpansion(TokenLocation { node_id: self.current_node.id, offset: tok.offset }))
+    }
+
+    pub fn type_hierarchy(&mut self) -> TypeHierarchy {
+        self.load(GenHref::TypeHierarchy(self.current_node.id))
+    }
+
     pub fn node(&'a self) -> &'a Node {
         &self.current_node
     }
@@ -330,7 +335,7 @@ pub fn defaut_args() -> Args {
     let temp_dir_ = testdir!();
     Args {
         repo: TERRITORY_ROOT.join("repos/example"),
-        storage_mode: crate::args::StorageMode::File,
+        storage_mode: "file".to_string(),
         outdir: temp_dir_.join("output"),
         compression: CompressionMode::None,
         writer_concurrency: 1,
@@ -348,14 +353,30 @@ pub fn defaut_args() -> Args {
         slice: 1,
         stage: None,
         no_references: false,
+        no_text_index: false,
+        search_shard_items: 20000,
         clang_extra_args: Some(vec![format!("-resource-dir={resource_dir}")]),
         fatal_missing_spans: false,
+        scanners: ScannerMode::Thread,
         scanner_socket_path: Path::new("/tmp").join(format!("clangrs-scanner-{}.sock", random::<u64>())),
         scanner_socket_timeout: 5,
+        scanner_restarts: 0,
+        scanner_idle_timeout: 900,
+        scanner_tu_failures: 10,
+        tu_retries: 1,
+        max_quarantined_percent: 5,
         log_dir: None,
         index_system: true,
         uim_input: None,
         max_node_len: 100_000,
+        changed_files: None,
+        git_diff: None,
+        resume: false,
+        watch: false,
+        watch_debounce_ms: 500,
+        scanner_bin: PathBuf::from("cscanner"),
+        compile_commands_dir: None,
+        archive: None,
     }
 }
 
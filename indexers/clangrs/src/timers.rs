@@ -1,5 +1,5 @@
 use std::future::Future;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time::{Duration, Instant};
 
 use log::info;
@@ -39,6 +39,14 @@ impl Timers {
         res
     }
 
+    /// Seconds spent under each label, for the run report.
+    pub fn seconds(&self) -> BTreeMap<String, f64> {
+        self.counts
+            .iter()
+            .map(|(label, elapsed)| (label.clone(), elapsed.as_secs_f64()))
+            .collect()
+    }
+
     pub fn dump(&self) {
         info!("{:#?}", self);
 
@@ -9,7 +9,7 @@ use crate::args::{get_debug_cfg, Args};
 use crate::filetree::FileTree;
 use crate::intermediate_model::{UsesMap, UsesMapQuery};
 use crate::{buildroot, storage};
-use crate::intermediate_model::sqlite::{self, Paths, SpanStore};
+use crate::intermediate_model::sqlite::{self, Paths, SpanStore, TypeHierarchyMap};
 use crate::writer::{append_pb_node, InvertedIndexWriter, NodeWriter};
 
 
@@ -33,6 +33,7 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
     }
     let nodes_uim_path = uim_dir.join("nodes.uim");
     let search_uim_path = uim_dir.join("search.uim");
+    let types_uim_path = uim_dir.join("types.uim");
 
     std::fs::create_dir_all(&args.intermediate_path).unwrap();
     std::fs::create_dir_all(&args.outdir).unwrap();
@@ -50,7 +51,7 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
     let mut index_pass_out = Vec::new();
 
     let (storage_done, storage_channel) = storage::start_from_args(&args).await;
-    let mut inverted_index_writer = InvertedIndexWriter::start(&args, storage_channel.clone());
+    let mut inverted_index_writer = InvertedIndexWriter::start(&args, storage_channel.clone(), store.output_map.clone());
 
     // preprocessing and IDs
     pblib::decode_loop(&buf, &mut |mut n: pb::Node, _, _| {
@@ -134,6 +135,7 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
                                         use_location: uim_location.clone().into(),
                                         use_path: to_relpath(&args, &n.path),
                                         linked_via_sym: false,
+                                        kind: pb::ReferenceKind::RkUnknown,
                                     });
                             }
                         }
@@ -156,6 +158,10 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
         n.uim_reference_context = None;
         n.uim_nest_level = None;
     }
+    if types_uim_path.exists() {
+        process_uim_type_relations(
+            &args, file_tree.paths, &store.span_store, &types_uim_path, &store.type_hierarchy);
+    }
 
     {
         let mut node_writer = NodeWriter::start(
@@ -188,6 +194,7 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
         }
     }
     store.uses_map.write(&mut node_writer);
+    store.type_hierarchy.write(&mut node_writer);
     node_writer.join();
 
     // output stage
@@ -196,7 +203,7 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
             let mut l = stdout().lock();
             territory_core::pretty_print::node(&mut l, &n).unwrap();
         }
-        append_pb_node(&mut blob_id, &mut out, n, &store.output_map, args.compression);
+        append_pb_node(&mut blob_id, &mut out, n, &store.output_map, args.compression, !args.no_text_index);
     }
 
 
@@ -206,9 +213,20 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
         storage_channel.clone(),
         store.output_map.clone());
 
+    process_uim_search_index(
+        &args, &store.paths, &store.span_store, &search_uim_path, &mut inverted_index_writer).await;
+    let search_shards = inverted_index_writer.join().await;
+
     buildroot::write_slicemap_tries(
         &args.repo_id, &args.build_id, args.compression,
-        &mut node_writer, &store.output_map, &store.paths,
+        &mut node_writer,
+        buildroot::TrieInputs {
+            output_map: &store.output_map,
+            paths: &store.paths,
+            tu_includes: &store.tu_includes,
+            search_shards,
+            text_index: !args.no_text_index,
+        },
         storage_channel.clone()
     ).await;
     match blob_id {
@@ -224,13 +242,9 @@ pub async fn index_uim(args: Args, uim_dir: &Path) {
         }
     }
 
-    process_uim_search_index(
-        &args, &store.paths, &store.span_store, &search_uim_path, &mut inverted_index_writer).await;
-    inverted_index_writer.join().await;
-
     drop(storage_channel);
     node_writer.join();
-    storage_done.await.unwrap();
+    storage_done.await.unwrap().expect("failed to store blobs");
 
 }
 
@@ -265,6 +279,41 @@ async fn process_uim_search_index(
 }
 
 
+fn process_uim_type_relations(
+    args: &Args,
+    paths: &Paths,
+    span_store: &SpanStore,
+    types_uim_path: &Path,
+    type_hierarchy: &TypeHierarchyMap,
+) {
+    let mut buf = Vec::new();
+    File::open(types_uim_path).unwrap().read_to_end(&mut buf).unwrap();
+
+    let resolve = |href: &UniHref| resolve_uni_href(args, paths, span_store, href)
+        .map(|node_id| TokenLocation { node_id, offset: href.offset });
+
+    pblib::decode_loop(&buf, &mut |rel: pb::UimTypeRelation, _, _| {
+        let subtype = match rel.subtype.as_ref().map(resolve) {
+            Some(Ok(loc)) => loc,
+            Some(Err(e)) => {
+                println!("failed to resolve subtype {}: {}", rel.subtype_name, e);
+                return;
+            }
+            None => return,
+        };
+        let supertype = match rel.supertype.as_ref().map(resolve) {
+            Some(Ok(loc)) => Some(loc),
+            Some(Err(e)) => {
+                println!("failed to resolve supertype {}: {}", rel.supertype_name, e);
+                None
+            }
+            None => None,
+        };
+        type_hierarchy.record(subtype, &rel.subtype_name, supertype, &rel.supertype_name);
+    }).unwrap();
+}
+
+
 fn resolve_uni_href(
     args: &Args,
     paths: &Paths,
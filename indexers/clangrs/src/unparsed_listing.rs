@@ -105,6 +105,7 @@ fn write_unparsed(
                     href: None,
                     sym_id: None,
                     references: territory_core::ReferencesLink::None,
+                    has_macro_expansion: false,
                 },
                 line: 0,
                 offset: 0,
@@ -2,14 +2,16 @@ use itertools::Itertools;
 use log::info;
 
 use cscanner::ast::ClangCurKind;
+use territory_core::territory::index::ReferenceKind;
 use territory_core::{
+    Override,
     Ref,
     TokenLocation,
     TokenKind,
     GToken, nice_location,
 };
 
-use crate::intermediate_model::sqlite::{SqliteServices, SqliteGSMReader, SqliteUMWriter};
+use crate::intermediate_model::sqlite::{SqliteServices, SqliteGSMReader, SqliteUMWriter, TypeHierarchyMap};
 use crate::writer::IntermediateNodeFileReader;
 use crate::args::Args;
 use crate::intermediate_model::{
@@ -21,12 +23,16 @@ use crate::intermediate_model::{
 };
 
 
+const ASSIGNMENT_OPERATORS: &[&str] = &["=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>="];
+
+
 pub fn make_reference_to_location(
     node: &SemNode,
-    tok: &GToken<SemTokenContext>,
+    tok_index: usize,
     via_usr: bool,
 ) -> Ref {
     let use_node_id = node.id;
+    let tok = &node.text[tok_index];
 
     Ref {
         href: use_node_id,
@@ -34,6 +40,61 @@ pub fn make_reference_to_location(
         use_location: tok.context.loc,
         linked_via_sym: via_usr,
         use_path: node.context.path.clone(),
+        kind: reference_kind(node, tok_index),
+    }
+}
+
+
+/// Tells how the token at `tok_index` uses the symbol it refers to. The
+/// cursor kind covers type uses, macros and includes; for expressions the
+/// neighbouring tokens decide, as libclang doesn't expose operator kinds.
+fn reference_kind(node: &SemNode, tok_index: usize) -> ReferenceKind {
+    use ClangCurKind::*;
+
+    let Some(sem) = &node.text[tok_index].context.sem else {
+        return ReferenceKind::RkUnknown;
+    };
+    match sem.kind {
+        TypeRef | TemplateRef => return ReferenceKind::RkTypeUse,
+        MacroExpansion => return ReferenceKind::RkMacro,
+        InclusionDirective => return ReferenceKind::RkInclude,
+        DeclRefExpr | MemberRefExpr | MemberRef => {},
+        _ => return ReferenceKind::RkUnknown,
+    }
+
+    let is_code = |tok: &&GToken<SemTokenContext>| ![TokenKind::WS, TokenKind::Comment].contains(&tok.type_);
+    let mut before = node.text[..tok_index].iter().rev().filter(is_code);
+    let mut after = node.text[tok_index + 1..].iter().filter(is_code);
+    fn text(tok: Option<&GToken<SemTokenContext>>) -> Option<&str> {
+        tok.map(|tok| tok.text.as_str())
+    }
+    let next = text(after.next());
+
+    // the object of a member access (`a` in `a.b = 1`) is only read
+    if matches!(next, Some("." | "->")) {
+        return ReferenceKind::RkRead;
+    }
+    if next == Some("(") {
+        return ReferenceKind::RkCall;
+    }
+    if next.is_some_and(|t| ASSIGNMENT_OPERATORS.contains(&t)) || matches!(next, Some("++" | "--")) {
+        return ReferenceKind::RkWrite;
+    }
+
+    // skip to the start of a member access chain: `&a.b->c` takes the address of `c`
+    let mut prev = before.next();
+    let mut prev_prev = before.next();
+    while matches!(text(prev), Some("." | "->")) && prev_prev.is_some_and(|tok| tok.type_ == TokenKind::Identifier) {
+        prev = before.next();
+        prev_prev = before.next();
+    }
+    match text(prev) {
+        Some("++" | "--") => ReferenceKind::RkWrite,
+        // `a & b` is a bitwise and, `(&b)`, `= &b` or `return &b` take the address
+        Some("&") if !prev_prev.is_some_and(|tok| {
+            [TokenKind::Identifier, TokenKind::Literal].contains(&tok.type_) || [")", "]"].contains(&tok.text.as_str())
+        }) => ReferenceKind::RkAddressOf,
+        _ => ReferenceKind::RkRead,
     }
 }
 
@@ -54,11 +115,11 @@ fn ref_context(
 
 fn make_reference(
     node: &SemNode,
-    tok: &GToken<SemTokenContext>,
+    tok_index: usize,
 ) -> Option<(TokenLocation, Ref)> {
-    if let Some(defn_token_location) = tok.context.local_definition {
+    if let Some(defn_token_location) = node.text[tok_index].context.local_definition {
         if defn_token_location.node_id != node.id {
-            let ref_ = make_reference_to_location(node, tok, false);
+            let ref_ = make_reference_to_location(node, tok_index, false);
             Some((defn_token_location, ref_))
         } else {
             None
@@ -70,9 +131,9 @@ fn make_reference(
 
 
 fn collect_intra_tu_uses(uses: &mut impl UsesMap, node: &SemNode) {
-    for tok in &node.text {
+    for (i, tok) in node.text.iter().enumerate() {
         if tok.type_ != TokenKind::Identifier { continue; }
-        if let Some((defn_id, ref_)) = make_reference(node, tok) {
+        if let Some((defn_id, ref_)) = make_reference(node, i) {
             uses.record_use(defn_id, ref_)
         }
     }
@@ -84,7 +145,7 @@ pub fn collect_cross_tu_uses(
     uses: &mut impl UsesMap,
     b: &SemNode,
 ) {
-    for tok in &b.text {
+    for (i, tok) in b.text.iter().enumerate() {
         if let Some(sem) = &tok.context.sem {
 
             if let (ClangCurKind::DeclRefExpr, None, Some(name)) = (sem.kind, tok.context.local_definition, &sem.name) {
@@ -93,7 +154,7 @@ pub fn collect_cross_tu_uses(
                         ext_location @ TokenLocation{ node_id: ext_node_id, offset: _ext_offset }
                     ) = global_defs.get(usr) {
                         if ext_node_id != b.id {
-                            let ref_ = make_reference_to_location(b, tok, true);
+                            let ref_ = make_reference_to_location(b, i, true);
                             uses.record_use(ext_location, ref_);
                         }
                     } else {
@@ -108,6 +169,58 @@ pub fn collect_cross_tu_uses(
 }
 
 
+/// Links the virtual method defined in `node` with the methods it
+/// overrides. Overridden methods are linked to their definition when there
+/// is one, and to their declaration otherwise (pure virtual methods).
+pub fn collect_overrides(
+    global_defs: &impl GlobalSymbolMapReader,
+    uses: &mut impl UsesMap,
+    node: &SemNode,
+) {
+    if node.context.overrides.is_empty() { return; }
+    let Some(sem) = &node.context.sem else { return; };
+    let Some(location) = sem.usr.as_ref().and_then(|usr| global_defs.get(usr)) else {
+        info!("missing method definition: {:?} at {}", sem.usr, nice_location(&node.path, &node.start));
+        return;
+    };
+    let method = Override { location, name: sem.definition_context.iter().rev().join("::") };
+
+    for ovr in &node.context.overrides {
+        let base_location = ovr.method.usr.as_ref()
+            .and_then(|usr| global_defs.get(usr))
+            .or(ovr.declaration);
+        let Some(location) = base_location else {
+            info!("missing overridden method: {} at {}", ovr.method.name, nice_location(&node.path, &node.start));
+            continue;
+        };
+        uses.record_override(method.clone(), Override { location, name: ovr.method.name.clone() });
+    }
+}
+
+
+/// Records the direct base classes of the class defined in `node`. Bases
+/// without a definition in the index are kept by name only.
+pub fn collect_type_hierarchy(
+    global_defs: &impl GlobalSymbolMapReader,
+    type_hierarchy: &TypeHierarchyMap,
+    node: &SemNode,
+) {
+    if node.context.bases.is_empty() { return; }
+    let Some(sem) = &node.context.sem else { return; };
+    let Some(usr) = &sem.usr else { return; };
+    let Some(subtype) = global_defs.get(usr) else {
+        info!("missing class definition: {} at {}", usr, nice_location(&node.path, &node.start));
+        return;
+    };
+    let subtype_name = sem.display_name.as_ref().or(sem.name.as_ref()).cloned().unwrap_or_default();
+
+    for base in &node.context.bases {
+        let supertype = base.usr.as_ref().and_then(|usr| global_defs.get(usr));
+        type_hierarchy.record(subtype, &subtype_name, supertype, &base.name);
+    }
+}
+
+
 pub fn uses_stage(args: &Args) {
     let mut stores = sqlite::new_from_args(args);
     uses_stage_with_store(args, &mut stores)
@@ -117,11 +230,11 @@ pub fn uses_stage_with_store(args: &Args, stores: &mut SqliteServices<SqliteGSMR
     let SqliteServices {
         global_symbol_map: ref mut global_defs,
         ref mut uses_map,
+        ref type_hierarchy,
         ..
     } = stores;
 
-    if args.no_references { return; }
-
+    let references_enabled = !args.no_references;
     let mut node_file_reader = IntermediateNodeFileReader::new_with_slice(args, 1);
 
     let (sender, recv) = crossbeam_channel::bounded(args.par);
@@ -130,11 +243,16 @@ pub fn uses_stage_with_store(args: &Args, stores: &mut SqliteServices<SqliteGSMR
         let ch = recv.clone();
         let mut uses_map = uses_map.clone();
         let mut global_defs = global_defs.clone();
+        let type_hierarchy = type_hierarchy.clone();
 
         let t  = std::thread::spawn(move || {
             while let Ok(n) = ch.recv() {
-                collect_intra_tu_uses(&mut uses_map, &n);
-                collect_cross_tu_uses(&mut global_defs, &mut uses_map, &n);
+                if references_enabled {
+                    collect_intra_tu_uses(&mut uses_map, &n);
+                    collect_cross_tu_uses(&mut global_defs, &mut uses_map, &n);
+                    collect_overrides(&global_defs, &mut uses_map, &n);
+                }
+                collect_type_hierarchy(&global_defs, &type_hierarchy, &n);
             }
         });
         threads.push(t);
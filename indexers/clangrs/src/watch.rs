@@ -0,0 +1,349 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use log::{info, warn};
+use prost::Message;
+
+use territory_core::territory::index::Build;
+use territory_core::{AbsolutePath, RelativePath};
+
+use crate::archive::build_blobs;
+use crate::args::Args;
+use crate::index_reader::read_file;
+
+
+/// Builds of previous cycles kept on disk for readers that opened them
+/// before the swap. Blobs only older builds use are removed.
+const KEEP_PUBLISHED: usize = 2;
+
+
+/// Drives `--watch`: collects changes in the repo and publishes the build of
+/// each cycle under the build ID given on the command line.
+///
+/// Every cycle is indexed into its own build, `<build_id>.<generation>`, so
+/// that blobs and the search index of the published build are never written
+/// to while being read. Once a cycle is done, the `Build` root is copied over
+/// `builds/<repo_id>/<build_id>` with a rename and the search index directory
+/// is swapped the same way through a symlink.
+pub struct RepoWatcher {
+    args: Args,
+    repo: PathBuf,
+    ignored: Vec<PathBuf>,
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    generation: usize,
+    published: VecDeque<String>,
+}
+
+impl RepoWatcher {
+    pub fn new(args: &Args) -> Result<Self, Box<dyn Error>> {
+        if args.storage_mode != "file" {
+            return Err("--watch requires file storage".into());
+        }
+        let repo = args.repo.canonicalize()?;
+        let ignored = [Some(&args.outdir), Some(&args.intermediate_path), args.log_dir.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|p| {
+                std::fs::create_dir_all(p)?;
+                p.canonicalize()
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut watcher = Self {
+            args: args.clone(),
+            repo: repo.clone(),
+            ignored,
+            inotify: Inotify::init()?,
+            dirs: HashMap::new(),
+            generation: 0,
+            published: VecDeque::new(),
+        };
+        watcher.watch_tree(&repo, &mut HashSet::new())?;
+        info!("watching {} directories under {:?}", watcher.dirs.len(), repo);
+        Ok(watcher)
+    }
+
+    /// Arguments of the next cycle. `changed` is `None` for a full run.
+    pub fn next_cycle(&mut self, changed: Option<&HashSet<RelativePath>>) -> Result<Args, Box<dyn Error>> {
+        self.generation += 1;
+        let mut args = self.args.clone();
+        args.build_id = format!("{}.{}", self.args.build_id, self.generation);
+        args.git_diff = None;
+        args.changed_files = match changed {
+            Some(changed) => {
+                let list_path = self.args.intermediate_path.join("watch-changed-files");
+                std::fs::create_dir_all(&self.args.intermediate_path)?;
+                let listing: Vec<String> = changed.iter().map(|p| p.to_string()).collect();
+                std::fs::write(&list_path, listing.join("\n"))?;
+                Some(list_path)
+            },
+            None => None,
+        };
+        Ok(args)
+    }
+
+    /// Makes the build of `cycle` the one served under the watched build ID.
+    pub fn publish(&mut self, cycle: &Args) -> Result<(), Box<dyn Error>> {
+        let base_id = &self.args.build_id;
+
+        let builds_dir = self.args.outdir.join("builds").join(&self.args.repo_id);
+        let tmp_build = builds_dir.join(format!(".{}.tmp", base_id));
+        std::fs::copy(builds_dir.join(&cycle.build_id), &tmp_build)?;
+        std::fs::rename(&tmp_build, builds_dir.join(base_id))?;
+
+        let search_dir = self.args.outdir.join("search").join(&self.args.repo_id);
+        let link = search_dir.join(base_id);
+        if link.is_dir() && !link.is_symlink() {
+            warn!("replacing search index directory {:?} with a link to watched builds", link);
+            std::fs::remove_dir_all(&link)?;
+        }
+        let tmp_link = search_dir.join(format!(".{}.tmp", base_id));
+        let _ = std::fs::remove_file(&tmp_link);
+        symlink(&cycle.build_id, &tmp_link)?;
+        std::fs::rename(&tmp_link, &link)?;
+
+        info!("published build {} as {}", cycle.build_id, base_id);
+
+        self.published.push_back(cycle.build_id.clone());
+        if self.published.len() <= KEEP_PUBLISHED {
+            return Ok(());
+        }
+        let blobs_dir = self.args.outdir.join("nodes").join(&self.args.repo_id);
+        let mut kept = BTreeSet::new();
+        for id in self.published.iter().skip(self.published.len() - KEEP_PUBLISHED) {
+            kept.extend(build_blobs(&blobs_dir, &read_build(&builds_dir.join(id))?)?);
+        }
+        while self.published.len() > KEEP_PUBLISHED {
+            let old = self.published.pop_front().unwrap();
+            match read_build(&builds_dir.join(&old)).and_then(|build| build_blobs(&blobs_dir, &build)) {
+                Ok(blobs) => {
+                    let unused: Vec<_> = blobs.difference(&kept).collect();
+                    for blob_id in &unused {
+                        let _ = std::fs::remove_file(blobs_dir.join("f").join(blob_id.to_string()));
+                    }
+                    info!("removed {} blobs only used by build {}", unused.len(), old);
+                },
+                Err(e) => warn!("keeping the blobs of build {}, failed to list them: {}", old, e),
+            }
+            let _ = std::fs::remove_file(builds_dir.join(&old));
+            let _ = std::fs::remove_dir_all(search_dir.join(&old));
+        }
+        Ok(())
+    }
+
+    /// Blocks until files in the repo change and no further changes arrive
+    /// for `--watch-debounce-ms`. Returns `None` if events were lost and the
+    /// next cycle has to re-index everything.
+    pub fn wait_for_changes(&mut self) -> Result<Option<HashSet<RelativePath>>, Box<dyn Error>> {
+        let debounce = Duration::from_millis(self.args.watch_debounce_ms);
+        let mut buffer = [0; 4096];
+        let mut changed = HashSet::new();
+        let mut overflow = false;
+
+        while changed.is_empty() && !overflow {
+            let events = read_owned(self.inotify.read_events_blocking(&mut buffer)?);
+            overflow |= self.collect(events, &mut changed)?;
+            loop {
+                std::thread::sleep(debounce);
+                let events = match self.inotify.read_events(&mut buffer) {
+                    Ok(events) => read_owned(events),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => Vec::new(),
+                    Err(e) => return Err(e.into()),
+                };
+                if events.is_empty() {
+                    break;
+                }
+                overflow |= self.collect(events, &mut changed)?;
+            }
+        }
+
+        if overflow {
+            warn!("inotify queue overflowed, re-indexing everything");
+            return Ok(None);
+        }
+        info!("{} files changed", changed.len());
+        Ok(Some(changed))
+    }
+
+    fn collect(
+        &mut self,
+        events: Vec<(WatchDescriptor, EventMask, Option<OsString>)>,
+        changed: &mut HashSet<RelativePath>,
+    ) -> Result<bool, Box<dyn Error>> {
+        let mut overflow = false;
+        for (wd, mask, name) in events {
+            if mask.contains(EventMask::Q_OVERFLOW) {
+                overflow = true;
+                continue;
+            }
+            if mask.contains(EventMask::IGNORED) {
+                self.dirs.remove(&wd);
+                continue;
+            }
+            let (Some(dir), Some(name)) = (self.dirs.get(&wd), name) else { continue };
+            let path = dir.join(name);
+            if self.is_ignored(&path) {
+                continue;
+            }
+            if mask.contains(EventMask::ISDIR) {
+                if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    self.watch_tree(&path, changed)?;
+                }
+                continue;
+            }
+            changed.insert(AbsolutePath::from(path).to_relative(&self.repo));
+        }
+        Ok(overflow)
+    }
+
+    /// Adds watches for `dir` and its subdirectories, recording files already
+    /// in them as changed.
+    fn watch_tree(&mut self, dir: &Path, changed: &mut HashSet<RelativePath>) -> Result<(), Box<dyn Error>> {
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO;
+        let wd = self.inotify.watches().add(dir, mask)?;
+        self.dirs.insert(wd, dir.to_path_buf());
+
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if self.is_ignored(&path) {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.watch_tree(&path, changed)?;
+            } else if file_type.is_file() {
+                changed.insert(AbsolutePath::from(path).to_relative(&self.repo));
+            }
+        }
+        Ok(())
+    }
+
+    /// Hidden files and directories (.git, .territory) and the indexer's
+    /// own output are not watched.
+    fn is_ignored(&self, path: &Path) -> bool {
+        let hidden = path
+            .strip_prefix(&self.repo)
+            .map(|rel| rel.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.')))
+            .unwrap_or(true);
+        hidden || self.ignored.iter().any(|ignored| path.starts_with(ignored))
+    }
+}
+
+
+fn read_build(path: &Path) -> Result<Build, Box<dyn Error>> {
+    Ok(Build::decode(&read_file(path)?[..])?)
+}
+
+
+fn read_owned(events: inotify::Events) -> Vec<(WatchDescriptor, EventMask, Option<OsString>)> {
+    events.map(|e| (e.wd, e.mask, e.name.map(|n| n.to_os_string()))).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    use testdir::testdir;
+
+    use territory_core::RelativePath;
+
+    use prost::Message;
+
+    use territory_core::territory::index::{BlobSliceLoc, Build};
+
+    use crate::testlib::defaut_args;
+    use super::RepoWatcher;
+
+    #[test]
+    fn changes_outside_ignored_dirs() {
+        let dir = testdir!();
+        let repo = dir.join("repo");
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::write(repo.join("a.c"), "").unwrap();
+
+        let mut args = defaut_args();
+        args.repo = repo.clone();
+        args.outdir = repo.join("out");
+        args.watch_debounce_ms = 10;
+        let mut watcher = RepoWatcher::new(&args).unwrap();
+
+        std::fs::write(repo.join("a.c"), "int a;").unwrap();
+        std::fs::write(repo.join(".git").join("index"), "").unwrap();
+        std::fs::write(repo.join("out").join("blob"), "").unwrap();
+        std::fs::create_dir_all(repo.join("sub")).unwrap();
+        std::fs::write(repo.join("sub").join("b.h"), "").unwrap();
+
+        let changed = watcher.wait_for_changes().unwrap().unwrap();
+        assert_eq!(changed, HashSet::from([
+            RelativePath::from(PathBuf::from("a.c")),
+            RelativePath::from(PathBuf::from("sub/b.h")),
+        ]));
+    }
+
+    #[test]
+    fn publish_swaps_builds_and_removes_unused_blobs() {
+        let dir = testdir!();
+        let repo = dir.join("repo");
+        std::fs::create_dir_all(&repo).unwrap();
+
+        let mut args = defaut_args();
+        args.repo = repo.clone();
+        args.outdir = dir.join("out");
+        args.intermediate_path = dir.join("intermediate");
+        let mut watcher = RepoWatcher::new(&args).unwrap();
+
+        let builds_dir = args.outdir.join("builds").join(&args.repo_id);
+        let search_dir = args.outdir.join("search").join(&args.repo_id);
+        let blobs_dir = args.outdir.join("nodes").join(&args.repo_id).join("f");
+        std::fs::create_dir_all(&builds_dir).unwrap();
+        std::fs::create_dir_all(&blobs_dir).unwrap();
+        let blob = |blob_id| Some(BlobSliceLoc { blob_id, start_offset: 0, end_offset: 1 });
+
+        // each cycle shares one blob with the cycle before
+        for generation in 1..=3u64 {
+            let cycle = watcher.next_cycle(None).unwrap();
+            let build = Build {
+                id: cycle.build_id.clone(),
+                include_graph: blob(generation),
+                search_shards: blob(generation + 1),
+                ..Build::default()
+            };
+            std::fs::write(builds_dir.join(&cycle.build_id), build.encode_to_vec()).unwrap();
+            std::fs::create_dir_all(search_dir.join(&cycle.build_id)).unwrap();
+            for blob_id in [generation, generation + 1] {
+                std::fs::write(blobs_dir.join(blob_id.to_string()), [0]).unwrap();
+            }
+
+            watcher.publish(&cycle).unwrap();
+
+            let published = std::fs::read(builds_dir.join(&args.build_id)).unwrap();
+            assert_eq!(Build::decode(&published[..]).unwrap(), build);
+            let link = std::fs::read_link(search_dir.join(&args.build_id)).unwrap();
+            assert_eq!(link, PathBuf::from(&cycle.build_id));
+        }
+
+        let first = format!("{}.1", args.build_id);
+        assert!(!builds_dir.join(&first).exists());
+        assert!(!search_dir.join(&first).exists());
+        assert!(builds_dir.join(format!("{}.2", args.build_id)).exists());
+        assert!(!blobs_dir.join("1").exists());
+        for blob_id in 2..=4 {
+            assert!(blobs_dir.join(blob_id.to_string()).exists(), "blob {blob_id}");
+        }
+    }
+}
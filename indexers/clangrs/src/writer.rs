@@ -1,6 +1,6 @@
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::{stdout, Write};
+use std::io::{stdout, Seek, SeekFrom, Write};
 use std::path::{PathBuf, Path};
 use std::sync::Arc;
 use std::sync::atomic::AtomicUsize;
@@ -14,10 +14,14 @@ use prost::bytes::BytesMut;
 use ring::digest::{Context, SHA256};
 
 use territory_core::search::TrieIndex;
+use territory_core::search_shards::{pair_bits, split_shards};
+use territory_core::text_search::trigrams;
 use territory_core::{legacy_refs_path, BlobID, HNBlob, Node, NodeID, Refs, TokenLocation};
 use territory_core::territory::index::{self as pb, BlobSliceLoc, IndexItem, Build};
 use crate::args::{get_debug_cfg, Args, CompressionMode};
 use crate::intermediate_model::{SemFile, sqlite::OutputMap};
+use crate::report::{BlobBytes, BlobsByKind};
+use crate::status::{self, Status};
 use crate::storage::StorageChannel;
 use crate::testlib::repr_diff;
 
@@ -29,6 +33,8 @@ enum Work {
     Blob(HNBlob),
     References(Refs),
     ReferencesBlob(ReferencesBlob),
+    TypeHierarchy(Vec<pb::TypeHierarchy>),
+    MacroExpansions(Vec<pb::MacroExpansion>),
     Build(Build),
 }
 
@@ -36,6 +42,7 @@ pub struct WriterStats {
     pub total_written: usize,
     pub pb_bytes_reused: usize,
     pub pb_bytes_count: usize,
+    pub by_kind: BlobsByKind,
 }
 
 
@@ -66,6 +73,7 @@ impl NodeWriter {
             let mut local_write_counter = 0;
             let mut local_byte_counter = 0;
             let mut local_reuse_counter = 0;
+            let mut local_by_kind = BlobsByKind::new();
             let t_args = args.clone();
             let t_storage_channel = storage_channel.clone();
             let t_output_map = output_map.clone();
@@ -74,28 +82,42 @@ impl NodeWriter {
                     let result = tr.recv();
                     match result {
                         Ok(work) => {
-                            match &work  {
+                            let (kind, wrote, reused) = match &work  {
                                 Work::Blob(file) => {
                                     let (wrote, reused) = write_blob_pb(
                                         &t_args, &file, &t_storage_channel, &t_output_map);
-                                    local_byte_counter += wrote;
-                                    local_reuse_counter += reused;
+                                    ("nodes", wrote, reused)
                                 }
                                 Work::References(refs) => {
-                                    local_byte_counter += write_references_pb(
+                                    let wrote = write_references_pb(
                                         &t_args, refs, &t_storage_channel);
+                                    ("references", wrote, 0)
                                 }
                                 Work::ReferencesBlob(refs_file) => {
                                     let (wrote, reused) = write_references_file_pb(
                                         &t_args, refs_file, &t_storage_channel, &t_output_map);
-                                    local_byte_counter += wrote;
-                                    local_reuse_counter += reused;
+                                    ("file_references", wrote, reused)
+                                }
+                                Work::TypeHierarchy(types) => {
+                                    let (wrote, reused) = write_type_hierarchy_pb(
+                                        &t_args, types, &t_storage_channel, &t_output_map);
+                                    ("type_hierarchy", wrote, reused)
+                                }
+                                Work::MacroExpansions(expansions) => {
+                                    let (wrote, reused) = write_macro_expansions_pb(
+                                        &t_args, expansions, &t_storage_channel, &t_output_map);
+                                    ("macro_expansions", wrote, reused)
                                 }
                                 Work::Build(build) => {
-                                    local_byte_counter +=write_build_pb(
+                                    let wrote = write_build_pb(
                                         &t_args, build, &t_storage_channel);
+                                    ("build", wrote, 0)
                                 }
-                            }
+                            };
+                            local_byte_counter += wrote;
+                            local_reuse_counter += reused;
+                            local_by_kind.entry(kind).or_default()
+                                .add(&BlobBytes { written: wrote, reused });
 
                             t_write_counter.fetch_add(
                                 1,
@@ -110,6 +132,7 @@ impl NodeWriter {
                     total_written: local_write_counter,
                     pb_bytes_reused: local_reuse_counter,
                     pb_bytes_count: local_byte_counter,
+                    by_kind: local_by_kind,
                 }
             })
         }).collect();
@@ -157,6 +180,26 @@ impl NodeWriter {
         }
     }
 
+    pub fn submit_type_hierarchy(&mut self, types: Vec<pb::TypeHierarchy>) {
+        if get_debug_cfg().print_blob_writes {
+            println!("{types:#?}");
+        }
+        match &self.sender {
+            Some(s) => { s.send(Work::TypeHierarchy(types)).unwrap(); self.total_submitted += 1; },
+            None => { panic!("submit to closed sender"); },
+        }
+    }
+
+    pub fn submit_macro_expansions(&mut self, expansions: Vec<pb::MacroExpansion>) {
+        if get_debug_cfg().print_blob_writes {
+            println!("{expansions:#?}");
+        }
+        match &self.sender {
+            Some(s) => { s.send(Work::MacroExpansions(expansions)).unwrap(); self.total_submitted += 1; },
+            None => { panic!("submit to closed sender"); },
+        }
+    }
+
     pub fn submit_build(&mut self, build: Build) {
         match &self.sender {
             Some(s) => { s.send(Work::Build(build)).unwrap(); self.total_submitted += 1; },
@@ -178,13 +221,21 @@ impl NodeWriter {
             }
         }
 
-        let mut ws = WriterStats { total_written: 0, pb_bytes_reused: 0, pb_bytes_count: 0 };
+        let mut ws = WriterStats { total_written: 0, pb_bytes_reused: 0, pb_bytes_count: 0, by_kind: BlobsByKind::new() };
         for jh in self.join_handles {
             let local_ws = jh.join().unwrap();
             ws.total_written += local_ws.total_written;
             ws.pb_bytes_reused += local_ws.pb_bytes_reused;
             ws.pb_bytes_count += local_ws.pb_bytes_count;
+            for (kind, bytes) in local_ws.by_kind {
+                ws.by_kind.entry(kind).or_default().add(&bytes);
+            }
         }
+        status::report(Status::Blobs {
+            written: ws.total_written,
+            bytes_written: ws.pb_bytes_count,
+            bytes_reused: ws.pb_bytes_reused,
+        });
         ws
     }
 }
@@ -220,7 +271,7 @@ fn write_blob_pb(
             territory_core::pretty_print::node(&mut l, &pb_node).unwrap();
         }
 
-        reused += append_pb_node(&mut blob_id, &mut total_output, pb_node, output_map, args.compression);
+        reused += append_pb_node(&mut blob_id, &mut total_output, pb_node, output_map, args.compression, !args.no_text_index);
 
     }
 
@@ -237,9 +288,17 @@ pub fn append_pb_node(
     pb_node: pb::Node,
     output_map: &OutputMap,
     compression_mode: CompressionMode,
+    text_index: bool,
 ) -> usize {
     let mut output = Vec::new();
     pb_node.encode(&mut output).unwrap();
+    let node_trigrams = || {
+        if text_index && pb_node.kind() != pb::NodeKind::Directory {
+            trigrams(&pb_node.text)
+        } else {
+            Vec::new()
+        }
+    };
 
     let mut context = Context::new(&SHA256);
     context.update(&output);
@@ -258,9 +317,14 @@ pub fn append_pb_node(
         let blob_id = blob_id.get_or_insert_with(|| output_map.new_blob_id());
         let loc = BlobSliceLoc { blob_id: blob_id.0, start_offset, end_offset };
         output_map.store_node_location(pb_node.id, &loc, hash);
+        output_map.store_node_trigrams(pb_node.id, &node_trigrams());
 
         return 0;
     } else {
+        // unchanged nodes keep their trigrams unless the previous run skipped them
+        if text_index && !output_map.has_node_trigrams(pb_node.id) {
+            output_map.store_node_trigrams(pb_node.id, &node_trigrams());
+        }
         return output.len();
     }
 }
@@ -347,6 +411,107 @@ pub fn write_references_file_pb(
 }
 
 
+/// Writes all `TypeHierarchy` messages to one blob.
+fn write_type_hierarchy_pb(
+    args: &Args,
+    types: &[pb::TypeHierarchy],
+    storage_channel: &StorageChannel,
+    output_map: &OutputMap,
+) -> (usize, usize) {
+    let mut total_output = Vec::new();
+    let mut reused = 0;
+
+    let blob_id @ BlobID(blob_id_int) = output_map.new_blob_id();
+
+    for type_hierarchy in types {
+        let mut output = Vec::new();
+        type_hierarchy.encode(&mut output).unwrap();
+
+        let mut context = Context::new(&SHA256);
+        context.update(&output);
+        let hash = context.finish();
+
+        let mut comp_output = apply_compression(args.compression, output);
+
+        let start_offset: u64 = total_output.len().try_into().expect("output too long to address");
+        let end_offset = start_offset.checked_add(
+            comp_output.len().try_into().expect("output too long to address")
+        ).expect("output too long to address");
+        let slice_location = BlobSliceLoc { blob_id: blob_id_int, start_offset, end_offset };
+
+        let slice_location = match output_map.get_existing_slice_loc_or_insert(hash, slice_location) {
+            Some(previous_slice_location) => {
+                reused += comp_output.len();
+                previous_slice_location
+            },
+            None => {
+                total_output.append(&mut comp_output);
+                slice_location
+            },
+        };
+        output_map.store_type_hierarchy_location(type_hierarchy.node_id, &slice_location);
+    }
+
+    let len = total_output.len();
+    if len > 0 {
+        submit_hashed_blob(&args.repo_id, blob_id, storage_channel, total_output);
+    }
+
+    (len, reused)
+}
+
+
+/// Writes the `MacroExpansion` messages of a file to one blob.
+fn write_macro_expansions_pb(
+    args: &Args,
+    expansions: &[pb::MacroExpansion],
+    storage_channel: &StorageChannel,
+    output_map: &OutputMap,
+) -> (usize, usize) {
+    let mut total_output = Vec::new();
+    let mut reused = 0;
+
+    let blob_id @ BlobID(blob_id_int) = output_map.new_blob_id();
+
+    for expansion in expansions {
+        let mut output = Vec::new();
+        expansion.encode(&mut output).unwrap();
+
+        let mut context = Context::new(&SHA256);
+        context.update(&output);
+        let hash = context.finish();
+
+        let mut comp_output = apply_compression(args.compression, output);
+
+        let start_offset: u64 = total_output.len().try_into().expect("output too long to address");
+        let end_offset = start_offset.checked_add(
+            comp_output.len().try_into().expect("output too long to address")
+        ).expect("output too long to address");
+        let slice_location = BlobSliceLoc { blob_id: blob_id_int, start_offset, end_offset };
+
+        let slice_location = match output_map.get_existing_slice_loc_or_insert(hash, slice_location) {
+            Some(previous_slice_location) => {
+                reused += comp_output.len();
+                previous_slice_location
+            },
+            None => {
+                total_output.append(&mut comp_output);
+                slice_location
+            },
+        };
+        let token_location = TokenLocation { node_id: expansion.node_id, offset: expansion.offset };
+        output_map.store_macro_expansion_location(token_location, &slice_location);
+    }
+
+    let len = total_output.len();
+    if len > 0 {
+        submit_hashed_blob(&args.repo_id, blob_id, storage_channel, total_output);
+    }
+
+    (len, reused)
+}
+
+
 fn write_build_pb(
     args: &Args,
     build: &Build,
@@ -393,10 +558,11 @@ pub struct InvertedIndexWriter {
     sender: crossbeam_channel::Sender<pb::IndexItem>,
     join_handle: thread::JoinHandle<(Vec<u8>, Vec<IndexItem>)>,
     storage_channel: StorageChannel,
+    output_map: OutputMap,
 }
 
 impl InvertedIndexWriter {
-    pub fn start(args: &Args, storage_channel: StorageChannel) -> InvertedIndexWriter {
+    pub fn start(args: &Args, storage_channel: StorageChannel, output_map: OutputMap) -> InvertedIndexWriter {
 
 
         let (sender, receiver) = crossbeam_channel::unbounded::<pb::IndexItem>();
@@ -417,14 +583,15 @@ impl InvertedIndexWriter {
                 }
             }
         });
-        InvertedIndexWriter { args: args.clone(), sender, join_handle, storage_channel }
+        InvertedIndexWriter { args: args.clone(), sender, join_handle, storage_channel, output_map }
     }
 
     pub fn submit_item(&mut self, item: pb::IndexItem) {
         self.sender.send(item).unwrap();
     }
 
-    pub async fn join(self) {
+    /// Writes the search index, returning the location of the shard manifest.
+    pub async fn join(self) -> BlobSliceLoc {
         if !self.args.fastwait {
             loop {
                 if self.join_handle.is_finished() { break; }
@@ -436,6 +603,8 @@ impl InvertedIndexWriter {
         }
         drop(self.sender);
         let (buf, mut items) = self.join_handle.join().unwrap();
+        let search_shards = write_search_shards(
+            &self.args, &self.output_map, &self.storage_channel, items.clone()).await;
 
         let trie = TrieIndex::from_index_items(&mut items);
         let mut trie_buf = BytesMut::new();
@@ -447,10 +616,47 @@ impl InvertedIndexWriter {
         let index_path = PathBuf::from("search").join(&self.args.repo_id).join(&self.args.build_id).join("all");
         let buf = apply_compression(self.args.compression, buf);
         self.storage_channel.submit_blob(index_path, buf).await;
+
+        search_shards
     }
 }
 
 
+/// Writes the search index split into shards of `--search-shard-items`,
+/// followed by their manifest, as slices of one blob.
+async fn write_search_shards(
+    args: &Args,
+    output_map: &OutputMap,
+    storage_channel: &StorageChannel,
+    items: Vec<IndexItem>,
+) -> BlobSliceLoc {
+    let blob_id = output_map.new_blob_id();
+    let mut blob = Vec::new();
+    let mut append = |data: Vec<u8>| {
+        let mut data = apply_compression(args.compression, data);
+        let start_offset = blob.len().try_into().unwrap();
+        blob.append(&mut data);
+        BlobSliceLoc { blob_id: blob_id.0, start_offset, end_offset: blob.len().try_into().unwrap() }
+    };
+
+    let mut manifest = pb::SearchShards::default();
+    for (first_key, mut shard_items) in split_shards(items, args.search_shard_items) {
+        let item_count = shard_items.len().try_into().unwrap();
+        let pair_bits = pair_bits(&shard_items);
+        let mut buf = BytesMut::new();
+        TrieIndex::from_index_items(&mut shard_items).dump(&mut buf).unwrap();
+        let location = Some(append(buf.to_vec()));
+        manifest.shards.push(pb::SearchShard { first_key, location, item_count, pair_bits });
+    }
+    let root = append(manifest.encode_to_vec());
+    info!("search index split into {} shards", manifest.shards.len());
+
+    let path = PathBuf::from("nodes").join(&args.repo_id).join("f").join(blob_id.0.to_string());
+    storage_channel.submit_blob(path, blob).await;
+    root
+}
+
+
 pub fn apply_compression(mode: CompressionMode, v: Vec<u8>) -> Vec<u8> {
     match mode {
         CompressionMode::None => v,
@@ -483,6 +689,25 @@ impl IntermediateNodeFileWriter {
         }
     }
 
+    /// Continues a semfile written by an interrupted run, dropping anything
+    /// after `len`.
+    pub fn resume(temp_file_path: &Path, len: u64) -> Self {
+        info!("appending intermediate data to {:?} after {} bytes", temp_file_path, len);
+        let mut file = File::options().write(true).open(temp_file_path).unwrap();
+        file.set_len(len).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        Self {
+            count: 0,
+            writer: std::io::BufWriter::new(file),
+        }
+    }
+
+    /// Length of the semfile with everything appended so far written out.
+    pub fn flushed_len(&mut self) -> u64 {
+        self.writer.flush().unwrap();
+        self.writer.get_mut().stream_position().unwrap()
+    }
+
     pub fn append(&mut self, semfile: &SemFile) {
         if get_debug_cfg().pretty_semfiles {
             serde_json::to_writer_pretty(&mut self.writer, semfile).unwrap();
@@ -532,6 +757,6 @@ impl<'a> IntermediateNodeFileReader<'a> {
 }
 
 
-fn semfile_path(args: &Args, slice: usize) -> PathBuf {
+pub(crate) fn semfile_path(args: &Args, slice: usize) -> PathBuf {
     args.intermediate_path.join(format!("semfile.{}", slice))
 }
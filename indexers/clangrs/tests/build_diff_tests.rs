@@ -0,0 +1,98 @@
+use testdir::testdir;
+
+use clangrs::index_reader::IndexReader;
+use clangrs::testlib::RepoWriter;
+use territory_core::build_diff::{diff_builds, BuildDiff};
+
+
+fn diff_after_update(path: &str, before: &str, after: &str) -> BuildDiff {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add_c_unit("mod1.c", r#"
+void b();
+void a() { b(); }
+"#).unwrap();
+    repo_writer.add_c_unit("mod2.c", r#"
+void b() { }
+void c() { }
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+    repo_writer.update(path, before).unwrap();
+
+    let walker = repo_writer.index_repo();
+    repo_writer.update(path, after).unwrap();
+    repo_writer.index_repo_with_args(|args| { args.build_id = "test_build_2".to_string(); });
+
+    let old = IndexReader::open(walker.index_path(), "test_repo", "test_build").unwrap();
+    let new = IndexReader::open(walker.index_path(), "test_repo", "test_build_2").unwrap();
+    diff_builds(&old, &new).unwrap()
+}
+
+
+#[test]
+fn same_sources_no_diff() {
+    let code = "\nvoid b() { }\nvoid c() { }\n";
+    let diff = diff_after_update("mod2.c", code, code);
+
+    assert!(diff.is_empty(), "{diff:#?}");
+}
+
+
+#[test]
+fn changed_node_and_gained_reference() {
+    let diff = diff_after_update(
+        "mod1.c",
+        "\nvoid b();\nvoid a() { b(); }\n",
+        "\nvoid b();\nvoid a() { b(); b(); }\n");
+
+    assert_eq!(diff.nodes_added, vec![]);
+    assert_eq!(diff.nodes_removed, vec![]);
+    assert!(diff.nodes_changed.iter().any(|c| c.new.path == "mod1.c" && c.new.title.starts_with("void a()")));
+
+    let b_refs = diff.references.iter().find(|r| r.symbol == "b").expect("no reference changes for b");
+    assert_eq!(b_refs.path, "mod2.c");
+    assert_eq!(b_refs.gained.len(), 1);
+    assert_eq!(b_refs.gained[0].use_path, "mod1.c");
+    assert_eq!(b_refs.lost, vec![]);
+}
+
+
+#[test]
+fn shifted_symbols_are_not_moved() {
+    let diff = diff_after_update(
+        "mod2.c",
+        "\nvoid b() { }\nvoid c() { }\n",
+        "\n\n\nvoid b() { }\nvoid c() { }\n");
+
+    assert_eq!(diff.symbols_moved, vec![]);
+
+    // uses of b() didn't change, only their target shifted
+    assert!(diff.references.iter().all(|r| r.symbol != "b"), "{:?}", diff.references);
+}
+
+
+#[test]
+fn reordered_symbol_definitions() {
+    let diff = diff_after_update(
+        "mod2.c",
+        "\nvoid b() { }\nvoid c() { }\nvoid d() { }\n",
+        "\nvoid c() { }\nvoid d() { }\nvoid b() { }\n");
+
+    let moved: Vec<_> = diff.symbols_moved.iter().map(|m| (m.new.title.as_str(), m.old.line, m.new.line)).collect();
+    assert_eq!(moved, vec![("void b() { }", 2, 4)]);
+}
+
+
+#[test]
+fn same_named_symbols_in_one_node() {
+    // the edit keeps the length of the first block, so the second v stays at
+    // the same offset
+    let diff = diff_after_update(
+        "mod2.c",
+        "\nvoid b() { }\nvoid c() { { int v = 0; v++;    } { int v = 0; v++; } }\n",
+        "\nvoid b() { }\nvoid c() { { int v = 0; v++; v; } { int v = 0; v++; } }\n");
+
+    let v_refs: Vec<_> = diff.references.iter().filter(|r| r.symbol == "v").collect();
+    assert_eq!(v_refs.len(), 1, "{v_refs:?}");
+    assert_eq!(v_refs[0].gained.len(), 1);
+    assert_eq!(v_refs[0].lost, vec![]);
+}
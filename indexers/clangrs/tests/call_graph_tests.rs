@@ -0,0 +1,114 @@
+use testdir::testdir;
+
+use clangrs::index_reader::IndexReader;
+use clangrs::testlib::RepoWriter;
+use territory_core::call_graph::CallGraph;
+use territory_core::GenHref;
+
+
+fn call_graph(files: &[(&str, &str)]) -> (IndexReader, CallGraph) {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    for (path, code) in files {
+        repo_writer.add_c_unit(path, code).unwrap();
+    }
+    repo_writer.write_clang_compile_commands().unwrap();
+    let walker = repo_writer.index_repo();
+
+    let reader = IndexReader::open(walker.index_path(), "test_repo", "test_build").unwrap();
+    let graph = CallGraph::extract(&reader, reader.index_items().unwrap()).unwrap();
+    (reader, graph)
+}
+
+
+fn edges(graph: &CallGraph) -> Vec<(&str, &str, usize)> {
+    let name = |id| graph.functions.iter().find(|f| f.id == id).unwrap().name.as_str();
+    graph.calls.iter().map(|c| (name(c.caller), name(c.callee), c.sites.len())).collect()
+}
+
+
+#[test]
+fn calls_across_files() {
+    let (_, graph) = call_graph(&[
+        ("mod1.c", r#"
+void b();
+void a() { b(); b(); }
+"#),
+        ("mod2.c", r#"
+static int counter;
+void c() { counter++; }
+void b() { c(); }
+"#),
+    ]);
+
+    let mut names: Vec<_> = graph.functions.iter().map(|f| f.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, vec!["a", "b", "c"]);
+
+    let mut edges = edges(&graph);
+    edges.sort();
+    assert_eq!(edges, vec![("a", "b", 2), ("b", "c", 1)]);
+}
+
+
+#[test]
+fn function_pointers_are_not_functions() {
+    let (_, graph) = call_graph(&[
+        ("mod1.c", r#"
+void b() { }
+void (*fp)() = b;
+void a() { fp(); }
+"#),
+    ]);
+
+    assert!(graph.functions.iter().all(|f| f.name != "fp"));
+    assert_eq!(edges(&graph), vec![]);
+}
+
+
+#[test]
+fn taking_the_address_is_not_a_call() {
+    let (_, graph) = call_graph(&[
+        ("mod1.c", r#"
+void b() { }
+void c() { }
+void a() { void (*fp)() = &b; fp(); c(); }
+"#),
+    ]);
+
+    assert_eq!(edges(&graph), vec![("a", "c", 1)]);
+}
+
+
+#[test]
+fn recursive_calls() {
+    let (_, graph) = call_graph(&[
+        ("mod1.c", r#"
+int b(int n);
+int a(int n) { return n ? a(n - 1) + b(n) : 0; }
+int b(int n) { return n > 1 ? a(n - 2) : 1; }
+"#),
+    ]);
+
+    let mut edges = edges(&graph);
+    edges.sort();
+    assert_eq!(edges, vec![("a", "a", 1), ("a", "b", 1), ("b", "a", 1)]);
+}
+
+#[test]
+fn ids_round_trip_through_hrefs() {
+    let (reader, graph) = call_graph(&[
+        ("mod1.c", r#"
+void b() { }
+void a() { b(); }
+"#),
+    ]);
+
+    let json = serde_json::to_value(&graph).unwrap();
+    let caller = json["calls"][0]["caller"].as_str().unwrap();
+    let node = reader.node(&GenHref::from_url(caller).unwrap()).unwrap();
+    assert!(node.text.starts_with("void a()"), "{}", node.text);
+
+    let mut dot = Vec::new();
+    graph.write_dot(&mut dot).unwrap();
+    assert!(String::from_utf8(dot).unwrap().contains(&format!("\"{caller}\" -> ")));
+}
@@ -0,0 +1,59 @@
+use testdir::testdir;
+
+use clangrs::index_reader::IndexReader;
+use clangrs::testlib::RepoWriter;
+use territory_core::include_graph::IncludeGraph;
+
+
+#[test]
+fn includes_across_tus() {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add("include/common.h", r#"
+#include "types.h"
+int common(my_int x);
+"#).unwrap();
+    repo_writer.add("include/types.h", "typedef int my_int;\n").unwrap();
+    repo_writer.add("src/local.h", "int local();\n").unwrap();
+    for name in ["a", "b"] {
+        repo_writer.add(&format!("src/{name}.c"), r#"
+#include <common.h>
+#include "local.h"
+int main() { return common(local()); }
+"#).unwrap();
+        repo_writer.add_custom_compile_command(serde_json::json!({
+            "file": format!("{name}.c"),
+            "directory": repo_writer.repo_dir().join("src"),
+            "command": format!("cc -I../include -c {name}.c"),
+        }));
+    }
+    repo_writer.write_clang_compile_commands().unwrap();
+    let walker = repo_writer.index_repo_with_args(|args| { args.index_system = false; });
+
+    let reader = IndexReader::open(walker.index_path(), "test_repo", "test_build").unwrap();
+    let graph = IncludeGraph::load(&reader).unwrap();
+
+    let includes: Vec<_> = graph.includes("src/a.c")
+        .into_iter()
+        .map(|i| (i.included.as_str(), i.spelling.as_str(), i.search_path.as_deref(), i.tu_count))
+        .collect();
+    assert_eq!(includes, [
+        ("include/common.h", "common.h", Some("include"), 1),
+        ("src/local.h", "local.h", Some("src"), 1),
+    ]);
+
+    let includers: Vec<_> = graph.included_by("include/common.h").into_iter().map(|i| i.includer.as_str()).collect();
+    assert_eq!(includers, ["src/a.c", "src/b.c"]);
+
+    // headers are scanned once per TU including them
+    let types = graph.included_by("include/types.h");
+    assert_eq!(types.len(), 1);
+    assert_eq!(types[0].tu_count, 2);
+
+    let rebuild: Vec<_> = graph.transitively_included_by("include/types.h").into_iter().collect();
+    assert_eq!(rebuild, ["include/common.h", "src/a.c", "src/b.c"]);
+
+    let mut dot = Vec::new();
+    graph.write_dot(&mut dot).unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+    assert!(dot.contains("\"include/common.h\" -> \"include/types.h\" [label=\"2\"];"), "{dot}");
+}
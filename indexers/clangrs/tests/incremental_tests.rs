@@ -87,3 +87,86 @@ void b() { (void)1; }
     save_href(&mut walker, &mut changed_locs_after, "b");
     assert_ne!(changed_locs_before, changed_locs_after);
 }
+
+
+fn write_changed_files(repo_writer: &RepoWriter, paths: &[&str]) -> std::path::PathBuf {
+    let list_path = repo_writer.dir().parent().unwrap().join("changed_files");
+    std::fs::write(&list_path, paths.join("\n")).unwrap();
+    list_path
+}
+
+
+#[test]
+fn changed_files_reparse_only_affected_units() {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add("common.h", r#"
+int common();
+"#).unwrap();
+    repo_writer.add_c_unit("mod1.c", r#"
+#include "common.h"
+void a() { common(); }
+"#).unwrap();
+    repo_writer.add_c_unit("mod2.c", r#"
+void b() { }
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+    repo_writer.index_repo();
+
+    repo_writer.update("mod2.c", r#"
+void b() { (void)1; }
+"#).unwrap();
+    let changed = write_changed_files(&repo_writer, &["mod2.c"]);
+
+    let mut walker = repo_writer.index_repo_with_args(|args| {
+        args.build_id = "test_build_2".to_string();
+        args.changed_files = Some(changed);
+    });
+
+    walker.follow_token("mod2.c");
+    walker.follow_token("b");
+    assert!(walker.node().text.contains("(void)1;"), "{}", walker.node().text);
+
+    walker.reset();
+    walker.follow_token("mod1.c");
+    walker.follow_token("a");
+    assert!(walker.find_token("common").unwrap().context.href.is_some());
+
+    walker.reset();
+    walker.follow_token("common.h");
+    assert!(walker.find_token("common").is_some());
+}
+
+
+#[test]
+fn changed_header_reparses_including_units() {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add("common.h", r#"
+int common();
+"#).unwrap();
+    repo_writer.add_c_unit("mod1.c", r#"
+#include "common.h"
+void a() { common(); }
+"#).unwrap();
+    repo_writer.add_c_unit("mod2.c", r#"
+void b() { }
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+    repo_writer.index_repo();
+
+    repo_writer.update("common.h", r#"
+int common(int x);
+"#).unwrap();
+    let changed = write_changed_files(&repo_writer, &["common.h"]);
+
+    let mut walker = repo_writer.index_repo_with_args(|args| {
+        args.build_id = "test_build_2".to_string();
+        args.changed_files = Some(changed);
+    });
+
+    walker.follow_token("common.h");
+    assert!(walker.node().text.contains("int common(int x);"), "{}", walker.node().text);
+
+    walker.reset();
+    walker.follow_token("mod2.c");
+    assert!(walker.find_token("b").is_some());
+}
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use prost::Message;
+
+use territory_core::resolver::Resolver;
+use territory_core::search::Options;
+use territory_core::territory::index::{Node, NodeKind};
+use territory_core::{pb_node_tokens, GenHref, IntoGenHref, TokenLocation};
+
+use clangrs::archive::{Archive, ArchiveResolver};
+use clangrs::args::CompressionMode;
+use clangrs::index_reader::IndexReader;
+use clangrs::testlib::{defaut_args, inspect_repo};
+
+
+fn index_example_with_compression(compression: CompressionMode) -> IndexReader {
+    let mut args = defaut_args();
+    args.compression = compression;
+    inspect_repo(&args);
+
+    IndexReader::open(&args.outdir, &args.repo_id, &args.build_id).unwrap()
+}
+
+
+#[test]
+fn root_by_empty_path() {
+    let reader = index_example_with_compression(CompressionMode::None);
+
+    let root = reader.node(&GenHref::from_url("path:").unwrap()).unwrap();
+    assert_eq!(root.path, "/");
+    assert_eq!(root.kind(), NodeKind::Directory);
+    assert_eq!(root.id, reader.build().repo_root_node_id);
+}
+
+
+#[test]
+fn nested_path_walk() {
+    let reader = index_example_with_compression(CompressionMode::None);
+
+    let dir = reader.node(&GenHref::from_url("path:dir").unwrap()).unwrap();
+    assert_eq!(dir.kind(), NodeKind::Directory);
+
+    let file = reader.node(&GenHref::from_url("path:dir/mod2.c").unwrap()).unwrap();
+    assert_eq!(file.path, "dir/mod2.c");
+    assert_eq!(file.kind(), NodeKind::SourceFile);
+
+    assert!(reader.node(&GenHref::from_url("path:dir/nope.c").unwrap()).is_err());
+}
+
+
+#[test]
+fn follow_node_and_references() {
+    let reader = index_example_with_compression(CompressionMode::None);
+
+    let file = reader.node(&GenHref::from_url("path:mod1.c").unwrap()).unwrap();
+    let foo_href = pb_node_tokens(&file)
+        .into_iter()
+        .find(|tok| tok.text == "foo")
+        .and_then(|tok| tok.context.href)
+        .expect("foo not linked from file node");
+
+    let foo = reader.node(&foo_href.into_gen_href()).unwrap();
+    assert!(foo.text.starts_with("int foo() {"));
+
+    let foo_tok = pb_node_tokens(&foo)
+        .into_iter()
+        .find(|tok| tok.text == "foo")
+        .unwrap();
+    let refs = reader.references(&GenHref::RefsId(TokenLocation {
+        node_id: foo.id,
+        offset: foo_tok.offset,
+    })).unwrap();
+    assert_eq!(refs.refs.len(), 1);
+    assert_eq!(refs.refs[0].context, "baz");
+}
+
+
+#[test]
+fn search() {
+    let reader = index_example_with_compression(CompressionMode::None);
+
+    let results = reader.search("foo", &Options::default()).unwrap();
+    assert_eq!(results.iter().map(|r| r.item.key.as_str()).collect::<Vec<_>>(), vec!["foo"]);
+
+    let node = reader.node(&results[0].item.href.into_gen_href()).unwrap();
+    assert!(node.text.starts_with("int foo() {"));
+}
+
+
+#[test]
+fn sharded_search() {
+    let mut args = defaut_args();
+    args.compression = CompressionMode::Gzip;
+    args.search_shard_items = 1;
+    inspect_repo(&args);
+    let reader = IndexReader::open(&args.outdir, &args.repo_id, &args.build_id).unwrap();
+    assert!(reader.build().search_shards.is_some());
+
+    let whole = reader.search_index().unwrap();
+    for query in ["foo", "^ba", "=bar", "mod"] {
+        let options = Options { limit: Some(5), ..Options::default() };
+        let sharded: Vec<_> = reader.search(query, &options).unwrap().into_iter().map(|r| r.item).collect();
+        let expected: Vec<_> = whole.search(query, &options).into_iter().map(|r| r.item).collect();
+        assert_eq!(sharded, expected, "query {query:?}");
+    }
+}
+
+
+#[test]
+fn gzip_compressed_build() {
+    let reader = index_example_with_compression(CompressionMode::Gzip);
+
+    let file = reader.node(&GenHref::from_url("path:dir/mod2.c").unwrap()).unwrap();
+    assert_eq!(file.path, "dir/mod2.c");
+
+    let results = reader.search("bar", &Options::default()).unwrap();
+    assert_eq!(results[0].item.key, "bar");
+}
+
+
+#[test]
+fn packed_archive() {
+    let mut args = defaut_args();
+    args.compression = CompressionMode::Gzip;
+    let archive_path = args.outdir.with_file_name("build.tty");
+    args.archive = Some(archive_path.clone());
+    inspect_repo(&args);
+
+    let dir_reader = IndexReader::open(&args.outdir, &args.repo_id, &args.build_id).unwrap();
+    // the output directory is not needed once the build is packed
+    std::fs::remove_dir_all(&args.outdir).unwrap();
+    let reader = IndexReader::open_archive(&archive_path).unwrap();
+    assert_eq!(reader.build(), dir_reader.build());
+
+    let file = reader.node(&GenHref::from_url("path:dir/mod2.c").unwrap()).unwrap();
+    assert_eq!(file.path, "dir/mod2.c");
+
+    let results = reader.search("foo", &Options::default()).unwrap();
+    assert_eq!(results[0].item.key, "foo");
+    let foo = reader.node(&results[0].item.href.into_gen_href()).unwrap();
+    assert!(foo.text.starts_with("int foo() {"));
+
+    let archive = Arc::new(Archive::open(&archive_path).unwrap());
+    let resolver = ArchiveResolver::new(archive).unwrap();
+    let loc = resolver.resolve_href(&GenHref::NodeId(foo.id)).unwrap();
+    assert_eq!(Node::decode(&resolver.load_bytes(&loc).unwrap()[..]).unwrap(), foo);
+}
@@ -0,0 +1,74 @@
+use lsp_types::{Position, Url};
+
+use clangrs::index_reader::IndexReader;
+use clangrs::lsp::LspBackend;
+use clangrs::testlib::{defaut_args, inspect_repo};
+
+
+fn index_example_for_lsp() -> (LspBackend, Url, Url) {
+    let args = defaut_args();
+    inspect_repo(&args);
+
+    let repo = args.repo.canonicalize().unwrap();
+    // published builds are served without the intermediate DB
+    std::fs::remove_file(&args.db_path).unwrap();
+    let reader = IndexReader::open(&args.outdir, &args.repo_id, &args.build_id).unwrap();
+    let backend = LspBackend::new(&repo, reader);
+
+    let mod1 = Url::from_file_path(repo.join("mod1.c")).unwrap();
+    let mod2 = Url::from_file_path(repo.join("dir/mod2.c")).unwrap();
+    (backend, mod1, mod2)
+}
+
+
+#[test]
+fn definition_in_other_file() {
+    let (backend, mod1, mod2) = index_example_for_lsp();
+
+    // `bar` in `return bar(DEFA);`
+    let locations = backend.definition(&mod1, Position { line: 4, character: 12 }).unwrap();
+    assert_eq!(locations.len(), 1);
+    assert_eq!(locations[0].uri, mod2);
+    assert_eq!(locations[0].range.start, Position { line: 3, character: 4 });
+    assert_eq!(locations[0].range.end, Position { line: 3, character: 7 });
+}
+
+
+#[test]
+fn references_from_definition_and_use() {
+    let (backend, mod1, _) = index_example_for_lsp();
+
+    // `foo` in `int foo() {`
+    let from_definition = backend.references(&mod1, Position { line: 3, character: 5 }, false).unwrap();
+    assert_eq!(from_definition.len(), 1);
+    assert_eq!(from_definition[0].uri, mod1);
+    assert_eq!(from_definition[0].range.start, Position { line: 8, character: 4 });
+
+    // `foo` in `foo();`
+    let from_use = backend.references(&mod1, Position { line: 8, character: 5 }, true).unwrap();
+    assert_eq!(from_use.len(), 2);
+    assert_eq!(from_use[0].range.start, Position { line: 3, character: 4 });
+}
+
+
+#[test]
+fn document_symbols() {
+    let (backend, mod1, _) = index_example_for_lsp();
+
+    let mut names: Vec<_> = backend.document_symbols(&mod1).unwrap()
+        .into_iter()
+        .map(|sym| sym.name)
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["baz", "foo"]);
+}
+
+
+#[test]
+fn workspace_symbols() {
+    let (backend, _, mod2) = index_example_for_lsp();
+
+    let symbols = backend.workspace_symbols("bar").unwrap();
+    assert_eq!(symbols[0].name, "bar");
+    assert_eq!(symbols[0].location.uri, mod2);
+}
@@ -0,0 +1,81 @@
+use territory_core::search::Options;
+use territory_core::territory::index::ReferenceKind;
+use territory_core::{pb_node_tokens, IntoGenHref};
+
+use clangrs::index_reader::IndexReader;
+use clangrs::query::{self, Command};
+use clangrs::testlib::{defaut_args, inspect_repo};
+
+
+fn index_example() -> IndexReader {
+    let args = defaut_args();
+    inspect_repo(&args);
+
+    IndexReader::open(&args.outdir, &args.repo_id, &args.build_id).unwrap()
+}
+
+
+fn run(reader: &IndexReader, command: Command) -> String {
+    let mut out = Vec::new();
+    query::run(reader, &command, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+
+#[test]
+fn search() {
+    let reader = index_example();
+
+    let out = run(&reader, Command::Search { query: "foo".into(), limit: 20, by_length: false, smart: false });
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 1, "{out}");
+    let fields: Vec<_> = lines[0].split('\t').collect();
+    assert_eq!(fields[0], "foo");
+    assert_eq!(fields[2], "mod1.c");
+    assert!(fields[3].starts_with("id:"), "{out}");
+}
+
+
+#[test]
+fn browse() {
+    let reader = index_example();
+
+    let out = run(&reader, Command::Ls { dir: "".into() });
+    let names: Vec<_> = out.lines().map(|l| l.split('\t').next().unwrap()).collect();
+    assert!(names.contains(&"dir/"), "{out}");
+    assert!(names.contains(&"mod1.c"), "{out}");
+    assert!(names.contains(&"shared.h"), "{out}");
+
+    let out = run(&reader, Command::Ls { dir: "dir".into() });
+    assert!(out.lines().any(|l| l.starts_with("mod2.c\t")), "{out}");
+    assert!(query::run(&reader, &Command::Ls { dir: "mod1.c".into() }, &mut Vec::new()).is_err());
+
+    let out = run(&reader, Command::Tree);
+    let lines: Vec<_> = out.lines().collect();
+    let dir = lines.iter().position(|l| *l == "dir/").expect(&out);
+    assert_eq!(lines[dir + 1], "  mod2.c", "{out}");
+
+    let out = run(&reader, Command::Show { node: "dir/mod2.c".into() });
+    assert!(out.contains("int bar(int x) {"), "{out}");
+}
+
+
+#[test]
+fn references() {
+    let reader = index_example();
+
+    let foo = reader.search("foo", &Options::default()).unwrap().remove(0);
+    let foo = reader.node(&foo.item.href.into_gen_href()).unwrap();
+    let foo_tok = pb_node_tokens(&foo).into_iter().find(|tok| tok.text == "foo").unwrap();
+
+    let out = run(&reader, Command::Refs { node_id: foo.id, offset: foo_tok.offset, kind: None });
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines.len(), 1, "{out}");
+    let fields: Vec<_> = lines[0].split('\t').collect();
+    assert!(fields[0].starts_with("mod1.c:9:"), "{out}");
+    assert_eq!(fields[1], "baz");
+    assert_eq!(fields[3], "call");
+
+    let out = run(&reader, Command::Refs { node_id: foo.id, offset: foo_tok.offset, kind: Some(ReferenceKind::RkWrite) });
+    assert_eq!(out, "");
+}
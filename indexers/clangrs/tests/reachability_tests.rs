@@ -4,11 +4,11 @@ use std::io::Write;
 
 use testdir::testdir;
 
-use territory_core::territory::index::{reference, NodeKind, Location};
-use territory_core::{TokenKind, ReferencesLink, pb_node_tokens};
+use territory_core::territory::index::{reference, NodeKind, Location, ReferenceKind, References};
+use territory_core::{GenHref, TokenKind, ReferencesLink, pb_node_tokens};
 
 use clangrs::testlib::{
-    index_example, index_with_changed_args, repr_diff, run_scanner_thread, write_single_file_repo, RepoWriter, TERRITORY_ROOT
+    index_example, index_with_changed_args, repr_diff, write_single_file_repo, RepoWriter, TERRITORY_ROOT
 };
 
 
@@ -24,11 +24,6 @@ fn exec() {
     let repo_path = TERRITORY_ROOT.join("repos/example");
     let scanner_sock_path = temp_dir_.join("scanner.sock");
     let par = 1;
-    let mut scanners = Vec::new();
-    for _ in 1..=par {
-        let s = run_scanner_thread(repo_path.clone(), scanner_sock_path.clone());
-        scanners.push(s);
-    }
 
     let cmdout = Command::new(clangrs_path)
         .args([
@@ -41,6 +36,7 @@ fn exec() {
             "--intermediate-path", temp_dir_.join("model").to_str().expect("bad model path"),
             "--db-path", temp_dir_.join("model").join("sem.db").to_str().unwrap(),
             "--fastwait",
+            "--scanners", "thread",
             "--scanner-socket-path", scanner_sock_path.to_str().unwrap(),
             "--log-dir", temp_dir_.join("logs").to_str().unwrap(),
         ])
@@ -58,8 +54,7 @@ fn exec() {
 
     let log_text = read_to_string(temp_dir_.join("logs/index")).expect("can't read scan log file");
     assert!(log_text.contains("clangrs indexer starting"));
-
-    for s in scanners { s.join().unwrap(); }
+    assert!(log_text.contains("scanner 1 finished"));
 }
 
 
@@ -665,6 +660,75 @@ void use_ptr(struct S *s) { s->y; }
 }
 
 
+#[test]
+fn macro_expansion() {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add_c_unit("main.c", r#"
+#define LOW 2
+#define MAX(a, b) ((a) > (b) ? (a) : (b))
+#define LIMIT(x) MAX(x, LOW)
+int limit(int v) { return LIMIT(v); }
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+
+    let mut walker = repo_writer.index_repo();
+    walker.follow_token("main.c");
+    walker.follow_token("limit");
+    let expansion = walker.macro_expansion("LIMIT");
+    assert_eq!(expansion.name, "LIMIT");
+    assert_eq!(expansion.text, "((v) > (2) ? (v) : (2))");
+    assert_eq!(
+        expansion.steps.iter().map(|step| (step.name.as_str(), step.depth)).collect::<Vec<_>>(),
+        vec![("LIMIT", 0), ("MAX", 1), ("LOW", 2)]);
+    assert!(walker.find_token("v").is_some_and(|tok| !tok.context.has_macro_expansion));
+
+    let max_definition = expansion.steps[1].definition.as_ref().unwrap();
+    walker.go_to_node(GenHref::NodeId(max_definition.node_id));
+    assert_eq!(walker.node().text, "#define MAX(a, b) ((a) > (b) ? (a) : (b))");
+}
+
+
+#[test]
+fn reference_kinds() {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add_c_unit("main.c", r#"
+struct S { int x; };
+int g;
+void set(struct S *s) { s->x = 1; g++; }
+int get(struct S *s) { return s->x + g; }
+int *addr(struct S *s) { return &s->x; }
+int call(struct S *s) { return get(s); }
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+
+    let mut walker = repo_writer.index_repo();
+    walker.follow_token("main.c");
+    let kinds = |refs: References| {
+        let mut kinds = refs.refs
+            .iter()
+            .map(|r| (r.use_location.as_ref().unwrap().line, r.kind()))
+            .collect::<Vec<_>>();
+        kinds.sort();
+        kinds
+    };
+
+    assert_eq!(kinds(walker.token_references("g")), vec![
+        (4, ReferenceKind::RkWrite),
+        (5, ReferenceKind::RkRead),
+    ]);
+    assert_eq!(kinds(walker.token_references("get")), vec![
+        (7, ReferenceKind::RkCall),
+    ]);
+
+    walker.follow_token("S");
+    assert_eq!(kinds(walker.token_references("x")), vec![
+        (4, ReferenceKind::RkWrite),
+        (5, ReferenceKind::RkRead),
+        (6, ReferenceKind::RkAddressOf),
+    ]);
+}
+
+
 #[test]
 fn which_struct_tokens_have_references() {
     let mut repo_writer = RepoWriter::new(&testdir!());
@@ -1,6 +1,6 @@
 use testdir::testdir;
 
-use territory_core::{pb_node_tokens, TokenKind};
+use territory_core::{pb_node_tokens, GenHref, TokenKind};
 use territory_core::territory::index::Location;
 use clangrs::testlib::{ repr_diff, RepoWriter };
 
@@ -1631,3 +1631,84 @@ class B { U x; }
     walker.follow_token("B");
     assert_eq!(walker.node().container, Some(file_node_id));
 }
+
+
+#[test]
+fn type_hierarchy() {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add("defs.h", r"
+class Base { };
+class Other { };
+class Derived : public Base, private Other { };
+").unwrap();
+    repo_writer.add_cpp_unit("a.cpp", r#"
+#include "defs.h"
+class MoreDerived : public Derived { };
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+
+    let mut walker = repo_writer.index_repo();
+    walker.follow_token("defs.h");
+    walker.follow_token("Base");
+    let base_id = walker.node().id;
+    let base = walker.type_hierarchy();
+    assert!(base.supertypes.is_empty());
+    assert_eq!(base.subtypes.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), vec!["Derived"]);
+    let derived_id = base.subtypes[0].href.as_ref().unwrap().node_id;
+
+    walker.reset();
+    walker.follow_token("defs.h");
+    walker.follow_token("Derived");
+    assert_eq!(walker.node().id, derived_id);
+    let derived = walker.type_hierarchy();
+    let supertypes = derived.supertypes
+        .iter()
+        .map(|t| (t.name.as_str(), t.href.as_ref().map(|h| h.node_id)))
+        .collect::<Vec<_>>();
+    assert_eq!(supertypes.len(), 2);
+    assert!(supertypes.contains(&("Base", Some(base_id))));
+    assert!(supertypes.iter().any(|(name, _)| *name == "Other"));
+    assert_eq!(derived.subtypes.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), vec!["MoreDerived"]);
+}
+
+
+#[test]
+fn virtual_method_overrides() {
+    let mut repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add("defs.h", r"
+class Shape {
+public:
+    virtual int area() const = 0;
+};
+class Square : public Shape {
+public:
+    int area() const override;
+};
+").unwrap();
+    repo_writer.add_cpp_unit("square.cpp", r#"
+#include "defs.h"
+int Square::area() const { return 4; }
+"#).unwrap();
+    repo_writer.add_cpp_unit("main.cpp", r#"
+#include "defs.h"
+int total(Shape *s) { return s->area(); }
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+
+    let mut walker = repo_writer.index_repo();
+    walker.follow_token("main.cpp");
+    walker.follow_token("total");
+    walker.follow_token("area");
+    assert!(walker.node().text.starts_with("class Shape {"));
+
+    let refs = walker.token_references("area");
+    assert_eq!(refs.refs.len(), 1, "{:?}", refs);
+    assert_eq!(refs.overridden_by.len(), 1, "{:?}", refs);
+    let overriding = &refs.overridden_by[0];
+    assert_eq!(overriding.name, "Square::area");
+
+    walker.go_to_node(GenHref::NodeId(overriding.href.as_ref().unwrap().node_id));
+    assert!(walker.node().text.starts_with("int Square::area() const {"));
+    let refs = walker.token_references("area");
+    assert_eq!(refs.overrides.iter().map(|o| o.name.as_str()).collect::<Vec<_>>(), vec!["Shape::area"]);
+}
@@ -0,0 +1,65 @@
+use testdir::testdir;
+
+use clangrs::index_reader::IndexReader;
+use clangrs::testlib::RepoWriter;
+use territory_core::text_search::TextSearchOptions;
+use territory_core::GenHref;
+
+
+fn index_greeter() -> IndexReader {
+    let repo_writer = RepoWriter::new(&testdir!());
+    repo_writer.add("greet.c", r#"
+// TODO: localize the greeting
+const char *greeting() {
+    return "Hello, world";
+}
+
+int main() {
+    return greeting()[0] == 'H';
+}
+"#).unwrap();
+    repo_writer.write_clang_compile_commands().unwrap();
+    let walker = repo_writer.index_repo();
+    IndexReader::open(walker.index_path(), "test_repo", "test_build").unwrap()
+}
+
+
+#[test]
+fn literal_search() {
+    let reader = index_greeter();
+
+    let matches = reader.search_text("hello, WORLD", &TextSearchOptions::default()).unwrap();
+    let lines: Vec<_> = matches.iter().map(|m| (m.path.as_str(), m.line, m.text.trim())).collect();
+    assert_eq!(lines, [("greet.c", 4, "return \"Hello, world\";")]);
+
+    let case_sensitive = TextSearchOptions { case_sensitive: true, ..Default::default() };
+    assert!(reader.search_text("hello, WORLD", &case_sensitive).unwrap().is_empty());
+
+    // comments are part of the node text too
+    let todos = reader.search_text("TODO:", &TextSearchOptions::default()).unwrap();
+    assert_eq!(todos.len(), 1);
+    assert_eq!(todos[0].line, 2);
+}
+
+
+#[test]
+fn regex_search() {
+    let reader = index_greeter();
+
+    let options = TextSearchOptions { regex: true, ..Default::default() };
+    let matches = reader.search_text(r"greet\w*\(\)", &options).unwrap();
+    let lines: Vec<_> = matches.iter().map(|m| m.line).collect();
+    assert_eq!(lines, [3, 8]);
+
+    assert!(reader.search_text(".*", &options).is_err(), "patterns without trigrams are rejected");
+}
+
+
+#[test]
+fn trigram_hrefs_resolve() {
+    let reader = index_greeter();
+
+    let trigram = u32::from_be_bytes([0, b'w', b'o', b'r']);
+    assert!(reader.resolve_href(&GenHref::Trigram(trigram)).is_ok());
+    assert!(reader.resolve_href(&GenHref::Trigram(u32::from_be_bytes([0, b'#', b'#', b'#']))).is_err());
+}
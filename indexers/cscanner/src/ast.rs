@@ -3,14 +3,14 @@ use std::{collections::HashMap, path::PathBuf};
 use serde::{Serialize, Deserialize};
 
 use territory_core::{
-    AbsolutePath, GToken, Location, NodeKind, Offset, RelativePath
+    AbsolutePath, GToken, Location, NodeKind, Offset, RelativePath, TokenKind
 };
 
 
 pub type TransportID = u64;
 
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct ClangCommand {
     pub index: u64,
     pub file: PathBuf,
@@ -34,6 +34,15 @@ pub struct Block {
     pub context: ClangNodeContext,
 }
 
+/// Text of the comment token that ends a block cut at `max_block_len` tokens.
+pub const TRUNCATED_MARKER: &str = "<TRUNCATED>";
+
+impl Block {
+    pub fn is_truncated(&self) -> bool {
+        self.text.last().is_some_and(|tok| tok.type_ == TokenKind::Comment && tok.text == TRUNCATED_MARKER)
+    }
+}
+
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClangTokenContext {
@@ -60,6 +69,34 @@ pub struct ClangNodeContext {
     pub nested: Option<Vec<(Location, Location, TransportID)>>,
     pub nest_level: usize,
     pub is_forward_decl: bool,
+    /// Direct base classes, for C++ class definitions
+    #[serde(default)]
+    pub bases: Vec<BaseClass>,
+    /// Methods overridden by a virtual method definition
+    #[serde(default)]
+    pub overrides: Vec<OverriddenMethod>,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BaseClass {
+    #[serde(rename="u")]
+    pub usr: Option<String>,
+    #[serde(rename="n")]
+    pub name: String,
+}
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OverriddenMethod {
+    #[serde(rename="u")]
+    pub usr: Option<String>,
+    /// Qualified name, e.g. `ns::Base::f`
+    #[serde(rename="n")]
+    pub name: String,
+    /// Declaration of the method, used when it has no definition
+    #[serde(rename="d")]
+    pub declaration: Option<LocalDefinitionLocation>,
 }
 
 
@@ -739,6 +776,29 @@ pub struct Sem {
     pub display_name: Option<String>,
     #[serde(rename="C")]
     pub curloc: String,
+    /// Set on macro expansion cursors
+    #[serde(rename="x", default)]
+    pub macro_expansion: Option<MacroExpansion>,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacroExpansion {
+    #[serde(rename="t")]
+    pub text: String,
+    #[serde(rename="s")]
+    pub steps: Vec<MacroExpansionStep>,
+}
+
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacroExpansionStep {
+    #[serde(rename="n")]
+    pub name: String,
+    #[serde(rename="l")]
+    pub depth: u32,
+    #[serde(rename="d")]
+    pub definition: Option<LocalDefinitionLocation>,
 }
 
 
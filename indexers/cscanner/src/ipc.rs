@@ -19,10 +19,35 @@ pub struct LockGrant {
     pub already_processed: HashSet<RelativePath>,
 }
 
+/// `#include` directive of a TU.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Include {
+    pub includer: RelativePath,
+    pub included: RelativePath,
+    /// File name as written in the directive
+    pub spelling: String,
+    /// Include path the file was found through, if it can be told from the
+    /// spelling
+    pub search_path: Option<RelativePath>,
+}
+
+/// Diagnostics clang reported while parsing a TU.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DiagnosticCounts {
+    pub errors: usize,
+    pub warnings: usize,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Control {
     Next,
-    TUDone { source_set: HashSet<RelativePath> },
+    TUDone {
+        source_set: HashSet<RelativePath>,
+        #[serde(default)]
+        includes: Vec<Include>,
+        #[serde(default)]
+        diagnostics: DiagnosticCounts,
+    },
     GotBlock { block: Block },
     GotCommands { commands: Vec<ClangCommand> },
     Reschedule { command: ClangCommand },
@@ -4,6 +4,7 @@ pub mod source;
 pub mod ast;
 pub mod ipc;
 pub mod commands;
+mod macros;
 
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
@@ -17,9 +18,10 @@ use std::os::unix::net::UnixStream;
 use std::time::{Duration, Instant};
 
 use clap::Parser;
-use ipc::{log, LockGrant, ScanOpts};
+use ipc::{log, DiagnosticCounts, Include, LockGrant, ScanOpts};
 use itertools::Itertools;
 use clang::EntityKind;
+use clang::diagnostic::Severity;
 use if_chain::if_chain;
 use rand::{thread_rng, RngCore};
 
@@ -31,7 +33,8 @@ use crate::source::{
     RangeLocations,
     clang_file_path, curloc, find_root, from_clang_location, from_clang_token_kind,
 };
-use crate::ast::{TransportID, Sem, Block, ClangCommand, ClangNodeContext, ClangTokenContext, LocalDefinitionLocation};
+use crate::ast::{TransportID, Sem, BaseClass, OverriddenMethod, Block, TRUNCATED_MARKER, ClangCommand, ClangNodeContext, ClangTokenContext, LocalDefinitionLocation};
+use crate::macros::MacroTable;
 use crate::ipc::{USDriverConn, ScannerSays, DriverConn, DriverSays, Control};
 
 
@@ -177,8 +180,9 @@ pub fn cut_tu<'tu, 'p>(
 ) {
     let lock_grant = lock_files(&driver_conn, files_in_tu.clone());
 
+    let macros = MacroTable::collect(repo_path, tu);
     let mut visited = HashSet::new();
-    cut_file(Arc::clone(&driver_conn), repo_path, file, tu, &lock_grant, &mut visited, opts);
+    cut_file(Arc::clone(&driver_conn), repo_path, file, tu, &macros, &lock_grant, &mut visited, opts);
 }
 
 fn lock_files(
@@ -200,22 +204,48 @@ fn lock_files(
 fn collect_file_tree<'tu>(
     repo_path: &'tu Path,
     result: &mut HashSet<RelativePath>,
+    includes: &mut Vec<Include>,
     tu: &'tu clang::TranslationUnit<'tu>,
     file: &clang::source::File<'tu>
 ) {
     let path = clang_file_path(&file);
     let rel_path = path.to_relative(&repo_path);
     if result.contains(&rel_path) { return; }
-    result.insert(rel_path);
+    result.insert(rel_path.clone());
 
     for incl in file.get_includes() {
         if let Some(f) = incl.get_file() {
-            collect_file_tree(&repo_path, result, tu, &f);
+            includes.push(include_edge(repo_path, &rel_path, &incl, &f));
+            collect_file_tree(&repo_path, result, includes, tu, &f);
         }
     }
 }
 
 
+fn include_edge(
+    repo_path: &Path,
+    includer: &RelativePath,
+    directive: &clang::Entity,
+    file: &clang::source::File,
+) -> Include {
+    let path = clang_file_path(file);
+    let spelling = directive.get_name().unwrap_or_default();
+    // "/usr/include/sys/types.h" included as "sys/types.h" was found through "/usr/include"
+    let search_path = path.as_ref().to_str()
+        .and_then(|p| p.strip_suffix(spelling.as_str()))
+        .and_then(|dir| dir.strip_suffix('/'))
+        .filter(|dir| !dir.is_empty() && !spelling.is_empty())
+        .map(|dir| AbsolutePath::from(PathBuf::from(dir)).to_relative(repo_path));
+
+    Include {
+        includer: includer.clone(),
+        included: path.to_relative(repo_path),
+        spelling,
+        search_path,
+    }
+}
+
+
 enum FileType {
     C,
     Asm,
@@ -265,7 +295,13 @@ pub fn scan_clang_command(
 
     let res = match parse_result {
         Ok(tu) => {
+            let mut diagnostics = DiagnosticCounts::default();
             for diag in tu.get_diagnostics() {
+                match diag.get_severity() {
+                    Severity::Error | Severity::Fatal => { diagnostics.errors += 1; },
+                    Severity::Warning => { diagnostics.warnings += 1; },
+                    Severity::Ignored | Severity::Note => {},
+                }
                 log(&driver_conn, &diag.formatter().format());
                 // log(&driver_conn, &format!(
                 //     "{:?} {:?} {}",
@@ -278,7 +314,8 @@ pub fn scan_clang_command(
             let f = tu.get_file(&command.file).expect(&format!("file missing from TU: {:?}", command.file));
 
             let mut files_in_tu = HashSet::new();
-            collect_file_tree(&repo_path, &mut files_in_tu, &tu, &f);
+            let mut includes = Vec::new();
+            collect_file_tree(&repo_path, &mut files_in_tu, &mut includes, &tu, &f);
 
             cut_tu(Arc::clone(&driver_conn), repo_path, &files_in_tu, f, &tu, opts);
             // let cut_elapsed = start.elapsed();
@@ -287,7 +324,7 @@ pub fn scan_clang_command(
             // let start = std::time::Instant::now();
             {
                 let mut l = driver_conn.lock().unwrap();
-                l.send(ScannerSays::Control(Control::TUDone { source_set: files_in_tu }))?;
+                l.send(ScannerSays::Control(Control::TUDone { source_set: files_in_tu, includes, diagnostics }))?;
                 let res = l.receive()?;
                 assert!(res.is_continue());
             }
@@ -315,6 +352,7 @@ fn cut_file<'tu, 'p>(
     repo_path: &'tu Path,
     file: clang::source::File<'tu>,
     tu: &'tu clang::TranslationUnit<'tu>,
+    macros: &MacroTable,
     lock_grant: &LockGrant,
     visited: &mut HashSet<RelativePath>,
     opts: &ScanOpts,
@@ -337,7 +375,7 @@ fn cut_file<'tu, 'p>(
 
     for incl in file.get_includes() {
         if let Some(f) = incl.get_file() {
-            cut_file(Arc::clone(&driver_conn), repo_path, f, tu, lock_grant, visited, opts);
+            cut_file(Arc::clone(&driver_conn), repo_path, f, tu, macros, lock_grant, visited, opts);
         } else {
             log(&driver_conn, &format!("expected #include to point to a file: {}", curloc(repo_path, &incl)));
         }
@@ -384,7 +422,7 @@ fn cut_file<'tu, 'p>(
                 cur: tu.get_entity(),
             }];
             let mut result = Vec::new();
-            cut_nodes(driver_conn, repo_path, file, tu, &mut result, opts, path, &text, &annotated, &cuts, None, 0);
+            cut_nodes(driver_conn, repo_path, file, tu, macros, &mut result, opts, path, &text, &annotated, &cuts, None, 0);
             // assert!(result.is_empty(), "nest_level=0 should not write results to array");
         },
         FileType::Asm => whole_file(driver_conn, repo_path, tu, opts, flen, path, text, &annotated)
@@ -440,7 +478,7 @@ fn whole_file<'tu>(
             let gt = GToken {
                 offset: end.off,
                 line: end.line,
-                text: "<TRUNCATED>".to_string(),
+                text: TRUNCATED_MARKER.to_string(),
                 type_: TokenKind::Comment,
                 context: ClangTokenContext::Token { sem: None, start: *end, end: *end },
             };
@@ -469,6 +507,8 @@ fn whole_file<'tu>(
             nested: None,
             nest_level: 0,
             is_forward_decl: false,
+            bases: Vec::new(),
+            overrides: Vec::new(),
         },
     };
     insert_whitespace(&mut source_file_node, &text);
@@ -604,6 +644,7 @@ fn cut_nodes<'tu>(
     repo_path: &Path,
     file: clang::source::File<'tu>,
     tu: &'tu clang::TranslationUnit<'tu>,
+    macros: &MacroTable,
     // sent_sems: HashSet<TransportID>,
     result: &mut Vec<(Location, Location, TransportID)>,
     opts: &ScanOpts,
@@ -652,7 +693,10 @@ fn cut_nodes<'tu>(
                         sem: at.cur.map(|cur| {
                             let h = cur_hash(&cur);
                             if block_sems.contains_key(&h) { return h; }
-                            let sem = cur_to_sem(repo_path, &cur, &definition_context);
+                            let mut sem = cur_to_sem(repo_path, &cur, &definition_context);
+                            if cur.get_kind() == EntityKind::MacroExpansion {
+                                sem.macro_expansion = macros.expand(repo_path, &cur);
+                            }
                             block_sems.insert(h, sem);
                             return h;
                         }),
@@ -671,7 +715,7 @@ fn cut_nodes<'tu>(
                     let gt = GToken {
                         offset: at.end.off,
                         line: at.end.line,
-                        text: "<TRUNCATED>".to_string(),
+                        text: TRUNCATED_MARKER.to_string(),
                         type_: TokenKind::Comment,
                         context: ClangTokenContext::Token { sem: None, start: at.end, end: at.end },
                     };
@@ -717,7 +761,9 @@ fn cut_nodes<'tu>(
                 is_forward_decl: {
                     let d = cut.cur.get_definition();
                     d.is_some() && d != Some(cut.cur)
-                }
+                },
+                bases: base_classes(&cut.cur),
+                overrides: overridden_methods(repo_path, &cut.cur),
             },
         };
 
@@ -744,6 +790,7 @@ fn cut_nodes<'tu>(
                     repo_path,
                     file,
                     tu,
+                    macros,
                     &mut nested_result,
                     opts,
                     path.clone(),
@@ -982,7 +1029,10 @@ fn cur_to_sem(repo_dir: &Path, cur: &clang::Entity, definition_context: &Vec<Str
     };
     let defn = ref_.and_then(|defn_cur| {
         let kind = defn_cur.get_kind();
-        if ![VarDecl, MacroDefinition].contains(&kind) && !defn_cur.is_definition() || kind == Namespace
+        // pure virtual methods have no definition, link to the declaration
+        // so that the overrides can be found from there
+        if ![VarDecl, MacroDefinition].contains(&kind) && !defn_cur.is_definition() && !defn_cur.is_pure_virtual_method()
+            || kind == Namespace
         {
             return None;
         }
@@ -1021,7 +1071,50 @@ fn cur_to_sem(repo_dir: &Path, cur: &clang::Entity, definition_context: &Vec<Str
         definition_context: definition_context.clone(),
         display_name: cur.get_display_name().map(Into::into),
         curloc: curloc(repo_dir, cur),
+        macro_expansion: None,
+    }
+}
+
+
+fn base_classes(cur: &clang::Entity) -> Vec<BaseClass> {
+    if !cur.is_definition() {
+        return Vec::new();
+    }
+
+    cur.get_children()
+        .into_iter()
+        .filter(|c| c.get_kind() == EntityKind::BaseSpecifier)
+        .filter_map(|base| {
+            let ty = base.get_type()?;
+            // link specializations (`Base<int>`) to the template they come from
+            let decl = ty.get_declaration().map(|d| d.get_template().unwrap_or(d));
+            Some(BaseClass {
+                usr: decl.and_then(|d| d.get_usr()).map(|clang::Usr(u)| u),
+                name: ty.get_display_name(),
+            })
+        })
+        .collect()
+}
+
+
+fn overridden_methods(repo_dir: &Path, cur: &clang::Entity) -> Vec<OverriddenMethod> {
+    if cur.get_kind() != EntityKind::Method || !cur.is_definition() || !cur.is_virtual_method() {
+        return Vec::new();
     }
+
+    cur.get_overridden_methods()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|m| {
+            let mut name = get_definition_context(m);
+            name.reverse();
+            OverriddenMethod {
+                usr: m.get_usr().map(|clang::Usr(u)| u),
+                name: name.join("::"),
+                declaration: query_cur_location(repo_dir, &m),
+            }
+        })
+        .collect()
 }
 
 
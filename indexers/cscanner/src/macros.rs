@@ -0,0 +1,420 @@
+//! Expansion of macro invocations.
+//!
+//! libclang doesn't expose what the preprocessor expanded an invocation to, so
+//! it is expanded again from the definitions seen in the TU: arguments are
+//! substituted (including `#` and `##`) and the result is rescanned with the
+//! macros being expanded disabled. Macros redefined within a TU are expanded
+//! with their last definition, except for the invoked macro itself.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use clang::{Entity, EntityKind, TranslationUnit};
+use if_chain::if_chain;
+
+use crate::ast::{LocalDefinitionLocation, MacroExpansion, MacroExpansionStep, TRUNCATED_MARKER};
+use crate::query_cur_location;
+
+
+// bounds the work done for runaway expansions; token lists are cut at
+// MAX_TOKENS wherever they grow
+const MAX_STEPS: usize = 1000;
+const MAX_TOKENS: usize = 10_000;
+
+
+struct MacroDefinition {
+    /// `None` for object-like macros
+    params: Option<Vec<String>>,
+    variadic: bool,
+    body: Vec<String>,
+    location: Option<LocalDefinitionLocation>,
+}
+
+impl MacroDefinition {
+    fn from_entity(repo_dir: &Path, cur: &Entity) -> Option<Self> {
+        let name = cur.get_name()?;
+        let range = cur.get_range()?;
+        let end_line = range.get_end().get_spelling_location().line;
+        // older libclang versions tokenize one token past the end of the
+        // definition, which is on the next line
+        let tokens = range
+            .tokenize()
+            .into_iter()
+            .filter(|tok| tok.get_location().get_spelling_location().line <= end_line)
+            .map(|tok| tok.get_spelling())
+            .collect::<Vec<_>>();
+        let start = tokens.iter().position(|tok| *tok == name)?;
+
+        Some(Self::parse(&tokens[start + 1..], cur.is_function_like_macro(), query_cur_location(repo_dir, cur)))
+    }
+
+    /// `tokens` follow the macro name
+    fn parse(tokens: &[String], function_like: bool, location: Option<LocalDefinitionLocation>) -> Self {
+        if !function_like {
+            return Self { params: None, variadic: false, body: tokens.to_vec(), location };
+        }
+
+        let close = tokens.iter().position(|tok| tok == ")").unwrap_or(tokens.len());
+        let mut params = Vec::new();
+        let mut variadic = false;
+        for param in tokens.get(1..close).unwrap_or_default().split(|tok| tok == ",") {
+            match param {
+                [dots] if dots == "..." => {
+                    variadic = true;
+                    params.push("__VA_ARGS__".to_string());
+                },
+                [name, dots] if dots == "..." => {
+                    variadic = true;
+                    params.push(name.clone());
+                },
+                [name] => params.push(name.clone()),
+                _ => {},
+            }
+        }
+
+        Self {
+            params: Some(params),
+            variadic,
+            body: tokens.get(close + 1..).unwrap_or_default().to_vec(),
+            location,
+        }
+    }
+
+    fn is_param(&self, tok: &str) -> bool {
+        self.params.as_ref().is_some_and(|params| params.iter().any(|p| p == tok))
+    }
+}
+
+
+#[derive(Default)]
+pub struct MacroTable {
+    definitions: HashMap<String, MacroDefinition>,
+}
+
+impl MacroTable {
+    pub fn collect(repo_dir: &Path, tu: &TranslationUnit) -> Self {
+        let mut definitions = HashMap::new();
+        for cur in tu.get_entity().get_children() {
+            if cur.get_kind() != EntityKind::MacroDefinition {
+                continue;
+            }
+            let (Some(name), Some(definition)) = (cur.get_name(), MacroDefinition::from_entity(repo_dir, &cur)) else {
+                continue;
+            };
+            definitions.insert(name, definition);
+        }
+        Self { definitions }
+    }
+
+    /// Expands the invocation at a `MacroExpansion` cursor. Built-in macros
+    /// aren't expanded.
+    pub fn expand(&self, repo_dir: &Path, cur: &Entity) -> Option<MacroExpansion> {
+        let name = cur.get_name()?;
+        let definition = MacroDefinition::from_entity(repo_dir, &cur.get_reference()?)?;
+        let tokens = cur
+            .get_range()?
+            .tokenize()
+            .into_iter()
+            .map(|tok| tok.get_spelling())
+            .collect::<Vec<_>>();
+
+        self.expand_invocation(&name, &definition, tokens.get(1..).unwrap_or_default())
+    }
+
+    fn expand_invocation(&self, name: &str, definition: &MacroDefinition, input: &[String]) -> Option<MacroExpansion> {
+        let mut expander = Expander { table: self, steps: Vec::new(), truncated: false };
+        let (mut tokens, _) = expander.expand_macro(name, definition, input, &HashSet::new(), 0)?;
+        if expander.truncated {
+            tokens.push(TRUNCATED_MARKER.to_string());
+        }
+        Some(MacroExpansion { text: join_tokens(&tokens), steps: expander.steps })
+    }
+}
+
+
+struct Expander<'t> {
+    table: &'t MacroTable,
+    steps: Vec<MacroExpansionStep>,
+    /// Set once tokens were dropped at `MAX_TOKENS`
+    truncated: bool,
+}
+
+impl<'t> Expander<'t> {
+    /// Appends `tokens` to `out` up to `MAX_TOKENS`.
+    fn extend(&mut self, out: &mut Vec<String>, tokens: impl IntoIterator<Item=String>) {
+        let mut tokens = tokens.into_iter();
+        out.extend(tokens.by_ref().take(MAX_TOKENS.saturating_sub(out.len())));
+        if tokens.next().is_some() {
+            self.truncated = true;
+        }
+    }
+
+    /// Expands `definition` invoked with `input` following its name. Returns
+    /// the expanded tokens and the number of input tokens taken as arguments,
+    /// or `None` if a function-like macro isn't followed by arguments.
+    fn expand_macro(
+        &mut self,
+        name: &str,
+        definition: &MacroDefinition,
+        input: &[String],
+        disabled: &HashSet<String>,
+        depth: u32,
+    ) -> Option<(Vec<String>, usize)> {
+        let (args, consumed) = match definition.params {
+            Some(_) => collect_args(input)?,
+            None => (Vec::new(), 0),
+        };
+
+        self.steps.push(MacroExpansionStep {
+            name: name.to_string(),
+            depth,
+            definition: definition.location.clone(),
+        });
+
+        let substituted = self.substitute(definition, &args, disabled, depth);
+        let mut disabled = disabled.clone();
+        disabled.insert(name.to_string());
+
+        Some((self.rescan(&paste(substituted), &disabled, depth + 1), consumed))
+    }
+
+    fn rescan(&mut self, tokens: &[String], disabled: &HashSet<String>, depth: u32) -> Vec<String> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let tok = &tokens[i];
+            if_chain! {
+                if self.steps.len() < MAX_STEPS && out.len() < MAX_TOKENS;
+                if !disabled.contains(tok);
+                if let Some(definition) = self.table.definitions.get(tok);
+                if let Some((expanded, consumed)) = self.expand_macro(tok, definition, &tokens[i + 1..], disabled, depth);
+                then {
+                    self.extend(&mut out, expanded);
+                    i += 1 + consumed;
+                    continue;
+                }
+            }
+            self.extend(&mut out, [tok.clone()]);
+            i += 1;
+        }
+        out
+    }
+
+    fn substitute(
+        &mut self,
+        definition: &MacroDefinition,
+        args: &[Vec<String>],
+        disabled: &HashSet<String>,
+        depth: u32,
+    ) -> Vec<String> {
+        let params = definition.params.as_deref().unwrap_or_default();
+        let bound: HashMap<&str, Vec<String>> = params
+            .iter()
+            .enumerate()
+            .map(|(i, param)| {
+                let arg = if definition.variadic && i == params.len() - 1 {
+                    args.get(i..).unwrap_or_default().join(&",".to_string())
+                } else {
+                    args.get(i).cloned().unwrap_or_default()
+                };
+                (param.as_str(), arg)
+            })
+            .collect();
+
+        // arguments are expanded once, however often they're used
+        let mut expanded: HashMap<&str, Vec<String>> = HashMap::new();
+
+        let body = &definition.body;
+        let mut out = Vec::new();
+        let mut i = 0;
+        while i < body.len() {
+            let tok = &body[i];
+            let next = body.get(i + 1);
+            if tok == "#" && next.is_some_and(|n| definition.is_param(n)) {
+                self.extend(&mut out, [stringify(&bound[next.unwrap().as_str()])]);
+                i += 2;
+                continue;
+            }
+            if let Some(arg) = bound.get(tok.as_str()) {
+                let pasted = i > 0 && body[i - 1] == "##" || next.is_some_and(|n| n == "##");
+                if pasted {
+                    // placeholder, so that pasting with an empty argument
+                    // leaves the other operand
+                    if arg.is_empty() {
+                        self.extend(&mut out, [String::new()]);
+                    }
+                    self.extend(&mut out, arg.iter().cloned());
+                } else {
+                    if !expanded.contains_key(tok.as_str()) {
+                        let tokens = self.rescan(arg, disabled, depth + 1);
+                        expanded.insert(tok.as_str(), tokens);
+                    }
+                    self.extend(&mut out, expanded[tok.as_str()].iter().cloned());
+                }
+            } else {
+                self.extend(&mut out, [tok.clone()]);
+            }
+            i += 1;
+        }
+        out
+    }
+}
+
+
+/// Splits the parenthesized arguments at the start of `input`. Returns the
+/// arguments and the number of tokens they span.
+fn collect_args(input: &[String]) -> Option<(Vec<Vec<String>>, usize)> {
+    if input.first()? != "(" {
+        return None;
+    }
+
+    let mut args = vec![Vec::new()];
+    let mut nesting = 0;
+    for (i, tok) in input.iter().enumerate().skip(1) {
+        match tok.as_str() {
+            ")" if nesting == 0 => return Some((args, i + 1)),
+            "," if nesting == 0 => { args.push(Vec::new()); continue; },
+            "(" => nesting += 1,
+            ")" => nesting -= 1,
+            _ => {},
+        }
+        args.last_mut().unwrap().push(tok.clone());
+    }
+    None
+}
+
+
+fn paste(tokens: Vec<String>) -> Vec<String> {
+    let mut out: Vec<String> = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(tok) = tokens.next() {
+        match (tok.as_str(), out.last_mut(), tokens.peek()) {
+            ("##", Some(lhs), Some(_)) => lhs.push_str(&tokens.next().unwrap()),
+            _ => out.push(tok),
+        }
+    }
+    out.retain(|tok| !tok.is_empty());
+    out
+}
+
+
+fn stringify(tokens: &[String]) -> String {
+    format!("\"{}\"", join_tokens(tokens).replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+
+fn is_word(tok: &str) -> bool {
+    tok.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+}
+
+
+/// Joins tokens the way code is usually formatted.
+fn join_tokens(tokens: &[String]) -> String {
+    const NO_SPACE_AFTER: &[&str] = &["(", "[", ".", "->", "~", "!"];
+    const NO_SPACE_BEFORE: &[&str] = &[")", "]", ",", ";", ".", "->"];
+
+    let mut text = String::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        if i > 0 {
+            let prev = tokens[i - 1].as_str();
+            let space = if NO_SPACE_AFTER.contains(&prev) || NO_SPACE_BEFORE.contains(&tok.as_str()) {
+                false
+            } else if tok == "(" || tok == "[" {
+                !is_word(prev) && prev != ")" && prev != "]"
+            } else {
+                true
+            };
+            if space {
+                text.push(' ');
+            }
+        }
+        text.push_str(tok);
+    }
+    text
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::ast::TRUNCATED_MARKER;
+    use super::{MacroDefinition, MacroTable, MAX_TOKENS};
+
+    fn toks(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    fn table(defs: &[(&str, bool, &str)]) -> MacroTable {
+        let mut table = MacroTable::default();
+        for (name, function_like, tokens) in defs {
+            table.definitions.insert(name.to_string(), MacroDefinition::parse(&toks(tokens), *function_like, None));
+        }
+        table
+    }
+
+    fn expand(table: &MacroTable, name: &str, input: &str) -> (String, Vec<(String, u32)>) {
+        let expansion = table
+            .expand_invocation(name, &table.definitions[name], &toks(input))
+            .expect("expected an expansion");
+        (expansion.text, expansion.steps.into_iter().map(|s| (s.name, s.depth)).collect())
+    }
+
+    #[test]
+    fn object_like() {
+        let t = table(&[("SIZE", false, "( 4 * 1024 )")]);
+        assert_eq!(expand(&t, "SIZE", ""), ("(4 * 1024)".to_string(), vec![("SIZE".to_string(), 0)]));
+    }
+
+    #[test]
+    fn nested() {
+        let t = table(&[
+            ("MAX", true, "( a , b ) ( ( a ) > ( b ) ? ( a ) : ( b ) )"),
+            ("LIMIT", false, "MAX ( LOW , 10 )"),
+            ("LOW", false, "2"),
+        ]);
+        let (text, steps) = expand(&t, "LIMIT", "");
+        assert_eq!(text, "((2) > (10) ? (2) : (10))");
+        assert_eq!(steps, vec![
+            ("LIMIT".to_string(), 0),
+            ("MAX".to_string(), 1),
+            ("LOW".to_string(), 2),
+        ]);
+    }
+
+    #[test]
+    fn stringify_and_paste() {
+        let t = table(&[("DECL", true, "( type , name ) type name ## _v = # name")]);
+        assert_eq!(expand(&t, "DECL", "( int , x ) ;").0, "int x_v = \"x\"");
+    }
+
+    #[test]
+    fn variadic() {
+        let t = table(&[("LOG", true, "( fmt , ... ) printf ( fmt , __VA_ARGS__ )")]);
+        assert_eq!(expand(&t, "LOG", "( \"%d %d\" , a , f ( b , c ) )").0, "printf(\"%d %d\", a, f(b, c))");
+    }
+
+    #[test]
+    fn no_recursion() {
+        let t = table(&[("foo", false, "foo + 1")]);
+        assert_eq!(expand(&t, "foo", "").0, "foo + 1");
+    }
+
+    #[test]
+    fn runaway_expansion_is_truncated() {
+        let t = table(&[("D", true, "( x ) x x")]);
+        let input = format!("( {} 1 {} )", "D ( ".repeat(30), ") ".repeat(30));
+        let (text, _) = expand(&t, "D", &input);
+        let tokens: Vec<_> = text.split(' ').collect();
+        assert_eq!(tokens.len(), MAX_TOKENS + 1);
+        assert_eq!(tokens.last(), Some(&TRUNCATED_MARKER));
+    }
+
+    #[test]
+    fn function_like_without_args() {
+        let t = table(&[
+            ("F", true, "( x ) x"),
+            ("G", false, "F"),
+        ]);
+        assert_eq!(expand(&t, "G", "").0, "F");
+        assert!(t.expand_invocation("F", &t.definitions["F"], &toks("; x")).is_none());
+    }
+}
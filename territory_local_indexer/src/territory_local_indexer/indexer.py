@@ -1,10 +1,11 @@
+import json
+import os
 from dataclasses import dataclass
 from pathlib import Path
 from random import choices
 from string import ascii_lowercase
 from shutil import rmtree
-from subprocess import Popen, check_call, check_output
-from time import sleep
+from subprocess import CalledProcessError, Popen, check_call, check_output
 from typing import Literal
 
 from .configure import MetaConf
@@ -73,41 +74,19 @@ def index(
 
 
 def _index_c(env: BuildEnv):
-    sock_path = env.index_dir / 'indexer.sock'
-
-    indexer_proc = None
-    scanner_proc = None
-    try:
-        indexer_proc = Popen([
-            'clangrs',
-            '--repo', str(env.code_dir),
-            '--repo-id', env.repo_id,
-            '--build-id', env.build_id,
-            '--storage-mode', 'file',
-            '--intermediate-path', str(env.index_dir / 'work'),
-            '--db-path', str(env.index_dir / 'db'),
-            '--scanner-socket-path', str(sock_path),
-            '--outdir', str(env.mconf.ttdir / 'graph'),
-            '--log-dir', str(env.index_dir / 'logs'),
-            '--compression', 'none',
-        ])
-
-        while not sock_path.exists():
-            sleep(1)
-
-        args = [
-            'cscanner',
-            '--repo-path', str(env.code_dir),
-            '--sock', str(sock_path),
-            '--compile-commands-dir', str(env.code_dir),
-        ]
-        scanner_proc = Popen(args)
-
-    finally:
-        if indexer_proc and indexer_proc.wait() != 0:
-            raise RuntimeError('indexer failed')
-        if scanner_proc and scanner_proc.wait() != 0:
-            raise RuntimeError('scanner failed')
+    _run_with_progress([
+        'clangrs',
+        '--repo', str(env.code_dir),
+        '--repo-id', env.repo_id,
+        '--build-id', env.build_id,
+        '--storage-mode', 'file',
+        '--intermediate-path', str(env.index_dir / 'work'),
+        '--db-path', str(env.index_dir / 'db'),
+        '--scanner-socket-path', str(env.index_dir / 'indexer.sock'),
+        '--outdir', str(env.mconf.ttdir / 'graph'),
+        '--log-dir', str(env.index_dir / 'logs'),
+        '--compression', 'none',
+    ])
 
 
 def _index_go(env: BuildEnv):
@@ -156,5 +135,25 @@ def _index_python(env: BuildEnv):
     ])
 
 
+def _run_with_progress(cmd: list[str]):
+    read_fd, write_fd = os.pipe()
+    with Popen(cmd + ['--status-fd', str(write_fd)], pass_fds=[write_fd]) as proc:
+        os.close(write_fd)
+        with os.fdopen(read_fd) as status:
+            for line in status:
+                _show_progress(json.loads(line))
+    if proc.returncode:
+        raise CalledProcessError(proc.returncode, cmd)
+
+
+def _show_progress(event: dict):
+    if event['event'] == 'stage':
+        print(f"{event['stage']} stage {event['state']}")
+    elif event['event'] == 'tus':
+        eta = event['eta_secs']
+        left = f", about {eta}s left" if eta is not None else ''
+        print(f"parsed {event['done']} of {event['queued']} translation units{left}")
+
+
 def _get_branch(code_dir: Path):
     return check_output(['git', 'branch', '--show-current'], cwd=code_dir, encoding='utf8').strip()